@@ -2,6 +2,8 @@ extern crate bufstream;
 extern crate chrono;
 extern crate hyper;
 extern crate mogilefs_common;
+extern crate net2;
+extern crate openssl;
 extern crate rand;
 extern crate statsd;
 extern crate url;
@@ -14,17 +16,405 @@ extern crate log;
 extern crate lazy_static;
 
 use bufstream::BufStream;
-use chrono::UTC;
+use chrono::{DateTime, Duration, TimeZone, UTC};
+use hyper::client::Body;
+use hyper::header::{ByteRangeSpec, ContentEncoding, ContentLength, Encoding, HttpDate, IfModifiedSince, Range, UserAgent};
 use hyper::status::StatusCode;
-use mogilefs_common::{Request, Response, MogError, MogResult, BufReadMb, ToArgs, ToUrlencodedString};
+use mogilefs_common::{Request, Response, MogError, MogResult, BufReadMb, OpSpec, ToArgs, TRACE_ID_ARG, decode_mog_field, url_for_key};
 use mogilefs_common::requests::*;
-use std::io::{self, Read, Write};
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
-use url::percent_encoding;
+use net2::TcpBuilder;
+use openssl::crypto::hash::{Hasher, Type};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::cmp;
+use std::io::{self, BufRead, Cursor, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{self, Instant};
+use std::vec;
+use url::Url;
+use url::form_urlencoded::{byte_serialize, Serializer};
+
+/// The longest key stock MogileFS will store.
+pub const DEFAULT_MAX_KEY_LENGTH: usize = 255;
+
+/// The longest domain name stock MogileFS will store.
+pub const MAX_DOMAIN_LENGTH: usize = 255;
+
+/// How long the reaper waits for the tracker to answer a keepalive by
+/// default; see `MogClient::set_keepalive_timeout`.
+pub const DEFAULT_KEEPALIVE_TIMEOUT_SECS: u64 = 5;
+
+/// Something which can answer raw request lines itself, without going
+/// over the network. The server crate implements this for its
+/// `Tracker`, which lets the client be tested against one in-process
+/// (see `MogClient::in_process`).
+pub trait InProcessTracker: Send {
+    /// Handle a request line (without the trailing CRLF), returning
+    /// the rendered response line (also without one).
+    fn handle_line(&self, request_line: &[u8]) -> String;
+}
+
+/// Something which can read file content itself, without going to a
+/// storage server. The server crate implements this for its mem
+/// backend; see `MogClient::set_in_process_storage`.
+pub trait InProcessStorage: Send {
+    /// The bytes of `key`'s content in `range`, cut short if it runs
+    /// past the end, and empty if it starts past it.
+    fn read_range(&self, domain: &str, key: &str, range: ops::Range<u64>) -> MogResult<Vec<u8>>;
+}
+
+/// Something which wants to know about every request a `MogClient`
+/// makes, such as an audit log. See `MogClient::add_observer`.
+pub trait RequestObserver: Send {
+    /// Called once the response to `req_line` (the request line as it
+    /// was sent, without the trailing CRLF) has arrived, or the
+    /// request has failed, `latency` after it was sent.
+    fn observe(&self, req_line: &str, result: &MogResult<Response>, latency: Duration);
+}
+
+/// The result of a conditional fetch of a file's content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchResult {
+    /// The content was fetched; this many bytes of it.
+    Fetched(u64),
+    /// The content hasn't changed, so none was sent.
+    NotModified,
+}
+
+/// The step of storing a file at which it failed; see `StoreError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreStage {
+    /// Asking the tracker where to put the file. Nothing's been
+    /// stored, so the whole thing can be tried again.
+    Open,
+    /// Uploading the content to the storage servers. The tracker has
+    /// a file open which will never be closed, but trying the whole
+    /// thing again opens a new one.
+    Upload,
+    /// Telling the tracker where the content went. The content's
+    /// already stored, so only this needs trying again.
+    Close,
+}
+
+/// Why a `store_data_staged` failed, and at which step.
+#[derive(Debug)]
+pub struct StoreError {
+    pub stage: StoreStage,
+    pub error: MogError,
+    /// The `create_close` which failed, for a failure at the `Close`
+    /// stage, to resend with `MogClient::request`.
+    pub close: Option<CreateClose>,
+}
+
+impl StoreError {
+    fn new(stage: StoreStage, error: MogError) -> StoreError {
+        StoreError { stage: stage, error: error, close: None }
+    }
+}
+
+impl From<StoreError> for MogError {
+    fn from(err: StoreError) -> MogError {
+        err.error
+    }
+}
+
+/// How many times the client tries a request when it can't get a
+/// response from a tracker, and how long it waits in between.
+///
+/// The wait before each retry is picked at random, anywhere from
+/// nothing up to `base_delay` doubled once for every retry so far but
+/// never more than `max_delay` ("full jitter"), so clients which lost
+/// their tracker at the same moment don't all come back at once. The
+/// default tries three times without waiting at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to try, counting the first.
+    pub attempts: usize,
+    pub base_delay: time::Duration,
+    pub max_delay: time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            base_delay: time::Duration::from_millis(0),
+            max_delay: time::Duration::from_millis(0),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The longest the wait before retry number `retry` (counting
+    /// from zero) can be.
+    pub fn delay_bound(&self, retry: u32) -> time::Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::max_value());
+        let delay = self.base_delay.checked_mul(factor).unwrap_or(self.max_delay);
+        cmp::min(delay, self.max_delay)
+    }
+
+    /// A wait before retry number `retry`, picked uniformly between
+    /// zero and `delay_bound(retry)`.
+    pub fn delay<G: Rng>(&self, retry: u32, rng: &mut G) -> time::Duration {
+        let bound = self.delay_bound(retry);
+        let bound_nanos = bound.as_secs().saturating_mul(1_000_000_000).saturating_add(bound.subsec_nanos() as u64);
+        let nanos = rng.gen_range(0, bound_nanos.saturating_add(1));
+        time::Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+/// What a request does when all the connections its client's
+/// `ConnectionPool` may have are in use, by other clients' requests or
+/// by a reaper's keepalive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolBehavior {
+    /// Wait for as long as it takes (the default).
+    Block,
+    /// Wait at most `timeout`, then fail with
+    /// `MogError::PoolExhausted`.
+    FailFast { timeout: time::Duration },
+}
+
+impl Default for PoolBehavior {
+    fn default() -> PoolBehavior {
+        PoolBehavior::Block
+    }
+}
+
+/// How soon a request gets a connection when it has to wait for one;
+/// see `MogClient::request_with_priority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// The priority of a plain `request`.
+    Normal,
+    /// Ahead of every `Normal` request still waiting, for health
+    /// checks and the like which can't sit behind a slow batch.
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+/// A bounded set of tracker connections, which any number of clients,
+/// on any number of threads, can share; see
+/// `MogClient::set_connection_pool`. Each client starts out with a
+/// pool of its own, of one connection. Connections are opened as
+/// they're needed, and an idle one is reused before another is opened.
+#[derive(Clone)]
+pub struct ConnectionPool(Arc<PoolShared>);
+
+struct PoolShared {
+    state: Mutex<PoolState>,
+    /// Signalled whenever a connection goes back in the pool.
+    freed: Condvar,
+}
+
+struct PoolState {
+    /// The open connections nobody's using, most recently used last.
+    idle: Vec<IdleConnection>,
+    in_use: usize,
+    /// How many connections, idle or in use, are connected.
+    open: usize,
+    max_size: usize,
+    /// The requests waiting for a connection, each with its priority
+    /// and the order it came in. They get one in priority order, and
+    /// in the order they came in within each priority.
+    waiting: Vec<(Priority, u64)>,
+    next_ticket: u64,
+}
+
+impl PoolState {
+    /// An idle connection to `pinned`, if it's given, or else a new
+    /// one, if there's room; or failing that, any idle connection.
+    fn take(&mut self, pinned: Option<SocketAddr>) -> Option<IdleConnection> {
+        let matching = match pinned {
+            Some(addr) => self.idle.iter().rposition(|c| c.peer_addr() == Some(addr)),
+            None if !self.idle.is_empty() => Some(self.idle.len() - 1),
+            None => None,
+        };
+
+        match matching {
+            Some(i) => Some(self.idle.remove(i)),
+            None if self.in_use + self.idle.len() < self.max_size => Some(IdleConnection::new()),
+            None => self.idle.pop(),
+        }
+    }
+}
+
+impl ConnectionPool {
+    /// A pool of at most `max_size` connections (but at least one).
+    pub fn new(max_size: usize) -> ConnectionPool {
+        ConnectionPool(Arc::new(PoolShared {
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                in_use: 0,
+                open: 0,
+                max_size: cmp::max(1, max_size),
+                waiting: Vec::new(),
+                next_ticket: 0,
+            }),
+            freed: Condvar::new(),
+        }))
+    }
+
+    /// How many of the pool's connections are in use right now, by a
+    /// request or by a reaper's keepalive.
+    pub fn in_use(&self) -> usize {
+        self.state().in_use
+    }
+
+    /// How many requests are waiting for a connection.
+    #[cfg(test)]
+    fn waiting(&self) -> usize {
+        self.state().waiting.len()
+    }
+
+    fn state(&self) -> MutexGuard<PoolState> {
+        // Connections are only ever moved in and out whole, so it's
+        // fine to carry on if someone panicked while holding the lock.
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Take a connection out of the pool for a request, waiting for
+    /// one to come back as long as `behavior` allows, behind any
+    /// request of a higher `priority` (or of the same one which was
+    /// waiting first). An idle connection to `pinned`, if it's given,
+    /// is taken first, and then a new one; another tracker's is only
+    /// taken if there's no room for that.
+    fn checkout(&self, behavior: PoolBehavior, pinned: Option<SocketAddr>, priority: Priority) -> MogResult<Checkout> {
+        let deadline = match behavior {
+            PoolBehavior::Block => None,
+            PoolBehavior::FailFast { timeout } => Some(Instant::now() + timeout),
+        };
+        let mut state = self.state();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push((priority, ticket));
+
+        let mut taken = None;
+        loop {
+            let ahead = state.waiting.iter().any(|&(p, t)| p > priority || (p == priority && t < ticket));
+            if !ahead {
+                taken = state.take(pinned);
+                if taken.is_some() {
+                    break;
+                }
+            }
+
+            state = match deadline {
+                None => self.0.freed.wait(state).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    self.0.freed.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0
+                },
+            };
+        }
+
+        // Whoever's next in line may be able to go now.
+        state.waiting.retain(|&(_, t)| t != ticket);
+        self.0.freed.notify_all();
+
+        match taken {
+            Some(conn) => {
+                state.in_use += 1;
+                Ok(Checkout::new(self, conn))
+            },
+            None => {
+                warn!("No tracker connection free after waiting {:?}", behavior);
+                Err(MogError::PoolExhausted)
+            },
+        }
+    }
+
+    /// Reap each of the idle connections in turn (see
+    /// `IdleConnection::reap`). Each is taken out of the pool while
+    /// it's being reaped, so a keepalive keeps it in use.
+    fn reap(&self, max_idle: time::Duration, keepalive: Option<&[u8]>, timeout: time::Duration, delimiter: &[u8]) {
+        let count = self.state().idle.len();
+        for _ in 0..count {
+            let conn = {
+                let mut state = self.state();
+                if state.idle.is_empty() {
+                    break;
+                }
+                state.in_use += 1;
+                state.idle.remove(0)
+            };
+
+            let mut checkout = Checkout::new(self, conn);
+            checkout.conn.reap(max_idle, keepalive, timeout, delimiter);
+        }
+    }
+}
+
+/// Every key in every domain, as (domain, key) pairs, fetched from the
+/// tracker a page at a time; see `MogClient::list_all_keys_iter`.
+pub struct AllKeys<'a> {
+    client: &'a mut MogClient,
+    page: vec::IntoIter<(String, String)>,
+    after: Option<(String, String)>,
+    done: bool,
+}
+
+impl<'a> Iterator for AllKeys<'a> {
+    type Item = MogResult<(String, String)>;
+
+    fn next(&mut self) -> Option<MogResult<(String, String)>> {
+        loop {
+            if let Some(pair) = self.page.next() {
+                self.after = Some(pair.clone());
+                return Some(Ok(pair));
+            }
+
+            if self.done {
+                return None;
+            }
+
+            let req = ListAllKeys {
+                after_domain: self.after.as_ref().map(|&(ref d, _)| d.clone()),
+                after_key: self.after.as_ref().map(|&(_, ref k)| k.clone()),
+                limit: None,
+            };
+
+            match self.client.request(&req).and_then(|r| r.downcast::<ListAllKeysResponse>().ok_or(MogError::BadResponse)) {
+                Ok(page) => {
+                    self.done = page.0.is_empty();
+                    self.page = page.0.into_iter();
+                },
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
 
 pub struct MogClient {
     transport: MogClientTransport,
     statsd: Option<statsd::Client>,
+    trace_id: Option<String>,
+    max_key_length: usize,
+    default_domain: Option<String>,
+    default_class: Option<String>,
+    observers: Vec<Box<RequestObserver>>,
+    upload_deadline: Option<time::Duration>,
+    storage: StorageSettings,
+    storage_base_url: Option<Url>,
+    in_process_storage: Option<Box<InProcessStorage>>,
 }
 
 impl MogClient {
@@ -32,341 +422,3458 @@ impl MogClient {
         MogClient {
             transport: MogClientTransport::new(trackers),
             statsd: None,
+            trace_id: None,
+            max_key_length: DEFAULT_MAX_KEY_LENGTH,
+            default_domain: None,
+            default_class: None,
+            observers: Vec::new(),
+            upload_deadline: None,
+            storage: StorageSettings::default(),
+            storage_base_url: None,
+            in_process_storage: None,
+        }
+    }
+
+    /// Like `new`, but makes sure at least one of the trackers is
+    /// there by sending it a `noop`, failing with
+    /// `MogError::NoTrackers` if none of them answer. The trackers are
+    /// tried in the order given, and the client is left connected to
+    /// the first which answers.
+    pub fn new_connected<S: ToSocketAddrs>(trackers: &[S]) -> MogResult<MogClient> {
+        let mut client = MogClient::new(trackers);
+
+        for tracker in client.transport.hosts.clone() {
+            match client.request_to(tracker, &Noop) {
+                Ok(..) => return Ok(client),
+                Err(e) => warn!("Tracker {} didn't answer a noop: {}", tracker, e),
+            }
+        }
+
+        Err(MogError::NoTrackers)
+    }
+
+    /// Set the domain the `store_data` family of methods uses when
+    /// they're given `None`.
+    pub fn set_default_domain(&mut self, domain: Option<String>) {
+        self.default_domain = domain;
+    }
+
+    /// Set the class the `store_data` family of methods uses when
+    /// they're given `None`. Without one, the tracker picks the
+    /// domain's default class.
+    pub fn set_default_class(&mut self, class: Option<String>) {
+        self.default_class = class;
+    }
+
+    /// Set the base URL of storage laid out the way the mem backend
+    /// lays it out, for `url_for_key`.
+    pub fn set_storage_base_url(&mut self, base_url: Option<Url>) {
+        self.storage_base_url = base_url;
+    }
+
+    /// Where `key`'s content is under the storage base URL, if one's
+    /// been set, worked out without asking the tracker. This is only
+    /// right for storage with that fixed layout; anything else has to
+    /// ask with `get_paths_with_devs`.
+    pub fn url_for_key(&self, domain: &str, key: &str) -> Option<Url> {
+        self.storage_base_url.as_ref().map(|base| url_for_key(base, domain, key))
+    }
+
+    /// Create a client which hands its requests straight to `tracker`
+    /// rather than connecting to one. The requests and responses are
+    /// still serialized and parsed exactly as they would be on the
+    /// wire, so this makes for quick end-to-end tests which don't need
+    /// any sockets.
+    pub fn in_process<T: InProcessTracker + 'static>(tracker: T) -> MogClient {
+        let mut client = MogClient::new::<SocketAddr>(&[]);
+        client.transport.in_process = Some(Box::new(tracker));
+        client
+    }
+
+    /// Have `get_file_ranges` read content straight from `storage`,
+    /// rather than from the storage servers the tracker points at. The
+    /// tracker is still asked for the paths, so a missing key fails
+    /// the same way.
+    pub fn set_in_process_storage<S: InProcessStorage + 'static>(&mut self, storage: S) {
+        self.in_process_storage = Some(Box::new(storage));
+    }
+
+    /// Create a client which talks to a tracker listening on the Unix
+    /// socket at `path`, such as one on the same host started with a
+    /// `UnixThreadedListener`. The protocol is the same as over TCP;
+    /// the TCP settings, like the bind address, don't apply.
+    #[cfg(unix)]
+    pub fn new_unix<P: AsRef<Path>>(path: P) -> MogClient {
+        let mut client = MogClient::new::<SocketAddr>(&[]);
+        client.transport.unix_path = Some(path.as_ref().to_path_buf());
+        client
+    }
+
+    /// The requests the trackers in this crate understand, and the
+    /// arguments each takes, for checking what a user typed before
+    /// building a request out of it. Stock trackers only know some of
+    /// them.
+    pub fn supported_operations() -> Vec<OpSpec> {
+        mogilefs_common::supported_operations()
+    }
+
+    /// Change the sequence which ends request and response lines. The
+    /// protocol uses CRLF, but some proxies in between normalize that
+    /// to a bare LF.
+    pub fn set_delimiter(&mut self, delimiter: &[u8]) {
+        self.transport.delimiter = delimiter.to_vec();
+    }
+
+    /// Separate the arguments of requests with `separator` rather than
+    /// '&', and take it as well as '&' between the arguments of
+    /// responses. This isn't MogileFS, but some trackers which are
+    /// otherwise compatible do it. The separator should be an ASCII
+    /// punctuation character other than '*', '-', '.', '_', '=' or
+    /// '%', so it's never left unencoded in a value.
+    pub fn set_arg_separator(&mut self, separator: u8) {
+        self.transport.arg_separator = separator;
+    }
+
+    /// Turn Nagle's algorithm off (the default) or on for connections
+    /// to the trackers made from now on. With small request lines and
+    /// delayed ACKs, leaving it on can hold each request up by tens of
+    /// milliseconds.
+    pub fn set_tcp_nodelay(&mut self, nodelay: bool) {
+        self.transport.tcp_nodelay = nodelay;
+    }
+
+    /// Make connections to the trackers from now on come from
+    /// `address` (port 0 leaves the port up to the OS), rather than
+    /// whichever address the OS picks. This is for hosts with more
+    /// than one interface. It only applies to tracker connections; the
+    /// storage servers are still connected to however hyper likes.
+    pub fn set_bind_address(&mut self, address: Option<SocketAddr>) {
+        self.transport.bind_address = address;
+    }
+
+    /// Call `hook` with the tracker's address whenever a new
+    /// connection to one is made for a request, whether it's the
+    /// first or a reconnect after the last one failed. Connections
+    /// over a Unix socket (see `new_unix`) have no address, so they
+    /// don't call it.
+    pub fn on_reconnect<F: Fn(SocketAddr) + Send + 'static>(&mut self, hook: F) {
+        self.transport.on_reconnect.push(Box::new(hook));
+    }
+
+    /// Call `hook` with the tracker's address and the error whenever
+    /// a connection to a tracker can't be made, or fails during a
+    /// request. Connections closed by the reaper don't count, and
+    /// neither do connections over a Unix socket.
+    pub fn on_disconnect<F: Fn(SocketAddr, &io::Error) + Send + 'static>(&mut self, hook: F) {
+        self.transport.on_disconnect.push(Box::new(hook));
+    }
+
+    /// Change how many times a request is tried, and how long to wait
+    /// between tries; see `RetryPolicy`.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.transport.retry_policy = policy;
+    }
+
+    /// Change what requests do when all the connections the pool may
+    /// have are in use; see `PoolBehavior`.
+    pub fn set_pool_behavior(&mut self, behavior: PoolBehavior) {
+        self.transport.pool_behavior = behavior;
+    }
+
+    /// How many of the pool's tracker connections are in use right
+    /// now, by a request or by a reaper's keepalive. Each request also
+    /// reports this to statsd, if it's set up, as
+    /// `mogilefs_client.connections_in_use`, counted once it has its
+    /// connection.
+    pub fn connections_in_use(&self) -> usize {
+        self.transport.pool.in_use()
+    }
+
+    /// Seed the generator which picks the waits between retries, so
+    /// they come out the same every time. The seed mustn't be all
+    /// zeroes.
+    pub fn set_retry_seed(&mut self, seed: [u32; 4]) {
+        self.transport.retry_rng = XorShiftRng::from_seed(seed);
+    }
+
+    /// Give each upload of a file's content to a storage server at
+    /// most `deadline` to finish. An upload which overruns it is
+    /// abandoned for the next of the paths the tracker offered; if
+    /// there aren't any more, the store fails with a `TimedOut` I/O
+    /// error, after trying to cancel the open of the file, so the
+    /// key's previous content (if it had any) is left alone. Trackers
+    /// without multi-dest only ever offer the one path, so there a
+    /// timeout just fails the store.
+    pub fn set_upload_deadline(&mut self, deadline: Option<time::Duration>) {
+        self.upload_deadline = deadline;
+    }
+
+    /// Give up on any read from or write to a storage server which
+    /// takes longer than `timeout`, with a `TimedOut` I/O error. This
+    /// is separate from the tracker connection, since transfers take
+    /// much longer than tracker requests. As with
+    /// `set_upload_deadline`, an upload which times out moves on to
+    /// the next path the tracker offered.
+    pub fn set_storage_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.storage.timeout = timeout;
+    }
+
+    /// Try each request to a storage server up to `retries` more times
+    /// if it fails before the server answers (it couldn't connect, or
+    /// the connection failed). Timed-out uploads aren't retried on the
+    /// same path, but moved on from, as above.
+    pub fn set_storage_retries(&mut self, retries: usize) {
+        self.storage.retries = retries;
+    }
+
+    /// Send `user_agent` as the `User-Agent` of each request to a
+    /// storage server, rather than `DEFAULT_USER_AGENT`, so that a
+    /// deployment's traffic can be picked out of their access logs.
+    pub fn set_user_agent(&mut self, user_agent: String) {
+        self.storage.user_agent = user_agent;
+    }
+
+    /// Start a thread which checks the connection to the tracker
+    /// every `interval` between requests, closing it once it's been
+    /// idle for `max_idle`, so it isn't left to go stale behind a NAT
+    /// or firewall. With `keepalive`, a connection which isn't idle
+    /// enough to close yet is sent a `noop` instead, to keep it open.
+    /// The thread stops when the client is dropped, or when this is
+    /// called again.
+    pub fn start_reaper(&mut self, interval: time::Duration, max_idle: time::Duration, keepalive: bool) {
+        self.transport.start_reaper(interval, max_idle, keepalive);
+    }
+
+    /// The pool this client takes its tracker connections from, for
+    /// sharing with other clients.
+    pub fn connection_pool(&self) -> ConnectionPool {
+        self.transport.pool.clone()
+    }
+
+    /// Take tracker connections from `pool` from now on, sharing them
+    /// with the other clients using it, which should be talking to the
+    /// same trackers. The connection this client had is closed. Any
+    /// reaper is stopped, since it was looking after the old pool;
+    /// start it again afterwards.
+    pub fn set_connection_pool(&mut self, pool: ConnectionPool) {
+        self.transport.reaper = None;
+        self.transport.pool = pool;
+        self.transport.last_peer = None;
+    }
+
+    /// Close the connection if the tracker takes longer than `timeout`
+    /// to answer one of the reaper's keepalives, rather than leaving
+    /// requests waiting on it. This takes effect the next time the
+    /// reaper is started.
+    pub fn set_keepalive_timeout(&mut self, timeout: time::Duration) {
+        self.transport.keepalive_timeout = timeout;
+    }
+
+    /// Change the longest key the client will send, for deployments
+    /// whose trackers have been patched to allow longer ones.
+    pub fn set_max_key_length(&mut self, max_key_length: usize) {
+        self.max_key_length = max_key_length;
+    }
+
+    /// Tag subsequent requests with `trace_id`. If there isn't one
+    /// set, each request gets a freshly-generated id. Either way, the
+    /// id is logged along with the request and response, and the
+    /// tracker logs it too.
+    pub fn set_trace_id(&mut self, trace_id: Option<String>) {
+        self.trace_id = trace_id;
+    }
+
+    /// Tell `observer` about each request from now on, after the ones
+    /// added before it.
+    pub fn add_observer<O: RequestObserver + 'static>(&mut self, observer: O) {
+        self.observers.push(Box::new(observer));
+    }
+
+    pub fn report_stats_to(&mut self, host: &str, prefix: &str) -> MogResult<()> {
+        debug!("Reporting stats to statsd at {:?} with prefix {:?}", host, prefix);
+        match statsd::Client::new(host, prefix) {
+            Ok(s) => {
+                self.statsd = Some(s);
+                Ok(())
+            },
+            Err(e) => {
+                Err(MogError::Other("Statsd error".to_string(), Some(format!("{:?}", e))))
+            }
+        }
+    }
+
+    pub fn request<R: Request + ToArgs + ?Sized>(&mut self, req: &R) -> MogResult<Response> {
+        self.request_via(None, req, &[], Priority::Normal)
+    }
+
+    /// Like `request`, but if all the pool's connections are in use,
+    /// this gets the next one to come free ahead of any waiting
+    /// request of a lower priority. Requests already sent aren't
+    /// affected, so this only helps clients which share a pool (see
+    /// `set_connection_pool`), or whose reaper is busy.
+    pub fn request_with_priority<R: Request + ToArgs + ?Sized>(&mut self, req: &R, priority: Priority) -> MogResult<Response> {
+        self.request_via(None, req, &[], priority)
+    }
+
+    /// Send a request to one particular tracker, which must be one of
+    /// the ones this client was created with. Unlike `request`, this
+    /// won't try any of the other trackers if that one fails. A
+    /// client talking over a Unix socket has only the one tracker, so
+    /// this always fails for one.
+    pub fn request_to<R: Request + ToArgs + ?Sized>(&mut self, tracker: SocketAddr, req: &R) -> MogResult<Response> {
+        if let Some(ref path) = self.transport.unix_path {
+            return Err(MogError::Other("unknown_tracker".to_string(), Some(format!("{} can't be reached over the Unix socket {:?}", tracker, path))));
+        }
+
+        if !self.transport.hosts.contains(&tracker) {
+            return Err(MogError::Other("unknown_tracker".to_string(), Some(format!("{} is not a configured tracker", tracker))));
+        }
+
+        self.request_via(Some(tracker), req, &[], Priority::Normal)
+    }
+
+    /// Send a request with some of its arguments given as raw bytes,
+    /// replacing the string arguments of the same name. This is how
+    /// keys which aren't valid UTF-8 get to the tracker.
+    fn request_with_raw_args<R: Request + ToArgs + ?Sized>(&mut self, req: &R, raw_args: &[(&str, &[u8])]) -> MogResult<Response> {
+        self.request_via(None, req, raw_args, Priority::Normal)
+    }
+
+    fn request_via<R: Request + ToArgs + ?Sized>(&mut self, tracker: Option<SocketAddr>, req: &R, raw_args: &[(&str, &[u8])], priority: Priority) -> MogResult<Response> {
+        try!(self.validate_request(req));
+        let trace_id = self.trace_id.clone().unwrap_or_else(generate_trace_id);
+        info!("[trace_id={}] request = {:?}", trace_id, req);
+        let mut req_line = request_line(req, raw_args, &trace_id);
+        if self.transport.arg_separator != b'&' {
+            // Values are percent-encoded, so every '&' left is a
+            // separator.
+            req_line = req_line.replace('&', &(self.transport.arg_separator as char).to_string());
+        }
+
+        let t0 = UTC::now();
+        let resp_rslt = self.transport.do_request(req, &req_line, tracker, priority);
+        let t1 = UTC::now();
+
+        if let Some(ref mut s) = self.statsd {
+            // Errors tend to come back a lot faster than successes,
+            // so they're timed apart so as not to drag the successes'
+            // latency down.
+            s.incr(&format!("mogilefs_client.requests.{}", req.op()));
+            s.timer(&format!("mogilefs_client.request_timing.{}.{}", req.op(), outcome(&resp_rslt)),
+                    (t1 - t0).num_milliseconds() as f64);
+
+            // Statsd aggregates timers into histograms, so these let
+            // a slow tracker (a long wait for the first byte) be told
+            // apart from a slow network (a long wait after it).
+            if let Some(timing) = self.transport.last_timing {
+                s.timer(&format!("mogilefs_client.first_byte_timing.{}", req.op()),
+                        timing.first_byte.num_milliseconds() as f64);
+                s.timer(&format!("mogilefs_client.full_response_timing.{}", req.op()),
+                        timing.full_response.num_milliseconds() as f64);
+            }
+
+            if self.transport.last_attempts > 1 {
+                s.incr("mogilefs_client.retries");
+            }
+
+            if let Err(MogError::PoolExhausted) = resp_rslt {
+                s.incr("mogilefs_client.pool_exhausted");
+            }
+            s.gauge("mogilefs_client.connections_in_use", self.transport.last_in_use as f64);
+        }
+
+        for observer in self.observers.iter() {
+            observer.observe(&req_line, &resp_rslt, t1 - t0);
+        }
+
+        info!("[trace_id={}] response = {:?}", trace_id, resp_rslt);
+        resp_rslt
+    }
+
+    /// Store the content of `data` in MogileFS under `key`.
+    ///
+    /// The content is streamed to the storage server, chunked, as it's
+    /// read from `data`, rather than read into memory first. It can
+    /// only be read once, so only the first path the tracker gives is
+    /// tried; use `store_data_seekable` to move on to the next one if
+    /// that device is full or down.
+    ///
+    /// A `None` domain or class falls back to the defaults set with
+    /// `set_default_domain` and `set_default_class`. An explicit
+    /// `domain` can be passed as a plain `String`.
+    pub fn store_data<D: Into<Option<String>>, R: Read>(&mut self, domain: D, class: Option<String>, key: String, data: &mut R) -> MogResult<Response> {
+        let open_req = CreateOpen { domain: self.domain_or_default(domain.into()), class: class, key: key, multi_dest: true, size: None, if_absent: false, idempotency_token: None };
+        self.store_data_with(open_req, None, None, &mut OnceContent(data)).map_err(MogError::from)
+    }
+
+    /// Like `store_data`, from `data`'s current position to its end.
+    /// The upload is sent with its length, and if a storage device is
+    /// out of space or doesn't answer, `data` is rewound and the
+    /// upload is retried on the next device the tracker suggested.
+    pub fn store_data_seekable<D: Into<Option<String>>, R: Read + Seek>(&mut self, domain: D, class: Option<String>, key: String, data: &mut R) -> MogResult<Response> {
+        let open_req = CreateOpen { domain: self.domain_or_default(domain.into()), class: class, key: key, multi_dest: true, size: None, if_absent: false, idempotency_token: None };
+        let mut content = try!(SeekableContent::new(data));
+        self.store_data_with(open_req, None, None, &mut content).map_err(MogError::from)
+    }
+
+    /// Like `store_data`, but on failure says which step failed, so
+    /// the caller can tell whether to retry the whole store, or only
+    /// the `create_close` once the content's already been stored.
+    pub fn store_data_staged<D: Into<Option<String>>, R: Read>(&mut self, domain: D, class: Option<String>, key: String, data: &mut R) -> Result<Response, StoreError> {
+        let open_req = CreateOpen { domain: self.domain_or_default(domain.into()), class: class, key: key, multi_dest: true, size: None, if_absent: false, idempotency_token: None };
+        self.store_data_with(open_req, None, None, &mut OnceContent(data))
+    }
+
+    /// Like `store_data`, for content which is already encoded (say,
+    /// gzipped) with `content_encoding`. The storage servers in this
+    /// crate keep the encoding, and send it back as the
+    /// `Content-Encoding` of the file, as well as in its `file_info`.
+    /// The content itself is stored (and fetched by `get_file`) as-is.
+    pub fn store_data_encoded<D: Into<Option<String>>, R: Read>(&mut self, domain: D, class: Option<String>, key: String, content_encoding: &str, data: &mut R) -> MogResult<Response> {
+        let open_req = CreateOpen { domain: self.domain_or_default(domain.into()), class: class, key: key, multi_dest: true, size: None, if_absent: false, idempotency_token: None };
+        self.store_data_with(open_req, None, Some(content_encoding), &mut OnceContent(data)).map_err(MogError::from)
+    }
+
+    /// Like `store_data`, but fails with `MogError::KeyExists` rather
+    /// than replacing the file if `key` is already present.
+    pub fn store_data_if_absent<D: Into<Option<String>>, R: Read>(&mut self, domain: D, class: Option<String>, key: String, data: &mut R) -> MogResult<Response> {
+        let open_req = CreateOpen { domain: self.domain_or_default(domain.into()), class: class, key: key, multi_dest: true, size: None, if_absent: true, idempotency_token: None };
+        self.store_data_with(open_req, None, None, &mut OnceContent(data)).map_err(MogError::from)
+    }
+
+    /// Like `store_data`, but for a key which is raw bytes rather than
+    /// a string. The bytes are percent-encoded into the request as-is,
+    /// so a key which isn't valid UTF-8 reaches the tracker intact.
+    ///
+    /// This is only useful with trackers that treat keys as bytes, as
+    /// stock MogileFS does. The trackers in this crate decode request
+    /// arguments as UTF-8, and will mangle such a key.
+    pub fn store_data_bytes_key<D: Into<Option<String>>, R: Read>(&mut self, domain: D, class: Option<String>, key: &[u8], data: &mut R) -> MogResult<Response> {
+        let open_req = CreateOpen { domain: self.domain_or_default(domain.into()), class: class, key: String::from_utf8_lossy(key).into_owned(), multi_dest: true, size: None, if_absent: false, idempotency_token: None };
+        self.store_data_with(open_req, Some(key), None, &mut OnceContent(data)).map_err(MogError::from)
+    }
+
+    /// Add the rest of `data` to the end of the file at `key`, as
+    /// (and with the same defaults as) `store_data`.
+    ///
+    /// MogileFS can't append to a file, so this fetches the existing
+    /// content, adds `data` to it in memory, and stores the lot again
+    /// as a new file. That isn't atomic: anything stored to `key` in
+    /// between is lost. With a tracker in this crate over the mem
+    /// backend, use `SyncMemBackend::append_content` on the server
+    /// side instead if that matters.
+    pub fn append_data<D: Into<Option<String>>, R: Read>(&mut self, domain: D, class: Option<String>, key: String, data: &mut R) -> MogResult<Response> {
+        let domain = self.domain_or_default(domain.into());
+        let mut content = vec![];
+        try!(self.get_file(domain.clone(), key.clone(), &mut content));
+        try!(io::copy(data, &mut content));
+        self.store_data_seekable(domain, class, key, &mut Cursor::new(content))
+    }
+
+    /// Fetch the content stored under `key` into `writer`, returning
+    /// the number of bytes copied. The first path the tracker gives is
+    /// used.
+    pub fn get_file<W: Write>(&mut self, domain: String, key: String, writer: &mut W) -> MogResult<u64> {
+        match try!(self.get_file_with(domain, key, None, writer)) {
+            FetchResult::Fetched(len) => Ok(len),
+            FetchResult::NotModified => Err(MogError::BadResponse),
+        }
+    }
+
+    /// Like `get_file`, but checks the content against the checksum
+    /// the file was closed with, failing with
+    /// `MogError::ChecksumMismatch` if they differ. The content has
+    /// been written to `writer` by then, so it's up to the caller to
+    /// throw it away. Files without a checksum are fetched unchecked.
+    /// Only MogileFS's MD5 checksums ("MD5:<hex>") are understood.
+    pub fn get_file_verified<W: Write>(&mut self, domain: String, key: String, writer: &mut W) -> MogResult<u64> {
+        let info_req = FileInfo { domain: domain.clone(), key: key.clone() };
+        let info = try!(self.request(&info_req).and_then(|r| r.downcast::<FileInfoResponse>().ok_or(MogError::BadResponse)));
+        let expected = match info.checksum {
+            Some(ref checksum) => try!(md5_checksum_hex(checksum)),
+            None => return self.get_file(domain, key, writer),
+        };
+
+        let mut hashing = HashingWriter { inner: writer, hasher: Hasher::new(Type::MD5) };
+        let len = try!(self.get_file(domain, key.clone(), &mut hashing));
+        let actual: String = hashing.hasher.finish().iter().map(|b| format!("{:02x}", b)).collect();
+
+        if actual == expected {
+            Ok(len)
+        } else {
+            Err(MogError::ChecksumMismatch(key))
+        }
+    }
+
+    /// Like `get_file`, but asks the storage server to skip sending
+    /// the content if it hasn't changed since `since`. (HTTP dates only
+    /// go down to the second, so neither does this.)
+    pub fn get_file_if_modified_since<W: Write>(&mut self, domain: String, key: String, since: DateTime<UTC>, writer: &mut W) -> MogResult<FetchResult> {
+        self.get_file_with(domain, key, Some(since), writer)
+    }
+
+    fn get_file_with<W: Write>(&mut self, domain: String, key: String, since: Option<DateTime<UTC>>, writer: &mut W) -> MogResult<FetchResult> {
+        let paths_req = GetPaths { domain: domain, key: key, noverify: false, pathcount: None };
+        let t0 = UTC::now();
+        let paths = try!(self.request(&paths_req).and_then(|r| r.downcast::<GetPathsResponse>().ok_or(MogError::BadResponse)));
+        let path = try!(paths.0.first().map(|p| p.url.clone()).ok_or(MogError::NoPath));
+        let t1 = UTC::now();
+
+        let result = fetch_from_storage_since(&self.storage, &path, since).and_then(|res| {
+            match res {
+                Some(mut get_res) => Ok(FetchResult::Fetched(try!(io::copy(&mut get_res, writer)))),
+                None => Ok(FetchResult::NotModified),
+            }
+        });
+        let t2 = UTC::now();
+
+        self.time_storage_op("get_file", t1 - t0, t2 - t1, &result);
+        result
+    }
+
+    /// Fetch up to the first `n` bytes of the content stored under
+    /// `key`, or all of it if it's shorter, with a Range request to
+    /// the first path the tracker gives. Storage servers which ignore
+    /// the range send the whole file, and only `n` bytes of it are
+    /// read.
+    pub fn peek(&mut self, domain: String, key: String, n: u64) -> MogResult<Vec<u8>> {
+        let paths_req = GetPaths { domain: domain, key: key, noverify: false, pathcount: None };
+        let paths = try!(self.request(&paths_req).and_then(|r| r.downcast::<GetPathsResponse>().ok_or(MogError::BadResponse)));
+        let path = try!(paths.0.first().map(|p| p.url.clone()).ok_or(MogError::NoPath));
+
+        let mut peeked = vec![];
+        if n > 0 {
+            if let Some(get_res) = try!(fetch_range_from_storage(&self.storage, &path, &[ 0..n ])) {
+                try!(get_res.take(n).read_to_end(&mut peeked));
+            }
+        }
+        Ok(peeked)
+    }
+
+    /// Fetch each of `ranges` of the content stored under `key`, from
+    /// the first path the tracker gives, returning their bytes in the
+    /// same order. Ranges may overlap and come in any order: they're
+    /// sorted and merged before being asked for, so each byte is only
+    /// sent once, and cut back apart afterwards. A range which runs
+    /// past the end of the file is cut short, and one which starts
+    /// past it (or is empty) comes back empty.
+    ///
+    /// All the ranges are asked for in a single request, but if the
+    /// storage server doesn't answer with a multipart/byteranges
+    /// response, which plenty don't, each merged range is asked for
+    /// on its own instead. With in-process storage (see
+    /// `set_in_process_storage`), each merged range is read from that.
+    pub fn get_file_ranges(&mut self, domain: String, key: String, ranges: &[ops::Range<u64>]) -> MogResult<Vec<Vec<u8>>> {
+        let paths_req = GetPaths { domain: domain, key: key, noverify: false, pathcount: None };
+        let paths = try!(self.request(&paths_req).and_then(|r| r.downcast::<GetPathsResponse>().ok_or(MogError::BadResponse)));
+        let path = try!(paths.0.first().map(|p| p.url.clone()).ok_or(MogError::NoPath));
+
+        let spans = merge_ranges(ranges);
+        let mut parts = Vec::new();
+
+        if let Some(ref storage) = self.in_process_storage {
+            for span in spans.iter() {
+                parts.push((span.start, try!(storage.read_range(&paths_req.domain, &paths_req.key, span.clone()))));
+            }
+            return Ok(ranges.iter().map(|r| slice_from_parts(&parts, r)).collect());
+        }
+
+        if spans.len() > 1 {
+            if let Some(mut get_res) = try!(fetch_range_from_storage(&self.storage, &path, &spans)) {
+                // A server which ignores ranges sends the whole file,
+                // so there's no need to ask again.
+                if get_res.status == StatusCode::Ok {
+                    let mut body = vec![];
+                    try!(get_res.read_to_end(&mut body));
+                    parts.push((0, body));
+                } else if let Some(boundary) = byteranges_boundary(&get_res) {
+                    let mut body = vec![];
+                    try!(get_res.read_to_end(&mut body));
+                    parts = try!(parse_byteranges(&body, &boundary));
+                }
+            }
+        }
+
+        if parts.is_empty() {
+            for span in spans.iter() {
+                if let Some(mut get_res) = try!(fetch_range_from_storage(&self.storage, &path, &[ span.clone() ])) {
+                    // A server which ignores ranges sends the whole
+                    // file, which has all the rest of them in it too.
+                    let whole_file = get_res.status == StatusCode::Ok;
+                    let start = if whole_file { 0 } else { try!(response_range_start(&get_res)) };
+                    let mut body = vec![];
+                    try!(get_res.read_to_end(&mut body));
+                    parts.push((start, body));
+                    if whole_file {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(ranges.iter().map(|r| slice_from_parts(&parts, r)).collect())
+    }
+
+    /// Fetch the content stored under the raw-bytes `key` (see
+    /// `store_data_bytes_key`) into `writer`, returning the number of
+    /// bytes copied. The first path the tracker gives is used.
+    pub fn get_file_bytes_key<W: Write>(&mut self, domain: String, key: &[u8], writer: &mut W) -> MogResult<u64> {
+        let paths_req = GetPaths { domain: domain, key: String::from_utf8_lossy(key).into_owned(), noverify: false, pathcount: None };
+        let paths = try!(self.request_with_raw_args(&paths_req, &[ ("key", key) ]).and_then(|r| r.downcast::<GetPathsResponse>().ok_or(MogError::BadResponse)));
+        let path = try!(paths.0.first().map(|p| p.url.clone()).ok_or(MogError::NoPath));
+        let mut get_res = try!(fetch_from_storage(&self.storage, &path));
+        Ok(try!(io::copy(&mut get_res, writer)))
+    }
+
+    /// Ask the tracker where `key` is stored, keeping the device each
+    /// path is on, and getting at most `pathcount` paths if it's
+    /// given. Stock trackers don't report devices, so those are `None`
+    /// from them.
+    pub fn get_paths_with_devs(&mut self, domain: String, key: String, pathcount: Option<u64>) -> MogResult<Vec<DestPath>> {
+        let paths_req = GetPaths { domain: domain, key: key, noverify: false, pathcount: pathcount };
+        let paths = try!(self.request(&paths_req).and_then(|r| r.downcast::<GetPathsResponse>().ok_or(MogError::BadResponse)));
+        Ok(paths.0)
+    }
+
+    /// Ask the storage servers how long the content at `key` is, with
+    /// a HEAD request, rather than reading all of it. Each path the
+    /// tracker gives is tried in turn until one answers.
+    pub fn content_length(&mut self, domain: String, key: String) -> MogResult<u64> {
+        let paths = try!(self.get_paths_with_devs(domain, key, None));
+        let mut last_err = MogError::NoPath;
+
+        for path in paths.iter() {
+            match head_storage(&self.storage, &path.url) {
+                Ok(length) => return Ok(length),
+                Err(e) => {
+                    warn!("{}, trying the next path", e);
+                    last_err = e;
+                },
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Ask the tracker for everything about `key` at once: its fid,
+    /// size, class, and mtime, and the paths it's stored at. Only the
+    /// trackers in this crate can answer this; stock ones say they
+    /// don't know the command.
+    pub fn locate(&mut self, domain: String, key: String) -> MogResult<LocateResponse> {
+        self.request(&Locate { domain: domain, key: key }).and_then(|r| r.downcast::<LocateResponse>().ok_or(MogError::BadResponse))
+    }
+
+    /// Like `store_data`, but afterwards asks the tracker about the
+    /// new file, and fails with `MogError::BadResponse` if the size it
+    /// reports isn't the number of bytes uploaded. This catches
+    /// uploads which seemed to succeed but left the file unregistered,
+    /// at the cost of another round trip.
+    pub fn store_data_verified<D: Into<Option<String>>, R: Read>(&mut self, domain: D, class: Option<String>, key: String, data: &mut R) -> MogResult<Response> {
+        let domain = self.domain_or_default(domain.into());
+        let info_req = FileInfo { domain: domain.clone(), key: key.clone() };
+        let mut counted = CountingReader { inner: data, count: 0 };
+        let response = try!(self.store_data(domain, class, key, &mut counted));
+        let len = counted.count;
+
+        let info = try!(self.request(&info_req).and_then(|r| r.downcast::<FileInfoResponse>().ok_or(MogError::BadResponse)));
+        if info.length != len {
+            error!("Stored {} bytes to {:?}, but the tracker says it has {}", len, info_req.key, info.length);
+            return Err(MogError::BadResponse);
+        }
+
+        Ok(response)
+    }
+
+    /// Store the content sent down `source` as it arrives, without
+    /// holding more than a chunk of it at a time, until the sending
+    /// side hangs up. With a `sync_channel`, whatever's producing the
+    /// content is held back when it gets ahead of the upload.
+    ///
+    /// The upload deadline doesn't apply, since there's no telling how
+    /// long the content will take to produce. Instead, the upload
+    /// fails with a `TimedOut` I/O error if nothing comes down
+    /// `source` for `idle_timeout`. The content can't be sent twice,
+    /// so only the first path the tracker gives is tried.
+    pub fn store_stream<D: Into<Option<String>>>(&mut self, domain: D, class: Option<String>, key: String, source: mpsc::Receiver<Vec<u8>>, idle_timeout: Option<time::Duration>) -> MogResult<Response> {
+        let mut open_req = CreateOpen { domain: self.domain_or_default(domain.into()), class: class, key: key, multi_dest: false, size: None, if_absent: false, idempotency_token: None };
+        self.fill_open_defaults(&mut open_req);
+
+        let t0 = UTC::now();
+        let open_res = try!(self.request(&open_req).and_then(|r| r.downcast::<CreateOpenResponse>().ok_or(MogError::BadResponse)));
+        let (devid, path) = try!(open_res.paths.first().cloned().ok_or(MogError::NoPath));
+        let t1 = UTC::now();
+
+        debug!("Streaming data for {:?} to {}", open_req.key, path);
+        let mut body = ChannelReader { source: source, idle_timeout: idle_timeout, chunk: Cursor::new(vec![]) };
+        let result = upload_to_storage(&self.storage, &path, &mut body, None, None, None);
+        let t2 = UTC::now();
+
+        if let Err(e) = result {
+            let result = Err(e);
+            self.cancel_timed_out_upload(&open_req, open_res.fid, &[], &result);
+            self.time_storage_op("store_stream", t1 - t0, t2 - t1, &result);
+            return result;
+        }
+
+        let close_result = self.request(&CreateClose {
+            domain: open_req.domain,
+            key: open_req.key,
+            fid: open_res.fid,
+            devid: devid,
+            path: path,
+            checksum: None,
+        });
+        let t3 = UTC::now();
+
+        self.time_storage_op("store_stream", (t1 - t0) + (t3 - t2), t2 - t1, &close_result);
+        close_result
+    }
+
+    /// Fill in the domain and class of `open_req` from the defaults if
+    /// it doesn't give them, and give it an idempotency token.
+    fn fill_open_defaults(&self, open_req: &mut CreateOpen) {
+        if open_req.class.is_none() {
+            open_req.class = self.default_class.clone();
+        }
+
+        // If the create_open gets resent after a reconnect, the
+        // tracker can tell it's the same upload.
+        if open_req.idempotency_token.is_none() {
+            open_req.idempotency_token = Some(generate_trace_id());
+        }
+    }
+
+    /// Cancel the open for `fid` if `result` is an upload timing out,
+    /// so the tracker doesn't keep it around. Whatever the storage
+    /// server got of the content is never pointed at by the key.
+    fn cancel_timed_out_upload<T>(&mut self, open_req: &CreateOpen, fid: u64, raw_args: &[(&str, &[u8])], result: &MogResult<T>) {
+        if let Err(MogError::Io(ref e)) = *result {
+            if e.kind() == io::ErrorKind::TimedOut {
+                let cancel_req = CreateCancel { domain: open_req.domain.clone(), key: open_req.key.clone(), fid: fid };
+                if let Err(cancel_err) = self.request_with_raw_args(&cancel_req, raw_args) {
+                    warn!("Couldn't clean up after timed-out upload of {:?}: {}", open_req.key, cancel_err);
+                }
+            }
+        }
+    }
+
+    fn store_data_with<C: UploadContent>(&mut self, mut open_req: CreateOpen, raw_key: Option<&[u8]>, content_encoding: Option<&str>, data: &mut C) -> Result<Response, StoreError> {
+        self.fill_open_defaults(&mut open_req);
+
+        let len = try!(data.remaining().map_err(|e| StoreError::new(StoreStage::Open, MogError::from(e))));
+
+        // Register the file with MogileFS, and ask it where we can store it.
+        let raw_args: Vec<(&str, &[u8])> = raw_key.into_iter().map(|k| ("key", k)).collect();
+        let t0 = UTC::now();
+        let open_res = try!{
+            self.request_with_raw_args(&open_req, &raw_args)
+                .and_then(|r| r.downcast::<CreateOpenResponse>().ok_or(MogError::BadResponse))
+                .map_err(|e| StoreError::new(StoreStage::Open, e))
+        };
+        let t1 = UTC::now();
+
+        // Try the places MogileFS suggests in the order it gives them
+        // (the device it likes best first), moving on to the next one
+        // if a device is full.
+        let paths = open_res.paths.clone();
+        let mut result = Err(MogError::NoPath);
+        let mut sent = false;
+
+        'paths: for (devid, path) in paths.into_iter() {
+            let mut tries = 0;
+            loop {
+                if sent && !try!(data.rewind().map_err(|e| StoreError::new(StoreStage::Upload, MogError::from(e)))) {
+                    debug!("Can't send the content for {:?} again", open_req.key);
+                    break 'paths;
+                }
+                debug!("Storing data for {:?} to {}", open_req.key, path);
+                sent = true;
+                let deadline = self.upload_deadline.map(|d| Instant::now() + d);
+                result = upload_to_storage(&self.storage, &path, data, len, deadline, content_encoding).map(|_| (devid, path.clone()));
+                tries += 1;
+                match result {
+                    Err(MogError::Io(ref e)) if e.kind() != io::ErrorKind::TimedOut && tries <= self.storage.retries => {
+                        warn!("{}, retrying", e);
+                    },
+                    _ => break,
+                }
+            }
+
+            match result {
+                Err(MogError::OutOfSpace(ref p)) => {
+                    warn!("Storage device at {} is out of space, trying the next one", p);
+                },
+                Err(MogError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {
+                    warn!("{}, trying the next path", e);
+                },
+                _ => break,
+            }
+        }
+        let t2 = UTC::now();
+
+        self.cancel_timed_out_upload(&open_req, open_res.fid, &raw_args, &result);
+
+        let (devid, path) = match result {
+            Ok(dest) => dest,
+            Err(e) => {
+                let result = Err(e);
+                self.time_storage_op("store_data", t1 - t0, t2 - t1, &result);
+                return result.map_err(|e| StoreError::new(StoreStage::Upload, e));
+            },
+        };
+
+        // Tell MogileFS where we uploaded the file to, and return the
+        // result of telling it so.
+        let close_req = CreateClose {
+            domain: open_req.domain,
+            key: open_req.key,
+            fid: open_res.fid,
+            devid: devid,
+            path: path,
+            checksum: None,
+        };
+        let close_result = self.request_with_raw_args(&close_req, &raw_args);
+        let t3 = UTC::now();
+
+        self.time_storage_op("store_data", (t1 - t0) + (t3 - t2), t2 - t1, &close_result);
+        close_result.map_err(|e| StoreError { stage: StoreStage::Close, error: e, close: Some(close_req) })
+    }
+
+    /// Time the two halves of a storage operation apart: the round
+    /// trips to the tracker, and moving the content to or from the
+    /// storage servers. `result` is the outcome of the transfer, or of
+    /// the operation as a whole if the transfer worked.
+    fn time_storage_op<T>(&mut self, op: &str, tracker: Duration, transfer: Duration, result: &MogResult<T>) {
+        if let Some(ref mut s) = self.statsd {
+            s.timer(&format!("mogilefs_client.tracker_timing.{}", op),
+                    tracker.num_milliseconds() as f64);
+            s.timer(&format!("mogilefs_client.transfer_timing.{}.{}", op, outcome(result)),
+                    transfer.num_milliseconds() as f64);
+        }
+    }
+
+    /// Tell the tracker that the file opened as `fid` won't be
+    /// uploaded after all, rather than leaving it for the tracker to
+    /// clean up once it goes stale. Stock trackers don't know this
+    /// command.
+    pub fn cancel_open(&mut self, domain: String, key: String, fid: u64) -> MogResult<()> {
+        self.request(&CreateCancel { domain: domain, key: key, fid: fid }).map(|_| ())
+    }
+
+    /// Move the file at `key` to `class`. The trackers in this crate
+    /// report the file as under-replicated (with a `devcount` below
+    /// the new class's mindevcount) until it's been replicated again.
+    pub fn update_class(&mut self, domain: String, key: String, class: String) -> MogResult<()> {
+        self.request(&UpdateClass { domain: domain, key: key, new_class: class }).map(|_| ())
+    }
+
+    /// Copy the content of `from_key` to `to_key`.
+    ///
+    /// Trackers which hold the content themselves can do this in
+    /// place. For the rest (including real MogileFS trackers) the
+    /// content is fetched from one of the source's paths and stored
+    /// again under the new key. The content is held in memory while
+    /// that happens.
+    pub fn copy(&mut self, domain: String, from_key: String, to_key: String) -> MogResult<Response> {
+        let copy_req = CopyKey { domain: domain, from_key: from_key, to_key: to_key };
+
+        match self.request(&copy_req) {
+            Err(MogError::UnknownCommand(..)) => {
+                debug!("Tracker can't copy {:?} itself, copying through storage", copy_req.from_key);
+            },
+            rslt => return rslt,
+        }
+
+        let paths_req = GetPaths { domain: copy_req.domain.clone(), key: copy_req.from_key.clone(), noverify: false, pathcount: None };
+        let paths = try!(self.request(&paths_req).and_then(|r| r.downcast::<GetPathsResponse>().ok_or(MogError::BadResponse)));
+        let path = try!(paths.0.first().map(|p| p.url.clone()).ok_or(MogError::NoPath));
+
+        let mut get_res = try!(fetch_from_storage(&self.storage, &path));
+        let mut content = Vec::new();
+        try!(get_res.read_to_end(&mut content));
+        self.store_data_seekable(copy_req.domain, None, copy_req.to_key, &mut Cursor::new(content))
+    }
+
+    /// Rename each of `pairs` of keys in turn, all or nothing. If one
+    /// of them conflicts (its source doesn't exist, or its destination
+    /// already does), nothing is renamed, and the index of that pair is
+    /// returned; otherwise this returns `None`.
+    ///
+    /// The tracker only reports which key conflicted, so the index is
+    /// that of the first pair it could be from. That's only ambiguous
+    /// if the batch renames the same key more than once.
+    pub fn rename_many(&mut self, domain: String, pairs: Vec<(String, String)>) -> MogResult<Option<usize>> {
+        let req = RenameMany { domain: domain, pairs: pairs };
+
+        match self.request(&req) {
+            Ok(..) => Ok(None),
+            Err(MogError::UnknownKey(ref k)) if req.pairs.iter().any(|p| p.0 == *k) => {
+                Ok(req.pairs.iter().position(|p| p.0 == *k))
+            },
+            Err(MogError::KeyExists(ref k)) if req.pairs.iter().any(|p| p.1 == *k) => {
+                Ok(req.pairs.iter().position(|p| p.1 == *k))
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create `domain`, unless it already exists.
+    pub fn ensure_domain(&mut self, domain: &str) -> MogResult<()> {
+        self.create_domain_if_missing(domain).map(|_| ())
+    }
+
+    /// Create whichever of `domains` don't exist yet, returning the
+    /// ones which were created. This stops at the first error other
+    /// than the domain already existing.
+    pub fn ensure_domains(&mut self, domains: &[&str]) -> MogResult<Vec<String>> {
+        let mut created = Vec::new();
+
+        for domain in domains.iter() {
+            if try!(self.create_domain_if_missing(domain)) {
+                created.push(domain.to_string());
+            }
+        }
+
+        Ok(created)
+    }
+
+    fn create_domain_if_missing(&mut self, domain: &str) -> MogResult<bool> {
+        match self.request(&CreateDomain { domain: domain.to_string() }) {
+            Ok(..) => Ok(true),
+            Err(MogError::DomainExists(..)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Call `callback` with each key in `domain` (optionally limited
+    /// to those starting with `prefix`), in order. Keys are fetched a
+    /// page at a time, so only one page is held in memory. If the
+    /// callback returns an error, iteration stops and that error is
+    /// returned.
+    pub fn for_each_key<F>(&mut self, domain: &str, prefix: Option<&str>, mut callback: F) -> MogResult<()>
+        where F: FnMut(&str) -> MogResult<()>
+    {
+        let mut after = None;
+
+        loop {
+            let req = ListKeys {
+                domain: domain.to_string(),
+                prefix: prefix.map(|p| p.to_string()),
+                after: after.clone(),
+                limit: None,
+                order: KeyOrder::Lexicographic,
+                with_sizes: false,
+            };
+
+            let page = match self.request(&req) {
+                Ok(r) => try!(r.downcast::<ListKeysResponse>().ok_or(MogError::BadResponse)),
+                // Real trackers say there's nothing (left) to list
+                // with an error.
+                Err(MogError::Other(ref kind, _)) if kind == "none_match" => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            for key in page.keys.iter() {
+                try!(callback(key));
+            }
+
+            let next_after = page.next_after.or(page.keys.last().cloned());
+            if page.keys.is_empty() || next_after == after {
+                return Ok(());
+            }
+            after = next_after;
+        }
+    }
+
+    /// List a page of the keys in `domain` (optionally only those
+    /// starting with `prefix`, and after `after`), with each one's
+    /// size, in one request rather than a `file_info` per key. Stock
+    /// trackers don't send sizes, so they're all `None` from them, as
+    /// they are for files without content.
+    pub fn list_keys_detailed(&mut self, domain: &str, prefix: Option<&str>, after: Option<&str>, limit: Option<u64>) -> MogResult<Vec<(String, Option<u64>)>> {
+        let req = ListKeys {
+            domain: domain.to_string(),
+            prefix: prefix.map(|p| p.to_string()),
+            after: after.map(|a| a.to_string()),
+            limit: limit,
+            order: KeyOrder::Lexicographic,
+            with_sizes: true,
+        };
+        let page = try!(self.request(&req).and_then(|r| r.downcast::<ListKeysResponse>().ok_or(MogError::BadResponse)));
+        let details = page.details.unwrap_or_default();
+
+        Ok(page.keys.into_iter().enumerate()
+           .map(|(i, key)| (key, details.get(i).and_then(|d| d.size)))
+           .collect())
+    }
+
+    /// List every key in `domain` whose content was stored after
+    /// `since`, with when it was, in key order. Keys are fetched from
+    /// the tracker a page at a time. (Times only go down to the
+    /// second.)
+    pub fn list_keys_since(&mut self, domain: &str, since: DateTime<UTC>) -> MogResult<Vec<(String, DateTime<UTC>)>> {
+        let mut keys: Vec<(String, DateTime<UTC>)> = Vec::new();
+
+        loop {
+            let req = ListKeysSince {
+                domain: domain.to_string(),
+                since: since.timestamp(),
+                after: keys.last().map(|&(ref k, _)| k.clone()),
+                limit: None,
+            };
+            let page = try!(self.request(&req).and_then(|r| r.downcast::<ListKeysSinceResponse>().ok_or(MogError::BadResponse)));
+
+            if page.0.is_empty() {
+                return Ok(keys);
+            }
+
+            keys.extend(page.0.into_iter().map(|(k, mtime)| (k, UTC.timestamp(mtime, 0))));
+        }
+    }
+
+    /// Iterate over every key in every domain, by domain and then by
+    /// key, for sweeps over everything. Keys are fetched from the
+    /// tracker a page at a time, as the iteration gets to them. An
+    /// error ends the iteration. Only the trackers in this crate
+    /// can do this.
+    pub fn list_all_keys_iter(&mut self) -> AllKeys {
+        AllKeys {
+            client: self,
+            page: Vec::new().into_iter(),
+            after: None,
+            done: false,
+        }
+    }
+
+    /// Fetch the file and byte counts for every domain and class,
+    /// following the tracker's pagination until it runs out of rows.
+    pub fn usage(&mut self) -> MogResult<Vec<UsageRow>> {
+        let mut rows: Vec<UsageRow> = Vec::new();
+
+        loop {
+            let req = Usage {
+                after_domain: rows.last().map(|r| r.domain.clone()),
+                after_class: rows.last().map(|r| r.class.clone()),
+                limit: None,
+            };
+            let page = try!(self.request(&req).and_then(|r| r.downcast::<UsageResponse>().ok_or(MogError::BadResponse)));
+
+            if page.0.is_empty() {
+                return Ok(rows);
+            }
+
+            rows.extend(page.0.into_iter());
+        }
+    }
+
+    /// `domain`, or the default domain if it's `None`. With neither,
+    /// the domain is left empty, for `validate_request` to refuse.
+    fn domain_or_default(&self, domain: Option<String>) -> String {
+        domain.or_else(|| self.default_domain.clone()).unwrap_or_default()
+    }
+
+    /// Check the domain and key arguments of a request before sending
+    /// it, rather than letting the tracker reject them with an
+    /// unhelpful error.
+    fn validate_request<R: ToArgs + ?Sized>(&self, req: &R) -> MogResult<()> {
+        for (name, value) in req.to_args().into_iter() {
+            match name.as_ref() {
+                "domain" if !is_valid_name(&value, MAX_DOMAIN_LENGTH) => {
+                    return Err(MogError::InvalidDomain(value));
+                },
+                "key" | "from_key" | "to_key" if !is_valid_name(&value, self.max_key_length) => {
+                    return Err(MogError::InvalidKey(value));
+                },
+                n if (n.starts_with("from_key_") || n.starts_with("to_key_")) && !is_valid_name(&value, self.max_key_length) => {
+                    return Err(MogError::InvalidKey(value));
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ask the tracker what version it is and which commands it
+    /// supports.
+    pub fn server_version(&mut self) -> MogResult<ServerVersionResponse> {
+        self.request(&ServerVersion).and_then(|r| r.downcast::<ServerVersionResponse>().ok_or(MogError::BadResponse))
+    }
+
+    /// Flush and shut down the connection to the tracker, so that it
+    /// sees a clean end of stream rather than a reset.
+    ///
+    /// Just dropping the client flushes whatever's buffered, but
+    /// leaves closing the socket to the OS. Either way, if the client
+    /// goes away in the middle of a request (say, from a panic in
+    /// another thread holding it), the tracker may be left reading a
+    /// partial request line.
+    pub fn close(mut self) -> MogResult<()> {
+        self.transport.close()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.transport.is_connected()
+    }
+
+    /// The tracker this client's last request went to, if the
+    /// connection it used was still open once it was done. That's
+    /// this client's own connection, even in a pool shared with
+    /// others, though it may have been closed (by the reaper, say)
+    /// since.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.transport.last_peer
+    }
+
+    /// The tracker which answered the last request, or `None` if it
+    /// didn't get an answer. Unlike `peer_addr`, this is still right
+    /// after the connection has been dropped or replaced.
+    pub fn last_tracker(&self) -> Option<SocketAddr> {
+        self.transport.last_tracker
+    }
+
+    /// How many times the last request was sent before it got an
+    /// answer (or the client gave up), counting reconnects.
+    pub fn last_attempt_count(&self) -> usize {
+        self.transport.last_attempts
+    }
+}
+
+struct MogClientTransport {
+    hosts: Vec<SocketAddr>,
+    pool: ConnectionPool,
+    reaper: Option<Reaper>,
+    in_process: Option<Box<InProcessTracker>>,
+    unix_path: Option<PathBuf>,
+    last_timing: Option<ResponseTiming>,
+    last_tracker: Option<SocketAddr>,
+    /// The tracker the connection the last request went out on is to,
+    /// if it was still open afterwards.
+    last_peer: Option<SocketAddr>,
+    last_attempts: usize,
+    delimiter: Vec<u8>,
+    arg_separator: u8,
+    tcp_nodelay: bool,
+    bind_address: Option<SocketAddr>,
+    retry_policy: RetryPolicy,
+    retry_rng: XorShiftRng,
+    pool_behavior: PoolBehavior,
+    /// How many of the pool's connections were in use once the last
+    /// request had its own.
+    last_in_use: usize,
+    keepalive_timeout: time::Duration,
+    on_reconnect: Vec<Box<Fn(SocketAddr) + Send>>,
+    on_disconnect: Vec<Box<Fn(SocketAddr, &io::Error) + Send>>,
+}
+
+/// A connection to a tracker, as it's kept in a `ConnectionPool` in
+/// between requests.
+#[derive(Debug)]
+struct IdleConnection {
+    state: Option<ConnectionState>,
+    idle_since: Instant,
+}
+
+impl IdleConnection {
+    fn new() -> IdleConnection {
+        IdleConnection { state: Some(ConnectionState::new()), idle_since: Instant::now() }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state.as_ref().map(|s| s.is_connected()).unwrap_or(false)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.state.as_ref().and_then(|s| s.peer_addr())
+    }
+
+    /// Close the connection if it's been idle for `max_idle`, or else
+    /// send it `keepalive`, if there is one, closing it if the answer
+    /// takes longer than `timeout`.
+    fn reap(&mut self, max_idle: time::Duration, keepalive: Option<&[u8]>, timeout: time::Duration, delimiter: &[u8]) {
+        let stream = match self.state.take() {
+            Some(stream @ ConnectionState::Connected(..)) => stream,
+            state => {
+                self.state = state;
+                return;
+            },
+        };
+
+        if self.idle_since.elapsed() >= max_idle {
+            debug!("Closing connection to {:?}, idle since {:?}", stream.peer_addr(), self.idle_since);
+            if let ConnectionState::Connected(mut stream) = stream {
+                let _ = stream.flush().and_then(|_| stream.get_ref().shutdown(Shutdown::Write));
+            }
+            return;
+        }
+
+        self.state = Some(match keepalive {
+            Some(line) => {
+                let mut resp_line = Vec::new();
+                let (stream, err) = stream.set_read_timeout(Some(timeout))
+                    .write_and_flush(line)
+                    .read_until_mb(delimiter, &mut resp_line)
+                    .set_read_timeout(None)
+                    .take_err();
+                if let Some(e) = err {
+                    warn!("Keepalive failed, dropping connection: {}", e);
+                }
+                stream
+            },
+            None => stream,
+        });
+    }
+}
+
+/// A connection taken out of a `ConnectionPool`, which goes back when
+/// this is dropped, as long as it's still connected.
+struct Checkout {
+    pool: ConnectionPool,
+    conn: IdleConnection,
+    was_open: bool,
+}
+
+impl Checkout {
+    fn new(pool: &ConnectionPool, conn: IdleConnection) -> Checkout {
+        Checkout { pool: pool.clone(), was_open: conn.is_connected(), conn: conn }
+    }
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        let conn = mem::replace(&mut self.conn, IdleConnection { state: None, idle_since: Instant::now() });
+        let mut state = self.pool.state();
+        state.in_use -= 1;
+        match (self.was_open, conn.is_connected()) {
+            (true, false) => state.open -= 1,
+            (false, true) => state.open += 1,
+            _ => {},
+        }
+        if conn.is_connected() {
+            state.idle.push(conn);
+        }
+        self.pool.0.freed.notify_all();
+    }
+}
+
+/// The thread started by `MogClient::start_reaper`, which stops when
+/// this is dropped.
+struct Reaper {
+    stop: mpsc::Sender<()>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Connection reaper thread panicked");
+            }
+        }
+    }
+}
+
+/// How long the tracker took to answer the last request, measured
+/// from when the request line was flushed.
+#[derive(Debug, Clone, Copy)]
+struct ResponseTiming {
+    first_byte: Duration,
+    full_response: Duration,
+}
+
+impl MogClientTransport {
+    fn new<S: ToSocketAddrs + Sized>(tracker_addrs: &[S]) -> MogClientTransport {
+        MogClientTransport {
+            hosts: tracker_addrs.iter().flat_map(|a| a.to_socket_addrs().unwrap()).collect(),
+            pool: ConnectionPool::new(1),
+            reaper: None,
+            last_timing: None,
+            last_tracker: None,
+            last_peer: None,
+            last_attempts: 0,
+            in_process: None,
+            unix_path: None,
+            delimiter: b"\r\n".to_vec(),
+            arg_separator: b'&',
+            tcp_nodelay: true,
+            bind_address: None,
+            retry_policy: RetryPolicy::default(),
+            retry_rng: rand::weak_rng(),
+            pool_behavior: PoolBehavior::default(),
+            last_in_use: 0,
+            keepalive_timeout: time::Duration::from_secs(DEFAULT_KEEPALIVE_TIMEOUT_SECS),
+            on_reconnect: Vec::new(),
+            on_disconnect: Vec::new(),
+        }
+    }
+
+    /// Whether any of the pool's connections, idle or in use, is
+    /// connected.
+    fn is_connected(&self) -> bool {
+        self.pool.state().open > 0
+    }
+
+    fn start_reaper(&mut self, interval: time::Duration, max_idle: time::Duration, keepalive: bool) {
+        // Stop the old one first, if there is one.
+        self.reaper = None;
+
+        let (stop, stopped) = mpsc::channel();
+        let pool = self.pool.clone();
+        let delimiter = self.delimiter.clone();
+        let timeout = self.keepalive_timeout;
+        let mut noop = b"noop".to_vec();
+        noop.extend_from_slice(&delimiter);
+
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                pool.reap(max_idle, if keepalive { Some(&noop[..]) } else { None }, timeout, &delimiter);
+            }
+        });
+
+        self.reaper = Some(Reaper { stop: stop, thread: Some(thread) });
+    }
+
+    /// Flush and shut down each of the pool's idle connections.
+    fn close(&mut self) -> MogResult<()> {
+        let idle: Vec<IdleConnection> = {
+            let mut state = self.pool.state();
+            // Idle connections are all open ones.
+            let count = state.idle.len();
+            state.open -= count;
+            state.idle.drain(..).collect()
+        };
+        for conn in idle {
+            if let Some(ConnectionState::Connected(mut stream)) = conn.state {
+                try!(stream.flush());
+                try!(stream.get_ref().shutdown(Shutdown::Write));
+            }
+        }
+        Ok(())
+    }
+
+    fn random_tracker_addr(&self) -> MogResult<SocketAddr> {
+        let mut rng = rand::thread_rng();
+        let mut sample = rand::sample(&mut rng, self.hosts.iter(), 1);
+        sample.pop().cloned().ok_or(MogError::NoTrackers)
+    }
+
+    fn do_request<R: Request + ?Sized>(&mut self, request: &R, req_line: &str, pinned: Option<SocketAddr>, priority: Priority) -> MogResult<Response> {
+        if let Some(ref tracker) = self.in_process {
+            debug!("req_line = {:?}", req_line);
+            let started = UTC::now();
+            let resp_line = tracker.handle_line(req_line.as_bytes());
+            let elapsed = UTC::now() - started;
+
+            // The whole response comes back at once, and there's no
+            // tracker address to report.
+            self.last_timing = Some(ResponseTiming { first_byte: elapsed, full_response: elapsed });
+            self.last_tracker = None;
+            self.last_peer = None;
+            self.last_attempts = 1;
+            debug!("resp_line = {:?}", resp_line);
+            return response_from_bytes(request, resp_line.as_bytes());
+        }
+
+        let mut checkout = try!(self.pool.checkout(self.pool_behavior, pinned, priority));
+        self.last_in_use = self.pool.in_use();
+        let mut stream = checkout.conn.state.take().unwrap_or(ConnectionState::new());
+
+        // Hang up on whichever tracker we're talking to if the request
+        // is meant for a different one. A Unix socket connection has
+        // no address to compare, and there's nowhere else to go.
+        if pinned.is_some() && self.unix_path.is_none() && stream.is_connected() && stream.peer_addr() != pinned {
+            stream = ConnectionState::new();
+        }
+
+        let mut req_bytes = req_line.as_bytes().to_vec();
+        req_bytes.extend_from_slice(&self.delimiter);
+
+        let mut resp_line = Vec::new();
+        let mut tries = 0;
+        self.last_timing = None;
+        self.last_tracker = None;
+
+        loop {
+            let tracker = if stream.is_connected() {
+                stream.peer_addr()
+            } else if let Some(ref path) = self.unix_path {
+                debug!("Connecting to {:?}", path);
+                stream = stream.connect_unix(path);
+                None
+            } else {
+                let tracker = match pinned {
+                    Some(addr) => addr,
+                    None => try!(self.random_tracker_addr()),
+                };
+                debug!("Connecting to {:?}", tracker);
+                stream = stream.connect(&tracker, self.bind_address.as_ref(), self.tcp_nodelay);
+                if stream.is_connected() {
+                    for hook in self.on_reconnect.iter() {
+                        hook(tracker);
+                    }
+                }
+                Some(tracker)
+            };
+
+            debug!("req_line = {:?}", String::from_utf8_lossy(&req_bytes));
+            stream = stream.write_and_flush(&req_bytes);
+            let written = UTC::now();
+            stream = stream.wait_for_response();
+            let first_byte = UTC::now();
+            stream = stream.read_until_mb(&self.delimiter, &mut resp_line);
+            let full_response = UTC::now();
+            debug!("resp_line = {:?}", String::from_utf8_lossy(&resp_line));
+            tries += 1;
+
+            if stream.is_connected() {
+                self.last_timing = Some(ResponseTiming {
+                    first_byte: first_byte - written,
+                    full_response: full_response - written,
+                });
+                self.last_tracker = stream.peer_addr();
+                break;
+            }
+
+            if let (Some(tracker), Some(err)) = (tracker, stream.error()) {
+                for hook in self.on_disconnect.iter() {
+                    hook(tracker, err);
+                }
+            }
+
+            if tries >= cmp::max(1, self.retry_policy.attempts) {
+                break;
+            }
+
+            let delay = self.retry_policy.delay(tries as u32 - 1, &mut self.retry_rng);
+            if delay > time::Duration::from_millis(0) {
+                debug!("Retrying in {:?}", delay);
+                thread::sleep(delay);
+            }
+        }
+        self.last_attempts = tries;
+
+        let (stream, err) = stream.take_err();
+        self.last_peer = if stream.is_connected() { stream.peer_addr() } else { None };
+        checkout.conn.state = Some(stream);
+        checkout.conn.idle_since = Instant::now();
+        mem::drop(checkout);
+
+        match err {
+            Some(err) => Err(MogError::Io(err)),
+            None => {
+                if resp_line.ends_with(&self.delimiter) {
+                    let len = resp_line.len() - self.delimiter.len();
+                    resp_line.truncate(len);
+                }
+
+                // Either separator is taken, since the servers which
+                // use another one don't always.
+                let separator = self.arg_separator;
+                if separator != b'&' {
+                    for b in resp_line.iter_mut().filter(|b| **b == separator) {
+                        *b = b'&';
+                    }
+                }
+
+                response_from_bytes(request, &resp_line)
+            }
+        }
+    }
+}
+
+impl Drop for MogClientTransport {
+    fn drop(&mut self) {
+        for conn in self.pool.state().idle.iter_mut() {
+            if let Some(ConnectionState::Connected(ref mut stream)) = conn.state {
+                if let Err(e) = stream.flush() {
+                    warn!("Error flushing connection to {:?}: {}", stream.get_ref().peer_addr(), e);
+                }
+            }
+        }
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    // Socket timeouts show up as WouldBlock on some platforms.
+    err.kind() == io::ErrorKind::TimedOut || err.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Connect to `addr`, from `bind` if it's given.
+fn open_stream(addr: &SocketAddr, bind: Option<&SocketAddr>) -> io::Result<TcpStream> {
+    match bind {
+        Some(bind) => {
+            let builder = try!(match *bind {
+                SocketAddr::V4(..) => TcpBuilder::new_v4(),
+                SocketAddr::V6(..) => TcpBuilder::new_v6(),
+            });
+            try!(builder.bind(bind));
+            builder.connect(addr)
+        },
+        None => TcpStream::connect(addr),
+    }
+}
+
+#[cfg(unix)]
+fn open_unix_stream(path: &Path) -> io::Result<TrackerStream> {
+    UnixStream::connect(path).map(TrackerStream::Unix)
+}
+
+#[cfg(not(unix))]
+fn open_unix_stream(path: &Path) -> io::Result<TrackerStream> {
+    Err(io::Error::new(io::ErrorKind::Other, format!("Can't connect to {:?}: Unix sockets aren't supported here", path)))
+}
+
+/// How a request turned out, as the last part of its timer's name.
+fn outcome<T>(result: &MogResult<T>) -> &'static str {
+    match *result {
+        Ok(_) => "ok",
+        Err(_) => "err",
+    }
+}
+
+fn is_valid_name(name: &str, max_length: usize) -> bool {
+    !name.is_empty() && name.len() <= max_length && !name.chars().any(|c| c.is_control())
+}
+
+fn generate_trace_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// The `User-Agent` sent to storage servers, unless
+/// `MogClient::set_user_agent` says otherwise.
+pub const DEFAULT_USER_AGENT: &'static str = concat!("mogilefsd-rs/", env!("CARGO_PKG_VERSION"));
+
+/// How the client talks to the storage servers; see
+/// `MogClient::set_storage_timeout`, `set_storage_retries`, and
+/// `set_user_agent`.
+#[derive(Debug, Clone)]
+struct StorageSettings {
+    timeout: Option<time::Duration>,
+    retries: usize,
+    user_agent: String,
+}
+
+impl Default for StorageSettings {
+    fn default() -> StorageSettings {
+        StorageSettings {
+            timeout: None,
+            retries: 0,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+        }
+    }
+}
+
+impl StorageSettings {
+    fn user_agent(&self) -> UserAgent {
+        UserAgent(self.user_agent.clone())
+    }
+
+    /// An HTTP client with the timeout on its reads and writes, or
+    /// `limit` if that's shorter.
+    fn client(&self, limit: Option<time::Duration>) -> hyper::Client {
+        let timeout = match (self.timeout, limit) {
+            (Some(t), Some(l)) => Some(cmp::min(t, l)),
+            (t, l) => t.or(l),
+        };
+
+        let mut client = hyper::Client::new();
+        client.set_read_timeout(timeout);
+        client.set_write_timeout(timeout);
+        client
+    }
+
+    /// Send a request (which mustn't have a body, since that's been
+    /// used up the first time), retrying it if it fails before there's
+    /// a response.
+    fn send<F>(&self, path: &Url, mut send: F) -> MogResult<hyper::client::Response>
+        where F: FnMut() -> hyper::Result<hyper::client::Response>
+    {
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            match send() {
+                Err(hyper::Error::Io(ref e)) if tries <= self.retries => {
+                    warn!("Could not fetch {}: {}, retrying", path, e);
+                },
+                Err(hyper::Error::Io(ref e)) if is_timeout(e) => {
+                    return Err(MogError::Io(io::Error::new(io::ErrorKind::TimedOut, format!("Fetch from {} timed out", path))));
+                },
+                Err(e) => return Err(MogError::StorageError(Some(format!("Could not fetch {}: {}", path, e)))),
+                Ok(res) => return Ok(res),
+            }
+        }
+    }
+}
+
+fn fetch_from_storage(storage: &StorageSettings, path: &Url) -> MogResult<hyper::client::Response> {
+    try!(fetch_from_storage_since(storage, path, None)).ok_or(MogError::BadResponse)
+}
+
+/// HEAD `path`, returning the length the storage server reports.
+fn head_storage(storage: &StorageSettings, path: &Url) -> MogResult<u64> {
+    let client = storage.client(None);
+    let head_res = try!(storage.send(path, || client.head(path.clone()).header(storage.user_agent()).send()));
+
+    match (head_res.status, head_res.headers.get::<ContentLength>()) {
+        (StatusCode::Ok, Some(&ContentLength(length))) => Ok(length),
+        (StatusCode::Ok, None) => Err(MogError::StorageError(Some(format!("No Content-Length for {}", path)))),
+        _ => Err(MogError::StorageError(Some(format!("Bad response from storage server: {:?}", head_res)))),
+    }
+}
+
+/// GET `path`, conditionally on it having changed since `since` if
+/// that's given. Returns `None` if it hasn't changed.
+fn fetch_from_storage_since(storage: &StorageSettings, path: &Url, since: Option<DateTime<UTC>>) -> MogResult<Option<hyper::client::Response>> {
+    let client = storage.client(None);
+    let since_header = match since {
+        Some(since) => {
+            let date: HttpDate = try!{
+                since.format("%a, %d %b %Y %H:%M:%S GMT").to_string().parse()
+                    .map_err(|e| MogError::Other("Bad date".to_string(), Some(format!("{}", e))))
+            };
+            Some(IfModifiedSince(date))
+        },
+        None => None,
+    };
+
+    let get_res = try!(storage.send(path, || {
+        let get_req = client.get(path.clone()).header(storage.user_agent());
+        match since_header {
+            Some(ref header) => get_req.header(header.clone()).send(),
+            None => get_req.send(),
+        }
+    }));
+
+    match get_res.status {
+        StatusCode::Ok => Ok(Some(get_res)),
+        StatusCode::NotModified if since.is_some() => Ok(None),
+        _ => Err(MogError::StorageError(Some(format!("Bad response from storage server: {:?}", get_res)))),
+    }
+}
+
+/// GET `ranges` (which mustn't be empty) of `path`. Returns `None` if
+/// the storage server says there's nothing in them, which is what it
+/// says about an empty file, or a range past the end of one.
+fn fetch_range_from_storage(storage: &StorageSettings, path: &Url, ranges: &[ops::Range<u64>]) -> MogResult<Option<hyper::client::Response>> {
+    let client = storage.client(None);
+    let range = Range::Bytes(ranges.iter().map(|r| ByteRangeSpec::FromTo(r.start, r.end - 1)).collect());
+    let get_res = try!(storage.send(path, || client.get(path.clone()).header(storage.user_agent()).header(range.clone()).send()));
+
+    match get_res.status {
+        StatusCode::Ok | StatusCode::PartialContent => Ok(Some(get_res)),
+        StatusCode::RangeNotSatisfiable => Ok(None),
+        _ => Err(MogError::StorageError(Some(format!("Bad response from storage server: {:?}", get_res)))),
+    }
+}
+
+/// Sort `ranges`, dropping the empty ones and merging those which
+/// overlap or touch.
+fn merge_ranges(ranges: &[ops::Range<u64>]) -> Vec<ops::Range<u64>> {
+    let mut sorted: Vec<ops::Range<u64>> = ranges.iter().filter(|r| r.start < r.end).cloned().collect();
+    sorted.sort_by(|a, b| a.start.cmp(&b.start));
+
+    let mut merged: Vec<ops::Range<u64>> = Vec::new();
+    for range in sorted {
+        if let Some(last) = merged.last_mut() {
+            if range.start <= last.end {
+                last.end = cmp::max(last.end, range.end);
+                continue;
+            }
+        }
+        merged.push(range);
+    }
+    merged
+}
+
+/// The bytes of `range`, out of whichever of `parts` (each an offset
+/// into the file and the bytes from there) it starts in.
+fn slice_from_parts(parts: &[(u64, Vec<u8>)], range: &ops::Range<u64>) -> Vec<u8> {
+    if range.start >= range.end {
+        return vec![];
+    }
+
+    for &(start, ref bytes) in parts.iter() {
+        let end = start + bytes.len() as u64;
+        if start <= range.start && range.start < end {
+            let from = (range.start - start) as usize;
+            let to = (cmp::min(range.end, end) - start) as usize;
+            return bytes[from..to].to_vec();
+        }
+    }
+    vec![]
+}
+
+/// The boundary of a multipart/byteranges response, or `None` if it
+/// isn't one.
+fn byteranges_boundary(res: &hyper::client::Response) -> Option<String> {
+    if res.status != StatusCode::PartialContent {
+        return None;
+    }
+
+    let content_type = match res.headers.get_raw("Content-Type").and_then(|v| v.first()) {
+        Some(value) => String::from_utf8_lossy(value).into_owned(),
+        None => return None,
+    };
+    let mut params = content_type.split(';').map(|p| p.trim());
+    if params.next().map(|t| t.to_lowercase()) != Some("multipart/byteranges".to_string()) {
+        return None;
+    }
+
+    params
+        .find(|p| p.to_lowercase().starts_with("boundary="))
+        .map(|p| p["boundary=".len()..].trim_matches('"').to_string())
+}
+
+/// Where the content of a single-part 206 response starts in the file.
+fn response_range_start(res: &hyper::client::Response) -> MogResult<u64> {
+    res.headers.get_raw("Content-Range")
+        .and_then(|v| v.first())
+        .and_then(|v| content_range_start(&String::from_utf8_lossy(v)))
+        .ok_or_else(|| MogError::StorageError(Some(format!("No usable Content-Range in {:?}", res))))
+}
+
+/// The start of a "bytes <start>-<end>/<length>" Content-Range.
+fn content_range_start(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if !value.to_lowercase().starts_with("bytes ") {
+        return None;
+    }
+    value["bytes ".len()..].split('-').next().and_then(|s| s.trim().parse().ok())
+}
+
+/// Split a multipart/byteranges body into its parts, each the offset
+/// into the file its Content-Range gives and its bytes.
+fn parse_byteranges(body: &[u8], boundary: &str) -> MogResult<Vec<(u64, Vec<u8>)>> {
+    let bad_body = || MogError::StorageError(Some("Malformed multipart/byteranges response".to_string()));
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = Vec::new();
+    let mut pos = try!(find_bytes(body, &delimiter, 0).ok_or_else(&bad_body)) + delimiter.len();
+
+    loop {
+        // The last delimiter has a "--" after it.
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+
+        let headers_end = try!(find_bytes(body, b"\r\n\r\n", pos).ok_or_else(&bad_body));
+        let start = try!{
+            String::from_utf8_lossy(&body[pos..headers_end]).split("\r\n")
+                .filter(|h| h.to_lowercase().starts_with("content-range:"))
+                .filter_map(|h| content_range_start(&h["content-range:".len()..]))
+                .next()
+                .ok_or_else(&bad_body)
+        };
+
+        let content_start = headers_end + 4;
+        let next = try!(find_bytes(body, &delimiter, content_start).ok_or_else(&bad_body));
+        // The CRLF before the delimiter is part of it, not the content.
+        let content_end = if next >= content_start + 2 && &body[next - 2..next] == b"\r\n" { next - 2 } else { next };
+        parts.push((start, body[content_start..content_end].to_vec()));
+        pos = next + delimiter.len();
+    }
+}
+
+/// Where `needle` first appears in `haystack` at or after `from`.
+fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return None;
+    }
+    (from..haystack.len() - needle.len() + 1).find(|&i| &haystack[i..i + needle.len()] == needle)
+}
+
+/// The lower-case hex digest of an "MD5:<hex>" checksum.
+fn md5_checksum_hex(checksum: &str) -> MogResult<String> {
+    let mut parts = checksum.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(hash_type), Some(hex)) if hash_type.to_uppercase() == "MD5" => Ok(hex.to_lowercase()),
+        _ => Err(MogError::Other("unsupported_checksum".to_string(), Some(format!("Can't check a checksum of {:?}", checksum)))),
+    }
+}
+
+/// A writer which hashes everything written through it.
+struct HashingWriter<'a, W: Write + 'a> {
+    inner: &'a mut W,
+    hasher: Hasher,
+}
+
+impl<'a, W: Write + 'a> Write for HashingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        try!(self.hasher.write_all(&buf[..written]));
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The content `store_data_with` uploads.
+trait UploadContent: Read {
+    /// The number of bytes left to send, if that's known up front.
+    fn remaining(&mut self) -> io::Result<Option<u64>>;
+
+    /// Go back to the start of the content, to send it again to
+    /// another path. Returns false if it can't be sent again.
+    fn rewind(&mut self) -> io::Result<bool>;
+}
+
+/// Content from a plain reader, which can only be sent once.
+struct OnceContent<'a, R: Read + 'a>(&'a mut R);
+
+impl<'a, R: Read + 'a> Read for OnceContent<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a, R: Read + 'a> UploadContent for OnceContent<'a, R> {
+    fn remaining(&mut self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn rewind(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Content from a reader which can seek back to where it started.
+struct SeekableContent<'a, R: Read + Seek + 'a> {
+    inner: &'a mut R,
+    start: u64,
+}
+
+impl<'a, R: Read + Seek + 'a> SeekableContent<'a, R> {
+    fn new(inner: &'a mut R) -> io::Result<SeekableContent<'a, R>> {
+        let start = try!(inner.seek(SeekFrom::Current(0)));
+        Ok(SeekableContent { inner: inner, start: start })
+    }
+}
+
+impl<'a, R: Read + Seek + 'a> Read for SeekableContent<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<'a, R: Read + Seek + 'a> UploadContent for SeekableContent<'a, R> {
+    fn remaining(&mut self) -> io::Result<Option<u64>> {
+        let pos = try!(self.inner.seek(SeekFrom::Current(0)));
+        let end = try!(self.inner.seek(SeekFrom::End(0)));
+        try!(self.inner.seek(SeekFrom::Start(pos)));
+        Ok(Some(end - pos))
+    }
+
+    fn rewind(&mut self) -> io::Result<bool> {
+        try!(self.inner.seek(SeekFrom::Start(self.start)));
+        Ok(true)
+    }
+}
+
+/// A reader which counts the bytes read through it.
+struct CountingReader<'a, R: Read + 'a> {
+    inner: &'a mut R,
+    count: u64,
+}
+
+impl<'a, R: Read + 'a> Read for CountingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// A reader which fails with `TimedOut` once its deadline is past, so
+/// that hyper gives up on a slow upload part-way through the body.
+struct DeadlineReader<'a, R: Read + 'a> {
+    inner: &'a mut R,
+    deadline: Option<Instant>,
+}
+
+impl<'a, R: Read + 'a> Read for DeadlineReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.deadline {
+            Some(d) if Instant::now() >= d => Err(io::Error::new(io::ErrorKind::TimedOut, "upload deadline passed")),
+            _ => self.inner.read(buf),
+        }
+    }
+}
+
+/// A reader of the chunks sent down a channel, which ends when the
+/// sending side hangs up, and fails with `TimedOut` if nothing's been
+/// sent for `idle_timeout`.
+struct ChannelReader {
+    source: mpsc::Receiver<Vec<u8>>,
+    idle_timeout: Option<time::Duration>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = try!(self.chunk.read(buf));
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            let next = match self.idle_timeout {
+                Some(timeout) => match self.source.recv_timeout(timeout) {
+                    Ok(chunk) => chunk,
+                    Err(RecvTimeoutError::Timeout) => {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, format!("Nothing to upload for {:?}", timeout)));
+                    },
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                },
+                None => match self.source.recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return Ok(0),
+                },
+            };
+            self.chunk = Cursor::new(next);
+        }
+    }
+}
+
+/// PUT `data` at `path`: `len` bytes of it, or, if that's not known,
+/// all of it, chunked.
+fn upload_to_storage<R: Read>(storage: &StorageSettings, path: &Url, data: &mut R, len: Option<u64>, deadline: Option<Instant>, content_encoding: Option<&str>) -> MogResult<()> {
+    let timed_out = || MogError::Io(io::Error::new(io::ErrorKind::TimedOut, format!("Upload to {} timed out", path)));
+
+    // The client's timeouts bound each read and write on the socket;
+    // the reader bounds the upload as a whole.
+    let remaining = match deadline {
+        Some(d) => {
+            let now = Instant::now();
+            if now >= d {
+                return Err(timed_out());
+            }
+            Some(d - now)
+        },
+        None => None,
+    };
+    let client = storage.client(remaining);
+
+    let mut body = DeadlineReader { inner: data, deadline: deadline };
+    let mut put_req = client.put(path.clone()).header(storage.user_agent());
+    if let Some(encoding) = content_encoding {
+        let encoding: Encoding = try!{
+            encoding.parse().map_err(|e| MogError::Other("Bad content encoding".to_string(), Some(format!("{}", e))))
+        };
+        put_req = put_req.header(ContentEncoding(vec![ encoding ]));
+    }
+
+    let put_res = try!{
+        put_req
+            .body(match len {
+                Some(len) => Body::SizedBody(&mut body, len),
+                None => Body::ChunkedBody(&mut body),
+            })
+            .send()
+            .map_err(|e| match e {
+                hyper::Error::Io(ref io_err) if is_timeout(io_err) => timed_out(),
+                hyper::Error::Io(io_err) => MogError::Io(io::Error::new(io_err.kind(), format!("Could not store to {}: {}", path, io_err))),
+                e => MogError::StorageError(Some(format!("Could not store to {}: {}", path, e))),
+            })
+    };
+
+    match &put_res.status {
+        &StatusCode::Ok => Ok(()),
+        &StatusCode::Created => Ok(()),
+        &StatusCode::InsufficientStorage => Err(MogError::OutOfSpace(path.to_string())),
+        _ => Err(MogError::StorageError(Some(format!("Bad response from storage server: {:?}", put_res)))),
+    }
+}
+
+/// Render a request as the line sent to the tracker (without the
+/// CRLF), with `raw_args` replacing the arguments of the same name.
+fn request_line<R: Request + ?Sized>(request: &R, raw_args: &[(&str, &[u8])], trace_id: &str) -> String {
+    let mut args = Serializer::new(String::new())
+        .extend_pairs(request.to_args().into_iter().filter(|&(ref n, _)| !raw_args.iter().any(|&(r, _)| *n == r)))
+        .append_pair(TRACE_ID_ARG, trace_id)
+        .finish();
+    for &(name, value) in raw_args.iter() {
+        args.push('&');
+        args.push_str(name);
+        args.push('=');
+        args.extend(byte_serialize(value));
+    }
+    format!("{} {}", request.op(), args)
+}
+
+fn response_from_bytes<R: Request + ?Sized>(request: &R, bytes: &[u8]) -> MogResult<Response> {
+    let (op, args) = split_response(bytes);
+
+    match op {
+        Some(b"OK") => request.response_from_bytes(&args),
+        Some(b"ERR") => Err(MogError::from_bytes(&args)),
+        o @ _ => {
+            // Something in between may have encoded the status.
+            let status = o.map(decode_mog_field);
+            match status.as_ref().map(|s| &s[..]) {
+                Some("OK") => request.response_from_bytes(&args),
+                Some("ERR") => Err(MogError::from_bytes(&args)),
+                _ => Err(MogError::Other("Unknown response code".to_string(), status)),
+            }
+        },
+    }
+}
+
+/// Split a response line into its code and its arguments. Some older
+/// trackers leave out the space after "OK" (or "ERR"), so it's
+/// optional.
+fn split_response(bytes: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    for code in [ &b"OK"[..], &b"ERR"[..] ].iter() {
+        if bytes.starts_with(code) {
+            let rest = &bytes[code.len()..];
+            let args_start = rest.iter().position(|&b| b != b' ').unwrap_or(rest.len());
+            return (Some(*code), &rest[args_start..]);
+        }
+    }
+
+    let mut toks = bytes.splitn(2, |&b| b == b' ');
+    let op = toks.next();
+    (op, toks.next().unwrap_or(&[]))
+}
+
+#[derive(Debug)]
+enum ConnectionState {
+    NoConnection,
+    Connected(BufStream<TrackerStream>),
+    Error(io::Error),
+}
+
+/// A connection to a tracker, over TCP or a Unix socket.
+#[derive(Debug)]
+enum TrackerStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl TrackerStream {
+    /// The tracker's address, which Unix socket connections don't
+    /// have one of.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            TrackerStream::Tcp(ref stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            TrackerStream::Unix(..) => None,
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match *self {
+            TrackerStream::Tcp(ref stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            TrackerStream::Unix(ref stream) => stream.shutdown(how),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        match *self {
+            TrackerStream::Tcp(ref stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            TrackerStream::Unix(ref stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for TrackerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            TrackerStream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            TrackerStream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for TrackerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            TrackerStream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            TrackerStream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            TrackerStream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            TrackerStream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl ConnectionState {
+    fn new() -> ConnectionState {
+        ConnectionState::NoConnection
+    }
+
+    fn is_connected(&self) -> bool {
+        match self {
+            &ConnectionState::Connected(..) => true,
+            _ => false,
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            &ConnectionState::Connected(ref stream) => {
+                stream.get_ref().peer_addr()
+            },
+            _ => None,
+        }
+    }
+
+    fn error(&self) -> Option<&io::Error> {
+        match self {
+            &ConnectionState::Error(ref ioe) => Some(ioe),
+            _ => None,
+        }
+    }
+
+    fn take_err(self) -> (ConnectionState, Option<io::Error>) {
+        use self::ConnectionState::*;
+
+        match self {
+            ConnectionState::Error(ioe) => (NoConnection, Some(ioe)),
+            _ => (self, None),
+        }
+    }
+
+    fn connect(self, addr: &SocketAddr, bind: Option<&SocketAddr>, nodelay: bool) -> ConnectionState {
+        use self::ConnectionState::*;
+
+        match self {
+            Connected(..) => self,
+            _ => {
+                trace!("Opening connection to {:?}...", addr);
+                match open_stream(addr, bind) {
+                    Ok(stream) => {
+                        trace!("... connected to {:?}", addr);
+                        if let Err(e) = stream.set_nodelay(nodelay) {
+                            warn!("Couldn't set TCP_NODELAY on connection to {:?}: {}", addr, e);
+                        }
+                        Connected(BufStream::new(TrackerStream::Tcp(stream)))
+                    },
+                    Err(ioe) => {
+                        error!("Error connecting to {:?}: {}", addr, ioe);
+                        Error(ioe)
+                    },
+                }
+            },
+        }
+    }
+
+    fn connect_unix(self, path: &Path) -> ConnectionState {
+        use self::ConnectionState::*;
+
+        match self {
+            Connected(..) => self,
+            _ => {
+                trace!("Opening connection to {:?}...", path);
+                match open_unix_stream(path) {
+                    Ok(stream) => {
+                        trace!("... connected to {:?}", path);
+                        Connected(BufStream::new(stream))
+                    },
+                    Err(ioe) => {
+                        error!("Error connecting to {:?}: {}", path, ioe);
+                        Error(ioe)
+                    },
+                }
+            },
+        }
+    }
+
+    fn set_read_timeout(self, timeout: Option<time::Duration>) -> ConnectionState {
+        use self::ConnectionState::*;
+
+        match self {
+            NoConnection | Error(..) => self,
+            Connected(stream) => {
+                match stream.get_ref().set_read_timeout(timeout) {
+                    Ok(..) => Connected(stream),
+                    Err(ioe) => {
+                        error!("Error setting read timeout on {:?}: {}", stream.get_ref().peer_addr(), ioe);
+                        Error(ioe)
+                    },
+                }
+            },
+        }
+    }
+
+    fn write_and_flush(self, line: &[u8]) -> ConnectionState {
+        use self::ConnectionState::*;
+
+        match self {
+            NoConnection | Error(..) => self,
+            Connected(mut stream) => {
+                let peer = stream.get_ref().peer_addr();
+                trace!("Writing {} bytes to {:?}...", line.len(), peer);
+                match stream.write_all(line).and_then(|_| stream.flush()) {
+                    Ok(..) => {
+                        trace!("... successfully wrote {} bytes to {:?}", line.len(), peer);
+                        Connected(stream)
+                    },
+                    Err(ioe) => {
+                        error!("Error writing to {:?}: {}", peer, ioe);
+                        Error(ioe)
+                    }
+                }
+            },
+        }
+    }
+
+    /// Block until the tracker has sent at least part of its
+    /// response, without consuming any of it.
+    fn wait_for_response(self) -> ConnectionState {
+        use self::ConnectionState::*;
+
+        match self {
+            NoConnection | Error(..) => self,
+            Connected(mut stream) => {
+                let result = stream.fill_buf().map(|_| ());
+                match result {
+                    Ok(..) => Connected(stream),
+                    Err(ioe) => {
+                        error!("Error reading from {:?}: {}", stream.get_ref().peer_addr(), ioe);
+                        Error(ioe)
+                    },
+                }
+            }
+        }
+    }
+
+    fn read_until_mb(self, delimiter: &[u8], buf: &mut Vec<u8>) -> ConnectionState {
+        use self::ConnectionState::*;
+
+        match self {
+            NoConnection | Error(..) => self,
+            Connected(mut stream) => {
+                let peer = stream.get_ref().peer_addr();
+                trace!("Waiting for response from {:?}...", peer);
+                match stream.read_until_mb(delimiter, buf) {
+                    Ok(..) => {
+                        trace!("... read {} bytes from {:?}", buf.len(), peer);
+                        Connected(stream)
+                    },
+                    Err(ioe) => {
+                        error!("Error reading from {:?}: {}", peer, ioe);
+                        Error(ioe)
+                    },
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, UTC};
+    use mogilefs_common::{MogError, MogResult, Response};
+    use mogilefs_common::requests::*;
+    use rand::{SeedableRng, XorShiftRng};
+    use std::cmp;
+    use std::env;
+    use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::str::FromStr;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time;
+    use super::*;
+    use url::Url;
+
+    lazy_static!{
+        static ref TEST_DOMAIN: String = domain_for_testing();
+    }
+
+    fn trackers_for_testing() -> Option<Vec<SocketAddr>> {
+        env::var("FILAMENT_TEST_TRACKERS").map(|val| {
+            val.split(",")
+                .into_iter()
+                .filter_map(|addr_str| SocketAddr::from_str(addr_str).ok())
+                .collect()
+        }).ok()
+    }
+
+    fn domain_for_testing() -> String {
+        env::var("FILAMENT_TEST_DOMAIN").ok().unwrap_or("filament_test".to_string())
+    }
+
+    fn skip() {
+        write!(&mut io::stdout(), "(skipped) ").unwrap();
+    }
+
+    /// A request a fake storage server got: its request and header
+    /// lines, and its body.
+    struct StorageRequest {
+        head: Vec<String>,
+        body: Vec<u8>,
+    }
+
+    impl StorageRequest {
+        fn header(&self, name: &str) -> Option<&str> {
+            let prefix = format!("{}:", name.to_lowercase());
+            self.head.iter()
+                .find(|line| line.to_lowercase().starts_with(&prefix))
+                .map(|line| line[prefix.len()..].trim())
+        }
+    }
+
+    /// A response from a fake storage server. `status` can carry
+    /// header lines after the status, and `length` is what the
+    /// Content-Length says, whatever the body is.
+    fn http_response(status: &str, length: usize, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, length).into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// Starts a fake storage server which sends back whatever
+    /// `respond` makes of each request, or takes the connection and
+    /// sits on it if that's `None`.
+    fn fake_storage<F>(mut respond: F) -> SocketAddr
+        where F: FnMut(StorageRequest) -> Option<Vec<u8>> + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut held = Vec::new();
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                let mut head = Vec::new();
+
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() { break; }
+                    head.push(line.trim_right().to_string());
+                }
+
+                let mut request = StorageRequest { head: head, body: Vec::new() };
+                if request.header("Transfer-Encoding") == Some("chunked") {
+                    loop {
+                        let mut size_line = String::new();
+                        reader.read_line(&mut size_line).unwrap();
+                        let size = usize::from_str_radix(size_line.trim(), 16).unwrap();
+                        let mut chunk = vec![0; size + 2];
+                        reader.read_exact(&mut chunk).unwrap();
+                        if size == 0 { break; }
+                        request.body.extend_from_slice(&chunk[..size]);
+                    }
+                } else {
+                    let content_length = request.header("Content-Length").map(|l| l.parse().unwrap()).unwrap_or(0);
+                    request.body = vec![0; content_length];
+                    reader.read_exact(&mut request.body).unwrap();
+                }
+
+                match respond(request) {
+                    // The client may hang up without reading the body.
+                    Some(response) => { let _ = writer.write_all(&response); },
+                    None => held.push(writer),
+                }
+            }
+        });
+
+        addr
+    }
+
+    /// Starts a fake tracker which answers each request line it gets
+    /// with whatever `respond` makes of it.
+    fn fake_tracker<F>(mut respond: F) -> SocketAddr
+        where F: FnMut(&str) -> String + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+
+            for line in reader.lines() {
+                let response = respond(&line.unwrap());
+                write!(writer, "{}\r\n", response).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    /// Starts a fake storage server which answers each PUT with
+    /// `status_line`.
+    fn mock_storage(status_line: &'static str) -> SocketAddr {
+        recording_storage(status_line).0
+    }
+
+    /// Like `mock_storage`, but passes along the header lines of each
+    /// request it gets.
+    fn recording_storage(status_line: &'static str) -> (SocketAddr, mpsc::Receiver<Vec<String>>) {
+        let (tx, rx) = mpsc::channel();
+        let addr = fake_storage(move |request| {
+            let _ = tx.send(request.head);
+            Some(http_response(status_line, 0, b""))
+        });
+        (addr, rx)
+    }
+
+    /// How a `ranged_storage` answers a request for several ranges.
+    #[derive(Clone, Copy)]
+    enum MultiRange {
+        /// With a multipart/byteranges response.
+        Multipart,
+        /// With only the first of them.
+        FirstOnly,
+        /// With the whole of the content, as it does any request.
+        Ignored,
+    }
+
+    /// Starts a fake storage server which serves the byte ranges each
+    /// request asks for out of `content`, passing along the `Range`
+    /// header it was asked with. `multi` says what it does with
+    /// several ranges at once.
+    fn ranged_storage(content: &'static [u8], multi: MultiRange) -> (SocketAddr, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        let addr = fake_storage(move |request| {
+            let range = request.header("Range").unwrap_or("").to_string();
+            let len = content.len() as u64;
+            let ranges: Vec<(u64, u64)> = range["bytes=".len()..].split(',')
+                .map(|r| {
+                    let mut ends = r.split('-').map(|e| e.parse::<u64>().unwrap());
+                    let start = ends.next().unwrap();
+                    (start, cmp::min(ends.next().unwrap(), len - 1))
+                })
+                .collect();
+            let _ = tx.send(range);
+
+            let (head, body) = match (multi, ranges.len()) {
+                (MultiRange::Ignored, _) => ("200 OK".to_string(), content.to_vec()),
+                (_, 1) | (MultiRange::FirstOnly, _) => {
+                    let (start, end) = ranges[0];
+                    (format!("206 Partial Content\r\nContent-Range: bytes {}-{}/{}", start, end, len), content[start as usize..end as usize + 1].to_vec())
+                },
+                (MultiRange::Multipart, _) => {
+                    let mut body = Vec::new();
+                    for &(start, end) in ranges.iter() {
+                        write!(body, "--XYZ\r\nContent-Type: text/plain\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", start, end, len).unwrap();
+                        body.extend_from_slice(&content[start as usize..end as usize + 1]);
+                        body.extend_from_slice(b"\r\n");
+                    }
+                    body.extend_from_slice(b"--XYZ--\r\n");
+                    ("206 Partial Content\r\nContent-Type: multipart/byteranges; boundary=XYZ".to_string(), body)
+                },
+            };
+            Some(http_response(&head, body.len(), &body))
+        });
+        (addr, rx)
+    }
+
+    /// Starts a fake storage server which answers each request with
+    /// a bodiless 200 claiming `length` bytes of content, as it would
+    /// for a HEAD.
+    fn sized_storage(length: u64) -> SocketAddr {
+        fake_storage(move |_| Some(http_response("200 OK", length as usize, b"")))
+    }
+
+    /// Starts a fake storage server which takes each connection and
+    /// then sits on it, sending something down the channel for each.
+    fn hung_storage() -> (SocketAddr, mpsc::Receiver<()>) {
+        let (tx, rx) = mpsc::channel();
+        let addr = fake_storage(move |_| {
+            let _ = tx.send(());
+            None
+        });
+        (addr, rx)
+    }
+
+    /// Starts a fake tracker which offers the `storage` servers as the
+    /// places to put a new file (or get an existing one), describes
+    /// every file as empty, and says OK to anything else.
+    fn mock_tracker(storage: Vec<SocketAddr>) -> SocketAddr {
+        mock_tracker_with_length(storage, 0)
+    }
+
+    /// Like `mock_tracker`, but describes every file as `length` bytes
+    /// long.
+    fn mock_tracker_with_length(storage: Vec<SocketAddr>, length: u64) -> SocketAddr {
+        fake_tracker(move |line| {
+            if line.starts_with("create_open ") {
+                let mut args = format!("fid=1&dev_count={}", storage.len());
+                for (i, host) in storage.iter().enumerate() {
+                    args.push_str(&format!("&devid_{}={}&path_{}=http://{}/dev{}/0/000/000/0000000001.fid",
+                                           i + 1, i + 1, i + 1, host, i + 1));
+                }
+                format!("OK {}", args)
+            } else if line.starts_with("file_info ") {
+                format!("OK fid=1&devcount=1&length={}&domain=test_domain&class=default&key=test/key/1", length)
+            } else if line.starts_with("get_paths ") {
+                let mut args = format!("paths={}", storage.len());
+                for (i, host) in storage.iter().enumerate() {
+                    args.push_str(&format!("&path{}=http://{}/dev{}/0/000/000/0000000001.fid&devid{}={}", i + 1, host, i + 1, i + 1, i + 1));
+                }
+                format!("OK {}", args)
+            } else {
+                "OK ".to_string()
+            }
+        })
+    }
+
+    /// Starts a fake tracker which says OK to everything, but takes
+    /// `delay` to answer the second request it gets.
+    fn slow_tracker(delay: time::Duration) -> SocketAddr {
+        let mut requests = 0;
+        fake_tracker(move |_| {
+            requests += 1;
+            if requests == 2 {
+                thread::sleep(delay);
+            }
+            "OK ".to_string()
+        })
+    }
+
+    /// Starts a fake tracker which offers `storage` as the place to
+    /// put a new file, and fails the first `create_close` it gets, but
+    /// not any after that.
+    fn failing_close_tracker(storage: SocketAddr) -> SocketAddr {
+        let mut closes = 0;
+        fake_tracker(move |line| {
+            if line.starts_with("create_open ") {
+                format!("OK fid=1&dev_count=1&devid_1=1&path_1=http://{}/dev1/0/000/000/0000000001.fid", storage)
+            } else if line.starts_with("create_close ") && closes == 0 {
+                closes += 1;
+                "ERR unknown_fid No+open+file".to_string()
+            } else {
+                "OK ".to_string()
+            }
+        })
+    }
+
+    /// Starts a fake tracker which offers `storage` as the place to
+    /// put a new file, says OK to anything else, and passes along each
+    /// request line it gets.
+    fn recording_tracker(storage: SocketAddr) -> (SocketAddr, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        let addr = fake_tracker(move |line| {
+            tx.send(line.to_string()).unwrap();
+            if line.starts_with("create_open ") {
+                format!("OK fid=1&dev_count=1&devid_1=1&path_1=http://{}/dev1/0/000/000/0000000001.fid", storage)
+            } else {
+                "OK ".to_string()
+            }
+        });
+        (addr, rx)
+    }
+
+    /// Starts a fake tracker which says OK to everything, but passes
+    /// along each request line it gets, and doesn't answer it until
+    /// it's told to.
+    fn gated_tracker() -> (SocketAddr, mpsc::Receiver<String>, mpsc::Sender<()>) {
+        let (arrived_tx, arrived) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let addr = fake_tracker(move |line| {
+            arrived_tx.send(line.to_string()).unwrap();
+            released.recv().unwrap();
+            "OK ".to_string()
+        });
+        (addr, arrived, release)
+    }
+
+    macro_rules! test_conn {
+        () => {
+            {
+                let trackers = match trackers_for_testing() {
+                    Some(vec) => vec,
+                    None => {
+                        skip();
+                        return;
+                    },
+                };
+                assert!(trackers.len() >= 1);
+                MogClient::new(&trackers)
+            }
+        }
+    }
+
+    #[test]
+    fn test_connection() {
+        let mut conn = test_conn!();
+        let response = conn.request(&Noop);
+        assert!(response.is_ok());
+        assert_eq!(Response::Empty, response.ok().unwrap());
+        assert!(conn.is_connected());
+    }
+
+    #[test]
+    fn test_store_data() {
+        let mut conn = test_conn!();
+        let content: Vec<u8> = b"New file content".iter().cloned().collect();
+        let mut content_reader = Cursor::new(content);
+        let response = conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content_reader);
+        assert!(response.is_ok());
+    }
+
+    #[test]
+    fn test_store_data_bodies() {
+        let (tx, rx) = mpsc::channel();
+        let storage = fake_storage(move |request| {
+            let _ = tx.send((request.header("Content-Length").map(|l| l.to_string()), request.body));
+            Some(http_response("201 Created", 0, b""))
+        });
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ storage ]) ]);
+
+        // A plain reader is sent chunked, since its length isn't known.
+        conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut &b"New file content"[..]).unwrap();
+        assert_eq!((None, b"New file content".to_vec()), rx.recv().unwrap());
+
+        let mut content_reader = Cursor::new(b"Old new file content".to_vec());
+        content_reader.set_position(4);
+        conn.store_data_seekable(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content_reader).unwrap();
+        assert_eq!((Some("16".to_string()), b"new file content".to_vec()), rx.recv().unwrap());
+    }
+
+    #[test]
+    fn test_store_data_out_of_space() {
+        let storage = vec![ mock_storage("507 Insufficient Storage"), mock_storage("507 Insufficient Storage") ];
+        let mut conn = MogClient::new(&[ mock_tracker(storage) ]);
+        let mut content_reader = Cursor::new(b"New file content".to_vec());
+
+        match conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content_reader) {
+            Err(MogError::OutOfSpace(..)) => {},
+            other => panic!("Storing to full devices returned {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_store_data_skips_full_device() {
+        let storage = vec![ mock_storage("507 Insufficient Storage"), mock_storage("201 Created") ];
+        let mut conn = MogClient::new(&[ mock_tracker(storage) ]);
+        let mut content_reader = Cursor::new(b"New file content".to_vec());
+        let response = conn.store_data_seekable(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content_reader);
+        assert!(response.is_ok(), "Storing with one full device returned {:?}", response);
+
+        let mut content_reader = Cursor::new(b"New file content".to_vec());
+        match conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content_reader) {
+            Err(MogError::OutOfSpace(..)) => {},
+            other => panic!("Storing once to a full device returned {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_store_data_tries_paths_in_order() {
+        // Only the first path works, so this fails if the paths are
+        // tried in any other order than the tracker's.
+        let storage = vec![ mock_storage("201 Created"), mock_storage("500 Internal Server Error") ];
+        let mut conn = MogClient::new(&[ mock_tracker(storage) ]);
+        let mut content_reader = Cursor::new(b"New file content".to_vec());
+        let response = conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content_reader);
+        assert!(response.is_ok(), "Storing to the first path returned {:?}", response);
+    }
+
+    #[test]
+    fn test_response_with_optional_space() {
+        let req = CreateDomain { domain: "test_domain".to_string() };
+        let expected = Response::CreateDomain(req.clone());
+
+        assert_eq!(Response::Empty, super::response_from_bytes(&Noop, b"OK").unwrap());
+        assert_eq!(Response::Empty, super::response_from_bytes(&Noop, b"OK ").unwrap());
+        assert_eq!(expected, super::response_from_bytes(&req, b"OK domain=test_domain").unwrap());
+        assert_eq!(expected, super::response_from_bytes(&req, b"OKdomain=test_domain").unwrap());
+
+        match super::response_from_bytes(&req, b"ERRunknown_key test") {
+            Err(MogError::UnknownKey(ref k)) if k == "test" => {},
+            r @ _ => panic!("Error response without a space came back as {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_response_with_encoded_fields() {
+        assert_eq!(Response::Empty, super::response_from_bytes(&Noop, b"%4FK ").unwrap());
+
+        match super::response_from_bytes(&Noop, b"ERR unknown_key test%2Fkey+1%2B2") {
+            Err(MogError::UnknownKey(ref k)) if k == "test/key 1+2" => {},
+            r @ _ => panic!("Encoded error came back as {:?}", r),
+        }
+
+        match super::response_from_bytes(&Noop, b"WAT huh%3F") {
+            Err(MogError::Other(_, Some(ref status))) if status == "WAT" => {},
+            r @ _ => panic!("Unknown status came back as {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_empty_responses_ignore_extra_args() {
+        let delete = Delete { domain: TEST_DOMAIN.clone(), key: "test/key/1".to_string() };
+        assert_eq!(Response::Empty, super::response_from_bytes(&delete, b"OK foo=bar").unwrap());
+        assert_eq!(Response::Empty, super::response_from_bytes(&delete, b"OK deleted=lots").unwrap());
+        assert_eq!(Response::Empty, super::response_from_bytes(&Noop, b"OK foo=bar&baz").unwrap());
+
+        let rename = Rename { domain: TEST_DOMAIN.clone(), from_key: "a".to_string(), to_key: "b".to_string(), overwrite: false };
+        assert_eq!(Response::Empty, super::response_from_bytes(&rename, b"OK foo=bar").unwrap());
+    }
+
+    #[test]
+    fn test_get_paths_with_devs() {
+        let tracker = mock_tracker(vec![ mock_storage("200 OK"), mock_storage("200 OK") ]);
+        let mut conn = MogClient::new(&[ tracker ]);
+        let paths = conn.get_paths_with_devs(TEST_DOMAIN.clone(), "test/key/1".to_string(), None).unwrap();
+        assert_eq!(vec![ Some(1), Some(2) ], paths.iter().map(|p| p.devid).collect::<Vec<_>>());
+        assert!(paths[1].url.path().starts_with("/dev2/"), "Second path was {}", paths[1].url);
+
+        // Paths from trackers which don't report devices still parse.
+        let response = super::response_from_bytes(&GetPaths { domain: TEST_DOMAIN.clone(), key: "test/key/1".to_string(), noverify: false, pathcount: None },
+                                                   b"OK paths=1&path1=http://127.0.0.1:7500/dev1/0/000/000/0000000001.fid");
+        match response {
+            Ok(Response::GetPaths(GetPathsResponse(ref paths))) if paths.len() == 1 && paths[0].devid.is_none() => {},
+            r @ _ => panic!("Paths without devices came back as {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_url_for_key() {
+        let mut conn = MogClient::new(&[ "127.0.0.1:7001" ]);
+        assert_eq!(None, conn.url_for_key("test_domain", "test/key/1"));
+
+        conn.set_storage_base_url(Some(Url::parse("http://storage.host/base/").unwrap()));
+        assert_eq!("http://storage.host/base/d/test_domain/k/test/key/1",
+                   conn.url_for_key("test_domain", "test/key/1").unwrap().as_str());
+    }
+
+    #[test]
+    fn test_content_length() {
+        let tracker = mock_tracker(vec![ mock_storage("500 Internal Server Error"), sized_storage(1234) ]);
+        let mut conn = MogClient::new(&[ tracker ]);
+        assert_eq!(1234, conn.content_length(TEST_DOMAIN.clone(), "test/key/1".to_string()).unwrap());
+
+        let tracker = mock_tracker(vec![ mock_storage("404 Not Found") ]);
+        let mut conn = MogClient::new(&[ tracker ]);
+        match conn.content_length(TEST_DOMAIN.clone(), "test/key/1".to_string()) {
+            Err(MogError::StorageError(..)) => {},
+            r @ _ => panic!("Content length with no good paths was {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_get_file_ranges() {
+        let ranges = [ 10..14, 0..4, 2..6, 18..30, 25..27, 5..5 ];
+        let expected: Vec<Vec<u8>> = vec![ b"abcd".to_vec(), b"0123".to_vec(), b"2345".to_vec(), b"ij".to_vec(), vec![], vec![] ];
+
+        // The overlapping ranges are merged, and all asked for at once.
+        let (storage, requests) = ranged_storage(b"0123456789abcdefghij", MultiRange::Multipart);
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ storage ]) ]);
+        assert_eq!(expected, conn.get_file_ranges(TEST_DOMAIN.clone(), "test/key/1".to_string(), &ranges).unwrap());
+        assert_eq!(vec![ "bytes=0-5,10-13,18-29".to_string() ], requests.try_iter().collect::<Vec<_>>());
+
+        // Without multipart responses, they're asked for one by one.
+        let (storage, requests) = ranged_storage(b"0123456789abcdefghij", MultiRange::FirstOnly);
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ storage ]) ]);
+        assert_eq!(expected, conn.get_file_ranges(TEST_DOMAIN.clone(), "test/key/1".to_string(), &ranges).unwrap());
+        assert_eq!(vec![ "bytes=0-5,10-13,18-29", "bytes=0-5", "bytes=10-13", "bytes=18-29" ], requests.try_iter().collect::<Vec<_>>());
+
+        // A server which ignores ranges sends the whole file, which
+        // has them all in it, so it's only asked once.
+        let (storage, requests) = ranged_storage(b"0123456789abcdefghij", MultiRange::Ignored);
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ storage ]) ]);
+        assert_eq!(expected, conn.get_file_ranges(TEST_DOMAIN.clone(), "test/key/1".to_string(), &ranges).unwrap());
+        assert_eq!(vec![ "bytes=0-5,10-13,18-29" ], requests.try_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_store_data_staged() {
+        let content = || Cursor::new(b"test content".to_vec());
+
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ mock_storage("500 Internal Server Error") ]) ]);
+        match conn.store_data_staged(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content()) {
+            Err(StoreError { stage: StoreStage::Upload, error: MogError::StorageError(..), close: None }) => {},
+            r => panic!("Storing to a broken storage server returned {:?}", r),
+        }
+
+        // Only the close failed, so only it needs resending.
+        let mut conn = MogClient::new(&[ failing_close_tracker(mock_storage("201 Created")) ]);
+        let close = match conn.store_data_staged(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content()) {
+            Err(StoreError { stage: StoreStage::Close, error: MogError::UnknownFid, close: Some(close) }) => close,
+            r => panic!("Storing with a failing close returned {:?}", r),
+        };
+        assert_eq!(1, close.fid);
+        conn.request(&close).unwrap();
+
+        // Nothing's been stored when the open fails.
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut conn = MogClient::new(&[ dead ]);
+        match conn.store_data_staged(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content()) {
+            Err(StoreError { stage: StoreStage::Open, .. }) => {},
+            r => panic!("Storing with no tracker returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_store_data_encoded() {
+        let (storage, requests) = recording_storage("201 Created");
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ storage ]) ]);
+        conn.store_data_encoded(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), "gzip", &mut Cursor::new(b"\x1f\x8b...".to_vec())).unwrap();
+        let headers = requests.recv().unwrap();
+        assert!(headers.iter().any(|h| h.to_lowercase() == "content-encoding: gzip"), "Headers were {:?}", headers);
+
+        conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut Cursor::new(b"plain".to_vec())).unwrap();
+        let headers = requests.recv().unwrap();
+        assert!(!headers.iter().any(|h| h.to_lowercase().starts_with("content-encoding:")), "Headers were {:?}", headers);
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let user_agent = |headers: &[String]| headers.iter()
+            .find(|h| h.to_lowercase().starts_with("user-agent:"))
+            .map(|h| h["user-agent:".len()..].trim().to_string());
+
+        let (storage, requests) = recording_storage("201 Created");
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ storage ]) ]);
+        conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut Cursor::new(b"content".to_vec())).unwrap();
+        assert_eq!(Some(DEFAULT_USER_AGENT.to_string()), user_agent(&requests.recv().unwrap()));
+
+        conn.set_user_agent("uploader/1.2".to_string());
+        conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut Cursor::new(b"content".to_vec())).unwrap();
+        assert_eq!(Some("uploader/1.2".to_string()), user_agent(&requests.recv().unwrap()));
+
+        // The storage server doesn't answer GETs properly, but it still
+        // sees what they were sent with.
+        let _ = conn.get_file(TEST_DOMAIN.clone(), "test/key/1".to_string(), &mut vec![]);
+        assert_eq!(Some("uploader/1.2".to_string()), user_agent(&requests.recv().unwrap()));
+    }
+
+    #[test]
+    fn test_store_data_verified() {
+        let content = b"New file content".to_vec();
+
+        let tracker = mock_tracker_with_length(vec![ mock_storage("201 Created") ], content.len() as u64);
+        let mut conn = MogClient::new(&[ tracker ]);
+        let response = conn.store_data_verified(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut Cursor::new(content.clone()));
+        assert!(response.is_ok(), "Verified store returned {:?}", response);
+
+        let tracker = mock_tracker_with_length(vec![ mock_storage("201 Created") ], 3);
+        let mut conn = MogClient::new(&[ tracker ]);
+        match conn.store_data_verified(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut Cursor::new(content)) {
+            Err(MogError::BadResponse) => {},
+            r @ _ => panic!("Verified store with the wrong size returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_for_each_key() {
+        // Two pages of two keys each, then nothing.
+        let addr = fake_tracker(|line| {
+            if line.contains("after=k2") {
+                "OK key_count=2&key_1=k3&key_2=k4&next_after=k4".to_string()
+            } else if line.contains("after=k4") {
+                "ERR none_match No+keys+match".to_string()
+            } else {
+                "OK key_count=2&key_1=k1&key_2=k2&next_after=k2".to_string()
+            }
+        });
+
+        let mut conn = MogClient::new(&[ addr ]);
+        let mut keys = Vec::new();
+        conn.for_each_key("test_domain", None, |k| { keys.push(k.to_string()); Ok(()) }).unwrap();
+        assert_eq!(vec![ "k1", "k2", "k3", "k4" ], keys);
+
+        let mut seen = 0;
+        let result = conn.for_each_key("test_domain", None, |k| {
+            seen += 1;
+            if k == "k2" { Err(MogError::Other("stop".to_string(), None)) } else { Ok(()) }
+        });
+        assert_eq!(2, seen);
+        match result {
+            Err(MogError::Other(ref kind, _)) if kind == "stop" => {},
+            r @ _ => panic!("Stopping early returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_ensure_domains() {
+        let addr = fake_tracker(|line| {
+            if line.starts_with("create_domain domain=existing&") {
+                "ERR domain_exists That+domain+already+exists".to_string()
+            } else if line.starts_with("create_domain domain=broken&") {
+                "ERR unknown_command Broken".to_string()
+            } else {
+                "OK domain=new".to_string()
+            }
+        });
+
+        let mut conn = MogClient::new(&[ addr ]);
+        assert!(conn.ensure_domain("existing").is_ok());
+        assert_eq!(vec![ "new_1".to_string(), "new_2".to_string() ],
+                   conn.ensure_domains(&[ "new_1", "existing", "new_2" ]).unwrap());
+
+        match conn.ensure_domains(&[ "new_3", "broken", "new_4" ]) {
+            Err(MogError::UnknownCommand(..)) => {},
+            r @ _ => panic!("Ensuring a broken domain returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_invalid_keys_and_domains() {
+        // Nothing is listening here, so anything that got as far as
+        // the network would fail differently.
+        let mut conn = MogClient::new(&[ "127.0.0.1:1" ]);
+        let delete = |domain: &str, key: &str| Delete { domain: domain.to_string(), key: key.to_string() };
+
+        match conn.request(&delete("test_domain", "")) {
+            Err(MogError::InvalidKey(..)) => {},
+            r @ _ => panic!("Empty key returned {:?}", r),
+        }
+
+        match conn.request(&delete("test_domain", "test\nkey")) {
+            Err(MogError::InvalidKey(..)) => {},
+            r @ _ => panic!("Key with a newline returned {:?}", r),
+        }
+
+        let long_key: String = (0..256).map(|_| 'k').collect();
+        match conn.request(&delete("test_domain", &long_key)) {
+            Err(MogError::InvalidKey(..)) => {},
+            r @ _ => panic!("Overlong key returned {:?}", r),
+        }
+
+        match conn.request(&Rename { domain: "test_domain".to_string(), from_key: "a".to_string(), to_key: "".to_string(), overwrite: false }) {
+            Err(MogError::InvalidKey(..)) => {},
+            r @ _ => panic!("Rename to an empty key returned {:?}", r),
+        }
+
+        match conn.request(&delete("", "test/key/1")) {
+            Err(MogError::InvalidDomain(..)) => {},
+            r @ _ => panic!("Empty domain returned {:?}", r),
+        }
+
+        conn.set_max_key_length(300);
+        match conn.request(&delete("test_domain", &long_key)) {
+            Err(MogError::Io(..)) => {},
+            r @ _ => panic!("Long key with a raised limit returned {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut line = String::new();
+
+            reader.read_line(&mut line).unwrap();
+            write!(writer, "OK \r\n").unwrap();
+
+            // The client hanging up shows up as a clean end of stream.
+            line.clear();
+            reader.read_line(&mut line).unwrap()
+        });
+
+        let mut conn = MogClient::new(&[ addr ]);
+        conn.request(&Noop).unwrap();
+        conn.close().unwrap();
+        assert_eq!(0, server.join().unwrap());
+    }
+
+    #[test]
+    fn test_new_connected() {
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let live = mock_tracker(vec![]);
+
+        let conn = MogClient::new_connected(&[ dead, live ]).unwrap();
+        assert_eq!(Some(live), conn.peer_addr());
+
+        match MogClient::new_connected(&[ dead ]) {
+            Err(MogError::NoTrackers) => {},
+            Err(e) => panic!("Connecting to only a dead tracker failed with {:?}", e),
+            Ok(..) => panic!("Connecting to only a dead tracker worked"),
         }
     }
 
-    pub fn report_stats_to(&mut self, host: &str, prefix: &str) -> MogResult<()> {
-        debug!("Reporting stats to statsd at {:?} with prefix {:?}", host, prefix);
-        match statsd::Client::new(host, prefix) {
-            Ok(s) => {
-                self.statsd = Some(s);
-                Ok(())
-            },
-            Err(e) => {
-                Err(MogError::Other("Statsd error".to_string(), Some(format!("{:?}", e))))
-            }
+    #[test]
+    fn test_request_to() {
+        let (tracker_1, tracker_2) = (mock_tracker(vec![]), mock_tracker(vec![]));
+        let mut conn = MogClient::new(&[ tracker_1, tracker_2 ]);
+
+        conn.request_to(tracker_1, &Noop).unwrap();
+        assert_eq!(Some(tracker_1), conn.peer_addr());
+        conn.request_to(tracker_2, &Noop).unwrap();
+        assert_eq!(Some(tracker_2), conn.peer_addr());
+
+        let unknown = SocketAddr::from_str("127.0.0.1:1").unwrap();
+        match conn.request_to(unknown, &Noop) {
+            Err(MogError::Other(ref kind, _)) if kind == "unknown_tracker" => {},
+            r @ _ => panic!("Request to an unconfigured tracker returned {:?}", r),
         }
     }
 
-    pub fn request<R: Request + ToArgs + ?Sized>(&mut self, req: &R) -> MogResult<Response> {
-        info!("request = {:?}", req);
+    #[test]
+    fn test_peer_addr_with_shared_pool() {
+        // Each client reports the connection it used, not whichever
+        // went back in the pool last.
+        let (tracker_1, tracker_2) = (mock_tracker(vec![]), mock_tracker(vec![]));
+        let mut conn = MogClient::new(&[ tracker_1, tracker_2 ]);
+        let mut other = MogClient::new(&[ tracker_1, tracker_2 ]);
+        conn.set_connection_pool(ConnectionPool::new(2));
+        other.set_connection_pool(conn.connection_pool());
 
-        let resp_rslt = if let Some(ref mut s) = self.statsd {
-            s.incr(&format!("mogilefs_client.requests.{}", req.op()));
+        conn.request_to(tracker_1, &Noop).unwrap();
+        other.request_to(tracker_2, &Noop).unwrap();
+        assert_eq!(Some(tracker_1), conn.peer_addr());
+        assert_eq!(Some(tracker_2), other.peer_addr());
+    }
 
-            let t0 = UTC::now();
-            let rslt = self.transport.do_request(req);
-            let t1 = UTC::now();
+    #[test]
+    fn test_store_data_bytes_key() {
+        let (addr, rx) = recording_tracker(mock_storage("201 Created"));
+        let mut conn = MogClient::new(&[ addr ]);
+        let mut content_reader = Cursor::new(b"New file content".to_vec());
+        let response = conn.store_data_bytes_key("test_domain".to_string(), None, b"caf\xe9", &mut content_reader);
+        assert!(response.is_ok(), "Response was {:?}", response);
 
-            s.timer(&format!("mogilefs_client.request_timing.{}", req.op()),
-                    (t1 - t0).num_milliseconds() as f64);
+        for op in [ "create_open ", "create_close " ].iter() {
+            let line = rx.recv().unwrap();
+            assert!(line.starts_with(op), "Request line was {:?}", line);
+            assert!(line.ends_with("&key=caf%E9"), "Request line was {:?}", line);
+            assert!(line.matches("key=").count() == 1, "Request line was {:?}", line);
+        }
+    }
 
-            rslt
-        } else {
-            self.transport.do_request(req)
-        };
+    #[test]
+    fn test_store_data_sends_idempotency_token() {
+        let (addr, rx) = recording_tracker(mock_storage("201 Created"));
+        let mut conn = MogClient::new(&[ addr ]);
+        conn.store_data("test_domain".to_string(), None, "test/key/1".to_string(), &mut Cursor::new(b"content".to_vec())).unwrap();
 
-        info!("response = {:?}", resp_rslt);
-        resp_rslt
+        let line = rx.recv().unwrap();
+        assert!(line.starts_with("create_open ") && line.contains("&idempotency_token="), "Request line was {:?}", line);
     }
 
-    pub fn store_data<R: Read>(&mut self, domain: String, class: Option<String>, key: String, data: &mut R) -> MogResult<Response> {
-        // Register the file with MogileFS, and ask it where we can store it.
-        let open_req = CreateOpen { domain: domain.clone(), class: class, key: key.clone(), multi_dest: true, size: None };
-        let open_res = try!(self.request(&open_req).and_then(|r| r.downcast::<CreateOpenResponse>().ok_or(MogError::BadResponse)));
+    #[test]
+    fn test_default_domain_and_class() {
+        let (addr, rx) = recording_tracker(mock_storage("201 Created"));
+        let mut conn = MogClient::new(&[ addr ]);
+        conn.set_default_domain(Some("default_domain".to_string()));
+        conn.set_default_class(Some("default_class".to_string()));
 
-        // Choose at random one of the places MogileFS suggests.
-        let mut rng = rand::thread_rng();
-        let &&(ref devid, ref path) = try!(rand::sample(&mut rng, open_res.paths.iter(), 1).first().ok_or(MogError::NoPath));
+        conn.store_data(None, None, "test/key/1".to_string(), &mut Cursor::new(b"content".to_vec())).unwrap();
+        let line = rx.recv().unwrap();
+        assert!(line.contains("domain=default_domain&"), "Request line was {:?}", line);
+        assert!(line.contains("class=default_class&"), "Request line was {:?}", line);
+        rx.recv().unwrap();
 
-        debug!("Storing data for {:?} to {}", key, path);
+        // Explicit arguments win.
+        conn.store_data("test_domain".to_string(), Some("other".to_string()), "test/key/1".to_string(), &mut Cursor::new(b"content".to_vec())).unwrap();
+        let line = rx.recv().unwrap();
+        assert!(line.contains("domain=test_domain&"), "Request line was {:?}", line);
+        assert!(line.contains("class=other&"), "Request line was {:?}", line);
 
-        // Upload the file.
-        let put_res = try!{
-            hyper::Client::new()
-                .put(path.clone())
-                .body(data)
-                .send()
-                .map_err(|e| MogError::StorageError(Some(format!("Could not store to {}: {}", path, e))))
-        };
+        rx.recv().unwrap();
 
-        match &put_res.status {
-            &StatusCode::Ok => {},
-            &StatusCode::Created => {},
-            _ => return Err(MogError::StorageError(Some(format!("Bad response from storage server: {:?}", put_res)))),
+        // An empty domain is just an invalid one, default or not.
+        match conn.store_data("".to_string(), None, "test/key/1".to_string(), &mut Cursor::new(b"content".to_vec())) {
+            Err(MogError::InvalidDomain(..)) => {},
+            r => panic!("Storing to an empty domain returned {:?}", r),
         }
+    }
 
-        // Tell MogileFS where we uploaded the file to, and return the
-        // result of telling it so.
-        self.request(&CreateClose {
-            domain: domain.clone(),
-            key: key.clone(),
-            fid: open_res.fid,
-            devid: *devid,
-            path: path.clone(),
-            checksum: None,
-        })
+    #[test]
+    fn test_last_tracker() {
+        let tracker = mock_tracker(vec![]);
+        let mut conn = MogClient::new(&[ tracker ]);
+        assert_eq!(None, conn.last_tracker());
+        conn.request(&Noop).unwrap();
+        assert_eq!(Some(tracker), conn.last_tracker());
     }
 
-    pub fn is_connected(&self) -> bool {
-        self.transport.is_connected()
+    struct ChannelObserver(mpsc::Sender<(String, bool)>);
+
+    impl RequestObserver for ChannelObserver {
+        fn observe(&self, req_line: &str, result: &MogResult<Response>, _latency: Duration) {
+            self.0.send((req_line.to_string(), result.is_ok())).unwrap();
+        }
     }
 
-    pub fn peer_addr(&self) -> Option<SocketAddr> {
-        self.transport.stream.as_ref().and_then(|s| s.peer_addr())
+    #[test]
+    fn test_upload_deadline() {
+        let (storage, _) = hung_storage();
+        let (tracker, requests) = recording_tracker(storage);
+        let mut conn = MogClient::new(&[ tracker ]);
+        conn.set_upload_deadline(Some(::std::time::Duration::from_millis(200)));
+
+        let result = conn.store_data("test_domain".to_string(), None, "test/key/1".to_string(), &mut Cursor::new(b"test content".to_vec()));
+        match result {
+            Err(MogError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {},
+            r => panic!("Result was {:?}", r),
+        }
+
+        assert!(requests.recv().unwrap().starts_with("create_open "));
+        let cancel = requests.recv().unwrap();
+        assert!(cancel.starts_with("create_cancel "), "Request was {:?}", cancel);
+        assert!(cancel.contains("fid=1"), "Request was {:?}", cancel);
     }
-}
 
-#[derive(Debug)]
-struct MogClientTransport {
-    hosts: Vec<SocketAddr>,
-    stream: Option<ConnectionState>,
-}
+    #[test]
+    fn test_store_stream_idle_timeout() {
+        let (storage, _) = hung_storage();
+        let (tracker, requests) = recording_tracker(storage);
+        let mut conn = MogClient::new(&[ tracker ]);
 
-impl MogClientTransport {
-    fn new<S: ToSocketAddrs + Sized>(tracker_addrs: &[S]) -> MogClientTransport {
-        MogClientTransport {
-            hosts: tracker_addrs.iter().flat_map(|a| a.to_socket_addrs().unwrap()).collect(),
-            stream: Some(ConnectionState::new()),
+        let (_tx, rx) = mpsc::sync_channel(1);
+        let result = conn.store_stream("test_domain".to_string(), None, "test/key/1".to_string(), rx, Some(time::Duration::from_millis(200)));
+        match result {
+            Err(MogError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {},
+            r => panic!("Result was {:?}", r),
         }
+
+        assert!(requests.recv().unwrap().starts_with("create_open "));
+        let cancel = requests.recv().unwrap();
+        assert!(cancel.starts_with("create_cancel "), "Request was {:?}", cancel);
+        assert!(cancel.contains("fid=1"), "Request was {:?}", cancel);
     }
 
-    fn is_connected(&self) -> bool {
-        match self.stream.as_ref() {
-            Some(ref stream) => stream.is_connected(),
-            None => false,
+    #[test]
+    fn test_storage_timeout() {
+        let (storage, connections) = hung_storage();
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ storage ]) ]);
+        conn.set_storage_timeout(Some(time::Duration::from_millis(200)));
+        conn.set_storage_retries(1);
+
+        match conn.get_file("test_domain".to_string(), "test/key/1".to_string(), &mut vec![]) {
+            Err(MogError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {},
+            r => panic!("Result was {:?}", r),
         }
+        assert_eq!(2, connections.try_iter().count());
     }
 
-    fn random_tracker_addr(&self) -> MogResult<SocketAddr> {
-        let mut rng = rand::thread_rng();
-        let mut sample = rand::sample(&mut rng, self.hosts.iter(), 1);
-        sample.pop().cloned().ok_or(MogError::NoTrackers)
+    #[test]
+    fn test_storage_timeout_falls_back() {
+        let (hung, _) = hung_storage();
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ hung, mock_storage("201 Created") ]) ]);
+        conn.set_storage_timeout(Some(time::Duration::from_millis(200)));
+
+        let response = conn.store_data_seekable("test_domain".to_string(), None, "test/key/1".to_string(), &mut Cursor::new(b"test content".to_vec()));
+        assert!(response.is_ok(), "Storing with one hung device returned {:?}", response);
     }
 
-    fn do_request<R: Request + ?Sized>(&mut self, request: &R) -> MogResult<Response> {
-        let mut stream = self.stream.take().unwrap_or(ConnectionState::new());
-        let req_line = format!("{} {}\r\n", request.op(), request.to_urlencoded_string());
-        let mut resp_line = Vec::new();
-        let mut tries = 0;
+    #[test]
+    fn test_request_observers() {
+        let (tx, rx) = mpsc::channel();
+        let mut conn = MogClient::new(&[ mock_tracker(vec![]) ]);
+        conn.add_observer(ChannelObserver(tx));
+        conn.set_trace_id(Some("observed".to_string()));
 
-        loop {
-            if !stream.is_connected() {
-                let tracker = try!(self.random_tracker_addr());
-                debug!("Connecting to {:?}", tracker);
-                stream = stream.connect(&tracker);
-            }
+        conn.request(&Noop).unwrap();
+        let (req_line, ok) = rx.recv().unwrap();
+        assert_eq!("noop trace_id=observed", req_line);
+        assert!(ok);
 
-            debug!("req_line = {:?}", req_line);
-            stream = stream.write_and_flush(req_line.as_bytes());
-            stream = stream.read_until_mb(&mut resp_line);
-            debug!("resp_line = {:?}", String::from_utf8_lossy(&resp_line));
-            tries += 1;
+        // Requests the client refuses to send aren't observed.
+        assert!(conn.request(&Delete { domain: "".to_string(), key: "test/key/1".to_string() }).is_err());
+        assert!(rx.try_recv().is_err());
+    }
 
-            if stream.is_connected() || tries >= 3 { break; }
-        }
+    #[test]
+    fn test_tcp_nodelay() {
+        let nodelay = |conn: &MogClient| match conn.transport.pool.state().idle.last().and_then(|c| c.state.as_ref()) {
+            Some(&ConnectionState::Connected(ref s)) => match *s.get_ref() {
+                TrackerStream::Tcp(ref s) => s.nodelay().unwrap(),
+                ref s => panic!("Not a TCP connection: {:?}", s),
+            },
+            _ => panic!("Not connected"),
+        };
 
+        let mut conn = MogClient::new(&[ mock_tracker(vec![]) ]);
+        conn.request(&Noop).unwrap();
+        assert!(nodelay(&conn));
 
-        let (stream, err) = stream.take_err();
-        self.stream = Some(stream);
+        let mut conn = MogClient::new(&[ mock_tracker(vec![]) ]);
+        conn.set_tcp_nodelay(false);
+        conn.request(&Noop).unwrap();
+        assert!(!nodelay(&conn));
+    }
 
-        match err {
-            Some(err) => Err(MogError::Io(err)),
-            None => {
-                if resp_line.ends_with(b"\r\n") {
-                    let len = resp_line.len();
-                    resp_line = resp_line.into_iter().take(len - 2).collect();
-                }
-                response_from_bytes(request, &resp_line)
-            }
+    #[test]
+    fn test_bind_address() {
+        let bind = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut conn = MogClient::new(&[ mock_tracker(vec![]) ]);
+        conn.set_bind_address(Some(bind));
+        conn.request(&Noop).unwrap();
+
+        match conn.transport.pool.state().idle.last().and_then(|c| c.state.as_ref()) {
+            Some(&ConnectionState::Connected(ref s)) => match *s.get_ref() {
+                TrackerStream::Tcp(ref s) => assert_eq!(bind, s.local_addr().unwrap()),
+                ref s => panic!("Not a TCP connection: {:?}", s),
+            },
+            _ => panic!("Not connected"),
         }
     }
-}
 
-fn response_from_bytes<R: Request + ?Sized>(request: &R, bytes: &[u8]) -> MogResult<Response> {
-    let mut toks = bytes.splitn(2, |&b| b == b' ');
-    let op = toks.next();
-    let args = toks.next().unwrap_or(&[]);
+    #[test]
+    fn test_connection_hooks() {
+        let tracker = mock_tracker(vec![]);
+        let (tx, rx) = mpsc::channel();
+        let mut conn = MogClient::new(&[ tracker ]);
+        conn.on_reconnect(move |addr| tx.send(addr).unwrap());
+        conn.request(&Noop).unwrap();
+        conn.request(&Noop).unwrap();
+        assert_eq!(vec![ tracker ], rx.try_iter().collect::<Vec<_>>());
 
-    match op {
-        Some(b"OK") => request.response_from_bytes(&args),
-        Some(b"ERR") => Err(MogError::from_bytes(&args)),
-        o @ _ => {
-            let err_str = o.map(|bs| {
-                percent_encoding::percent_decode(bs)
-                    .decode_utf8_lossy()
-                    .replace("+", " ")
-            });
-            Err(MogError::Other("Unknown response code".to_string(), err_str))
-        },
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let mut conn = MogClient::new(&[ dead ]);
+        conn.on_disconnect(move |addr, err| tx.send((addr, err.kind())).unwrap());
+        assert!(conn.request(&Noop).is_err());
+        let disconnects: Vec<_> = rx.try_iter().collect();
+        assert_eq!(3, disconnects.len());
+        assert!(disconnects.iter().all(|&(addr, kind)| addr == dead && kind == io::ErrorKind::ConnectionRefused));
     }
-}
 
-#[derive(Debug)]
-enum ConnectionState {
-    NoConnection,
-    Connected(BufStream<TcpStream>),
-    Error(io::Error),
-}
+    #[test]
+    fn test_arg_separator() {
+        let (tx, rx) = mpsc::channel();
+        let tracker = fake_tracker(move |line| {
+            tx.send(line.to_string()).unwrap();
+            "OK paths=2;path1=http://127.0.0.1/dev1/0/000/000/0000000001.fid;devid1=1&path2=http://127.0.0.1/dev2/0/000/000/0000000001.fid".to_string()
+        });
 
-impl ConnectionState {
-    fn new() -> ConnectionState {
-        ConnectionState::NoConnection
+        let mut conn = MogClient::new(&[ tracker ]);
+        conn.set_arg_separator(b';');
+        let paths = conn.get_paths_with_devs("test_domain".to_string(), "test/key;1".to_string(), None).unwrap();
+        assert_eq!(2, paths.len());
+        assert_eq!(Some(1), paths[0].devid);
+
+        let line = rx.recv().unwrap();
+        assert!(line.starts_with("get_paths domain=test_domain;key=test%2Fkey%3B1;"), "Request line was {:?}", line);
+        assert!(!line.contains('&'));
     }
 
-    fn is_connected(&self) -> bool {
-        match self {
-            &ConnectionState::Connected(..) => true,
-            _ => false,
-        }
+    #[test]
+    fn test_reaper() {
+        let mut conn = MogClient::new(&[ mock_tracker(vec![]) ]);
+        conn.request(&Noop).unwrap();
+        conn.start_reaper(::std::time::Duration::from_millis(10), ::std::time::Duration::from_millis(0), false);
+        thread::sleep(::std::time::Duration::from_millis(100));
+        assert!(!conn.is_connected());
+
+        let (tracker, requests) = recording_tracker(mock_storage("201 Created"));
+        let mut conn = MogClient::new(&[ tracker ]);
+        conn.request(&Noop).unwrap();
+        requests.recv().unwrap();
+        conn.start_reaper(::std::time::Duration::from_millis(10), ::std::time::Duration::from_secs(3600), true);
+        assert_eq!("noop", requests.recv_timeout(::std::time::Duration::from_secs(5)).unwrap());
+        assert!(conn.is_connected());
+
+        // Dropping the client stops the reaper.
+        drop(conn);
     }
 
-    fn peer_addr(&self) -> Option<SocketAddr> {
-        match self {
-            &ConnectionState::Connected(ref stream) => {
-                stream.get_ref().peer_addr().ok()
-            },
-            _ => None,
+    #[test]
+    fn test_reaper_keepalive_timeout() {
+        // The keepalive is the tracker's second request, which it
+        // doesn't answer for far longer than the timeout.
+        let mut conn = MogClient::new(&[ slow_tracker(time::Duration::from_secs(5)) ]);
+        conn.request(&Noop).unwrap();
+        conn.set_keepalive_timeout(time::Duration::from_millis(100));
+        conn.start_reaper(time::Duration::from_millis(10), time::Duration::from_secs(3600), true);
+
+        let start = time::Instant::now();
+        while conn.is_connected() && start.elapsed() < time::Duration::from_secs(2) {
+            thread::sleep(time::Duration::from_millis(10));
         }
+        assert!(!conn.is_connected());
     }
 
-    fn take_err(self) -> (ConnectionState, Option<io::Error>) {
-        use self::ConnectionState::*;
+    #[test]
+    fn test_pool_behavior() {
+        // The clients share a pool of one connection, which the first
+        // one's request holds until the tracker's let go of it.
+        let (tracker, arrived, release) = gated_tracker();
+        let mut conn = MogClient::new(&[ tracker ]);
+        let mut other = MogClient::new(&[ tracker ]);
+        other.set_connection_pool(conn.connection_pool());
+        assert_eq!(0, other.connections_in_use());
 
-        match self {
-            ConnectionState::Error(ioe) => (NoConnection, Some(ioe)),
-            _ => (self, None),
+        let busy = thread::spawn(move || conn.request(&Noop).map(|_| conn));
+        arrived.recv().unwrap();
+        assert_eq!(1, other.connections_in_use());
+
+        other.set_pool_behavior(PoolBehavior::FailFast { timeout: time::Duration::from_millis(50) });
+        match other.request(&Noop) {
+            Err(MogError::PoolExhausted) => {},
+            r => panic!("Expected PoolExhausted, got {:?}", r),
         }
+
+        // Blocking waits for the connection to come back, and then
+        // uses it (the tracker only takes the one).
+        other.set_pool_behavior(PoolBehavior::Block);
+        let waiting = thread::spawn(move || other.request(&Noop).map(|_| other));
+        release.send(()).unwrap();
+        let conn = busy.join().unwrap().unwrap();
+        arrived.recv().unwrap();
+        release.send(()).unwrap();
+        let other = waiting.join().unwrap().unwrap();
+
+        assert_eq!(0, conn.connections_in_use());
+        assert!(other.is_connected());
+        assert_eq!(Some(tracker), other.peer_addr());
     }
 
-    fn connect(self, addr: &SocketAddr) -> ConnectionState {
-        use self::ConnectionState::*;
+    #[test]
+    fn test_request_with_priority() {
+        // Three clients share a pool of one connection; while the
+        // first holds it, a normal request and then a high-priority
+        // one wait for it.
+        let (tracker, arrived, release) = gated_tracker();
+        let mut conn = MogClient::new(&[ tracker ]);
+        let mut normal = MogClient::new(&[ tracker ]);
+        let mut high = MogClient::new(&[ tracker ]);
+        normal.set_connection_pool(conn.connection_pool());
+        high.set_connection_pool(conn.connection_pool());
+        normal.set_trace_id(Some("normal".to_string()));
+        high.set_trace_id(Some("high".to_string()));
+        let pool = conn.connection_pool();
 
-        match self {
-            Connected(..) => self,
-            _ => {
-                trace!("Opening connection to {:?}...", addr);
-                match TcpStream::connect(addr) {
-                    Ok(stream) => {
-                        trace!("... connected to {:?}", addr);
-                        Connected(BufStream::new(stream))
-                    },
-                    Err(ioe) => {
-                        error!("Error connecting to {:?}: {}", addr, ioe);
-                        Error(ioe)
-                    },
-                }
-            },
+        let busy = thread::spawn(move || conn.request(&Noop).map(|_| conn));
+        arrived.recv().unwrap();
+        let normal = thread::spawn(move || normal.request(&Noop).map(|_| normal));
+        while pool.waiting() < 1 {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        let high = thread::spawn(move || high.request_with_priority(&Noop, Priority::High).map(|_| high));
+        while pool.waiting() < 2 {
+            thread::sleep(time::Duration::from_millis(1));
         }
+
+        // The high-priority request gets the connection first, even
+        // though it came in second.
+        release.send(()).unwrap();
+        busy.join().unwrap().unwrap();
+        assert_eq!("noop trace_id=high", arrived.recv().unwrap());
+        release.send(()).unwrap();
+        high.join().unwrap().unwrap();
+        assert_eq!("noop trace_id=normal", arrived.recv().unwrap());
+        release.send(()).unwrap();
+        let normal = normal.join().unwrap().unwrap();
+
+        assert_eq!(0, pool.waiting());
+        assert_eq!(Some(tracker), normal.peer_addr());
     }
 
-    fn write_and_flush(self, line: &[u8]) -> ConnectionState {
-        use self::ConnectionState::*;
+    #[test]
+    fn test_last_attempt_count() {
+        let mut conn = MogClient::new(&[ mock_tracker(vec![]) ]);
+        conn.request(&Noop).unwrap();
+        assert_eq!(1, conn.last_attempt_count());
 
-        match self {
-            NoConnection | Error(..) => self,
-            Connected(mut stream) => {
-                let peer = stream.get_ref().peer_addr();
-                trace!("Writing {} bytes to {:?}...", line.len(), peer);
-                match stream.write_all(line).and_then(|_| stream.flush()) {
-                    Ok(..) => {
-                        trace!("... successfully wrote {} bytes to {:?}", line.len(), peer);
-                        Connected(stream)
-                    },
-                    Err(ioe) => {
-                        error!("Error writing to {:?}: {}", peer, ioe);
-                        Error(ioe)
-                    }
-                }
-            },
-        }
+        // Nothing's listening here, so every attempt fails.
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut conn = MogClient::new(&[ dead ]);
+        assert!(conn.request(&Noop).is_err());
+        assert_eq!(3, conn.last_attempt_count());
     }
 
-    fn read_until_mb(self, buf: &mut Vec<u8>) -> ConnectionState {
-        use self::ConnectionState::*;
+    #[test]
+    fn test_retry_policy_attempts() {
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut conn = MogClient::new(&[ dead ]);
+        conn.set_retry_policy(RetryPolicy { attempts: 5, .. RetryPolicy::default() });
+        assert!(conn.request(&Noop).is_err());
+        assert_eq!(5, conn.last_attempt_count());
+    }
 
-        match self {
-            NoConnection | Error(..) => self,
-            Connected(mut stream) => {
-                let peer = stream.get_ref().peer_addr();
-                trace!("Waiting for response from {:?}...", peer);
-                match stream.read_until_mb(b"\r\n", buf) {
-                    Ok(..) => {
-                        trace!("... read {} bytes from {:?}", buf.len(), peer);
-                        Connected(stream)
-                    },
-                    Err(ioe) => {
-                        error!("Error reading from {:?}: {}", peer, ioe);
-                        Error(ioe)
-                    },
-                }
-            }
+    #[test]
+    fn test_retry_policy_full_jitter() {
+        let policy = RetryPolicy {
+            attempts: 10,
+            base_delay: time::Duration::from_millis(10),
+            max_delay: time::Duration::from_millis(100),
+        };
+        assert_eq!(time::Duration::from_millis(10), policy.delay_bound(0));
+        assert_eq!(time::Duration::from_millis(80), policy.delay_bound(3));
+        assert_eq!(time::Duration::from_millis(100), policy.delay_bound(4));
+        assert_eq!(time::Duration::from_millis(100), policy.delay_bound(40));
+
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let delays: Vec<_> = (0..200).map(|i| policy.delay(i % 8, &mut rng)).collect();
+        for (i, delay) in delays.iter().enumerate() {
+            assert!(*delay <= policy.delay_bound(i as u32 % 8));
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use mogilefs_common::Response;
-    use mogilefs_common::requests::*;
-    use std::env;
-    use std::io::{self, Cursor, Write};
-    use std::net::SocketAddr;
-    use std::str::FromStr;
-    use super::*;
+        // The same seed picks the same waits.
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let again: Vec<_> = (0..200).map(|i| policy.delay(i % 8, &mut rng)).collect();
+        assert_eq!(delays, again);
 
-    lazy_static!{
-        static ref TEST_DOMAIN: String = domain_for_testing();
+        assert_eq!(time::Duration::from_millis(0), RetryPolicy::default().delay(2, &mut rng));
     }
 
-    fn trackers_for_testing() -> Option<Vec<SocketAddr>> {
-        env::var("FILAMENT_TEST_TRACKERS").map(|val| {
-            val.split(",")
-                .into_iter()
-                .filter_map(|addr_str| SocketAddr::from_str(addr_str).ok())
-                .collect()
-        }).ok()
-    }
+    #[test]
+    fn test_get_file_if_modified_since() {
+        let mut conn = MogClient::new(&[ mock_tracker(vec![ mock_storage("304 Not Modified") ]) ]);
+        let mut content = Vec::new();
+        let result = conn.get_file_if_modified_since("test_domain".to_string(), "test/key/1".to_string(), UTC::now(), &mut content);
+        assert_eq!(FetchResult::NotModified, result.unwrap());
+        assert!(content.is_empty());
 
-    fn domain_for_testing() -> String {
-        env::var("FILAMENT_TEST_DOMAIN").ok().unwrap_or("filament_test".to_string())
+        // A 304 to an unconditional request makes no sense.
+        let result = conn.get_file("test_domain".to_string(), "test/key/1".to_string(), &mut content);
+        assert!(result.is_err(), "Result was {:?}", result);
     }
 
-    fn skip() {
-        write!(&mut io::stdout(), "(skipped) ").unwrap();
+    #[test]
+    fn test_response_timing() {
+        let mut conn = MogClient::new(&[ mock_tracker(vec![]) ]);
+        assert!(conn.transport.last_timing.is_none());
+        conn.request(&Noop).unwrap();
+
+        let timing = conn.transport.last_timing.unwrap();
+        assert!(timing.first_byte <= timing.full_response);
     }
 
-    macro_rules! test_conn {
-        () => {
-            {
-                let trackers = match trackers_for_testing() {
-                    Some(vec) => vec,
-                    None => {
-                        skip();
-                        return;
-                    },
-                };
-                assert!(trackers.len() >= 1);
-                MogClient::new(&trackers)
+    #[test]
+    fn test_in_process_response_timing() {
+        struct NoopTracker;
+
+        impl InProcessTracker for NoopTracker {
+            fn handle_line(&self, _request_line: &[u8]) -> String {
+                "OK ".to_string()
             }
         }
-    }
 
-    #[test]
-    fn test_connection() {
-        let mut conn = test_conn!();
-        let response = conn.request(&Noop);
-        assert!(response.is_ok());
-        assert_eq!(Response::Empty, response.ok().unwrap());
-        assert!(conn.is_connected());
+        let mut conn = MogClient::in_process(NoopTracker);
+        conn.request(&Noop).unwrap();
+        assert!(conn.transport.last_timing.is_some());
+        assert_eq!(None, conn.last_tracker());
     }
 
     #[test]
-    fn test_store_data() {
-        let mut conn = test_conn!();
-        let content: Vec<u8> = b"New file content".iter().cloned().collect();
-        let mut content_reader = Cursor::new(content);
-        let response = conn.store_data(TEST_DOMAIN.clone(), None, "test/key/1".to_string(), &mut content_reader);
-        assert!(response.is_ok());
+    fn test_request_sends_trace_id() {
+        let (tx, rx) = mpsc::channel();
+        let addr = fake_tracker(move |line| {
+            tx.send(line.to_string()).unwrap();
+            "OK ".to_string()
+        });
+
+        let mut conn = MogClient::new(&[ addr ]);
+        conn.set_trace_id(Some("abc123".to_string()));
+        assert!(conn.request(&Noop).is_ok());
+        assert_eq!("noop trace_id=abc123", rx.recv().unwrap());
+
+        // Without one set, each request gets its own.
+        conn.set_trace_id(None);
+        conn.request(&Noop).unwrap();
+        conn.request(&Noop).unwrap();
+        let (first, second) = (rx.recv().unwrap(), rx.recv().unwrap());
+        assert!(first.starts_with("noop trace_id="), "Request line was {:?}", first);
+        assert!(first != second);
     }
 }
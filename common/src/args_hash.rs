@@ -20,7 +20,7 @@ impl ArgsHash {
 
     /// Removes and returs a `String` associated with `key` from the
     /// hash. Returns `missing_error` if there is no value associated
-    /// with that `key`.
+    /// with that `key`, or the value is blank.
     pub fn extract_required_string(&mut self, key: &str, missing_error: MogError) -> MogResult<String> {
         self.0.remove(key).and_is_not_blank().ok_or(missing_error)
     }
@@ -49,12 +49,33 @@ impl ArgsHash {
         self.0.remove(key)
     }
 
+    /// Removes and returs a `String` associated with `key` from the
+    /// hash, if it is present and not blank. For arguments like
+    /// `class`, where leaving it blank means the same as leaving it
+    /// out.
+    pub fn extract_optional_nonblank_string(&mut self, key: &str) -> Option<String> {
+        self.0.remove(key).and_is_not_blank()
+    }
+
     /// Removes and returs a `u64` associated with `key` from the
     /// hash, if it is present.
     pub fn extract_optional_int(&mut self, key: &str) -> Option<u64> {
         self.0.remove(key).and_then(|f| u64::from_str_radix(&f, 10).ok())
     }
 
+    /// Removes and returns a `u64` associated with `key` from the
+    /// hash, if it is present. Unlike `extract_optional_int`, a value
+    /// which isn't a number is an `invalid_arg` error naming `key`,
+    /// rather than being taken as missing.
+    pub fn extract_optional_valid_int(&mut self, key: &str) -> MogResult<Option<u64>> {
+        match self.0.remove(key) {
+            Some(v) => u64::from_str_radix(&v, 10)
+                .map(Some)
+                .map_err(|_| MogError::Other("invalid_arg".to_string(), Some(key.to_string()))),
+            None => Ok(None),
+        }
+    }
+
     /// Removes and returs a `bool` associated with `key` from the
     /// hash, if it is present. The strings "t", "true", and "1" are
     /// all considered to be true, regardless of case.
@@ -65,6 +86,12 @@ impl ArgsHash {
         }
     }
 
+    /// Whether any argument still in the hash has a name starting
+    /// with `prefix`.
+    pub fn has_prefixed(&self, prefix: &str) -> bool {
+        self.0.keys().any(|k| k.starts_with(prefix))
+    }
+
     /// A helper function to extract a required argument with key
     /// "domain".
     pub fn extract_domain(&mut self) -> MogResult<String> {
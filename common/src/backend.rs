@@ -1,5 +1,5 @@
 use std::io::Read;
-use super::error::MogResult;
+use super::error::{MogError, MogResult};
 use super::request::{Request, Response};
 use super::requests::*;
 
@@ -15,6 +15,71 @@ pub trait Backend: Send + Sync {
     fn rename       (&self, &Rename)       -> MogResult<()>;
     fn list_keys    (&self, &ListKeys)     -> MogResult<ListKeysResponse>;
 
+    /// Delete a key, returning how many files were removed. Backends
+    /// which keep several copies of a file on different devices can
+    /// report them all; the default counts the one file.
+    fn delete_counted(&self, req: &Delete) -> MogResult<DeleteResponse> {
+        self.delete(req).map(|_| DeleteResponse { deleted: 1 })
+    }
+
+    /// Give up on a file which was opened and not yet closed. Only
+    /// backends which keep track of open files can do this.
+    fn create_cancel(&self, _req: &CreateCancel) -> MogResult<()> {
+        Err(MogError::UnknownCommand(Some("create_cancel".to_string())))
+    }
+
+    /// Rename several keys, all or nothing. Only backends which can
+    /// undo a partly-done batch can do this.
+    fn rename_many(&self, _req: &RenameMany) -> MogResult<()> {
+        Err(MogError::UnknownCommand(Some("rename_many".to_string())))
+    }
+
+    /// Copy a key's content to a new key. Only backends which hold
+    /// the content themselves can do this; the rest say they don't
+    /// know the command.
+    fn copy(&self, _req: &CopyKey) -> MogResult<()> {
+        Err(MogError::UnknownCommand(Some("copy_key".to_string())))
+    }
+
+    /// Move a stored file to a different class in its domain.
+    fn update_class(&self, _req: &UpdateClass) -> MogResult<()> {
+        Err(MogError::UnknownCommand(Some("updateclass".to_string())))
+    }
+
+    /// Replicate a file to one more device. Only backends which
+    /// simulate replication can be asked to do this.
+    fn replicate(&self, _req: &Replicate) -> MogResult<()> {
+        Err(MogError::UnknownCommand(Some("replicate".to_string())))
+    }
+
+    /// Report how many files and bytes are stored in each domain and
+    /// class.
+    fn usage(&self, _req: &Usage) -> MogResult<UsageResponse> {
+        Err(MogError::UnknownCommand(Some("usage".to_string())))
+    }
+
+    /// Report everything about a key: what `file_info` does, plus its
+    /// paths.
+    fn locate(&self, _req: &Locate) -> MogResult<LocateResponse> {
+        Err(MogError::UnknownCommand(Some("locate".to_string())))
+    }
+
+    /// List the keys whose content was stored after a time, with when
+    /// each was.
+    fn list_keys_since(&self, _req: &ListKeysSince) -> MogResult<ListKeysSinceResponse> {
+        Err(MogError::UnknownCommand(Some("list_keys_since".to_string())))
+    }
+
+    /// List the keys in every domain, by domain and then by key.
+    fn list_all_keys(&self, _req: &ListAllKeys) -> MogResult<ListAllKeysResponse> {
+        Err(MogError::UnknownCommand(Some("list_all_keys".to_string())))
+    }
+
+    /// Report the server version and the commands it understands.
+    fn server_version(&self, _req: &ServerVersion) -> MogResult<ServerVersionResponse> {
+        Ok(ServerVersionResponse::current())
+    }
+
     fn handle<R: Request + ?Sized>(&self, request: &R) -> MogResult<Response> where Self: Sized {
         request.perform(self)
     }
@@ -27,7 +92,7 @@ pub trait Backend: Send + Sync {
         use rand;
 
         // Register the file with MogileFS, and ask it where we can store it.
-        let open_req = CreateOpen { domain: domain.clone(), class: class, key: key.clone(), multi_dest: true, size: None };
+        let open_req = CreateOpen { domain: domain.clone(), class: class, key: key.clone(), multi_dest: true, size: None, if_absent: false, idempotency_token: None };
         let open_res = try!(self.create_open(&open_req));
 
         // Choose at random one of the places MogileFS suggests.
@@ -48,6 +113,7 @@ pub trait Backend: Send + Sync {
 
         match &put_res.status {
             &StatusCode::Ok | &StatusCode::Created => {},
+            &StatusCode::InsufficientStorage => return Err(OutOfSpace(path.to_string())),
             _ => return Err(StorageError(Some(format!("Bad response from storage server: {:?}", put_res)))),
         }
 
@@ -100,6 +166,50 @@ impl Backend for Box<Backend> {
     fn list_keys(&self, req: &ListKeys) -> MogResult<ListKeysResponse> {
         (&**self).list_keys(req)
     }
+
+    fn delete_counted(&self, req: &Delete) -> MogResult<DeleteResponse> {
+        (&**self).delete_counted(req)
+    }
+
+    fn rename_many(&self, req: &RenameMany) -> MogResult<()> {
+        (&**self).rename_many(req)
+    }
+
+    fn create_cancel(&self, req: &CreateCancel) -> MogResult<()> {
+        (&**self).create_cancel(req)
+    }
+
+    fn copy(&self, req: &CopyKey) -> MogResult<()> {
+        (&**self).copy(req)
+    }
+
+    fn update_class(&self, req: &UpdateClass) -> MogResult<()> {
+        (&**self).update_class(req)
+    }
+
+    fn replicate(&self, req: &Replicate) -> MogResult<()> {
+        (&**self).replicate(req)
+    }
+
+    fn usage(&self, req: &Usage) -> MogResult<UsageResponse> {
+        (&**self).usage(req)
+    }
+
+    fn locate(&self, req: &Locate) -> MogResult<LocateResponse> {
+        (&**self).locate(req)
+    }
+
+    fn list_keys_since(&self, req: &ListKeysSince) -> MogResult<ListKeysSinceResponse> {
+        (&**self).list_keys_since(req)
+    }
+
+    fn list_all_keys(&self, req: &ListAllKeys) -> MogResult<ListAllKeysResponse> {
+        (&**self).list_all_keys(req)
+    }
+
+    fn server_version(&self, req: &ServerVersion) -> MogResult<ServerVersionResponse> {
+        (&**self).server_version(req)
+    }
 }
 
 /// Middleware that wraps the handling of a Request.
@@ -162,4 +272,134 @@ impl Backend for BackendStack {
     fn list_keys(&self, req: &ListKeys) -> MogResult<ListKeysResponse> {
         self.backend.as_ref().unwrap().list_keys(req)
     }
+
+    fn delete_counted(&self, req: &Delete) -> MogResult<DeleteResponse> {
+        self.backend.as_ref().unwrap().delete_counted(req)
+    }
+
+    fn rename_many(&self, req: &RenameMany) -> MogResult<()> {
+        self.backend.as_ref().unwrap().rename_many(req)
+    }
+
+    fn copy(&self, req: &CopyKey) -> MogResult<()> {
+        self.backend.as_ref().unwrap().copy(req)
+    }
+
+    fn update_class(&self, req: &UpdateClass) -> MogResult<()> {
+        self.backend.as_ref().unwrap().update_class(req)
+    }
+
+    fn create_cancel(&self, req: &CreateCancel) -> MogResult<()> {
+        self.backend.as_ref().unwrap().create_cancel(req)
+    }
+
+    fn replicate(&self, req: &Replicate) -> MogResult<()> {
+        self.backend.as_ref().unwrap().replicate(req)
+    }
+
+    fn usage(&self, req: &Usage) -> MogResult<UsageResponse> {
+        self.backend.as_ref().unwrap().usage(req)
+    }
+
+    fn locate(&self, req: &Locate) -> MogResult<LocateResponse> {
+        self.backend.as_ref().unwrap().locate(req)
+    }
+
+    fn list_keys_since(&self, req: &ListKeysSince) -> MogResult<ListKeysSinceResponse> {
+        self.backend.as_ref().unwrap().list_keys_since(req)
+    }
+
+    fn list_all_keys(&self, req: &ListAllKeys) -> MogResult<ListAllKeysResponse> {
+        self.backend.as_ref().unwrap().list_all_keys(req)
+    }
+
+    fn server_version(&self, req: &ServerVersion) -> MogResult<ServerVersionResponse> {
+        self.backend.as_ref().unwrap().server_version(req)
+    }
+}
+
+/// A Backend wrapper which refuses anything that would change the
+/// wrapped backend, for serving from a replica.
+pub struct ReadOnly<B: Backend>(pub B);
+
+impl<B: Backend> Backend for ReadOnly<B> {
+    fn create_domain(&self, _req: &CreateDomain) -> MogResult<CreateDomain> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn create_open(&self, _req: &CreateOpen) -> MogResult<CreateOpenResponse> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn create_close(&self, _req: &CreateClose) -> MogResult<()> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn create_class(&self, _req: &CreateClass) -> MogResult<CreateClassResponse> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn get_paths(&self, req: &GetPaths) -> MogResult<GetPathsResponse> {
+        self.0.get_paths(req)
+    }
+
+    fn file_info(&self, req: &FileInfo) -> MogResult<FileInfoResponse> {
+        self.0.file_info(req)
+    }
+
+    fn delete(&self, _req: &Delete) -> MogResult<()> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn rename(&self, _req: &Rename) -> MogResult<()> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn list_keys(&self, req: &ListKeys) -> MogResult<ListKeysResponse> {
+        self.0.list_keys(req)
+    }
+
+    fn delete_counted(&self, _req: &Delete) -> MogResult<DeleteResponse> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn rename_many(&self, _req: &RenameMany) -> MogResult<()> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn create_cancel(&self, _req: &CreateCancel) -> MogResult<()> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn copy(&self, _req: &CopyKey) -> MogResult<()> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn update_class(&self, _req: &UpdateClass) -> MogResult<()> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn replicate(&self, _req: &Replicate) -> MogResult<()> {
+        Err(MogError::ReadOnly)
+    }
+
+    fn usage(&self, req: &Usage) -> MogResult<UsageResponse> {
+        self.0.usage(req)
+    }
+
+    fn locate(&self, req: &Locate) -> MogResult<LocateResponse> {
+        self.0.locate(req)
+    }
+
+    fn list_keys_since(&self, req: &ListKeysSince) -> MogResult<ListKeysSinceResponse> {
+        self.0.list_keys_since(req)
+    }
+
+    fn list_all_keys(&self, req: &ListAllKeys) -> MogResult<ListAllKeysResponse> {
+        self.0.list_all_keys(req)
+    }
+
+    fn server_version(&self, req: &ServerVersion) -> MogResult<ServerVersionResponse> {
+        self.0.server_version(req)
+    }
 }
@@ -6,8 +6,8 @@ use std::io;
 use std::str::{self, Utf8Error};
 use std::sync::mpsc::{SendError, RecvError};
 use std::sync::{MutexGuard, RwLockReadGuard, RwLockWriteGuard, PoisonError};
-use super::request::Renderable;
-use super::util::ToUrlencodedString;
+use super::request::{Renderable, ResponseFormat};
+use super::util::{ToUrlencodedString, decode_mog_field, to_json_string};
 use url::percent_encoding::{self, QUERY_ENCODE_SET};
 
 /// A specialization of `Result` with the error type hard-coded to
@@ -17,12 +17,14 @@ pub type MogResult<T> = Result<T, MogError>;
 /// The error types that mogilefsd can produce.
 #[derive(Debug)]
 pub enum MogError {
+    ChecksumMismatch(String),
     DomainExists(String),
     Io(io::Error),
     KeyExists(String),
     NoClass,
     NoConnection,
     NoContent(String),
+    NoDevices,
     NoDevid,
     NoDomain,
     NoFid,
@@ -30,11 +32,24 @@ pub enum MogError {
     NoPath,
     NoTrackers,
     InvalidMindevcount,
+    InvalidDomain(String),
+    InvalidKey(String),
+    OutOfSpace(String),
     Other(String, Option<String>),
     PoisonedMutex,
+    /// No connection to a tracker became free before the client's
+    /// fail-fast timeout.
+    PoolExhausted,
+    RateLimited,
+    ReadOnly,
     RecvError,
+    RequestTooLong,
     SendError,
+    /// The content stored for a file isn't the size it was opened
+    /// with.
+    SizeMismatch { declared: u64, actual: u64 },
     UnknownCommand(Option<String>),
+    UnknownFid,
     UnknownKey(String),
     UnregDomain(String),
     UnregClass(String),
@@ -51,12 +66,17 @@ impl MogError {
         use self::MogError::*;
 
         match *self {
+            ChecksumMismatch(..) => "checksum_mismatch",
             DomainExists(..) => "domain_exists",
             InvalidMindevcount => "invalid_mindevcount",
+            InvalidDomain(..) => "invalid_domain",
+            InvalidKey(..) => "invalid_key",
             KeyExists(..) => "key_exists",
             NoDomain => "no_domain",
             NoKey => "no_key",
+            ReadOnly => "read_only",
             UnknownCommand(..) => "unknown_command",
+            UnknownFid => "unknown_fid",
             UnknownKey(..) => "unknown_key",
             UnregClass(..) => "unreg_class",
             UnregDomain(..) => "unreg_domain",
@@ -67,12 +87,18 @@ impl MogError {
             NoClass => "no_class",
             NoConnection => "no_connection",
             NoContent(..) => "no_content",
+            NoDevices => "no_devices",
             NoDevid => "no_devid",
             NoFid => "no_fid",
             NoPath => "no_path",
             NoTrackers => "no_trackers",
+            OutOfSpace(..) => "out_of_space",
             PoisonedMutex => "poisoned_mutex",
+            PoolExhausted => "pool_exhausted",
+            RateLimited => "rate_limited",
+            RequestTooLong => "request_too_long",
             SendError => "send_error",
+            SizeMismatch { .. } => "size_mismatch",
             RecvError => "recv_error",
             UnknownCode(..) => "unknown_code",
             Utf8(..) => "utf8_error",
@@ -81,26 +107,73 @@ impl MogError {
         }
     }
 
-    /// Constructs a `MogError` from the bytes provided.
+    /// Whether the error is down to what the client asked for (a key
+    /// which doesn't exist, a bad argument, a request over a limit),
+    /// rather than something going wrong on the server. Errors the
+    /// server can't classify, like `Other`, count as the server's.
+    pub fn is_client_error(&self) -> bool {
+        use self::MogError::*;
+
+        match *self {
+            ChecksumMismatch(..) | DomainExists(..) | InvalidMindevcount | InvalidDomain(..) |
+            InvalidKey(..) | KeyExists(..) | NoClass | NoContent(..) | NoDevid | NoDomain |
+            NoFid | NoKey | RateLimited | ReadOnly | RequestTooLong | SizeMismatch { .. } |
+            UnknownCommand(..) | UnknownFid | UnknownKey(..) | UnregClass(..) | UnregDomain(..) => true,
+
+            Io(..) | NoConnection | NoDevices | NoPath | NoTrackers | OutOfSpace(..) | Other(..) |
+            PoisonedMutex | PoolExhausted | RecvError | SendError | UnknownCode(..) | Utf8(..) |
+            BadResponse | StorageError(..) => false,
+        }
+    }
+
+    /// Constructs a `MogError` from the bytes provided: the part of
+    /// an `ERR` response line after the `ERR`. Every token
+    /// `error_kind` produces comes back as the same variant, except
+    /// `utf8_error`, which can't be rebuilt and comes back as `Other`.
     pub fn from_bytes(bytes: &[u8]) -> MogError {
         use self::MogError::*;
 
         let mut toks = bytes.split(|&b| b == b' ');
         let op = toks.next();
-        let msg = toks.next().map(|m| {
-            percent_encoding::percent_decode(m)
-                .decode_utf8_lossy()
-                .replace("+", " ")
-        });
+        let msg = toks.next().map(decode_mog_field);
 
         match op.map(|o| str::from_utf8(o)) {
+            Some(Ok("bad_response")) => BadResponse,
+            Some(Ok("checksum_mismatch")) => ChecksumMismatch(msg.unwrap_or(String::new())),
+            Some(Ok("domain_exists")) => DomainExists(msg.unwrap_or(String::new())),
             Some(Ok("invalid_mindevcount")) => InvalidMindevcount,
+            Some(Ok("invalid_domain")) => InvalidDomain(msg.unwrap_or(String::new())),
+            Some(Ok("invalid_key")) => InvalidKey(msg.unwrap_or(String::new())),
+            Some(Ok("io_error")) => Io(io::Error::new(io::ErrorKind::Other, msg.unwrap_or(String::new()))),
+            Some(Ok("key_exists")) => KeyExists(msg.unwrap_or(String::new())),
             Some(Ok("no_class")) => NoClass,
+            Some(Ok("no_connection")) => NoConnection,
+            Some(Ok("no_content")) => NoContent(msg.unwrap_or(String::new())),
+            Some(Ok("no_devices")) => NoDevices,
             Some(Ok("no_devid")) => NoDevid,
             Some(Ok("no_domain")) => NoDomain,
             Some(Ok("no_fid")) => NoFid,
+            Some(Ok("no_key")) => NoKey,
             Some(Ok("no_path")) => NoPath,
+            Some(Ok("no_trackers")) => NoTrackers,
+            Some(Ok("out_of_space")) => OutOfSpace(msg.unwrap_or(String::new())),
+            Some(Ok("poisoned_mutex")) => PoisonedMutex,
+            Some(Ok("pool_exhausted")) => PoolExhausted,
+            Some(Ok("rate_limited")) => RateLimited,
+            Some(Ok("read_only")) => ReadOnly,
+            Some(Ok("recv_error")) => RecvError,
+            Some(Ok("request_too_long")) => RequestTooLong,
+            Some(Ok("send_error")) => SendError,
+            Some(Ok("size_mismatch")) => {
+                // Without the sizes in the message (from a tracker
+                // which words it differently), they're left at 0.
+                let (actual, declared) = msg.as_ref().and_then(|m| parse_sizes(m)).unwrap_or((0, 0));
+                SizeMismatch { declared: declared, actual: actual }
+            },
+            Some(Ok("storage_error")) => StorageError(msg),
+            Some(Ok("unknown_code")) => UnknownCode(msg.unwrap_or(String::new())),
             Some(Ok("unknown_command")) => UnknownCommand(msg),
+            Some(Ok("unknown_fid")) => UnknownFid,
             Some(Ok("unknown_key")) => UnknownKey(msg.unwrap_or(String::new())),
             Some(Ok("unreg_domain")) => UnregDomain(msg.unwrap_or(String::new())),
             Some(Ok("unreg_class")) => UnregClass(msg.unwrap_or(String::new())),
@@ -111,6 +184,16 @@ impl MogError {
     }
 }
 
+/// The actual and declared sizes in a `SizeMismatch`'s message, as
+/// its `Display` writes them.
+fn parse_sizes(msg: &str) -> Option<(u64, u64)> {
+    let mut numbers = msg.split(|c: char| !c.is_digit(10)).filter(|n| !n.is_empty()).map(|n| n.parse());
+    match (numbers.next(), numbers.next(), numbers.next()) {
+        (Some(Ok(actual)), Some(Ok(declared)), None) => Some((actual, declared)),
+        _ => None,
+    }
+}
+
 impl<'a, T> From<PoisonError<RwLockReadGuard<'a, T>>> for MogError {
     fn from (_: PoisonError<RwLockReadGuard<'a, T>>) -> MogError {
         MogError::PoisonedMutex
@@ -165,6 +248,9 @@ impl Display for MogError {
             DomainExists(ref d) => write!(f, "That domain already exists: {:?}", d),
 
             UnknownKey(ref d) => write!(f, "Unknown key: {:?}", d),
+            ChecksumMismatch(ref d) => write!(f, "Content doesn't match its checksum for key: {:?}", d),
+            InvalidKey(ref d) => write!(f, "Invalid key: {:?}", d),
+            InvalidDomain(ref d) => write!(f, "Invalid domain name: {:?}", d),
             KeyExists(ref d) => write!(f, "Target key name {:?} already exists, can't overwrite.", d),
 
             UnknownCommand(ref d) => write!(f, "Unknown command: {:?}", d),
@@ -173,6 +259,8 @@ impl Display for MogError {
             Other(ref op, ref desc) => write!(f, "{} {}", op, desc.clone().unwrap_or_default()),
             UnknownCode(ref c) => write!(f, "Unknown code: {:?}", c),
             StorageError(ref os) => write!(f, "Storage error: {:?}", os),
+            OutOfSpace(ref p) => write!(f, "Storage device out of space: {:?}", p),
+            SizeMismatch { declared, actual } => write!(f, "Content is {} bytes, but was declared as {}", actual, declared),
 
             _ => write!(f, "{}", self.description()),
         }
@@ -183,25 +271,36 @@ impl Error for MogError {
     fn description(&self) -> &str {
         use self::MogError::*;
         match *self {
+            ChecksumMismatch(..) => "Content doesn't match its checksum",
             DomainExists(..) => "Domain already exists",
             Io(ref io_err) => io_err.description(),
             KeyExists(..) => "Key already exists",
             InvalidMindevcount => "The mindevcount must be at least 1",
+            InvalidDomain(..) => "Invalid domain name",
+            InvalidKey(..) => "Invalid key",
             NoClass => "No class provided",
             NoConnection => "Could not connect to tracker",
             NoContent(..) => "No content",
+            NoDevices => "No devices available to store the file",
             NoDevid => "No device ID provided",
             NoDomain => "No domain provided",
             NoFid => "No file ID provided",
             NoKey => "No key provided",
             NoPath => "No path provided",
             NoTrackers => "No trackers provided",
+            OutOfSpace(..) => "Storage device out of space",
             Other(..) => "Other error",
             PoisonedMutex => "Poisoned mutex",
+            PoolExhausted => "No tracker connection free",
+            RateLimited => "Too many requests; slow down",
+            ReadOnly => "Tracker is read-only",
             RecvError => "Error receiving response",
+            RequestTooLong => "Request line too long",
             SendError => "Error sending request",
+            SizeMismatch { .. } => "Content doesn't match its declared size",
             UnknownCode(..) => "Unknown response code",
             UnknownCommand(..) => "Unknown command",
+            UnknownFid => "No open file with that file ID",
             UnknownKey(..) => "Unknown key",
             UnregDomain(..) => "Domain name invalid / not found",
             UnregClass(..) => "Class name invalid / not found",
@@ -214,7 +313,13 @@ impl Error for MogError {
 
 impl ToUrlencodedString for MogError {
     fn to_urlencoded_string(&self) -> String {
-        percent_encoding::percent_encode(self.description().as_bytes(), QUERY_ENCODE_SET).collect()
+        // A size mismatch sends its sizes along, so `from_bytes` can
+        // get them back.
+        let message = match *self {
+            MogError::SizeMismatch { .. } => self.to_string(),
+            _ => self.description().to_string(),
+        };
+        percent_encoding::percent_encode(message.as_bytes(), QUERY_ENCODE_SET).collect()
     }
 }
 
@@ -222,4 +327,96 @@ impl Renderable for MogError {
     fn render(&self) -> String {
         format!("ERR {} {}", self.error_kind(), self.to_urlencoded_string())
     }
+
+    fn render_as(&self, format: ResponseFormat) -> String {
+        match format {
+            ResponseFormat::Urlencoded => self.render(),
+            ResponseFormat::Json => {
+                format!("{{\"status\":\"ERR\",\"error\":{},\"message\":{}}}",
+                        to_json_string(self.error_kind()), to_json_string(&self.to_string()))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::str;
+    use super::MogError;
+    use super::MogError::*;
+    use super::super::request::Renderable;
+
+    #[test]
+    fn error_kinds_round_trip() {
+        // The message sent is the error's description, so that's what
+        // comes back in place of any string the error carried.
+        let errors = vec![
+            (ChecksumMismatch("k".to_string()), ChecksumMismatch("Content doesn't match its checksum".to_string())),
+            (DomainExists("d".to_string()), DomainExists("Domain already exists".to_string())),
+            (Io(io::Error::new(io::ErrorKind::Other, "oops")), Io(io::Error::new(io::ErrorKind::Other, "oops"))),
+            (KeyExists("k".to_string()), KeyExists("Key already exists".to_string())),
+            (NoClass, NoClass),
+            (NoConnection, NoConnection),
+            (NoContent("k".to_string()), NoContent("No content".to_string())),
+            (NoDevices, NoDevices),
+            (NoDevid, NoDevid),
+            (NoDomain, NoDomain),
+            (NoFid, NoFid),
+            (NoKey, NoKey),
+            (NoPath, NoPath),
+            (NoTrackers, NoTrackers),
+            (InvalidMindevcount, InvalidMindevcount),
+            (InvalidDomain("d".to_string()), InvalidDomain("Invalid domain name".to_string())),
+            (InvalidKey("k".to_string()), InvalidKey("Invalid key".to_string())),
+            (OutOfSpace("/p".to_string()), OutOfSpace("Storage device out of space".to_string())),
+            (PoisonedMutex, PoisonedMutex),
+            (PoolExhausted, PoolExhausted),
+            (RateLimited, RateLimited),
+            (ReadOnly, ReadOnly),
+            (RecvError, RecvError),
+            (RequestTooLong, RequestTooLong),
+            (SendError, SendError),
+            (SizeMismatch { declared: 10, actual: 4 }, SizeMismatch { declared: 10, actual: 4 }),
+            (UnknownCommand(Some("c".to_string())), UnknownCommand(Some("Unknown command".to_string()))),
+            (UnknownFid, UnknownFid),
+            (UnknownKey("k".to_string()), UnknownKey("Unknown key".to_string())),
+            (UnregDomain("d".to_string()), UnregDomain("Domain name invalid / not found".to_string())),
+            (UnregClass("c".to_string()), UnregClass("Class name invalid / not found".to_string())),
+            (UnknownCode("X".to_string()), UnknownCode("Unknown response code".to_string())),
+            (BadResponse, BadResponse),
+            (StorageError(None), StorageError(Some("Storage error".to_string()))),
+        ];
+
+        for (error, expected) in errors {
+            let rendered = error.render();
+            let parsed = MogError::from_bytes(rendered["ERR ".len()..].as_bytes());
+            assert_eq!(format!("{:?}", expected), format!("{:?}", parsed), "{:?} didn't come back as expected", error);
+        }
+
+        // A size mismatch worded some other way loses its sizes.
+        assert!(matches!(MogError::from_bytes(b"size_mismatch wrong+size"), SizeMismatch { declared: 0, actual: 0 }));
+    }
+
+    #[test]
+    fn client_errors() {
+        assert!(UnknownKey("k".to_string()).is_client_error());
+        assert!(UnknownCommand(None).is_client_error());
+        assert!(NoKey.is_client_error());
+        assert!(!Io(io::Error::new(io::ErrorKind::Other, "oops")).is_client_error());
+        assert!(!PoisonedMutex.is_client_error());
+        assert!(!Other("oops".to_string(), None).is_client_error());
+    }
+
+    #[test]
+    fn canonical_tokens() {
+        assert_eq!("unreg_domain", UnregDomain("d".to_string()).error_kind());
+        assert_eq!("unreg_class", UnregClass("c".to_string()).error_kind());
+        assert_eq!("unknown_key", UnknownKey("k".to_string()).error_kind());
+        assert_eq!("key_exists", KeyExists("k".to_string()).error_kind());
+        assert_eq!("no_devices", NoDevices.error_kind());
+
+        let utf8_error = str::from_utf8(b"\xff").unwrap_err();
+        assert!(matches!(MogError::from_bytes(Utf8(utf8_error).error_kind().as_bytes()), Other(..)));
+    }
 }
@@ -9,10 +9,12 @@ extern crate log;
 #[macro_use]
 extern crate matches;
 
-pub use backend::{Backend, BackendStack, AroundMiddleware};
+pub use backend::{Backend, BackendStack, AroundMiddleware, ReadOnly};
 pub use error::{MogError, MogResult};
-pub use request::{Request, Response, ToResponse, Renderable};
-pub use util::{BufReadMb, FromBytes, ToArgs, ToUrlencodedString};
+pub use request::{Request, Response, ResponseFormat, ToResponse, Renderable, supported_commands};
+pub use request::{ArgSpec, ArgType, OpSpec, supported_operations};
+pub use request::{TRACE_ID_ARG, parse_request, split_request_line, trace_id_from_bytes};
+pub use util::{BufReadMb, FromBytes, ToArgs, ToUrlencodedString, decode_mog_field, to_json_string, url_for_key};
 
 /// The specific request / response types, in a separate module for
 /// easy globbing.
@@ -20,14 +22,23 @@ pub mod requests {
     pub use request::CreateDomain;
     pub use request::{CreateOpen, CreateOpenResponse};
     pub use request::CreateClose;
+    pub use request::CreateCancel;
     pub use request::{CreateClass, CreateClassResponse};
-    pub use request::{GetPaths, GetPathsResponse};
+    pub use request::{DestPath, GetPaths, GetPathsResponse};
     pub use request::{FileInfo, FileInfoResponse};
-    pub use request::Rename;
+    pub use request::{Rename, RenameMany};
+    pub use request::CopyKey;
     pub use request::UpdateClass;
-    pub use request::Delete;
-    pub use request::{ListKeys, ListKeysResponse};
+    pub use request::Replicate;
+    pub use request::{Delete, DeleteResponse};
+    pub use request::{ListKeys, ListKeysResponse, KeyOrder, KeyDetails};
+    pub use request::{ListKeysSince, ListKeysSinceResponse};
+    pub use request::{ListAllKeys, ListAllKeysResponse};
     pub use request::Noop;
+    pub use request::{ServerVersion, ServerVersionResponse};
+    pub use request::{GetStats, GetStatsResponse};
+    pub use request::{Usage, UsageResponse, UsageRow};
+    pub use request::{Locate, LocateResponse};
 }
 
 mod args_hash;
@@ -2,12 +2,12 @@
 
 use std::any::Any;
 use std::fmt::Debug;
-use std::str;
+use std::str::{self, FromStr};
 use super::args_hash::ArgsHash;
 use super::backend::{Backend};
 use super::error::{MogError, MogResult};
-use super::util::{FromBytes, ToArgs, ToUrlencodedString};
-use url::Url;
+use super::util::{FromBytes, ToArgs, ToUrlencodedString, to_json_string};
+use url::{form_urlencoded, Url};
 
 /// A tracker request.
 pub trait Request: Debug + ToArgs + Sync + Send {
@@ -15,9 +15,15 @@ pub trait Request: Debug + ToArgs + Sync + Send {
     /// string, for this request type.
     fn op(&self) -> &'static str;
 
+    /// Whether this request changes anything on the tracker, as
+    /// opposed to only reading from it.
+    fn is_mutating(&self) -> bool;
+
     /// Construct the appropriate response type for this request. This
     /// method shouldn't need to use the receiver `self`, but it is
-    /// included to make the trait object-safe.
+    /// included to make the trait object-safe. Requests whose response
+    /// is empty ignore any arguments that come with it, so a tracker
+    /// which says more than we expect doesn't break us.
     fn response_from_bytes(&self, &[u8]) -> MogResult<Response>;
 
     /// Perform this request's action on the `Backend`. Ultimately
@@ -28,6 +34,8 @@ pub trait Request: Debug + ToArgs + Sync + Send {
 impl<R: Request + ?Sized> Request for Box<R> {
     fn op(&self) -> &'static str { (**self).op() }
 
+    fn is_mutating(&self) -> bool { (**self).is_mutating() }
+
     fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
         (**self).response_from_bytes(bytes)
     }
@@ -37,30 +45,168 @@ impl<R: Request + ?Sized> Request for Box<R> {
     }
 }
 
+/// A function which parses the argument part of a request line into
+/// a particular kind of `Request`.
+type RequestParser = fn(&[u8]) -> MogResult<Box<Request>>;
+
+fn parse_args<R: Request + FromBytes + 'static>(args: &[u8]) -> MogResult<Box<Request>> {
+    R::from_bytes(args).map(|r| Box::new(r) as Box<Request>)
+}
+
+/// The kind of value a request argument takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    Str,
+    Int,
+    /// "1", "t" or "true", in any case, for true; anything else is
+    /// false.
+    Bool,
+    Url,
+    /// One of a fixed set of strings.
+    OneOf(&'static [&'static str]),
+}
+
+/// A request argument's name, and the kind of value it takes.
+/// Numbered arguments (the pairs of a `rename_many`, say) have an `N`
+/// where the number goes, counting from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub arg_type: ArgType,
+}
+
+/// An op code, and the arguments its requests take (apart from the
+/// trace ID, which any request can have).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpSpec {
+    pub op: &'static str,
+    pub required: &'static [ArgSpec],
+    pub optional: &'static [ArgSpec],
+}
+
+/// A request type which can be parsed from a request line, and the
+/// arguments it takes there.
+trait OpArgs {
+    fn op_spec() -> OpSpec;
+}
+
+/// Implements `OpArgs` for a request type. Each use sits next to the
+/// type's `FromBytes` impl, so the two are kept in step in one place.
+macro_rules! op_spec {
+    ($request:ty, $op:expr,
+     required: [$($req_name:expr => $req_type:expr),*],
+     optional: [$($opt_name:expr => $opt_type:expr),*]) => {
+        impl OpArgs for $request {
+            fn op_spec() -> OpSpec {
+                #[allow(unused_imports)]
+                use self::ArgType::*;
+                static SPEC: OpSpec = OpSpec {
+                    op: $op,
+                    required: &[ $(ArgSpec { name: $req_name, arg_type: $req_type }),* ],
+                    optional: &[ $(ArgSpec { name: $opt_name, arg_type: $opt_type }),* ],
+                };
+                SPEC
+            }
+        }
+    }
+}
+
+/// The requests we know how to handle: what they look like, and how
+/// to parse them.
+static REQUEST_PARSERS: &'static [(fn() -> OpSpec, RequestParser)] = &[
+    (CreateDomain::op_spec, parse_args::<CreateDomain>),
+    (CreateOpen::op_spec, parse_args::<CreateOpen>),
+    (CreateClose::op_spec, parse_args::<CreateClose>),
+    (CreateCancel::op_spec, parse_args::<CreateCancel>),
+    (CreateClass::op_spec, parse_args::<CreateClass>),
+    (FileInfo::op_spec, parse_args::<FileInfo>),
+    (GetPaths::op_spec, parse_args::<GetPaths>),
+    (Rename::op_spec, parse_args::<Rename>),
+    (RenameMany::op_spec, parse_args::<RenameMany>),
+    (CopyKey::op_spec, parse_args::<CopyKey>),
+    (UpdateClass::op_spec, parse_args::<UpdateClass>),
+    (Replicate::op_spec, parse_args::<Replicate>),
+    (Delete::op_spec, parse_args::<Delete>),
+    (ListKeys::op_spec, parse_args::<ListKeys>),
+    (Noop::op_spec, parse_args::<Noop>),
+    (ServerVersion::op_spec, parse_args::<ServerVersion>),
+    (GetStats::op_spec, parse_args::<GetStats>),
+    (Usage::op_spec, parse_args::<Usage>),
+    (Locate::op_spec, parse_args::<Locate>),
+    (ListKeysSince::op_spec, parse_args::<ListKeysSince>),
+    (ListAllKeys::op_spec, parse_args::<ListAllKeys>),
+];
+
+/// The op codes of all the requests that can be parsed from a
+/// request line.
+pub fn supported_commands() -> Vec<&'static str> {
+    REQUEST_PARSERS.iter().map(|&(spec, _)| spec().op).collect()
+}
+
+/// Every request that can be parsed from a request line, with the
+/// arguments it takes, for checking a request over before it's built.
+pub fn supported_operations() -> Vec<OpSpec> {
+    REQUEST_PARSERS.iter().map(|&(spec, _)| spec()).collect()
+}
+
+/// Parse a raw request line into a `Request`, without handling it.
+/// This never panics, whatever the bytes are, so it's what to point a
+/// fuzzer at.
+pub fn parse_request(bytes: &[u8]) -> MogResult<Box<Request>> {
+    let (op, args) = split_request_line(bytes);
+
+    match str::from_utf8(op) {
+        Ok("") => Err(MogError::UnknownCommand(None)),
+        Ok(string) => {
+            match REQUEST_PARSERS.iter().find(|&&(spec, _)| spec().op == string) {
+                Some(&(_, parser)) => parser(args),
+                None => Err(MogError::UnknownCommand(Some(string.to_string()))),
+            }
+        },
+        Err(utf8e) => Err(MogError::Utf8(utf8e)),
+    }
+}
+
 impl FromBytes for Box<Request> {
     fn from_bytes(bytes: &[u8]) -> MogResult<Box<Request>> {
-        let mut toks = bytes.split(|&b| b == b' ');
-        let op = toks.next();
-        let args = toks.next().unwrap_or(&[]);
+        parse_request(bytes)
+    }
+}
 
-        match op.map(|bs| str::from_utf8(bs)) {
-            Some(Ok("create_domain")) => CreateDomain::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("create_open"))   => CreateOpen::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("create_close"))  => CreateClose::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("create_class"))  => CreateClass::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("file_info"))     => FileInfo::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("get_paths"))     => GetPaths::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("rename"))        => Rename::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("updateclass"))   => UpdateClass::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("delete"))        => Delete::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("list_keys"))     => ListKeys::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
-            Some(Ok("noop"))          => Noop::from_bytes(args).map(|r| Box::new(r) as Box<Request>),
+/// The argument a client can add to any request to tag it with a
+/// trace id, so that it can be followed through both the client's and
+/// the tracker's logs.
+pub static TRACE_ID_ARG: &'static str = "trace_id";
 
-            Some(Ok(""))     => Err(MogError::UnknownCommand(None)),
-            Some(Ok(string)) => Err(MogError::UnknownCommand(Some(string.to_string()))),
-            Some(Err(utf8e)) => Err(MogError::Utf8(utf8e)),
-            None => Err(MogError::UnknownCommand(None)),
-        }
+/// Pull the trace id out of a raw request line, if it has one.
+pub fn trace_id_from_bytes(bytes: &[u8]) -> Option<String> {
+    let (_, args) = split_request_line(bytes);
+    ArgsHash::from_bytes(args).extract_optional_string(TRACE_ID_ARG).and_then(|id| {
+        if id.is_empty() { None } else { Some(id) }
+    })
+}
+
+/// Splits a request line into its op and its arguments, ignoring any
+/// surrounding whitespace (including the CRLF, if it's still there)
+/// and any extra spaces between the two.
+pub fn split_request_line(bytes: &[u8]) -> (&[u8], &[u8]) {
+    let line = trim_whitespace(bytes);
+
+    match line.iter().position(|&b| b == b' ') {
+        Some(i) => (&line[..i], trim_whitespace(&line[i..])),
+        None => (line, &line[line.len()..]),
+    }
+}
+
+fn trim_whitespace(bytes: &[u8]) -> &[u8] {
+    let is_space = |b: &u8| *b == b' ' || *b == b'\t' || *b == b'\r' || *b == b'\n';
+
+    match bytes.iter().position(|b| !is_space(b)) {
+        Some(start) => {
+            let end = bytes.iter().rposition(|b| !is_space(b)).unwrap();
+            &bytes[start..(end + 1)]
+        },
+        None => &bytes[bytes.len()..],
     }
 }
 
@@ -74,6 +220,13 @@ pub enum Response {
     FileInfo(FileInfoResponse),
     GetPaths(GetPathsResponse),
     ListKeys(ListKeysResponse),
+    ServerVersion(ServerVersionResponse),
+    Delete(DeleteResponse),
+    GetStats(GetStatsResponse),
+    Usage(UsageResponse),
+    Locate(LocateResponse),
+    ListKeysSince(ListKeysSinceResponse),
+    ListAllKeys(ListAllKeysResponse),
 }
 
 impl Response {
@@ -88,6 +241,13 @@ impl Response {
             FileInfo(r)     => downcast(r),
             GetPaths(r)     => downcast(r),
             ListKeys(r)     => downcast(r),
+            Response::ServerVersion(r) => downcast(r),
+            Delete(r)       => downcast(r),
+            Response::GetStats(r) => downcast(r),
+            Usage(r)        => downcast(r),
+            Locate(r)       => downcast(r),
+            Response::ListKeysSince(r) => downcast(r),
+            Response::ListAllKeys(r) => downcast(r),
         }
     }
 }
@@ -103,6 +263,13 @@ impl ToArgs for Response {
             &FileInfo(ref r)     => r.to_args(),
             &GetPaths(ref r)     => r.to_args(),
             &ListKeys(ref r)     => r.to_args(),
+            &Response::ServerVersion(ref r) => r.to_args(),
+            &Delete(ref r)       => r.to_args(),
+            &Response::GetStats(ref r) => r.to_args(),
+            &Usage(ref r)        => r.to_args(),
+            &Locate(ref r)       => r.to_args(),
+            &Response::ListKeysSince(ref r) => r.to_args(),
+            &Response::ListAllKeys(ref r) => r.to_args(),
         }
     }
 }
@@ -137,16 +304,43 @@ impl ToResponse for () {
     }
 }
 
+/// The ways a tracker can format its responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    /// The classic MogileFS format, e.g. "OK key=val&key2=val2".
+    Urlencoded,
+    /// A JSON object, e.g. `{"status":"OK","args":{"key":"val"}}`.
+    Json,
+}
+
 /// Something which can be rendered to a string for the MogileFS
 /// tracker's line-based protocol.
 pub trait Renderable {
     fn render(&self) -> String;
+
+    /// Render in a particular format. Implementations which only know
+    /// the classic format can leave this alone.
+    fn render_as(&self, _format: ResponseFormat) -> String {
+        self.render()
+    }
 }
 
 impl Renderable for Response {
     fn render(&self) -> String {
         format!("OK {}", self.to_urlencoded_string())
     }
+
+    fn render_as(&self, format: ResponseFormat) -> String {
+        match format {
+            ResponseFormat::Urlencoded => self.render(),
+            ResponseFormat::Json => {
+                let args: Vec<String> = self.to_args().iter()
+                    .map(|&(ref k, ref v)| format!("{}:{}", to_json_string(k), to_json_string(v)))
+                    .collect();
+                format!("{{\"status\":\"OK\",\"args\":{{{}}}}}", args.join(","))
+            },
+        }
+    }
 }
 
 /// A `create_domain` request.
@@ -165,6 +359,8 @@ pub struct CreateDomain {
 impl Request for CreateDomain {
     fn op(&self) -> &'static str { "create_domain" }
 
+    fn is_mutating(&self) -> bool { true }
+
     fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
         CreateDomain::from_bytes(bytes).map(|r| r.to_response())
     }
@@ -182,6 +378,8 @@ impl ToResponse for CreateDomain {
     }
 }
 
+op_spec!(CreateDomain, "create_domain", required: ["domain" => Str], optional: []);
+
 impl FromBytes for CreateDomain {
     fn from_bytes(bytes: &[u8]) -> MogResult<CreateDomain> {
         let mut args = ArgsHash::from_bytes(bytes);
@@ -215,12 +413,23 @@ pub struct CreateOpen {
     pub class: Option<String>,
     pub key: String,
     pub multi_dest: bool,
-    pub size: Option<u64>
+    pub size: Option<u64>,
+    /// If set, fail with `KeyExists` rather than opening a new fid
+    /// when the key is already present.
+    pub if_absent: bool,
+    /// A token identifying this particular upload. Our trackers
+    /// answer a repeat of a recent `create_open` with the same token
+    /// and key with the fid and paths they gave the first time, so
+    /// retrying one doesn't leave an orphaned fid behind. Stock
+    /// trackers ignore it.
+    pub idempotency_token: Option<String>,
 }
 
 impl Request for CreateOpen {
     fn op(&self) -> &'static str { "create_open" }
 
+    fn is_mutating(&self) -> bool { true }
+
     fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
         CreateOpenResponse::from_bytes(bytes).map(|r| r.to_response())
     }
@@ -230,14 +439,26 @@ impl Request for CreateOpen {
     }
 }
 
+op_spec!(CreateOpen, "create_open",
+         required: ["domain" => Str, "key" => Str],
+         optional: [
+             "class" => Str,
+             "multi_dest" => Bool,
+             "size" => Int,
+             "if_absent" => Bool,
+             "idempotency_token" => Str
+         ]);
+
 impl FromBytes for CreateOpen {
     fn from_bytes(bytes: &[u8]) -> MogResult<CreateOpen> {
         let mut args = ArgsHash::from_bytes(bytes);
         let domain = try!(args.extract_domain());
-        let class = args.extract_optional_string("class");
+        let class = args.extract_optional_nonblank_string("class");
         let key = try!(args.extract_key());
         let multi_dest = args.extract_bool_value("multi_dest", false);
-        let size = args.extract_optional_int("size");
+        let size = try!(args.extract_optional_valid_int("size"));
+        let if_absent = args.extract_bool_value("if_absent", false);
+        let idempotency_token = args.extract_optional_string("idempotency_token");
 
         Ok(CreateOpen {
             domain: domain,
@@ -245,6 +466,8 @@ impl FromBytes for CreateOpen {
             key: key,
             multi_dest: multi_dest,
             size: size,
+            if_absent: if_absent,
+            idempotency_token: idempotency_token,
         })
     }
 }
@@ -265,22 +488,40 @@ impl ToArgs for CreateOpen {
             rv.push(("size".to_string(), size.to_string()));
         }
 
+        if self.if_absent {
+            rv.push(("if_absent".to_string(), "1".to_string()));
+        }
+
+        if let Some(ref token) = self.idempotency_token {
+            rv.push(("idempotency_token".to_string(), token.clone()));
+        }
+
         rv
     }
 }
 
 /// The response to a `create_open` request.
 ///
-/// Looks like this:
+/// Looks like this, with `multi_dest`:
 ///
 /// ```text
 /// request = "create_open key=test/key/1&multi_dest=1&domain=test_domain_2\r\n"
 /// response = "OK devid_1=1&path_1=http://127.0.0.1:7500/dev1/0/000/001/0000001927.fid&dev_count=1&fid=1927\r\n"
 /// ```
+///
+/// And like this without it:
+///
+/// ```text
+/// request = "create_open key=test/key/1&domain=test_domain_2\r\n"
+/// response = "OK devid=1&path=http://127.0.0.1:7500/dev1/0/000/001/0000001927.fid&fid=1927\r\n"
+/// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CreateOpenResponse {
     pub fid: u64,
     pub paths: Vec<(u64, Url)>,
+    /// Whether the paths are (or are to be) in the numbered,
+    /// multi-dest form. The single form only has room for the first.
+    pub multi_dest: bool,
 }
 
 // impl Response for CreateOpenResponse {}
@@ -295,12 +536,17 @@ impl ToArgs for CreateOpenResponse {
     fn to_args(&self) -> Vec<(String, String)> {
         let mut args = vec!{
             ("fid".to_string(), self.fid.to_string()),
-            ("dev_count".to_string(), self.paths.len().to_string()),
         };
 
-        for (i, &(ref devid, ref url)) in self.paths.iter().enumerate() {
-            args.push((format!("devid_{}", i + 1), devid.to_string()));
-            args.push((format!("path_{}", i + 1), url.to_string()));
+        if self.multi_dest {
+            args.push(("dev_count".to_string(), self.paths.len().to_string()));
+            for (i, &(ref devid, ref url)) in self.paths.iter().enumerate() {
+                args.push((format!("devid_{}", i + 1), devid.to_string()));
+                args.push((format!("path_{}", i + 1), url.to_string()));
+            }
+        } else if let Some(&(ref devid, ref url)) = self.paths.first() {
+            args.push(("devid".to_string(), devid.to_string()));
+            args.push(("path".to_string(), url.to_string()));
         }
 
         args
@@ -311,18 +557,30 @@ impl FromBytes for CreateOpenResponse {
     fn from_bytes(bytes: &[u8]) -> MogResult<CreateOpenResponse> {
         let mut args = ArgsHash::from_bytes(bytes);
         let fid = try!(args.extract_required_int("fid", MogError::NoFid));
-        let devcount = try!(args.extract_required_int("dev_count", MogError::Other("No device count".to_string(), None)));
+        // Go by which form the response is in, not which was asked
+        // for: some trackers send the numbered form either way.
+        let devcount = args.extract_optional_int("dev_count");
         let mut paths = Vec::new();
 
-        for i in 1..(devcount + 1) {
-            let devid = try!(args.extract_required_int(&format!("devid_{}", i), MogError::NoDevid));
-            let url = try!(args.extract_required_url(&format!("path_{}", i), MogError::NoPath));
-            paths.push((devid, url));
+        match devcount {
+            Some(devcount) => {
+                for i in 1..(devcount + 1) {
+                    let devid = try!(args.extract_required_int(&format!("devid_{}", i), MogError::NoDevid));
+                    let url = try!(args.extract_required_url(&format!("path_{}", i), MogError::NoPath));
+                    paths.push((devid, url));
+                }
+            },
+            None => {
+                let devid = try!(args.extract_required_int("devid", MogError::NoDevid));
+                let url = try!(args.extract_required_url("path", MogError::NoPath));
+                paths.push((devid, url));
+            },
         }
 
         Ok(CreateOpenResponse {
             fid: fid,
             paths: paths,
+            multi_dest: devcount.is_some(),
         })
     }
 }
@@ -348,6 +606,8 @@ pub struct CreateClose {
 impl Request for CreateClose {
     fn op(&self) -> &'static str { "create_close" }
 
+    fn is_mutating(&self) -> bool { true }
+
     fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
         Ok(Response::Empty)
     }
@@ -357,6 +617,10 @@ impl Request for CreateClose {
     }
 }
 
+op_spec!(CreateClose, "create_close",
+         required: ["domain" => Str, "key" => Str, "fid" => Int, "devid" => Int, "path" => Url],
+         optional: ["checksum" => Str]);
+
 impl FromBytes for CreateClose {
     fn from_bytes(bytes: &[u8]) -> MogResult<CreateClose> {
         let mut args = ArgsHash::from_bytes(bytes);
@@ -396,6 +660,66 @@ impl ToArgs for CreateClose {
     }
 }
 
+/// A `create_cancel` request, to give up on a file that was opened
+/// but won't be closed. This isn't in stock MogileFS, which just
+/// waits for the open to go stale.
+///
+/// Looks like this:
+///
+/// ```text
+/// request = "create_cancel domain=test_domain&key=test/key/1&fid=1927\r\n"
+/// response = "OK \r\n"
+/// ```
+#[derive(Debug, Clone)]
+pub struct CreateCancel {
+    pub domain: String,
+    pub key: String,
+    pub fid: u64,
+}
+
+impl Request for CreateCancel {
+    fn op(&self) -> &'static str { "create_cancel" }
+
+    fn is_mutating(&self) -> bool { true }
+
+    fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
+        Ok(Response::Empty)
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.create_cancel(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(CreateCancel, "create_cancel",
+         required: ["domain" => Str, "key" => Str, "fid" => Int],
+         optional: []);
+
+impl FromBytes for CreateCancel {
+    fn from_bytes(bytes: &[u8]) -> MogResult<CreateCancel> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let domain = try!(args.extract_domain());
+        let key = try!(args.extract_key());
+        let fid = try!(args.extract_required_int("fid", MogError::NoFid));
+
+        Ok(CreateCancel {
+            domain: domain,
+            key: key,
+            fid: fid,
+        })
+    }
+}
+
+impl ToArgs for CreateCancel {
+    fn to_args(&self) -> Vec<(String, String)> {
+        vec!{
+            ("domain".to_string(), self.domain.clone()),
+            ("key".to_string(), self.key.clone()),
+            ("fid".to_string(), self.fid.to_string()),
+        }
+    }
+}
+
 /// A `create_class` request.
 ///
 /// Looks like this:
@@ -419,6 +743,8 @@ impl Request for CreateClass {
         "create_class"
     }
 
+    fn is_mutating(&self) -> bool { true }
+
     fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
         CreateClassResponse::from_bytes(bytes).map(|r| r.to_response())
     }
@@ -428,6 +754,10 @@ impl Request for CreateClass {
     }
 }
 
+op_spec!(CreateClass, "create_class",
+         required: ["domain" => Str, "class" => Str, "mindevcount" => Int],
+         optional: ["replpolicy" => Str, "hashtype" => Str, "update" => Bool]);
+
 impl FromBytes for CreateClass {
     fn from_bytes(bytes: &[u8]) -> MogResult<CreateClass> {
         let mut args = ArgsHash::from_bytes(bytes);
@@ -538,6 +868,8 @@ pub struct GetPaths {
 impl Request for GetPaths {
     fn op(&self) -> &'static str { "get_paths" }
 
+    fn is_mutating(&self) -> bool { false }
+
     fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
         GetPathsResponse::from_bytes(bytes).map(|r| r.to_response())
     }
@@ -547,6 +879,10 @@ impl Request for GetPaths {
     }
 }
 
+op_spec!(GetPaths, "get_paths",
+         required: ["domain" => Str, "key" => Str],
+         optional: ["noverify" => Bool, "pathcount" => Int]);
+
 impl FromBytes for GetPaths {
     fn from_bytes(bytes: &[u8]) -> MogResult<GetPaths> {
         let mut args = ArgsHash::from_bytes(bytes);
@@ -589,7 +925,16 @@ impl ToArgs for GetPaths {
 /// response = "OK paths=1&path1=http://127.0.0.1:7500/dev1/0/000/001/0000001927.fid\r\n"
 /// ```
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct GetPathsResponse(pub Vec<Url>);
+pub struct GetPathsResponse(pub Vec<DestPath>);
+
+/// One of the places a file is stored. Stock trackers don't say which
+/// device each path is on, but ours do, as `devidN` alongside each
+/// `pathN`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestPath {
+    pub devid: Option<u64>,
+    pub url: Url,
+}
 
 // impl Response for GetPathsResponse {}
 
@@ -606,7 +951,10 @@ impl FromBytes for GetPathsResponse {
         let mut response = GetPathsResponse(Vec::new());
 
         for i in 1..(paths + 1) {
-            response.0.push(try!(args.extract_required_url(&format!("path{}", i), MogError::NoPath)));
+            response.0.push(DestPath {
+                devid: args.extract_optional_int(&format!("devid{}", i)),
+                url: try!(args.extract_required_url(&format!("path{}", i), MogError::NoPath)),
+            });
         }
 
         Ok(response)
@@ -619,8 +967,11 @@ impl ToArgs for GetPathsResponse {
             ("paths".to_string(), self.0.len().to_string()),
         };
 
-        for (i, url) in self.0.iter().enumerate() {
-            args.push((format!("path{}", i + 1), url.to_string()));
+        for (i, path) in self.0.iter().enumerate() {
+            args.push((format!("path{}", i + 1), path.url.to_string()));
+            if let Some(devid) = path.devid {
+                args.push((format!("devid{}", i + 1), devid.to_string()));
+            }
         }
 
         args
@@ -644,6 +995,8 @@ pub struct FileInfo {
 impl Request for FileInfo {
     fn op(&self) -> &'static str { "file_info" }
 
+    fn is_mutating(&self) -> bool { false }
+
     fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
         FileInfoResponse::from_bytes(bytes).map(|r| r.to_response())
     }
@@ -653,6 +1006,8 @@ impl Request for FileInfo {
     }
 }
 
+op_spec!(FileInfo, "file_info", required: ["domain" => Str, "key" => Str], optional: []);
+
 impl FromBytes for FileInfo {
     fn from_bytes(bytes: &[u8]) -> MogResult<FileInfo> {
         let mut args = ArgsHash::from_bytes(bytes);
@@ -691,6 +1046,12 @@ pub struct FileInfoResponse {
     pub domain: String,
     pub class: String,
     pub key: String,
+    /// The `Content-Encoding` the file was stored with. Stock trackers
+    /// don't know about this, so it's only ever set by ours.
+    pub content_encoding: Option<String>,
+    /// The checksum the file was closed with, like "MD5:<hex>", if
+    /// the tracker reports one.
+    pub checksum: Option<String>,
 }
 
 // impl Response for FileInfoResponse {}
@@ -712,20 +1073,32 @@ impl FromBytes for FileInfoResponse {
             domain: try!(args.extract_required_string("domain", MogError::NoDomain)),
             class: try!(args.extract_required_string("class", MogError::NoClass)),
             key: try!(args.extract_required_string("key", MogError::NoKey)),
+            content_encoding: args.extract_optional_string("content_encoding"),
+            checksum: args.extract_optional_nonblank_string("checksum"),
         })
     }
 }
 
 impl ToArgs for FileInfoResponse {
     fn to_args(&self) -> Vec<(String, String)> {
-        vec!{
+        let mut rv = vec!{
             ("domain".to_string(), self.domain.clone()),
             ("key".to_string(), self.key.clone()),
             ("class".to_string(), self.class.clone()),
             ("fid".to_string(), self.fid.to_string()),
             ("devcount".to_string(), self.devcount.to_string()),
             ("length".to_string(), self.length.to_string()),
+        };
+
+        if let Some(ref encoding) = self.content_encoding {
+            rv.push(("content_encoding".to_string(), encoding.clone()));
+        }
+
+        if let Some(ref checksum) = self.checksum {
+            rv.push(("checksum".to_string(), checksum.clone()));
         }
+
+        rv
     }
 }
 
@@ -737,16 +1110,23 @@ impl ToArgs for FileInfoResponse {
 /// request = "rename domain=test_domain_2&from_key=test/key/1&to_key=test/key/2\r\n"
 /// response = "OK \r\n"
 /// ```
+///
+/// If `overwrite` is set, an existing file at `to_key` is replaced
+/// rather than the request failing with `key_exists`. That's an
+/// extension to the protocol, which stock MogileFS trackers ignore.
 #[derive(Debug, Clone)]
 pub struct Rename {
     pub domain: String,
     pub from_key: String,
     pub to_key: String,
+    pub overwrite: bool,
 }
 
 impl Request for Rename {
     fn op(&self) -> &'static str { "rename" }
 
+    fn is_mutating(&self) -> bool { true }
+
     fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
         Ok(Response::Empty)
     }
@@ -756,22 +1136,171 @@ impl Request for Rename {
     }
 }
 
+op_spec!(Rename, "rename",
+         required: ["domain" => Str, "from_key" => Str, "to_key" => Str],
+         optional: ["overwrite" => Bool]);
+
 impl FromBytes for Rename {
     fn from_bytes(bytes: &[u8]) -> MogResult<Rename> {
         let mut args = ArgsHash::from_bytes(bytes);
         let domain = try!(args.extract_domain());
         let from_key = try!(args.extract_required_string("from_key", MogError::NoKey));
         let to_key = try!(args.extract_required_string("to_key", MogError::NoKey));
+        let overwrite = args.extract_bool_value("overwrite", false);
 
         Ok(Rename {
             domain: domain,
             from_key: from_key,
             to_key: to_key,
+            overwrite: overwrite,
         })
     }
 }
 
 impl ToArgs for Rename {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut rv = vec!{
+            ("domain".to_string(), self.domain.clone()),
+            ("from_key".to_string(), self.from_key.clone()),
+            ("to_key".to_string(), self.to_key.clone()),
+        };
+
+        if self.overwrite {
+            rv.push(("overwrite".to_string(), "1".to_string()));
+        }
+
+        rv
+    }
+}
+
+/// A `rename_many` request, which renames several keys at once.
+///
+/// This isn't part of the MogileFS protocol. The renames happen in
+/// order, and either all of them do or (if one fails) none of them
+/// do. Looks like this:
+///
+/// ```text
+/// request = "rename_many domain=test_domain_2&pair_count=2&from_key_1=a/1&to_key_1=b/1&from_key_2=a/2&to_key_2=b/2\r\n"
+/// response = "OK \r\n"
+/// ```
+#[derive(Debug, Clone)]
+pub struct RenameMany {
+    pub domain: String,
+    pub pairs: Vec<(String, String)>,
+}
+
+impl Request for RenameMany {
+    fn op(&self) -> &'static str { "rename_many" }
+
+    fn is_mutating(&self) -> bool { true }
+
+    fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
+        Ok(Response::Empty)
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.rename_many(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(RenameMany, "rename_many",
+         required: ["domain" => Str],
+         optional: ["pair_count" => Int, "from_key_N" => Str, "to_key_N" => Str]);
+
+impl FromBytes for RenameMany {
+    fn from_bytes(bytes: &[u8]) -> MogResult<RenameMany> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let domain = try!(args.extract_domain());
+        let count = try!(args.extract_optional_valid_int("pair_count")).unwrap_or(0);
+        let mut pairs = Vec::new();
+
+        // Saturating, so a bogus count fails on the first missing pair
+        // rather than overflowing.
+        for i in 1..count.saturating_add(1) {
+            let from_key = try!(args.extract_required_string(&format!("from_key_{}", i), MogError::NoKey));
+            let to_key = try!(args.extract_required_string(&format!("to_key_{}", i), MogError::NoKey));
+            pairs.push((from_key, to_key));
+        }
+
+        // Pairs past the count (or with no count at all) would
+        // otherwise be dropped without a word.
+        if args.has_prefixed("from_key_") || args.has_prefixed("to_key_") {
+            return Err(MogError::Other("invalid_arg".to_string(), Some("pair_count".to_string())));
+        }
+
+        Ok(RenameMany {
+            domain: domain,
+            pairs: pairs,
+        })
+    }
+}
+
+impl ToArgs for RenameMany {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut rv = vec!{
+            ("domain".to_string(), self.domain.clone()),
+            ("pair_count".to_string(), self.pairs.len().to_string()),
+        };
+
+        for (i, &(ref from_key, ref to_key)) in self.pairs.iter().enumerate() {
+            rv.push((format!("from_key_{}", i + 1), from_key.clone()));
+            rv.push((format!("to_key_{}", i + 1), to_key.clone()));
+        }
+
+        rv
+    }
+}
+
+/// A `copy_key` request.
+///
+/// This isn't part of the MogileFS protocol; backends which don't hold
+/// the content themselves will reply with `unknown_command`, and the
+/// copy has to be done by reading the content and storing it again.
+/// Looks like this:
+///
+/// ```text
+/// request = "copy_key domain=test_domain_2&from_key=test/key/1&to_key=test/key/2\r\n"
+/// response = "OK \r\n"
+/// ```
+#[derive(Debug, Clone)]
+pub struct CopyKey {
+    pub domain: String,
+    pub from_key: String,
+    pub to_key: String,
+}
+
+impl Request for CopyKey {
+    fn op(&self) -> &'static str { "copy_key" }
+
+    fn is_mutating(&self) -> bool { true }
+
+    fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
+        Ok(Response::Empty)
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.copy(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(CopyKey, "copy_key", required: ["domain" => Str, "from_key" => Str, "to_key" => Str], optional: []);
+
+impl FromBytes for CopyKey {
+    fn from_bytes(bytes: &[u8]) -> MogResult<CopyKey> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let domain = try!(args.extract_domain());
+        let from_key = try!(args.extract_required_string("from_key", MogError::NoKey));
+        let to_key = try!(args.extract_required_string("to_key", MogError::NoKey));
+
+        Ok(CopyKey {
+            domain: domain,
+            from_key: from_key,
+            to_key: to_key,
+        })
+    }
+}
+
+impl ToArgs for CopyKey {
     fn to_args(&self) -> Vec<(String, String)> {
         vec!{
             ("domain".to_string(), self.domain.clone()),
@@ -799,16 +1328,19 @@ pub struct UpdateClass {
 impl Request for UpdateClass {
     fn op(&self) -> &'static str { "updateclass" }
 
+    fn is_mutating(&self) -> bool { true }
+
     fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
         Ok(Response::Empty)
     }
 
-    fn perform(&self, _backend: &Backend) -> MogResult<Response> {
-        // backend.update_class(self).map(|r| r.to_response())
-        Ok(Response::Empty)
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.update_class(self).map(|r| r.to_response())
     }
 }
 
+op_spec!(UpdateClass, "updateclass", required: ["domain" => Str, "key" => Str, "class" => Str], optional: []);
+
 impl FromBytes for UpdateClass {
     fn from_bytes(bytes: &[u8]) -> MogResult<UpdateClass> {
         let mut args = ArgsHash::from_bytes(bytes);
@@ -834,46 +1366,53 @@ impl ToArgs for UpdateClass {
     }
 }
 
-/// A `delete` request.
+/// A `replicate` request.
 ///
-/// Looks like this:
+/// This isn't part of the MogileFS protocol. It asks a backend which
+/// only simulates replication (such as the in-memory one) to copy a
+/// file to one more device, until it's on as many as its class's
+/// mindevcount. Looks like this:
 ///
 /// ```text
-/// request = "delete domain=test_domain_2&key=test/key/2\r\n"
+/// request = "replicate domain=test_domain_2&key=test/key/2\r\n"
 /// response = "OK \r\n"
 /// ```
 #[derive(Debug, Clone)]
-pub struct Delete {
+pub struct Replicate {
     pub domain: String,
     pub key: String,
 }
 
-impl Request for Delete {
-    fn op(&self) -> &'static str { "delete" }
+impl Request for Replicate {
+    fn op(&self) -> &'static str { "replicate" }
+
+    fn is_mutating(&self) -> bool { true }
 
     fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
         Ok(Response::Empty)
     }
 
     fn perform(&self, backend: &Backend) -> MogResult<Response> {
-        backend.delete(self).map(|r| r.to_response())
+        backend.replicate(self).map(|r| r.to_response())
     }
 }
 
-impl FromBytes for Delete {
-    fn from_bytes(bytes: &[u8]) -> MogResult<Delete> {
+op_spec!(Replicate, "replicate", required: ["domain" => Str, "key" => Str], optional: []);
+
+impl FromBytes for Replicate {
+    fn from_bytes(bytes: &[u8]) -> MogResult<Replicate> {
         let mut args = ArgsHash::from_bytes(bytes);
         let domain = try!(args.extract_domain());
         let key = try!(args.extract_key());
 
-        Ok(Delete {
+        Ok(Replicate {
             domain: domain,
             key: key,
         })
     }
 }
 
-impl ToArgs for Delete {
+impl ToArgs for Replicate {
     fn to_args(&self) -> Vec<(String, String)> {
         vec!{
             ("domain".to_string(), self.domain.clone()),
@@ -882,54 +1421,170 @@ impl ToArgs for Delete {
     }
 }
 
-/// A `list_keys` request.
+/// A `delete` request.
 ///
 /// Looks like this:
 ///
 /// ```text
-/// request = "list_keys domain=development_public&prefix=Photo&after=&limit=10\r\n"
-/// response = "OK key_4=Photo/120418/image/thumb&key_6=Photo/12285/image/thumb&key_5=Photo/12285/image&key_count=10&key_10=Photo/126010/image/thumb&key_7=Photo/126009/image&key_8=Photo/126009/image/thumb&key_1=Photo/1105/image&key_3=Photo/120418/image&next_after=Photo/126010/image/thumb&key_2=Photo/1105/image/thumb&key_9=Photo/126010/image\r\n"
+/// request = "delete domain=test_domain_2&key=test/key/2\r\n"
+/// response = "OK deleted=1\r\n"
 /// ```
+///
+/// Real MogileFS trackers just respond with "OK", which comes back as
+/// an empty response.
 #[derive(Debug, Clone)]
-pub struct ListKeys {
+pub struct Delete {
     pub domain: String,
-    pub prefix: Option<String>,
-    pub after: Option<String>,
-    pub limit: Option<u64>,
+    pub key: String,
 }
 
-impl Request for ListKeys {
-    fn op(&self) -> &'static str { "list_keys" }
+impl Request for Delete {
+    fn op(&self) -> &'static str { "delete" }
+
+    fn is_mutating(&self) -> bool { true }
 
     fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
-        ListKeysResponse::from_bytes(bytes).map(|r| r.to_response())
+        if ArgsHash::from_bytes(bytes).extract_optional_int("deleted").is_some() {
+            DeleteResponse::from_bytes(bytes).map(|r| r.to_response())
+        } else {
+            Ok(Response::Empty)
+        }
     }
 
     fn perform(&self, backend: &Backend) -> MogResult<Response> {
-        backend.list_keys(self).map(|r| r.to_response())
+        backend.delete_counted(self).map(|r| r.to_response())
     }
 }
 
-impl FromBytes for ListKeys {
-    fn from_bytes(bytes: &[u8]) -> MogResult<ListKeys> {
+op_spec!(Delete, "delete", required: ["domain" => Str, "key" => Str], optional: []);
+
+impl FromBytes for Delete {
+    fn from_bytes(bytes: &[u8]) -> MogResult<Delete> {
         let mut args = ArgsHash::from_bytes(bytes);
         let domain = try!(args.extract_domain());
-        let prefix = args.extract_optional_string("prefix");
-        let limit = args.extract_optional_int("limit");
-        let after = args.extract_optional_string("after");
+        let key = try!(args.extract_key());
 
-        Ok(ListKeys {
+        Ok(Delete {
             domain: domain,
-            prefix: prefix,
-            limit: limit,
-            after: after,
+            key: key,
         })
     }
 }
 
-impl ToArgs for ListKeys {
+impl ToArgs for Delete {
     fn to_args(&self) -> Vec<(String, String)> {
-        let mut rv = vec!{
+        vec!{
+            ("domain".to_string(), self.domain.clone()),
+            ("key".to_string(), self.key.clone()),
+        }
+    }
+}
+
+/// The response to a `delete` request: the number of files which were
+/// removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeleteResponse {
+    pub deleted: u64,
+}
+
+impl ToResponse for DeleteResponse {
+    fn to_response(self) -> Response {
+        Response::Delete(self)
+    }
+}
+
+impl FromBytes for DeleteResponse {
+    fn from_bytes(bytes: &[u8]) -> MogResult<DeleteResponse> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let deleted = try!(args.extract_required_int("deleted", MogError::Other("No deleted count".to_string(), None)));
+        Ok(DeleteResponse { deleted: deleted })
+    }
+}
+
+impl ToArgs for DeleteResponse {
+    fn to_args(&self) -> Vec<(String, String)> {
+        vec!{
+            ("deleted".to_string(), self.deleted.to_string()),
+        }
+    }
+}
+
+/// A `list_keys` request.
+///
+/// Looks like this:
+///
+/// ```text
+/// request = "list_keys domain=development_public&prefix=Photo&after=&limit=10\r\n"
+/// response = "OK key_4=Photo/120418/image/thumb&key_6=Photo/12285/image/thumb&key_5=Photo/12285/image&key_count=10&key_10=Photo/126010/image/thumb&key_7=Photo/126009/image&key_8=Photo/126009/image/thumb&key_1=Photo/1105/image&key_3=Photo/120418/image&next_after=Photo/126010/image/thumb&key_2=Photo/1105/image/thumb&key_9=Photo/126010/image\r\n"
+/// ```
+#[derive(Debug, Clone)]
+pub struct ListKeys {
+    pub domain: String,
+    pub prefix: Option<String>,
+    pub after: Option<String>,
+    pub limit: Option<u64>,
+    pub order: KeyOrder,
+    /// Ask for each key's size and mtime too. Only the trackers in
+    /// this crate give them; stock ones ignore this.
+    pub with_sizes: bool,
+}
+
+impl Request for ListKeys {
+    fn op(&self) -> &'static str { "list_keys" }
+
+    fn is_mutating(&self) -> bool { false }
+
+    fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
+        let mut response = try!(ListKeysResponse::from_bytes(bytes));
+        if self.with_sizes && response.details.is_none() {
+            response.details = Some(vec![ KeyDetails::default(); response.keys.len() ]);
+        }
+        Ok(response.to_response())
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.list_keys(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(ListKeys, "list_keys",
+         required: ["domain" => Str],
+         optional: [
+             "prefix" => Str,
+             "limit" => Int,
+             "after" => Str,
+             "order" => OneOf(&["lexicographic",
+             "mtime_desc"]),
+             "with_sizes" => Bool
+         ]);
+
+impl FromBytes for ListKeys {
+    fn from_bytes(bytes: &[u8]) -> MogResult<ListKeys> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let domain = try!(args.extract_domain());
+        let prefix = args.extract_optional_string("prefix");
+        let limit = args.extract_optional_int("limit");
+        let after = args.extract_optional_string("after");
+        let order = match args.extract_optional_string("order") {
+            Some(ref o) if !o.is_empty() => try!(o.parse()),
+            _ => KeyOrder::Lexicographic,
+        };
+        let with_sizes = args.extract_bool_value("with_sizes", false);
+
+        Ok(ListKeys {
+            domain: domain,
+            prefix: prefix,
+            limit: limit,
+            after: after,
+            order: order,
+            with_sizes: with_sizes,
+        })
+    }
+}
+
+impl ToArgs for ListKeys {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut rv = vec!{
             ("domain".to_string(), self.domain.clone()),
         };
 
@@ -945,10 +1600,54 @@ impl ToArgs for ListKeys {
             rv.push(("limit".to_string(), self.limit.clone().unwrap().to_string()));
         }
 
+        if self.order != KeyOrder::Lexicographic {
+            rv.push(("order".to_string(), self.order.as_str().to_string()));
+        }
+
+        if self.with_sizes {
+            rv.push(("with_sizes".to_string(), "1".to_string()));
+        }
+
         rv
     }
 }
 
+/// The order in which a `list_keys` request returns its keys.
+///
+/// `Lexicographic` is the classic MogileFS behavior, and is what's
+/// used if the request doesn't say otherwise. `MtimeDesc` lists the
+/// most recently modified keys first, with keys that have never had
+/// content stored sorting last. For `MtimeDesc`, the `after` cursor is
+/// whatever the backend returned as `next_after`, rather than a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyOrder {
+    Lexicographic,
+    MtimeDesc,
+}
+
+impl KeyOrder {
+    /// Return the string used for this ordering in the `order`
+    /// request argument.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            KeyOrder::Lexicographic => "lexicographic",
+            KeyOrder::MtimeDesc => "mtime_desc",
+        }
+    }
+}
+
+impl FromStr for KeyOrder {
+    type Err = MogError;
+
+    fn from_str(s: &str) -> MogResult<KeyOrder> {
+        match s {
+            "lexicographic" => Ok(KeyOrder::Lexicographic),
+            "mtime_desc" => Ok(KeyOrder::MtimeDesc),
+            _ => Err(MogError::Other("unknown_order".to_string(), Some(format!("Unknown key order: {:?}", s)))),
+        }
+    }
+}
+
 /// The response to a `list_keys` request.
 ///
 /// Looks like this:
@@ -957,8 +1656,29 @@ impl ToArgs for ListKeys {
 /// request = "list_keys domain=rn_development_public&prefix=Photo&after=&limit=10\r\n"
 /// response = "OK key_4=Photo/120418/image/thumb&key_6=Photo/12285/image/thumb&key_5=Photo/12285/image&key_count=10&key_10=Photo/126010/image/thumb&key_7=Photo/126009/image&key_8=Photo/126009/image/thumb&key_1=Photo/1105/image&key_3=Photo/120418/image&next_after=Photo/126010/image/thumb&key_2=Photo/1105/image/thumb&key_9=Photo/126010/image\r\n"
 /// ```
+///
+/// `next_after` is the cursor to pass as `after` to get the next page
+/// of keys. If it's `None`, the last key in `keys` is used, which is
+/// the right thing for lexicographic listings.
+///
+/// If the request asked for sizes, `details` has one entry for each
+/// key, in the same order, sent as `key_N_size` and `key_N_mtime`
+/// (each left out if it's unknown).
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ListKeysResponse(pub Vec<String>);
+pub struct ListKeysResponse {
+    pub keys: Vec<String>,
+    pub next_after: Option<String>,
+    pub details: Option<Vec<KeyDetails>>,
+}
+
+/// A key's size and mtime (in seconds since the epoch), as a
+/// `list_keys` request with `with_sizes` set gives them. Either is
+/// `None` for a file which hasn't had content stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyDetails {
+    pub size: Option<u64>,
+    pub mtime: Option<i64>,
+}
 
 // impl Response for ListKeysResponse {}
 
@@ -972,12 +1692,25 @@ impl FromBytes for ListKeysResponse {
     fn from_bytes(bytes: &[u8]) -> MogResult<ListKeysResponse> {
         let mut args = ArgsHash::from_bytes(bytes);
         let key_count = try!(args.extract_required_int("key_count", MogError::Other("No key count".to_string(), None)));
-        let mut response = ListKeysResponse(Vec::new());
+        let mut response = ListKeysResponse { keys: Vec::new(), next_after: None, details: None };
+        let mut details = Vec::new();
+        let mut any_details = false;
 
         for i in 1..(key_count + 1) {
-            response.0.push(try!(args.extract_required_string(&format!("key_{}", i), MogError::NoKey)));
+            response.keys.push(try!(args.extract_required_string(&format!("key_{}", i), MogError::NoKey)));
+            let size = args.extract_optional_int(&format!("key_{}_size", i));
+            let mtime = match args.extract_optional_string(&format!("key_{}_mtime", i)) {
+                Some(m) => Some(try!(m.parse().map_err(|_| MogError::Other("Bad mtime".to_string(), Some(m))))),
+                None => None,
+            };
+            any_details = any_details || size.is_some() || mtime.is_some();
+            details.push(KeyDetails { size: size, mtime: mtime });
         }
 
+        if any_details {
+            response.details = Some(details);
+        }
+        response.next_after = args.extract_optional_string("next_after");
         Ok(response)
     }
 }
@@ -985,13 +1718,22 @@ impl FromBytes for ListKeysResponse {
 impl ToArgs for ListKeysResponse {
     fn to_args(&self) -> Vec<(String, String)> {
         let mut args = vec!{
-            ("key_count".to_string(), self.0.len().to_string()),
+            ("key_count".to_string(), self.keys.len().to_string()),
         };
 
-        for (i, key) in self.0.iter().enumerate() {
+        for (i, key) in self.keys.iter().enumerate() {
             args.push((format!("key_{}", i+1), key.to_string()));
-            if i == self.0.len() - 1 {
-                args.push(("next_after".to_string(), key.to_string()));
+            if let Some(details) = self.details.as_ref().and_then(|d| d.get(i)) {
+                if let Some(size) = details.size {
+                    args.push((format!("key_{}_size", i+1), size.to_string()));
+                }
+                if let Some(mtime) = details.mtime {
+                    args.push((format!("key_{}_mtime", i+1), mtime.to_string()));
+                }
+            }
+            if i == self.keys.len() - 1 {
+                let next_after = self.next_after.as_ref().unwrap_or(key);
+                args.push(("next_after".to_string(), next_after.to_string()));
             }
         }
 
@@ -1013,6 +1755,8 @@ pub struct Noop;
 impl Request for Noop {
     fn op(&self) -> &'static str { "noop" }
 
+    fn is_mutating(&self) -> bool { false }
+
     fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
         Ok(Response::Empty)
     }
@@ -1022,6 +1766,8 @@ impl Request for Noop {
     }
 }
 
+op_spec!(Noop, "noop", required: [], optional: []);
+
 impl FromBytes for Noop {
     fn from_bytes(_bytes: &[u8]) -> MogResult<Noop> {
         Ok(Noop)
@@ -1033,3 +1779,1031 @@ impl ToArgs for Noop {
         vec![]
     }
 }
+
+/// A `server_version` request.
+///
+/// This isn't part of the MogileFS protocol; it lets a client find
+/// out what it's talking to. Looks like this:
+///
+/// ```text
+/// request = "server_version \r\n"
+/// response = "OK version=0.5.0-dev&command_count=2&command_1=create_domain&command_2=noop\r\n"
+/// ```
+#[derive(Debug)]
+pub struct ServerVersion;
+
+impl Request for ServerVersion {
+    fn op(&self) -> &'static str { "server_version" }
+
+    fn is_mutating(&self) -> bool { false }
+
+    fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
+        ServerVersionResponse::from_bytes(bytes).map(|r| r.to_response())
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.server_version(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(ServerVersion, "server_version", required: [], optional: []);
+
+impl FromBytes for ServerVersion {
+    fn from_bytes(_bytes: &[u8]) -> MogResult<ServerVersion> {
+        Ok(ServerVersion)
+    }
+}
+
+impl ToArgs for ServerVersion {
+    fn to_args(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+}
+
+/// The response to a `server_version` request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerVersionResponse {
+    pub version: String,
+    pub commands: Vec<String>,
+}
+
+impl ServerVersionResponse {
+    /// The version of this library, and the commands it knows how to
+    /// parse.
+    pub fn current() -> ServerVersionResponse {
+        ServerVersionResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            commands: supported_commands().iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+impl ToResponse for ServerVersionResponse {
+    fn to_response(self) -> Response {
+        Response::ServerVersion(self)
+    }
+}
+
+impl FromBytes for ServerVersionResponse {
+    fn from_bytes(bytes: &[u8]) -> MogResult<ServerVersionResponse> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let version = try!(args.extract_required_string("version", MogError::Other("No version".to_string(), None)));
+        let command_count = try!(args.extract_required_int("command_count", MogError::Other("No command count".to_string(), None)));
+        let mut commands = Vec::new();
+
+        for i in 1..(command_count + 1) {
+            commands.push(try!(args.extract_required_string(&format!("command_{}", i), MogError::UnknownCommand(None))));
+        }
+
+        Ok(ServerVersionResponse {
+            version: version,
+            commands: commands,
+        })
+    }
+}
+
+impl ToArgs for ServerVersionResponse {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut args = vec!{
+            ("version".to_string(), self.version.clone()),
+            ("command_count".to_string(), self.commands.len().to_string()),
+        };
+
+        for (i, command) in self.commands.iter().enumerate() {
+            args.push((format!("command_{}", i + 1), command.clone()));
+        }
+
+        args
+    }
+}
+
+/// A `usage` request.
+///
+/// This isn't part of the MogileFS protocol. It reports how many files
+/// and bytes are stored in each domain and class, sorted by domain and
+/// then class. Like `list_keys`, it's paginated: pass the domain and
+/// class of the last row you got as `after_domain` and `after_class`
+/// to get the next page. Looks like this:
+///
+/// ```text
+/// request = "usage limit=2\r\n"
+/// response = "OK row_count=2&domain_1=d1&class_1=default&files_1=2&bytes_1=100&domain_2=d2&class_2=default&files_2=0&bytes_2=0\r\n"
+/// ```
+#[derive(Debug, Clone)]
+pub struct Usage {
+    pub after_domain: Option<String>,
+    pub after_class: Option<String>,
+    pub limit: Option<u64>,
+}
+
+impl Usage {
+    /// Whether a row with `domain` and `class` comes after this
+    /// request's cursor.
+    pub fn is_after(&self, domain: &str, class: &str) -> bool {
+        match (self.after_domain.as_ref(), self.after_class.as_ref()) {
+            (Some(d), Some(c)) => (domain, class) > (d.as_str(), c.as_str()),
+            (Some(d), None) => domain > d.as_str(),
+            _ => true,
+        }
+    }
+}
+
+impl Request for Usage {
+    fn op(&self) -> &'static str { "usage" }
+
+    fn is_mutating(&self) -> bool { false }
+
+    fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
+        UsageResponse::from_bytes(bytes).map(|r| r.to_response())
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.usage(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(Usage, "usage",
+         required: [],
+         optional: ["after_domain" => Str, "after_class" => Str, "limit" => Int]);
+
+impl FromBytes for Usage {
+    fn from_bytes(bytes: &[u8]) -> MogResult<Usage> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        Ok(Usage {
+            after_domain: args.extract_optional_string("after_domain"),
+            after_class: args.extract_optional_string("after_class"),
+            limit: args.extract_optional_int("limit"),
+        })
+    }
+}
+
+impl ToArgs for Usage {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut rv = vec![];
+
+        if let Some(ref d) = self.after_domain {
+            rv.push(("after_domain".to_string(), d.clone()));
+        }
+
+        if let Some(ref c) = self.after_class {
+            rv.push(("after_class".to_string(), c.clone()));
+        }
+
+        if let Some(l) = self.limit {
+            rv.push(("limit".to_string(), l.to_string()));
+        }
+
+        rv
+    }
+}
+
+/// One row of a `usage` response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRow {
+    pub domain: String,
+    pub class: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// The response to a `usage` request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageResponse(pub Vec<UsageRow>);
+
+impl ToResponse for UsageResponse {
+    fn to_response(self) -> Response {
+        Response::Usage(self)
+    }
+}
+
+impl FromBytes for UsageResponse {
+    fn from_bytes(bytes: &[u8]) -> MogResult<UsageResponse> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let row_count = try!(args.extract_required_int("row_count", MogError::Other("No row count".to_string(), None)));
+        let mut rows = Vec::new();
+
+        for i in 1..(row_count + 1) {
+            let missing = || MogError::Other("Missing usage row".to_string(), Some(format!("row {}", i)));
+            rows.push(UsageRow {
+                domain: try!(args.extract_required_string(&format!("domain_{}", i), missing())),
+                class: try!(args.extract_required_string(&format!("class_{}", i), missing())),
+                files: try!(args.extract_required_int(&format!("files_{}", i), missing())),
+                bytes: try!(args.extract_required_int(&format!("bytes_{}", i), missing())),
+            });
+        }
+
+        Ok(UsageResponse(rows))
+    }
+}
+
+impl ToArgs for UsageResponse {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut args = vec![ ("row_count".to_string(), self.0.len().to_string()) ];
+
+        for (i, row) in self.0.iter().enumerate() {
+            args.push((format!("domain_{}", i + 1), row.domain.clone()));
+            args.push((format!("class_{}", i + 1), row.class.clone()));
+            args.push((format!("files_{}", i + 1), row.files.to_string()));
+            args.push((format!("bytes_{}", i + 1), row.bytes.to_string()));
+        }
+
+        args
+    }
+}
+
+/// A `locate` request.
+///
+/// This isn't part of the MogileFS protocol. It's `file_info` and
+/// `get_paths` rolled into one, for tools which want to know
+/// everything about a key at once. Looks like this:
+///
+/// ```text
+/// request = "locate domain=test_domain&key=test/key/1\r\n"
+/// response = "OK fid=3&length=4&class=default&mtime=1464000000&paths=1&path1=http://127.0.0.1:7500/dev1/0/000/000/0000000003.fid&devid1=1\r\n"
+/// ```
+#[derive(Debug, Clone)]
+pub struct Locate {
+    pub domain: String,
+    pub key: String,
+}
+
+impl Request for Locate {
+    fn op(&self) -> &'static str { "locate" }
+
+    fn is_mutating(&self) -> bool { false }
+
+    fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
+        LocateResponse::from_bytes(bytes).map(|r| r.to_response())
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.locate(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(Locate, "locate", required: ["domain" => Str, "key" => Str], optional: []);
+
+impl FromBytes for Locate {
+    fn from_bytes(bytes: &[u8]) -> MogResult<Locate> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        Ok(Locate {
+            domain: try!(args.extract_domain()),
+            key: try!(args.extract_key()),
+        })
+    }
+}
+
+impl ToArgs for Locate {
+    fn to_args(&self) -> Vec<(String, String)> {
+        vec!{
+            ("domain".to_string(), self.domain.clone()),
+            ("key".to_string(), self.key.clone()),
+        }
+    }
+}
+
+/// The response to a `locate` request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocateResponse {
+    pub fid: u64,
+    pub length: u64,
+    pub class: String,
+    /// When the content was last stored, in seconds since the epoch,
+    /// if it has been.
+    pub mtime: Option<i64>,
+    pub paths: Vec<DestPath>,
+}
+
+impl ToResponse for LocateResponse {
+    fn to_response(self) -> Response {
+        Response::Locate(self)
+    }
+}
+
+impl FromBytes for LocateResponse {
+    fn from_bytes(bytes: &[u8]) -> MogResult<LocateResponse> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let mtime = match args.extract_optional_string("mtime") {
+            Some(m) => Some(try!(m.parse().map_err(|_| MogError::Other("Bad mtime".to_string(), Some(m))))),
+            None => None,
+        };
+
+        Ok(LocateResponse {
+            fid: try!(args.extract_required_int("fid", MogError::NoFid)),
+            length: try!(args.extract_required_int("length", MogError::Other("No file size".to_string(), None))),
+            class: try!(args.extract_required_string("class", MogError::NoClass)),
+            mtime: mtime,
+            paths: try!(GetPathsResponse::from_bytes(bytes)).0,
+        })
+    }
+}
+
+impl ToArgs for LocateResponse {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut rv = vec!{
+            ("fid".to_string(), self.fid.to_string()),
+            ("length".to_string(), self.length.to_string()),
+            ("class".to_string(), self.class.clone()),
+        };
+
+        if let Some(mtime) = self.mtime {
+            rv.push(("mtime".to_string(), mtime.to_string()));
+        }
+
+        rv.extend(GetPathsResponse(self.paths.clone()).to_args());
+        rv
+    }
+}
+
+/// A `list_keys_since` request.
+///
+/// This isn't part of the MogileFS protocol. It lists the keys whose
+/// content was stored after `since` (in seconds since the epoch), in
+/// order, with when each was stored, for tools which keep another
+/// copy in sync. Keys which have never had content stored aren't
+/// listed. Looks like this:
+///
+/// ```text
+/// request = "list_keys_since domain=test_domain&since=1464000000&after=&limit=10\r\n"
+/// response = "OK key_count=2&key_1=test/key/1&mtime_1=1464000001&key_2=test/key/5&mtime_2=1464000100&next_after=test/key/5\r\n"
+/// ```
+#[derive(Debug, Clone)]
+pub struct ListKeysSince {
+    pub domain: String,
+    pub since: i64,
+    pub after: Option<String>,
+    pub limit: Option<u64>,
+}
+
+impl Request for ListKeysSince {
+    fn op(&self) -> &'static str { "list_keys_since" }
+
+    fn is_mutating(&self) -> bool { false }
+
+    fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
+        ListKeysSinceResponse::from_bytes(bytes).map(|r| r.to_response())
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.list_keys_since(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(ListKeysSince, "list_keys_since",
+         required: ["domain" => Str, "since" => Int],
+         optional: ["after" => Str, "limit" => Int]);
+
+impl FromBytes for ListKeysSince {
+    fn from_bytes(bytes: &[u8]) -> MogResult<ListKeysSince> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let domain = try!(args.extract_domain());
+        let since = match args.extract_optional_string("since") {
+            Some(s) => try!(s.parse().map_err(|_| MogError::Other("Bad since".to_string(), Some(s)))),
+            None => return Err(MogError::Other("No since".to_string(), None)),
+        };
+
+        Ok(ListKeysSince {
+            domain: domain,
+            since: since,
+            after: args.extract_optional_nonblank_string("after"),
+            limit: args.extract_optional_int("limit"),
+        })
+    }
+}
+
+impl ToArgs for ListKeysSince {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut rv = vec!{
+            ("domain".to_string(), self.domain.clone()),
+            ("since".to_string(), self.since.to_string()),
+        };
+
+        if let Some(ref after) = self.after {
+            rv.push(("after".to_string(), after.clone()));
+        }
+
+        if let Some(limit) = self.limit {
+            rv.push(("limit".to_string(), limit.to_string()));
+        }
+
+        rv
+    }
+}
+
+/// The response to a `list_keys_since` request: the keys, each with
+/// when its content was stored. It's rendered like a `list_keys`
+/// response, plus an `mtime_N` for each `key_N`, and the last key is
+/// the cursor for the next page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListKeysSinceResponse(pub Vec<(String, i64)>);
+
+impl ToResponse for ListKeysSinceResponse {
+    fn to_response(self) -> Response {
+        Response::ListKeysSince(self)
+    }
+}
+
+impl FromBytes for ListKeysSinceResponse {
+    fn from_bytes(bytes: &[u8]) -> MogResult<ListKeysSinceResponse> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let key_count = try!(args.extract_required_int("key_count", MogError::Other("No key count".to_string(), None)));
+        let mut keys = Vec::new();
+
+        for i in 1..key_count.saturating_add(1) {
+            let key = try!(args.extract_required_string(&format!("key_{}", i), MogError::NoKey));
+            let mtime = match args.extract_optional_string(&format!("mtime_{}", i)) {
+                Some(m) => try!(m.parse().map_err(|_| MogError::Other("Bad mtime".to_string(), Some(m)))),
+                None => return Err(MogError::Other("No mtime".to_string(), Some(key))),
+            };
+            keys.push((key, mtime));
+        }
+
+        Ok(ListKeysSinceResponse(keys))
+    }
+}
+
+impl ToArgs for ListKeysSinceResponse {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut args = vec!{
+            ("key_count".to_string(), self.0.len().to_string()),
+        };
+
+        for (i, &(ref key, mtime)) in self.0.iter().enumerate() {
+            args.push((format!("key_{}", i+1), key.clone()));
+            args.push((format!("mtime_{}", i+1), mtime.to_string()));
+        }
+
+        if let Some(&(ref key, _)) = self.0.last() {
+            args.push(("next_after".to_string(), key.clone()));
+        }
+
+        args
+    }
+}
+
+/// A `list_all_keys` request.
+///
+/// This isn't part of the MogileFS protocol. It lists the keys in
+/// every domain, by domain and then by key, as (domain, key) pairs,
+/// for admin tools which sweep through everything. The cursor is the
+/// last pair of the previous page; a cursor with just a domain starts
+/// after that whole domain. Looks like this:
+///
+/// ```text
+/// request = "list_all_keys after_domain=test_domain&after_key=test/key/1&limit=10\r\n"
+/// response = "OK key_count=2&domain_1=test_domain&key_1=test/key/2&domain_2=test_domain_2&key_2=a/key&next_after_domain=test_domain_2&next_after_key=a/key\r\n"
+/// ```
+#[derive(Debug, Clone)]
+pub struct ListAllKeys {
+    pub after_domain: Option<String>,
+    pub after_key: Option<String>,
+    pub limit: Option<u64>,
+}
+
+impl ListAllKeys {
+    /// Whether `key` in `domain` comes after this request's cursor.
+    pub fn is_after(&self, domain: &str, key: &str) -> bool {
+        match (self.after_domain.as_ref(), self.after_key.as_ref()) {
+            (Some(d), Some(k)) => (domain, key) > (d.as_str(), k.as_str()),
+            (Some(d), None) => domain > d.as_str(),
+            _ => true,
+        }
+    }
+}
+
+impl Request for ListAllKeys {
+    fn op(&self) -> &'static str { "list_all_keys" }
+
+    fn is_mutating(&self) -> bool { false }
+
+    fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
+        ListAllKeysResponse::from_bytes(bytes).map(|r| r.to_response())
+    }
+
+    fn perform(&self, backend: &Backend) -> MogResult<Response> {
+        backend.list_all_keys(self).map(|r| r.to_response())
+    }
+}
+
+op_spec!(ListAllKeys, "list_all_keys",
+         required: [],
+         optional: ["after_domain" => Str, "after_key" => Str, "limit" => Int]);
+
+impl FromBytes for ListAllKeys {
+    fn from_bytes(bytes: &[u8]) -> MogResult<ListAllKeys> {
+        let mut args = ArgsHash::from_bytes(bytes);
+
+        Ok(ListAllKeys {
+            after_domain: args.extract_optional_nonblank_string("after_domain"),
+            after_key: args.extract_optional_nonblank_string("after_key"),
+            limit: args.extract_optional_int("limit"),
+        })
+    }
+}
+
+impl ToArgs for ListAllKeys {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut rv = vec![];
+
+        if let Some(ref domain) = self.after_domain {
+            rv.push(("after_domain".to_string(), domain.clone()));
+        }
+
+        if let Some(ref key) = self.after_key {
+            rv.push(("after_key".to_string(), key.clone()));
+        }
+
+        if let Some(limit) = self.limit {
+            rv.push(("limit".to_string(), limit.to_string()));
+        }
+
+        rv
+    }
+}
+
+/// The response to a `list_all_keys` request: (domain, key) pairs,
+/// with the last one as the cursor for the next page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListAllKeysResponse(pub Vec<(String, String)>);
+
+impl ToResponse for ListAllKeysResponse {
+    fn to_response(self) -> Response {
+        Response::ListAllKeys(self)
+    }
+}
+
+impl FromBytes for ListAllKeysResponse {
+    fn from_bytes(bytes: &[u8]) -> MogResult<ListAllKeysResponse> {
+        let mut args = ArgsHash::from_bytes(bytes);
+        let key_count = try!(args.extract_required_int("key_count", MogError::Other("No key count".to_string(), None)));
+        let mut keys = Vec::new();
+
+        for i in 1..key_count.saturating_add(1) {
+            let domain = try!(args.extract_required_string(&format!("domain_{}", i), MogError::NoDomain));
+            let key = try!(args.extract_required_string(&format!("key_{}", i), MogError::NoKey));
+            keys.push((domain, key));
+        }
+
+        Ok(ListAllKeysResponse(keys))
+    }
+}
+
+impl ToArgs for ListAllKeysResponse {
+    fn to_args(&self) -> Vec<(String, String)> {
+        let mut args = vec!{
+            ("key_count".to_string(), self.0.len().to_string()),
+        };
+
+        for (i, &(ref domain, ref key)) in self.0.iter().enumerate() {
+            args.push((format!("domain_{}", i+1), domain.clone()));
+            args.push((format!("key_{}", i+1), key.clone()));
+        }
+
+        if let Some(&(ref domain, ref key)) = self.0.last() {
+            args.push(("next_after_domain".to_string(), domain.clone()));
+            args.push(("next_after_key".to_string(), key.clone()));
+        }
+
+        args
+    }
+}
+
+/// A `get_stats` request.
+///
+/// This isn't part of the MogileFS protocol. It asks the tracker for
+/// its request, error, and connection counters, which only the tracker
+/// itself keeps, so a bare backend can't answer it. Looks like this:
+///
+/// ```text
+/// request = "get_stats \r\n"
+/// response = "OK connections=2&errors_unknown_key=1&requests_noop=3\r\n"
+/// ```
+#[derive(Debug)]
+pub struct GetStats;
+
+impl Request for GetStats {
+    fn op(&self) -> &'static str { "get_stats" }
+
+    fn is_mutating(&self) -> bool { false }
+
+    fn response_from_bytes(&self, bytes: &[u8]) -> MogResult<Response> {
+        GetStatsResponse::from_bytes(bytes).map(|r| r.to_response())
+    }
+
+    fn perform(&self, _backend: &Backend) -> MogResult<Response> {
+        Err(MogError::UnknownCommand(Some(self.op().to_string())))
+    }
+}
+
+op_spec!(GetStats, "get_stats", required: [], optional: []);
+
+impl FromBytes for GetStats {
+    fn from_bytes(_bytes: &[u8]) -> MogResult<GetStats> {
+        Ok(GetStats)
+    }
+}
+
+impl ToArgs for GetStats {
+    fn to_args(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+}
+
+/// The response to a `get_stats` request: a list of named counters,
+/// sorted by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetStatsResponse(pub Vec<(String, u64)>);
+
+impl GetStatsResponse {
+    /// Look up a counter by name.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.0.iter().find(|&&(ref n, _)| n == name).map(|&(_, v)| v)
+    }
+}
+
+impl ToResponse for GetStatsResponse {
+    fn to_response(self) -> Response {
+        Response::GetStats(self)
+    }
+}
+
+impl FromBytes for GetStatsResponse {
+    fn from_bytes(bytes: &[u8]) -> MogResult<GetStatsResponse> {
+        let mut stats: Vec<(String, u64)> = form_urlencoded::parse(bytes).into_iter()
+            .filter_map(|(k, v)| v.parse().ok().map(|v| (k.into_owned(), v)))
+            .collect();
+        stats.sort();
+        Ok(GetStatsResponse(stats))
+    }
+}
+
+impl ToArgs for GetStatsResponse {
+    fn to_args(&self) -> Vec<(String, String)> {
+        self.0.iter().map(|&(ref k, v)| (k.clone(), v.to_string())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::error::MogError;
+    use super::super::util::{FromBytes, ToArgs, ToUrlencodedString};
+    use url::Url;
+
+    #[test]
+    fn parse_malformed_requests() {
+        let lines: &[&[u8]] = &[
+            b"", b" ", b"\r\n", b"\xff\xfe", b"noop\xff", b"\xffop domain=d",
+            b"create_open", b"create_open domain", b"create_open domain=&key=",
+            b"get_paths domain=d&key=%", b"get_paths domain=d&key=%f", b"get_paths domain=\xff&key=\xc3",
+            b"list_keys domain=d&limit=-1", b"list_keys domain=d&limit=99999999999999999999999",
+            b"rename_many domain=d&pair_count=18446744073709551615", b"rename_many domain=d&pair_count=2&from_key_1=a",
+            b"create_close domain=d&key=k&fid=&devid=x&path=not a url", b"&&&=&==", b"noop =",
+        ];
+
+        // None of these may panic.
+        for line in lines.iter() {
+            let _ = parse_request(line);
+        }
+
+        // Every truncation of some valid lines, too.
+        let valid: &[&[u8]] = &[
+            b"create_open domain=d&key=test%2Fkey&class=c&multi_dest=1&size=10",
+            b"rename_many domain=d&pair_count=1&from_key_1=a&to_key_1=b",
+        ];
+        for line in valid.iter() {
+            assert!(parse_request(line).is_ok());
+            for end in 0..line.len() {
+                let _ = parse_request(&line[..end]);
+            }
+        }
+
+        assert!(matches!(parse_request(b"\xff\xfe"), Err(MogError::Utf8(..))));
+        assert!(matches!(parse_request(b"rename_many domain=d&pair_count=18446744073709551615"), Err(MogError::NoKey)));
+    }
+
+    #[test]
+    fn missing_required_args() {
+        let lines: &[(&[u8], &str)] = &[
+            (b"create_domain", "no_domain"),
+            (b"create_domain domain=", "no_domain"),
+            (b"create_open key=k", "no_domain"),
+            (b"create_open domain=&key=k", "no_domain"),
+            (b"create_open domain=d", "no_key"),
+            (b"create_open domain=d&key=", "no_key"),
+            (b"create_close key=k&fid=1&devid=1&path=http://h/p", "no_domain"),
+            (b"create_close domain=d&fid=1&devid=1&path=http://h/p", "no_key"),
+            (b"create_close domain=d&key=k&devid=1&path=http://h/p", "no_fid"),
+            (b"create_close domain=d&key=k&fid=1&path=http://h/p", "no_devid"),
+            (b"create_close domain=d&key=k&fid=1&devid=1", "no_path"),
+            (b"create_cancel domain=d&key=k", "no_fid"),
+            (b"create_class class=c&mindevcount=1", "no_domain"),
+            (b"create_class domain=d&mindevcount=1", "no_class"),
+            (b"create_class domain=d&class=&mindevcount=1", "no_class"),
+            (b"create_class domain=d&class=c", "invalid_mindevcount"),
+            (b"file_info key=k", "no_domain"),
+            (b"file_info domain=d", "no_key"),
+            (b"get_paths key=k", "no_domain"),
+            (b"get_paths domain=d", "no_key"),
+            (b"rename from_key=a&to_key=b", "no_domain"),
+            (b"rename domain=d&to_key=b", "no_key"),
+            (b"rename domain=d&from_key=a", "no_key"),
+            (b"rename_many pair_count=0", "no_domain"),
+            (b"copy_key domain=d&from_key=a", "no_key"),
+            (b"updateclass key=k&class=c", "no_domain"),
+            (b"updateclass domain=d&class=c", "no_key"),
+            (b"updateclass domain=d&key=k", "no_class"),
+            (b"updateclass domain=d&key=k&class=", "no_class"),
+            (b"replicate domain=d", "no_key"),
+            (b"delete key=k", "no_domain"),
+            (b"delete domain=d", "no_key"),
+            (b"list_keys", "no_domain"),
+            (b"locate domain=d", "no_key"),
+        ];
+
+        for &(line, kind) in lines.iter() {
+            match parse_request(line) {
+                Err(e) => assert_eq!(kind, e.error_kind(), "for {:?}", String::from_utf8_lossy(line)),
+                Ok(req) => panic!("{:?} parsed as {:?}", String::from_utf8_lossy(line), req),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_create_open_args_are_named() {
+        match CreateOpen::from_bytes(b"domain=d&key=k&size=big") {
+            Err(MogError::Other(ref kind, Some(ref field))) => assert_eq!(("invalid_arg", "size"), (&kind[..], &field[..])),
+            r => panic!("Result was {:?}", r),
+        }
+
+        assert_eq!(Some(10), CreateOpen::from_bytes(b"domain=d&key=k&size=10").unwrap().size);
+        assert_eq!(None, CreateOpen::from_bytes(b"domain=d&key=k").unwrap().size);
+    }
+
+    #[test]
+    fn rename_many_pair_count_covers_every_pair() {
+        let lines: &[&[u8]] = &[
+            b"domain=d&from_key_1=a&to_key_1=b",
+            b"domain=d&pair_count=1&from_key_1=a&to_key_1=b&from_key_2=c&to_key_2=d",
+            b"domain=d&pair_count=0&to_key_3=c",
+            b"domain=d&pair_count=two&from_key_1=a&to_key_1=b",
+        ];
+        for line in lines.iter() {
+            match RenameMany::from_bytes(line) {
+                Err(MogError::Other(ref kind, Some(ref field))) => assert_eq!(("invalid_arg", "pair_count"), (&kind[..], &field[..])),
+                r => panic!("{:?} parsed as {:?}", String::from_utf8_lossy(line), r),
+            }
+        }
+
+        assert_eq!(1, RenameMany::from_bytes(b"domain=d&pair_count=1&from_key_1=a&to_key_1=b").unwrap().pairs.len());
+        assert!(RenameMany::from_bytes(b"domain=d").unwrap().pairs.is_empty());
+    }
+
+    #[test]
+    fn blank_class_is_no_class() {
+        let req = CreateOpen::from_bytes(b"domain=d&key=k&class=").unwrap();
+        assert_eq!(None, req.class);
+    }
+
+    #[test]
+    fn supported_commands_are_parseable() {
+        let commands = supported_commands();
+        assert!(commands.contains(&"noop"));
+        assert!(commands.contains(&"server_version"));
+
+        for op in commands.iter() {
+            let parsed = Box::<Request>::from_bytes(op.as_bytes());
+            assert!(!matches!(parsed, Err(MogError::UnknownCommand(..))),
+                    "{:?} was an unknown command", op);
+        }
+    }
+
+    #[test]
+    fn supported_operations_match_parsers() {
+        let ops: Vec<&str> = supported_operations().iter().map(|o| o.op).collect();
+        assert_eq!(supported_commands(), ops);
+
+        let example = |arg: &ArgSpec| {
+            let name = arg.name.replace("_N", "_1");
+            match arg.arg_type {
+                ArgType::Str => format!("{}=x", name),
+                ArgType::Int | ArgType::Bool => format!("{}=1", name),
+                ArgType::Url => format!("{}=http://127.0.0.1/dev1/0/000/000/0000000001.fid", name),
+                ArgType::OneOf(values) => format!("{}={}", name, values[values.len() - 1]),
+            }
+        };
+
+        for spec in supported_operations() {
+            let args: Vec<String> = spec.required.iter().map(&example).collect();
+            let line = format!("{} {}", spec.op, args.join("&"));
+            let request = Box::<Request>::from_bytes(line.as_bytes());
+            assert!(request.is_ok(), "{:?} didn't parse", line);
+            assert_eq!(spec.op, request.unwrap().op());
+
+            // Everything required really is.
+            for i in 0..args.len() {
+                let mut missing = args.clone();
+                missing.remove(i);
+                let line = format!("{} {}", spec.op, missing.join("&"));
+                assert!(Box::<Request>::from_bytes(line.as_bytes()).is_err(), "{:?} parsed", line);
+            }
+
+            // And everything optional is picked up, and sent on again.
+            let mut all_args = args.clone();
+            all_args.extend(spec.optional.iter().map(&example));
+            let line = format!("{} {}", spec.op, all_args.join("&"));
+            let request = match Box::<Request>::from_bytes(line.as_bytes()) {
+                Ok(request) => request,
+                Err(e) => panic!("{:?} didn't parse: {:?}", line, e),
+            };
+            let sent: Vec<String> = request.to_args().into_iter().map(|(k, _)| k).collect();
+            for arg in spec.optional {
+                let name = arg.name.replace("_N", "_1");
+                assert!(sent.contains(&name), "{:?} lost {:?}: {:?}", line, name, sent);
+            }
+        }
+    }
+
+    #[test]
+    fn mutating_requests() {
+        let parse = |line: &[u8]| Box::<Request>::from_bytes(line).unwrap();
+        assert!(parse(b"delete domain=d&key=k").is_mutating());
+        assert!(parse(b"rename domain=d&from_key=a&to_key=b").is_mutating());
+        assert!(parse(b"create_domain domain=d").is_mutating());
+        assert!(!parse(b"file_info domain=d&key=k").is_mutating());
+        assert!(!parse(b"list_keys domain=d").is_mutating());
+        assert!(!parse(b"noop").is_mutating());
+    }
+
+    #[test]
+    fn split_request_line() {
+        assert_eq!((b"noop".as_ref(), b"".as_ref()), super::split_request_line(b"noop\r\n"));
+        assert_eq!((b"noop".as_ref(), b"".as_ref()), super::split_request_line(b" noop "));
+        assert_eq!((b"create_open".as_ref(), b"domain=x".as_ref()), super::split_request_line(b"create_open  domain=x"));
+        assert_eq!((b"".as_ref(), b"".as_ref()), super::split_request_line(b"\r\n"));
+    }
+
+    #[test]
+    fn trace_id_from_bytes() {
+        assert_eq!(Some("abc123".to_string()), super::trace_id_from_bytes(b"noop trace_id=abc123\r\n"));
+        assert_eq!(Some("abc123".to_string()), super::trace_id_from_bytes(b"delete domain=d&key=k&trace_id=abc123"));
+        assert_eq!(None, super::trace_id_from_bytes(b"noop trace_id="));
+        assert_eq!(None, super::trace_id_from_bytes(b"noop"));
+    }
+
+    #[test]
+    fn delete_response_from_bytes() {
+        let req = Delete { domain: "test_domain".to_string(), key: "test/key/1".to_string() };
+        assert_eq!(Response::Empty, req.response_from_bytes(b"").unwrap());
+        assert_eq!(Response::Delete(DeleteResponse { deleted: 1 }), req.response_from_bytes(b"deleted=1").unwrap());
+        assert_eq!("deleted=2", DeleteResponse { deleted: 2 }.to_response().to_urlencoded_string());
+    }
+
+    #[test]
+    fn usage_response_round_trip() {
+        let response = UsageResponse(vec![
+            UsageRow { domain: "d1".to_string(), class: "default".to_string(), files: 2, bytes: 100 },
+            UsageRow { domain: "d2".to_string(), class: "default".to_string(), files: 0, bytes: 0 },
+        ]);
+        let encoded = response.to_urlencoded_string();
+        assert_eq!(response, UsageResponse::from_bytes(encoded.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn locate_response_round_trip() {
+        let response = LocateResponse {
+            fid: 3,
+            length: 4,
+            class: "default".to_string(),
+            mtime: Some(1464000000),
+            paths: vec![
+                DestPath { devid: Some(2), url: Url::parse("http://127.0.0.1:7500/dev2/0/000/000/0000000003.fid").unwrap() },
+                DestPath { devid: Some(1), url: Url::parse("http://127.0.0.1:7500/dev1/0/000/000/0000000003.fid").unwrap() },
+            ],
+        };
+        let encoded = response.to_urlencoded_string();
+        assert_eq!(response, LocateResponse::from_bytes(encoded.as_bytes()).unwrap());
+
+        let unstored = LocateResponse { mtime: None, paths: vec![], ..response };
+        assert_eq!(unstored, LocateResponse::from_bytes(unstored.to_urlencoded_string().as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn create_open_response_multi_dest() {
+        let response = CreateOpenResponse::from_bytes(b"devid_1=2&path_1=http://h/dev2/a.fid&devid_2=1&path_2=http://h/dev1/a.fid&dev_count=2&fid=9").unwrap();
+        assert!(response.multi_dest);
+        assert_eq!(vec![ 2, 1 ], response.paths.iter().map(|&(devid, _)| devid).collect::<Vec<_>>());
+        assert_eq!("/dev1/a.fid", response.paths[1].1.path());
+
+        let encoded = response.to_urlencoded_string();
+        assert!(encoded.contains("dev_count=2"), "Encoded as {:?}", encoded);
+        assert_eq!(response, CreateOpenResponse::from_bytes(encoded.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn create_open_response_single_dest() {
+        let response = CreateOpenResponse::from_bytes(b"devid=2&path=http://h/dev2/a.fid&fid=9").unwrap();
+        assert!(!response.multi_dest);
+        assert_eq!(9, response.fid);
+        assert_eq!(vec![ 2 ], response.paths.iter().map(|&(devid, _)| devid).collect::<Vec<_>>());
+
+        let encoded = response.to_urlencoded_string();
+        assert!(!encoded.contains("dev_count") && !encoded.contains("devid_1"), "Encoded as {:?}", encoded);
+        assert_eq!(response, CreateOpenResponse::from_bytes(encoded.as_bytes()).unwrap());
+
+        assert!(matches!(CreateOpenResponse::from_bytes(b"fid=9&path=http://h/dev2/a.fid"), Err(MogError::NoDevid)));
+    }
+
+    #[test]
+    fn list_all_keys_round_trip() {
+        let req = ListAllKeys::from_bytes(b"after_domain=d1&after_key=b&limit=10").unwrap();
+        assert!(!req.is_after("d1", "a"));
+        assert!(!req.is_after("d1", "b"));
+        assert!(req.is_after("d1", "c"));
+        assert!(req.is_after("d2", "a"));
+
+        let domain_only = ListAllKeys::from_bytes(b"after_domain=d1&after_key=").unwrap();
+        assert!(!domain_only.is_after("d1", "z"));
+        assert!(domain_only.is_after("d2", "a"));
+
+        let response = ListAllKeysResponse(vec![ ("d1".to_string(), "c".to_string()), ("d2".to_string(), "a/key".to_string()) ]);
+        let encoded = response.to_urlencoded_string();
+        assert!(encoded.contains("next_after_domain=d2&next_after_key=a%2Fkey"), "Encoded as {:?}", encoded);
+        assert_eq!(response, ListAllKeysResponse::from_bytes(encoded.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn list_keys_since_round_trip() {
+        let req = ListKeysSince::from_bytes(b"domain=d&since=1464000000&after=&limit=10").unwrap();
+        assert_eq!((1464000000, None, Some(10)), (req.since, req.after.clone(), req.limit));
+        assert!(ListKeysSince::from_bytes(b"domain=d").is_err());
+        assert!(ListKeysSince::from_bytes(b"domain=d&since=yesterday").is_err());
+
+        let response = ListKeysSinceResponse(vec![ ("test/key/1".to_string(), 1464000001), ("test/key/5".to_string(), 1464000100) ]);
+        let encoded = response.to_urlencoded_string();
+        assert!(encoded.contains("next_after=test%2Fkey%2F5"), "Encoded as {:?}", encoded);
+        assert_eq!(response, ListKeysSinceResponse::from_bytes(encoded.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn list_keys_with_sizes_round_trip() {
+        assert!(ListKeys::from_bytes(b"domain=d&with_sizes=1").unwrap().with_sizes);
+        assert!(!ListKeys::from_bytes(b"domain=d").unwrap().with_sizes);
+
+        let response = ListKeysResponse {
+            keys: vec![ "test/key/1".to_string(), "test/key/2".to_string() ],
+            next_after: None,
+            details: Some(vec![ KeyDetails { size: Some(11), mtime: Some(1464000000) }, KeyDetails::default() ]),
+        };
+        let encoded = response.to_urlencoded_string();
+        assert!(encoded.contains("key_1_size=11&key_1_mtime=1464000000"), "Encoded as {:?}", encoded);
+        assert!(!encoded.contains("key_2_size"), "Encoded as {:?}", encoded);
+        let parsed = ListKeysResponse::from_bytes(encoded.as_bytes()).unwrap();
+        assert_eq!(response.details, parsed.details);
+
+        // A tracker which doesn't know about sizes doesn't send any.
+        let stock = b"key_count=1&key_1=test/key/1&next_after=test/key/1";
+        assert_eq!(None, ListKeysResponse::from_bytes(stock).unwrap().details);
+        let req = ListKeys { domain: "d".to_string(), prefix: None, after: None, limit: None, order: KeyOrder::Lexicographic, with_sizes: true };
+        match req.response_from_bytes(stock).unwrap() {
+            Response::ListKeys(r) => assert_eq!(Some(vec![ KeyDetails::default() ]), r.details),
+            r => panic!("Response was {:?}", r),
+        }
+    }
+
+    #[test]
+    fn usage_is_after() {
+        let req = Usage { after_domain: Some("d1".to_string()), after_class: Some("b".to_string()), limit: None };
+        assert!(!req.is_after("d1", "a"));
+        assert!(!req.is_after("d1", "b"));
+        assert!(req.is_after("d1", "c"));
+        assert!(req.is_after("d2", "a"));
+        assert!(Usage { after_domain: None, after_class: None, limit: None }.is_after("d0", "a"));
+    }
+
+    #[test]
+    fn render_response_formats() {
+        let response = Response::CreateDomain(CreateDomain { domain: "test \"domain\"".to_string() });
+        assert_eq!("OK domain=test+%22domain%22", response.render_as(ResponseFormat::Urlencoded));
+        assert_eq!(response.render(), response.render_as(ResponseFormat::Urlencoded));
+        assert_eq!("{\"status\":\"OK\",\"args\":{\"domain\":\"test \\\"domain\\\"\"}}",
+                   response.render_as(ResponseFormat::Json));
+        assert_eq!("{\"status\":\"OK\",\"args\":{}}", Response::Empty.render_as(ResponseFormat::Json));
+    }
+
+    #[test]
+    fn render_error_formats() {
+        let error = MogError::UnknownKey("test/key/3".to_string());
+        assert_eq!(error.render(), error.render_as(ResponseFormat::Urlencoded));
+        assert_eq!("{\"status\":\"ERR\",\"error\":\"unknown_key\",\"message\":\"Unknown key: \\\"test/key/3\\\"\"}",
+                   error.render_as(ResponseFormat::Json));
+    }
+
+    #[test]
+    fn server_version_response_round_trip() {
+        let response = ServerVersionResponse::current();
+        let encoded = response.to_urlencoded_string();
+        let decoded = ServerVersionResponse::from_bytes(encoded.as_bytes()).unwrap();
+        assert_eq!(response, decoded);
+    }
+}
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::io::{self, BufRead};
 use super::error::MogResult;
+use url::Url;
 use url::form_urlencoded::Serializer;
+use url::percent_encoding::percent_decode;
 
 /// An extension of the standard library's `BufRead` trait which
 /// supports multibyte delimiters.
@@ -116,10 +118,60 @@ impl<T: ToArgs> ToUrlencodedString for T {
     }
 }
 
+/// Decodes a form-urlencoded field of a tracker response, such as a
+/// status token or an error message. A `+` is a space, but a `%2B` is a
+/// plus, so the `+`s have to be dealt with before the `%`s. Bytes
+/// which aren't UTF-8 once decoded are replaced.
+pub fn decode_mog_field(bytes: &[u8]) -> String {
+    let unplussed: Vec<u8> = bytes.iter().map(|&b| if b == b'+' { b' ' } else { b }).collect();
+    percent_decode(&unplussed).decode_utf8_lossy().into_owned()
+}
+
+/// The URL of `key`'s content under `base_url`, for storage laid out
+/// as the mem backend lays it out:
+/// `{base_url}/d/{domain}/k/{key}`. Whether or not `base_url` ends in
+/// a slash, there's just the one between it and the rest.
+pub fn url_for_key(base_url: &Url, domain: &str, key: &str) -> Url {
+    let mut new_path: Vec<&str> = base_url.path_segments().map(|s| s.collect()).unwrap_or(vec![]);
+    // A base URL ending in a slash ends in an empty segment.
+    if new_path.last() == Some(&"") {
+        new_path.pop();
+    }
+    new_path.extend([ "d", domain, "k" ].iter());
+    new_path.extend(key.split("/"));
+    new_path = new_path.into_iter().skip_while(|p| p.is_empty()).collect();
+
+    let mut key_url = base_url.clone();
+    key_url.set_path(&new_path.join("/"));
+    key_url
+}
+
+/// Quotes and escapes a string for inclusion in a JSON document.
+pub fn to_json_string(s: &str) -> String {
+    let mut rv = String::with_capacity(s.len() + 2);
+    rv.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => rv.push_str("\\\""),
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\r' => rv.push_str("\\r"),
+            '\t' => rv.push_str("\\t"),
+            c if (c as u32) < 0x20 => rv.push_str(&format!("\\u{:04x}", c as u32)),
+            c => rv.push(c),
+        }
+    }
+
+    rv.push('"');
+    rv
+}
+
 #[cfg(test)]
 mod tests {
-    use super::BufReadMb;
+    use super::{BufReadMb, decode_mog_field, url_for_key};
     use std::io::{BufReader, Cursor, Read};
+    use url::Url;
 
     static CRLF: &'static [u8] = b"\r\n";
 
@@ -172,4 +224,32 @@ mod tests {
         reader.read_to_end(&mut dst_buf).unwrap();
         assert!(dst_buf.is_empty());
     }
+
+    #[test]
+    fn to_json_string() {
+        assert_eq!(r#""plain""#, super::to_json_string("plain"));
+        assert_eq!(r#""a \"quoted\" \\ string""#, super::to_json_string(r#"a "quoted" \ string"#));
+        assert_eq!(r#""line\r\nbreak\u0001""#, super::to_json_string("line\r\nbreak\u{1}"));
+    }
+
+    #[test]
+    fn decode_mog_fields() {
+        assert_eq!("OK", decode_mog_field(b"OK"));
+        assert_eq!("no such key", decode_mog_field(b"no+such+key"));
+        assert_eq!("no such key", decode_mog_field(b"no%20such%20key"));
+        assert_eq!("1 + 1 = 2", decode_mog_field(b"1+%2B+1%20%3D+2"));
+        assert_eq!("OK", decode_mog_field(b"%4FK"));
+        assert_eq!("caf\u{e9}", decode_mog_field(b"caf%C3%A9"));
+        assert_eq!("bad \u{fffd}", decode_mog_field(b"bad+%FF"));
+        assert_eq!("100%", decode_mog_field(b"100%"));
+    }
+
+    #[test]
+    fn url_for_key_trailing_slash() {
+        let url = |base: &str| url_for_key(&Url::parse(base).unwrap(), "dom", "a/b").to_string();
+        assert_eq!("http://h/d/dom/k/a/b", url("http://h"));
+        assert_eq!("http://h/d/dom/k/a/b", url("http://h/"));
+        assert_eq!("http://h/base/d/dom/k/a/b", url("http://h/base"));
+        assert_eq!("http://h/base/d/dom/k/a/b", url("http://h/base/"));
+    }
 }
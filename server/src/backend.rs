@@ -1,5 +1,5 @@
 use mogilefs_common::MogResult;
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
 use time::Tm;
 use url::Url;
 
@@ -7,14 +7,44 @@ pub trait StorageBackend: Send + Sync {
     fn url_for_key(&self, domain: &str, key: &str) -> Url;
 
     fn file_metadata(&self, domain: &str, key: &str) -> MogResult<StorageMetadata>;
+
+    /// Store the content read from `reader`. This is what the storage
+    /// handler calls with the request body, so backends which write
+    /// somewhere other than memory should copy it to its destination
+    /// as it's read, rather than collecting it first.
     fn store_reader_content<R: Read>(&self, domain: &str, key: &str, reader: &mut R) -> MogResult<()>;
-    fn store_bytes_content(&self, domain: &str, key: &str, content: &[u8]) -> MogResult<()>;
+
+    /// Store content which is already in memory. By default this just
+    /// reads it through `store_reader_content`.
+    fn store_bytes_content(&self, domain: &str, key: &str, content: &[u8]) -> MogResult<()> {
+        self.store_reader_content(domain, key, &mut Cursor::new(content))
+    }
+
+    /// Store content along with the `Content-Encoding` it was uploaded
+    /// with, to be handed back in its metadata. By default the
+    /// encoding is dropped, and this is just `store_reader_content`.
+    fn store_encoded_reader_content<R: Read>(&self, domain: &str, key: &str, reader: &mut R, _content_encoding: Option<&str>) -> MogResult<()> {
+        self.store_reader_content(domain, key, reader)
+    }
+
     fn get_content<W: Write>(&self, domain: &str, key: &str, writer: &mut W) -> MogResult<()>;
 }
 
 #[derive(Debug)]
 pub struct StorageMetadata {
     pub size: u64,
+    /// How many bytes the backend actually uses to hold the content,
+    /// which may be less than `size` if it's compressed.
+    pub stored_size: u64,
     pub mtime: Tm,
-    // Etag, Content-Type?
+    /// A tag which changes whenever the content does (without the
+    /// quotes it gets in an `ETag` header), if the backend has one.
+    pub etag: Option<String>,
+    /// Whether the backend can serve parts of the content, so it's
+    /// worth advertising `Accept-Ranges`.
+    pub accepts_ranges: bool,
+    /// The `Content-Encoding` the content was stored with, if any.
+    /// This is the encoding of the content `get_content` writes, not
+    /// of whatever the backend holds internally.
+    pub content_encoding: Option<String>,
 }
@@ -0,0 +1,397 @@
+//! A storage backend wrapper which keeps recently-read content in
+//! memory.
+
+use mogilefs_common::{Backend, MogResult};
+use mogilefs_common::requests::*;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use super::backend::{StorageBackend, StorageMetadata};
+use url::Url;
+
+/// How well a `CachingBackend`'s cache is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// The number of files in the cache.
+    pub entries: usize,
+    /// The total size of the content in the cache.
+    pub bytes: usize,
+}
+
+/// A `StorageBackend` which serves `get_content` from a cache of the
+/// most recently used content, holding at most a fixed number of
+/// bytes, and goes to the wrapped backend for anything else.
+///
+/// Storing content invalidates the cached copy, as do deletes and
+/// renames when the wrapped backend is the tracker's backend too.
+pub struct CachingBackend<B: StorageBackend> {
+    backend: B,
+    capacity: usize,
+    cache: Mutex<Lru>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+type CacheKey = (String, String);
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<CacheKey, (Arc<Vec<u8>>, u64)>,
+    /// The entries' keys by when they were last used, oldest first.
+    order: BTreeMap<u64, CacheKey>,
+    clock: u64,
+    bytes: usize,
+    /// The keys being read from the wrapped backend, with how many
+    /// times each has been invalidated since the reads started and how
+    /// many reads there are. A read whose key was invalidated while it
+    /// was going on doesn't get cached, since it may be stale.
+    pending: HashMap<CacheKey, (u64, usize)>,
+}
+
+impl Lru {
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+        let clock = self.clock;
+
+        match self.entries.get_mut(key) {
+            Some(&mut (ref content, ref mut used)) => {
+                self.order.remove(&*used);
+                self.order.insert(clock, key.clone());
+                *used = clock;
+                Some(content.clone())
+            },
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: CacheKey, content: Arc<Vec<u8>>, capacity: usize) {
+        self.remove(&key);
+        if content.len() > capacity {
+            return;
+        }
+
+        while self.bytes + content.len() > capacity {
+            let oldest = match self.order.keys().next() {
+                Some(&used) => self.order[&used].clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+
+        self.clock += 1;
+        self.bytes += content.len();
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(key, (content, self.clock));
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((content, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.bytes -= content.len();
+        }
+    }
+
+    fn invalidate(&mut self, key: &CacheKey) {
+        self.remove(key);
+        if let Some(&mut (ref mut generation, _)) = self.pending.get_mut(key) {
+            *generation += 1;
+        }
+    }
+
+    /// Note that `key` is about to be read from the wrapped backend,
+    /// returning the generation to hand to `finish_read`.
+    fn begin_read(&mut self, key: &CacheKey) -> u64 {
+        let pending = self.pending.entry(key.clone()).or_insert((0, 0));
+        pending.1 += 1;
+        pending.0
+    }
+
+    /// Note that a read of `key` is done, caching its content if it
+    /// succeeded and `key` wasn't invalidated in the meantime.
+    fn finish_read(&mut self, key: CacheKey, generation: u64, content: Option<Arc<Vec<u8>>>, capacity: usize) {
+        let (current, readers) = match self.pending.get_mut(&key) {
+            Some(&mut (current, ref mut readers)) => {
+                *readers -= 1;
+                (current, *readers)
+            },
+            None => return,
+        };
+
+        if readers == 0 {
+            self.pending.remove(&key);
+        }
+
+        if let Some(content) = content {
+            if current == generation {
+                self.insert(key, content, capacity);
+            }
+        }
+    }
+}
+
+impl<B: StorageBackend> CachingBackend<B> {
+    /// Wrap `backend`, caching at most `capacity` bytes of content.
+    pub fn new(backend: B, capacity: usize) -> CachingBackend<B> {
+        CachingBackend {
+            backend: backend,
+            capacity: capacity,
+            cache: Mutex::new(Lru::default()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn cache_stats(&self) -> MogResult<CacheStats> {
+        let cache = try!(self.cache.lock());
+        Ok(CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: cache.entries.len(),
+            bytes: cache.bytes,
+        })
+    }
+
+    /// Forget what's cached for `key`.
+    pub fn invalidate(&self, domain: &str, key: &str) -> MogResult<()> {
+        try!(self.cache.lock()).invalidate(&(domain.to_string(), key.to_string()));
+        Ok(())
+    }
+
+    fn invalidate_after<T>(&self, domain: &str, key: &str, result: MogResult<T>) -> MogResult<T> {
+        try!(self.invalidate(domain, key));
+        result
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for CachingBackend<B> {
+    fn url_for_key(&self, domain: &str, key: &str) -> Url {
+        self.backend.url_for_key(domain, key)
+    }
+
+    fn file_metadata(&self, domain: &str, key: &str) -> MogResult<StorageMetadata> {
+        self.backend.file_metadata(domain, key)
+    }
+
+    fn store_reader_content<R: Read>(&self, domain: &str, key: &str, reader: &mut R) -> MogResult<()> {
+        let result = self.backend.store_reader_content(domain, key, reader);
+        self.invalidate_after(domain, key, result)
+    }
+
+    fn store_bytes_content(&self, domain: &str, key: &str, content: &[u8]) -> MogResult<()> {
+        let result = self.backend.store_bytes_content(domain, key, content);
+        self.invalidate_after(domain, key, result)
+    }
+
+    fn store_encoded_reader_content<R: Read>(&self, domain: &str, key: &str, reader: &mut R, content_encoding: Option<&str>) -> MogResult<()> {
+        let result = self.backend.store_encoded_reader_content(domain, key, reader, content_encoding);
+        self.invalidate_after(domain, key, result)
+    }
+
+    fn get_content<W: Write>(&self, domain: &str, key: &str, writer: &mut W) -> MogResult<()> {
+        let cache_key = (domain.to_string(), key.to_string());
+
+        let (cached, generation) = {
+            let mut cache = try!(self.cache.lock());
+            match cache.get(&cache_key) {
+                Some(content) => (Some(content), 0),
+                None => (None, cache.begin_read(&cache_key)),
+            }
+        };
+
+        let content = match cached {
+            Some(content) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                content
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let mut content = vec![];
+                let result = self.backend.get_content(domain, key, &mut content);
+                let content = Arc::new(content);
+                let fetched = if result.is_ok() { Some(content.clone()) } else { None };
+                try!(self.cache.lock()).finish_read(cache_key, generation, fetched, self.capacity);
+                try!(result);
+                content
+            },
+        };
+
+        try!(writer.write_all(&content));
+        Ok(())
+    }
+}
+
+impl<B: StorageBackend + Backend> Backend for CachingBackend<B> {
+    fn create_domain(&self, req: &CreateDomain) -> MogResult<CreateDomain> {
+        self.backend.create_domain(req)
+    }
+
+    fn create_open(&self, req: &CreateOpen) -> MogResult<CreateOpenResponse> {
+        let result = self.backend.create_open(req);
+        self.invalidate_after(&req.domain, &req.key, result)
+    }
+
+    fn create_close(&self, req: &CreateClose) -> MogResult<()> {
+        self.backend.create_close(req)
+    }
+
+    fn create_cancel(&self, req: &CreateCancel) -> MogResult<()> {
+        let result = self.backend.create_cancel(req);
+        self.invalidate_after(&req.domain, &req.key, result)
+    }
+
+    fn create_class(&self, req: &CreateClass) -> MogResult<CreateClassResponse> {
+        self.backend.create_class(req)
+    }
+
+    fn get_paths(&self, req: &GetPaths) -> MogResult<GetPathsResponse> {
+        self.backend.get_paths(req)
+    }
+
+    fn file_info(&self, req: &FileInfo) -> MogResult<FileInfoResponse> {
+        self.backend.file_info(req)
+    }
+
+    fn delete(&self, req: &Delete) -> MogResult<()> {
+        let result = self.backend.delete(req);
+        self.invalidate_after(&req.domain, &req.key, result)
+    }
+
+    fn rename(&self, req: &Rename) -> MogResult<()> {
+        let result = self.backend.rename(req);
+        try!(self.invalidate(&req.domain, &req.from_key));
+        self.invalidate_after(&req.domain, &req.to_key, result)
+    }
+
+    fn rename_many(&self, req: &RenameMany) -> MogResult<()> {
+        let result = self.backend.rename_many(req);
+        for &(ref from_key, ref to_key) in req.pairs.iter() {
+            try!(self.invalidate(&req.domain, from_key));
+            try!(self.invalidate(&req.domain, to_key));
+        }
+        result
+    }
+
+    fn list_keys(&self, req: &ListKeys) -> MogResult<ListKeysResponse> {
+        self.backend.list_keys(req)
+    }
+
+    fn delete_counted(&self, req: &Delete) -> MogResult<DeleteResponse> {
+        let result = self.backend.delete_counted(req);
+        self.invalidate_after(&req.domain, &req.key, result)
+    }
+
+    fn copy(&self, req: &CopyKey) -> MogResult<()> {
+        let result = self.backend.copy(req);
+        self.invalidate_after(&req.domain, &req.to_key, result)
+    }
+
+    fn update_class(&self, req: &UpdateClass) -> MogResult<()> {
+        self.backend.update_class(req)
+    }
+
+    fn replicate(&self, req: &Replicate) -> MogResult<()> {
+        self.backend.replicate(req)
+    }
+
+    fn usage(&self, req: &Usage) -> MogResult<UsageResponse> {
+        self.backend.usage(req)
+    }
+
+    fn locate(&self, req: &Locate) -> MogResult<LocateResponse> {
+        self.backend.locate(req)
+    }
+
+    fn list_keys_since(&self, req: &ListKeysSince) -> MogResult<ListKeysSinceResponse> {
+        self.backend.list_keys_since(req)
+    }
+
+    fn list_all_keys(&self, req: &ListAllKeys) -> MogResult<ListAllKeysResponse> {
+        self.backend.list_all_keys(req)
+    }
+
+    fn server_version(&self, req: &ServerVersion) -> MogResult<ServerVersionResponse> {
+        self.backend.server_version(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mogilefs_common::{Backend, MogError, MogResult};
+    use mogilefs_common::requests::*;
+    use std::sync::Arc;
+    use super::*;
+    use super::Lru;
+    use super::super::backend::StorageBackend;
+    use super::super::test_support::*;
+
+    fn fetch<B: StorageBackend>(backend: &B, key: &str) -> MogResult<Vec<u8>> {
+        let mut content = vec![];
+        try!(backend.get_content(TEST_DOMAIN, key, &mut content));
+        Ok(content)
+    }
+
+    #[test]
+    fn caches_content() {
+        let backend = CachingBackend::new(sync_backend_fixture(), 1024);
+        assert_eq!(TEST_CONTENT_1, &fetch(&backend, TEST_KEY_1).unwrap()[..]);
+        assert_eq!(TEST_CONTENT_1, &fetch(&backend, TEST_KEY_1).unwrap()[..]);
+        assert!(matches!(fetch(&backend, TEST_KEY_2), Err(MogError::NoContent(..))));
+
+        let stats = backend.cache_stats().unwrap();
+        assert_eq!((1, 2), (stats.hits, stats.misses));
+        assert_eq!(TEST_CONTENT_1.len(), stats.bytes);
+    }
+
+    #[test]
+    fn stores_and_deletes_invalidate() {
+        let backend = CachingBackend::new(sync_backend_fixture(), 1024);
+        fetch(&backend, TEST_KEY_1).unwrap();
+
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, b"New content").unwrap();
+        assert_eq!(0, backend.cache_stats().unwrap().entries);
+        assert_eq!(b"New content", &fetch(&backend, TEST_KEY_1).unwrap()[..]);
+
+        backend.delete(&Delete { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() }).unwrap();
+        assert_eq!(0, backend.cache_stats().unwrap().entries);
+        assert!(matches!(fetch(&backend, TEST_KEY_1), Err(MogError::UnknownKey(..))));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let backend = CachingBackend::new(sync_backend_fixture(), 2 * TEST_CONTENT_1.len());
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_2, TEST_CONTENT_1).unwrap();
+        backend.copy(&CopyKey { domain: TEST_DOMAIN.to_string(), from_key: TEST_KEY_1.to_string(), to_key: "test/key/3".to_string() }).unwrap();
+
+        fetch(&backend, TEST_KEY_1).unwrap();
+        fetch(&backend, TEST_KEY_2).unwrap();
+        fetch(&backend, TEST_KEY_1).unwrap();
+        fetch(&backend, "test/key/3").unwrap();
+        assert_eq!(2, backend.cache_stats().unwrap().entries);
+
+        // Key 2 was the least recently used, so it's the one that was
+        // evicted.
+        fetch(&backend, TEST_KEY_1).unwrap();
+        fetch(&backend, TEST_KEY_2).unwrap();
+        let stats = backend.cache_stats().unwrap();
+        assert_eq!((2, 4), (stats.hits, stats.misses));
+    }
+
+    #[test]
+    fn reads_invalidated_midway_arent_cached() {
+        let mut lru = Lru::default();
+        let key = (TEST_DOMAIN.to_string(), TEST_KEY_1.to_string());
+
+        let generation = lru.begin_read(&key);
+        lru.invalidate(&key);
+        lru.finish_read(key.clone(), generation, Some(Arc::new(TEST_CONTENT_1.to_vec())), 1024);
+        assert!(lru.get(&key).is_none());
+        assert!(lru.pending.is_empty());
+
+        let generation = lru.begin_read(&key);
+        lru.finish_read(key.clone(), generation, Some(Arc::new(TEST_CONTENT_1.to_vec())), 1024);
+        assert_eq!(Some(TEST_CONTENT_1), lru.get(&key).as_ref().map(|c| &c[..]));
+    }
+}
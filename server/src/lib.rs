@@ -1,4 +1,7 @@
+#![cfg_attr(feature = "nightly", feature(test))]
+
 extern crate chrono;
+extern crate flate2;
 extern crate hyper;
 extern crate iron;
 extern crate libc;
@@ -7,6 +10,7 @@ extern crate mogilefs_client;
 extern crate mogilefs_common;
 extern crate plugin;
 extern crate r2d2;
+extern crate rustc_serialize;
 extern crate statsd;
 extern crate threadpool;
 extern crate time;
@@ -25,7 +29,11 @@ extern crate regex;
 #[cfg(test)]
 extern crate env_logger;
 
+#[cfg(all(test, feature = "nightly"))]
+extern crate test;
+
 pub mod backend;
+pub mod cache;
 pub mod mem;
 pub mod net;
 pub mod proxy;
@@ -1,29 +1,312 @@
-use mogilefs_common::{Backend, MogError, MogResult};
+use flate2;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use mogilefs_client::InProcessStorage;
+use mogilefs_common::{Backend, MogError, MogResult, url_for_key};
 use mogilefs_common::requests::*;
-use std::collections::HashMap;
-use std::io::{self, Cursor, Read, Write};
-use std::sync::{Arc, RwLock};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::json::Json;
+use std::cmp::{self, Ordering};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, Cursor, Read, Write};
+use std::mem;
+use std::ops::{self, Bound, Deref};
+use std::sync::{Arc, RwLock, Weak};
+use std::usize;
 use super::super::backend::{StorageBackend, StorageMetadata};
-use super::{MemDomain, MemFileInfo};
-use time;
+use super::{Compression, MemDomain, MemFileInfo};
+use time::{self, Timespec};
 use url::Url;
 
+/// The hash used to identify identical content in dedup mode.
+type ContentHash = u64;
+
 #[derive(Debug)]
 pub struct MemBackend {
     domains: HashMap<String, MemDomain>,
     empty_domain: MemDomain,
+    dedup: bool,
+    compression: Compression,
+    /// The shared buffers by their content's hash, when dedup is on.
+    /// They're weak, so a buffer goes away with the last file (or
+    /// `read_content` caller) using it.
+    blobs: HashMap<ContentHash, Weak<Vec<u8>>>,
+    devices: Vec<MemDevice>,
+    /// The fid the next new file gets. Fids are never reused, so a
+    /// stale one can't be mistaken for a newer file's.
+    next_fid: u64,
+    /// The responses to recent `create_open`s with idempotency tokens.
+    /// Expired ones are dropped when they're looked up, or by
+    /// `purge_stale_opens`.
+    open_tokens: HashMap<OpenToken, (Timespec, CreateOpenResponse)>,
+    idempotency_window: time::Duration,
+    placement: Placement,
+    /// The operations which changed the backend, oldest first, and
+    /// how many to keep, if they're being recorded.
+    wal: VecDeque<WalEntry>,
+    wal_capacity: Option<usize>,
+    /// Whether closing a file whose content isn't the size it was
+    /// opened with fails, rather than just logging a warning.
+    strict_sizes: bool,
     pub base_url: Url,
 }
 
+/// The domain, key, and idempotency token of a `create_open`.
+type OpenToken = (String, String, String);
+
+/// How long a `create_open`'s idempotency token is remembered for, by
+/// default.
+pub const DEFAULT_IDEMPOTENCY_WINDOW_SECS: i64 = 5 * 60;
+
+/// A (pretend) storage device. Paths on healthier devices are
+/// offered first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemDevice {
+    pub devid: u64,
+    /// How healthy the device is, from 0 (barely working) up.
+    pub health: u32,
+}
+
+/// A change made to a `MemBackend`, as its write-ahead log records it;
+/// see `MemBackend::enable_wal`.
+#[derive(Debug, Clone)]
+pub enum WalEntry {
+    CreateDomain(CreateDomain),
+    CreateClass(CreateClass),
+    CreateOpen(CreateOpen),
+    CreateClose(CreateClose),
+    /// A cancelled open, or one removed by `purge_stale_opens`.
+    CreateCancel(CreateCancel),
+    Delete(Delete),
+    Rename(Rename),
+    RenameMany(RenameMany),
+    CopyKey(CopyKey),
+    UpdateClass(UpdateClass),
+    Replicate(Replicate),
+    /// A `swap_content` of the two keys in the domain.
+    SwapContent(String, String, String),
+    /// A file brought in by `import_domain`, without its content.
+    ImportFile(String, ExportedFile),
+}
+
+/// How a `MemBackend` orders a file's devices, and so which it
+/// offers first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Healthiest device first, then by devid. This is the default.
+    ByHealth,
+    /// By a hash of the domain, key, and devid (rendezvous hashing),
+    /// ignoring health. The same key always gets the same devices in
+    /// the same order, and adding a device doesn't reorder the others.
+    ConsistentHash,
+}
+
+/// How much sharing of content is going on in a `MemBackend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupStats {
+    /// The number of distinct content buffers being held.
+    pub unique_blobs: usize,
+    /// The number of files which have content.
+    pub logical_files: usize,
+}
+
+/// A file's content, as returned by `read_content`. It shares the
+/// backend's buffer rather than copying it, and holds no lock.
+#[derive(Debug, Clone)]
+pub struct MemContent(Arc<Vec<u8>>);
+
+impl Deref for MemContent {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A file's content, in pieces of at most a fixed size, as returned
+/// by `content_chunks`. Each chunk is a fresh buffer, so it can be
+/// dropped before the next is taken.
+#[derive(Debug)]
+pub struct ContentChunks {
+    content: MemContent,
+    chunk_size: usize,
+    offset: usize,
+}
+
+impl Iterator for ContentChunks {
+    type Item = MogResult<Vec<u8>>;
+
+    fn next(&mut self) -> Option<MogResult<Vec<u8>>> {
+        if self.offset >= self.content.len() {
+            return None;
+        }
+
+        let end = cmp::min(self.offset + self.chunk_size, self.content.len());
+        let chunk = self.content[self.offset..end].to_vec();
+        self.offset = end;
+        Some(Ok(chunk))
+    }
+}
+
 impl MemBackend {
     pub fn new(storage_base_url: Url) -> MemBackend {
         MemBackend {
             domains: HashMap::new(),
             empty_domain: MemDomain::new(""),
+            dedup: false,
+            compression: Compression::None,
+            blobs: HashMap::new(),
+            devices: Vec::new(),
+            next_fid: 1,
+            open_tokens: HashMap::new(),
+            idempotency_window: time::Duration::seconds(DEFAULT_IDEMPOTENCY_WINDOW_SECS),
+            placement: Placement::ByHealth,
+            wal: VecDeque::new(),
+            wal_capacity: None,
+            strict_sizes: false,
             base_url: storage_base_url,
         }
     }
 
+    /// Turn content deduplication on or off. When it's on, storing
+    /// content identical to some already-stored content shares the
+    /// existing buffer rather than keeping another copy. Turning it
+    /// off doesn't un-share anything already stored.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    /// Choose how content stored from now on is held. Content
+    /// already stored stays as it is, and is still read back
+    /// correctly.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Change how long a `create_open` with an idempotency token is
+    /// remembered, so that repeating it gets the same fid.
+    pub fn set_idempotency_window(&mut self, window: time::Duration) {
+        self.idempotency_window = window;
+    }
+
+    /// Choose how files' devices are ordered; see `Placement`.
+    pub fn set_placement(&mut self, placement: Placement) {
+        self.placement = placement;
+    }
+
+    /// Add a device, or change the health of an existing one. Without
+    /// any devices, every file lives on device 1, at the storage
+    /// server's base URL; with them, each file has a path on each
+    /// device, under a "dev<devid>" directory of the base URL.
+    pub fn set_device_health(&mut self, devid: u64, health: u32) {
+        match self.devices.iter().position(|d| d.devid == devid) {
+            Some(i) => self.devices[i].health = health,
+            None => self.devices.push(MemDevice { devid: devid, health: health }),
+        }
+    }
+
+    /// Choose what happens when a file is closed with content of a
+    /// different size than the `size` it was opened with: a warning
+    /// in the log (the default), or, if `strict` is set, a
+    /// `SizeMismatch` error, leaving the file open. Files opened
+    /// without a size aren't checked.
+    pub fn set_strict_sizes(&mut self, strict: bool) {
+        self.strict_sizes = strict;
+    }
+
+    /// Start recording every change to the backend's domains, classes
+    /// and files, in order, keeping the last `capacity` of them: each
+    /// tracker request which changes anything, the opens removed by
+    /// `purge_stale_opens`, `swap_content`, and the files brought in by
+    /// `import_domain`. Only changes which worked are recorded.
+    ///
+    /// Content isn't part of the log, so storing or appending it isn't
+    /// recorded, and imported files are recorded without theirs.
+    /// Neither are the settings (devices, dedup, compression and so
+    /// on) or the idempotency tokens.
+    pub fn enable_wal(&mut self, capacity: usize) {
+        self.wal_capacity = Some(capacity);
+        while self.wal.len() > capacity {
+            self.wal.pop_front();
+        }
+    }
+
+    /// What's been recorded since `enable_wal`, oldest first. Once
+    /// the log's full, the oldest entries are dropped to make room,
+    /// and it no longer goes back to the start.
+    pub fn wal_entries(&self) -> Vec<WalEntry> {
+        self.wal.iter().cloned().collect()
+    }
+
+    /// Make each of the changes in `wal` in turn, stopping at the
+    /// first which fails. Replaying a whole log, from the start, into
+    /// a new backend gets the same domains, classes and files (without
+    /// their content), with the same fids. The log has no content, so
+    /// closes aren't held to `set_strict_sizes` while replaying.
+    pub fn replay(&mut self, wal: &[WalEntry]) -> MogResult<()> {
+        let strict_sizes = mem::replace(&mut self.strict_sizes, false);
+        let mut result = Ok(());
+        for entry in wal.iter() {
+            result = self.replay_entry(entry);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.strict_sizes = strict_sizes;
+        result
+    }
+
+    fn replay_entry(&mut self, entry: &WalEntry) -> MogResult<()> {
+        match *entry {
+            WalEntry::CreateDomain(ref req) => self.create_domain(req).map(|_| ()),
+            WalEntry::CreateClass(ref req) => self.create_class(req).map(|_| ()),
+            WalEntry::CreateOpen(ref req) => self.create_open(req).map(|_| ()),
+            WalEntry::CreateClose(ref req) => self.create_close(req),
+            WalEntry::CreateCancel(ref req) => self.create_cancel(req),
+            WalEntry::Delete(ref req) => self.delete(req),
+            WalEntry::Rename(ref req) => self.rename(req),
+            WalEntry::RenameMany(ref req) => self.rename_many(req),
+            WalEntry::CopyKey(ref req) => self.copy(req),
+            WalEntry::UpdateClass(ref req) => self.update_class(req),
+            WalEntry::Replicate(ref req) => self.replicate(req),
+            WalEntry::SwapContent(ref domain, ref key_a, ref key_b) => self.swap_content(domain, key_a, key_b),
+            WalEntry::ImportFile(ref domain, ref record) => self.import_file(domain, record.clone()),
+        }
+    }
+
+    fn log_change(&mut self, entry: WalEntry) {
+        if let Some(capacity) = self.wal_capacity {
+            if capacity == 0 {
+                return;
+            }
+            if self.wal.len() >= capacity {
+                self.wal.pop_front();
+            }
+            self.wal.push_back(entry);
+        }
+    }
+
+    pub fn dedup_stats(&self) -> DedupStats {
+        let mut blobs = HashSet::new();
+        let mut files = 0;
+
+        for domain in self.domains.values() {
+            for (_, file_info) in domain.files() {
+                if let Some(ref content) = file_info.content {
+                    blobs.insert(&**content as *const Vec<u8>);
+                    files += 1;
+                }
+            }
+        }
+
+        DedupStats {
+            unique_blobs: blobs.len(),
+            logical_files: files,
+        }
+    }
+
     // Tracker methods.
 
     pub fn create_domain(&mut self, req: &CreateDomain) -> MogResult<CreateDomain> {
@@ -32,68 +315,399 @@ impl MemBackend {
         } else {
             let domain = MemDomain::new(&req.domain);
             self.domains.insert(req.domain.clone(), domain);
+            self.log_change(WalEntry::CreateDomain(req.clone()));
             Ok(CreateDomain { domain: req.domain.clone() })
         }
     }
 
     pub fn create_open(&mut self, req: &CreateOpen) -> MogResult<CreateOpenResponse> {
-        let fid = self.domains.len() + 1;
-        let url = self.url_for_key(&req.domain, &req.key);
-        let domain = try!(self.domain_mut(&req.domain));
-        let file_info = MemFileInfo::new(fid as u64, &req.key);
-        try!(domain.add_file(&req.key, file_info));
-
-        let mut response = CreateOpenResponse {
-            fid: fid as u64,
-            paths: Vec::new(),
+        // A repeat of a recent request with the same token (a retry
+        // of one whose response was lost, say) gets the same answer,
+        // as long as that file is still there.
+        let now = time::get_time();
+        let token = req.idempotency_token.as_ref().map(|t| (req.domain.clone(), req.key.clone(), t.clone()));
+        if let Some(ref token) = token {
+            let expired = match self.open_tokens.get(token) {
+                Some(&(at, _)) => now - at >= self.idempotency_window,
+                None => false,
+            };
+            if expired {
+                self.open_tokens.remove(token);
+            }
+        }
+        if let Some(&(_, ref response)) = token.as_ref().and_then(|t| self.open_tokens.get(t)) {
+            if try!(self.file(&req.domain, &req.key)).map(|f| f.fid()) == Some(response.fid) {
+                return Ok(response.clone());
+            }
+        }
+
+        if req.if_absent && try!(self.file(&req.domain, &req.key)).is_some() {
+            return Err(MogError::KeyExists(req.key.clone()));
+        }
+
+        // Check the class before taking a fid, so a failed open
+        // doesn't use one up, and a replay hands out the same ones.
+        let class = req.class.clone().and_then(|c| if c == "default" { None } else { Some(c) });
+        if let Some(ref class) = class {
+            if try!(self.domain(&req.domain)).mindevcount(Some(&class[..])).is_none() {
+                return Err(MogError::UnregClass(class.clone()));
+            }
+        }
+
+        let fid = self.allocate_fid();
+        let mut paths = self.dest_paths(&req.domain, &req.key);
+        if !req.multi_dest {
+            paths.truncate(1);
+        }
+        let mut file_info = MemFileInfo::new(fid, &req.key);
+        file_info.opened_at = Some(time::get_time());
+        file_info.declared_size = req.size;
+        file_info.class = class;
+        let replaced = {
+            let domain = try!(self.domain_mut(&req.domain));
+            let replaced = domain.remove_file(&req.key);
+            try!(domain.add_file(&req.key, file_info));
+            replaced
+        };
+
+        if let Some(old_file) = replaced {
+            self.release_content(old_file.content);
+        }
+
+        let response = CreateOpenResponse {
+            fid: fid,
+            paths: paths,
+            multi_dest: req.multi_dest,
         };
-        response.paths.push((1, url));
+
+        if let Some(token) = token {
+            self.open_tokens.insert(token, (now, response.clone()));
+        }
+
+        self.log_change(WalEntry::CreateOpen(req.clone()));
         Ok(response)
     }
 
+    pub fn create_close(&mut self, req: &CreateClose) -> MogResult<()> {
+        // The storage server request already stored the content, so
+        // all that's left is to check it's all there and make the file
+        // visible.
+        let strict_sizes = self.strict_sizes;
+        {
+            let file = try!(try!(self.file_mut(&req.domain, &req.key)).ok_or(MogError::UnknownKey(req.key.clone())));
+            if let Some(declared) = file.declared_size {
+                let actual = file.size.unwrap_or(0);
+                if declared != actual {
+                    if strict_sizes {
+                        return Err(MogError::SizeMismatch { declared: declared, actual: actual });
+                    }
+                    warn!("Closing {:?} with {} bytes of content, but it was opened with size {}", req.key, actual, declared);
+                }
+            }
+            file.opened_at = None;
+            file.checksum = req.checksum.clone();
+        }
+
+        self.log_change(WalEntry::CreateClose(req.clone()));
+        Ok(())
+    }
+
+    /// Remove a file which was opened and never closed, as
+    /// `purge_stale_opens` would once it got old. Whatever the open
+    /// replaced is already gone, so this doesn't bring it back.
+    pub fn create_cancel(&mut self, req: &CreateCancel) -> MogResult<()> {
+        // Fids aren't reused, so the fid picks out the one open being
+        // cancelled, even if the key has been opened again since.
+        let is_open = match try!(self.file(&req.domain, &req.key)) {
+            Some(file) => file.fid() == req.fid && file.opened_at.is_some(),
+            None => return Err(MogError::UnknownKey(req.key.clone())),
+        };
+        if !is_open {
+            return Err(MogError::UnknownFid);
+        }
+
+        let removed = try!(self.domain_mut(&req.domain)).remove_file(&req.key);
+        if let Some(file) = removed {
+            self.release_content(file.content);
+        }
+        self.log_change(WalEntry::CreateCancel(req.clone()));
+        Ok(())
+    }
+
+    /// Remove files which were opened at least `max_age` ago and never
+    /// closed, like MogileFS's cleanup of its temp files, and forget
+    /// the idempotency tokens which have expired. Returns how many
+    /// files were removed.
+    pub fn purge_stale_opens(&mut self, max_age: time::Duration) -> usize {
+        let now = time::get_time();
+        let mut removed = Vec::new();
+
+        let window = self.idempotency_window;
+        let expired: Vec<OpenToken> = self.open_tokens.iter()
+            .filter(|&(_, &(at, _))| now - at >= window)
+            .map(|(t, _)| t.clone())
+            .collect();
+        for token in expired.iter() {
+            self.open_tokens.remove(token);
+        }
+
+        for domain in self.domains.values_mut() {
+            let stale: Vec<String> = domain.files()
+                .filter(|&(_, f)| f.opened_at.map(|t| now - t >= max_age).unwrap_or(false))
+                .map(|(k, _)| k.to_string())
+                .collect();
+
+            for key in stale {
+                debug!("Purging stale open of {:?} in {:?}", key, domain.name());
+                if let Some(file) = domain.remove_file(&key) {
+                    removed.push((domain.name().to_string(), file));
+                }
+            }
+        }
+
+        let count = removed.len();
+        for (domain, file) in removed {
+            self.log_change(WalEntry::CreateCancel(CreateCancel {
+                domain: domain,
+                key: file.key().to_string(),
+                fid: file.fid(),
+            }));
+            self.release_content(file.content);
+        }
+        count
+    }
+
+    pub fn create_class(&mut self, req: &CreateClass) -> MogResult<CreateClassResponse> {
+        try!(try!(self.domain_mut(&req.domain)).add_class(&req.class, req.mindevcount, req.update));
+        self.log_change(WalEntry::CreateClass(req.clone()));
+        Ok(CreateClassResponse {
+            domain: req.domain.clone(),
+            class: req.class.clone(),
+            mindevcount: req.mindevcount,
+        })
+    }
+
     fn get_paths(&self, req: &GetPaths) -> MogResult<GetPathsResponse> {
-        let paths = try!(self.domain(&req.domain)
-                         .and_then(|d| d.file(&req.key).ok_or(MogError::UnknownKey(req.key.clone())))
-                         .map(|_| vec![ self.url_for_key(&req.domain, &req.key) ]));
-        Ok(GetPathsResponse(paths))
+        try!(self.domain(&req.domain).and_then(|d| closed_file(d, &req.key)));
+
+        // A pathcount of 0 means no limit, as it does for MogileFS.
+        let limit = match req.pathcount {
+            Some(n) if n > 0 => n as usize,
+            _ => usize::MAX,
+        };
+
+        Ok(GetPathsResponse(self.dest_paths(&req.domain, &req.key).into_iter()
+                            .take(limit)
+                            .map(|(devid, url)| DestPath { devid: Some(devid), url: url })
+                            .collect()))
     }
     
     fn file_info(&self, req: &FileInfo) -> MogResult<FileInfoResponse> {
         self.domain(&req.domain)
-            .and_then(|d| d.file(&req.key).ok_or(MogError::UnknownKey(req.key.clone())))
+            .and_then(|d| closed_file(d, &req.key))
             .map(|file_info| {
                 FileInfoResponse {
                     fid: file_info.fid(),
-                    devcount: 1,
+                    devcount: file_info.devcount,
                     length: file_info.size.unwrap_or(0),
                     domain: req.domain.clone(),
-                    class: "default".to_string(),
+                    class: file_info.class.clone().unwrap_or("default".to_string()),
                     key: file_info.key().to_string(),
+                    content_encoding: file_info.content_encoding.clone(),
+                    checksum: file_info.checksum.clone(),
                 }
             })
     }
     
+    fn locate(&self, req: &Locate) -> MogResult<LocateResponse> {
+        let file_info = try!(self.domain(&req.domain).and_then(|d| closed_file(d, &req.key)));
+        Ok(LocateResponse {
+            fid: file_info.fid(),
+            length: file_info.size.unwrap_or(0),
+            class: file_info.class.clone().unwrap_or("default".to_string()),
+            mtime: file_info.mtime.map(|t| t.to_timespec().sec),
+            paths: self.dest_paths(&req.domain, &req.key).into_iter()
+                .map(|(devid, url)| DestPath { devid: Some(devid), url: url })
+                .collect(),
+        })
+    }
+
     fn delete(&mut self, req: &Delete) -> MogResult<()> {
-        try!(self.domain_mut(&req.domain))
-            .remove_file(&req.key)
-            .map(|_| ())
-            .ok_or(MogError::UnknownKey(req.key.clone()))
+        let removed = try!(try!(self.domain_mut(&req.domain))
+                           .remove_file(&req.key)
+                           .ok_or(MogError::UnknownKey(req.key.clone())));
+        self.release_content(removed.content);
+        self.log_change(WalEntry::Delete(req.clone()));
+        Ok(())
     }
 
     fn rename(&mut self, req: &Rename) -> MogResult<()> {
-        self.domain_mut(&req.domain).and_then(|d| d.rename(&req.from_key, &req.to_key))
+        let replaced = try!(self.domain_mut(&req.domain).and_then(|d| d.rename(&req.from_key, &req.to_key, req.overwrite)));
+        if let Some(old_file) = replaced {
+            self.release_content(old_file.content);
+        }
+        self.log_change(WalEntry::Rename(req.clone()));
+        Ok(())
+    }
+
+    fn rename_many(&mut self, req: &RenameMany) -> MogResult<()> {
+        try!(self.domain_mut(&req.domain).and_then(|d| d.rename_many(&req.pairs)));
+        self.log_change(WalEntry::RenameMany(req.clone()));
+        Ok(())
+    }
+
+    fn usage(&self, req: &Usage) -> MogResult<UsageResponse> {
+        let mut names: Vec<&String> = self.domains.keys().collect();
+        names.sort();
+
+        let rows = names.into_iter()
+            .flat_map(|name| {
+                let domain = &self.domains[name];
+                domain.class_names().into_iter().map(move |class| (name, domain, class))
+            })
+            .filter(|&(name, _, class)| req.is_after(name, class))
+            .take(req.limit.unwrap_or(1000) as usize)
+            .map(|(name, domain, class)| {
+                let in_class = || domain.files().filter(move |&(_, f)| !f.is_open() && f.class.as_ref().map(|c| &c[..]).unwrap_or("default") == class);
+                UsageRow {
+                    domain: name.clone(),
+                    class: class.to_string(),
+                    files: in_class().count() as u64,
+                    bytes: in_class().map(|(_, f)| f.size.unwrap_or(0)).fold(0, |a, b| a + b),
+                }
+            })
+            .collect();
+
+        Ok(UsageResponse(rows))
+    }
+
+    fn update_class(&mut self, req: &UpdateClass) -> MogResult<()> {
+        let domain = try!(self.domains.get_mut(&req.domain).ok_or_else(|| MogError::UnregDomain(req.domain.clone())));
+        try!(closed_file(domain, &req.key));
+        try!(domain.update_class(&req.key, &req.new_class));
+        self.log_change(WalEntry::UpdateClass(req.clone()));
+        Ok(())
+    }
+
+    fn replicate(&mut self, req: &Replicate) -> MogResult<()> {
+        try!(self.domain_mut(&req.domain).and_then(|d| d.replicate(&req.key)));
+        self.log_change(WalEntry::Replicate(req.clone()));
+        Ok(())
+    }
+
+    fn copy(&mut self, req: &CopyKey) -> MogResult<()> {
+        try!(self.domain(&req.domain).and_then(|d| d.can_copy(&req.from_key, &req.to_key)));
+        let fid = self.allocate_fid();
+        try!(self.domain_mut(&req.domain).and_then(|d| d.copy(&req.from_key, &req.to_key, fid)));
+        self.log_change(WalEntry::CopyKey(req.clone()));
+        Ok(())
     }
 
     fn list_keys(&self, req: &ListKeys) -> MogResult<ListKeysResponse> {
         let after_key = req.after.as_ref().map(|s| s.as_ref()).unwrap_or("");
         let prefix = req.prefix.as_ref().map(|s| s.as_ref()).unwrap_or("");
         let limit = req.limit.unwrap_or(1000);
-        Ok(ListKeysResponse(try!(self.domain(&req.domain)).files()
-                            .filter(|&(k, _)| k.starts_with(prefix))
-                            .skip_while(|&(k, _)| k <= after_key)
-                            .take(limit as usize)
-                            .map(|(k, _)| k.to_string())
-                            .collect()))
+        let domain = try!(self.domain(&req.domain));
+
+        match req.order {
+            KeyOrder::Lexicographic => {
+                // Keys with the prefix are all together, so start at
+                // whichever of the prefix and the cursor comes later,
+                // and stop where the keys with the prefix end.
+                let start = if !prefix.is_empty() && prefix > after_key {
+                    Bound::Included(prefix)
+                } else if !after_key.is_empty() {
+                    Bound::Excluded(after_key)
+                } else {
+                    Bound::Unbounded
+                };
+
+                let page: Vec<(&str, &MemFileInfo)> = domain.files_with_prefix(prefix, start)
+                    .filter(|&(_, f)| !f.is_open())
+                    .take(limit as usize)
+                    .collect();
+
+                Ok(ListKeysResponse {
+                    keys: page.iter().map(|&(k, _)| k.to_string()).collect(),
+                    next_after: None,
+                    details: if req.with_sizes { Some(page.iter().map(|&(_, f)| key_details(f)).collect()) } else { None },
+                })
+            },
+            KeyOrder::MtimeDesc => {
+                let after = if after_key.is_empty() {
+                    None
+                } else {
+                    Some(try!(parse_mtime_cursor(after_key).ok_or_else(|| {
+                        MogError::Other("invalid_after".to_string(), Some(format!("Bad mtime cursor: {:?}", after_key)))
+                    })))
+                };
+
+                let mut files: Vec<((Option<Timespec>, &str), &MemFileInfo)> = domain.files_with_prefix(prefix, Bound::Included(prefix))
+                    .filter(|&(_, f)| !f.is_open())
+                    .map(|(k, f)| ((f.mtime.map(|t| t.to_timespec()), k), f))
+                    .collect();
+                files.sort_by(|a, b| cmp_mtime_desc(&a.0, &b.0));
+
+                let page: Vec<((Option<Timespec>, &str), &MemFileInfo)> = files.into_iter()
+                    .filter(|f| after.as_ref().map(|a| cmp_mtime_desc(&f.0, a) == Ordering::Greater).unwrap_or(true))
+                    .take(limit as usize)
+                    .collect();
+
+                Ok(ListKeysResponse {
+                    next_after: page.last().map(|&((mtime, key), _)| mtime_cursor(mtime, key)),
+                    keys: page.iter().map(|&((_, k), _)| k.to_string()).collect(),
+                    details: if req.with_sizes { Some(page.iter().map(|&(_, f)| key_details(f)).collect()) } else { None },
+                })
+            },
+        }
+    }
+
+    fn list_keys_since(&self, req: &ListKeysSince) -> MogResult<ListKeysSinceResponse> {
+        let start = match req.after {
+            Some(ref after) => Bound::Excluded(after.as_ref()),
+            None => Bound::Unbounded,
+        };
+        let limit = req.limit.unwrap_or(1000);
+        let domain = try!(self.domain(&req.domain));
+
+        Ok(ListKeysSinceResponse(
+            domain.files_with_prefix("", start)
+                .filter(|&(_, f)| !f.is_open())
+                .filter_map(|(k, f)| f.mtime.map(|t| (k.to_string(), t.to_timespec().sec)))
+                .filter(|&(_, mtime)| mtime > req.since)
+                .take(limit as usize)
+                .collect()))
+    }
+
+    fn list_all_keys(&self, req: &ListAllKeys) -> MogResult<ListAllKeysResponse> {
+        let mut names: Vec<&String> = self.domains.keys()
+            .filter(|name| req.after_domain.as_ref().map(|d| *name >= d).unwrap_or(true))
+            .collect();
+        names.sort();
+
+        let limit = req.limit.unwrap_or(1000) as usize;
+        let mut keys = Vec::new();
+
+        for name in names {
+            // Only the cursor's domain needs to skip any keys, so it
+            // starts from the cursor's key; the ones after it start
+            // from the beginning.
+            let start = match (req.after_domain.as_ref(), req.after_key.as_ref()) {
+                (Some(d), Some(k)) if d == name => Bound::Excluded(&k[..]),
+                _ => Bound::Unbounded,
+            };
+
+            let remaining = limit - keys.len();
+            keys.extend(self.domains[name].files_from(start)
+                        .filter(|&(k, f)| !f.is_open() && req.is_after(name, k))
+                        .map(|(k, _)| (name.clone(), k.to_string()))
+                        .take(remaining));
+            if keys.len() >= limit {
+                break;
+            }
+        }
+
+        Ok(ListAllKeysResponse(keys))
     }
 
     // Storage server methods.
@@ -105,9 +719,18 @@ impl MemBackend {
     pub fn file_metadata(&self, domain: &str, key: &str) -> MogResult<StorageMetadata> {
         let file_info = try!(try!(self.file(domain, key)).ok_or(MogError::UnknownKey(key.to_string())));
 
-        match (file_info.size, file_info.mtime) {
-            (Some(size), Some(mtime)) => {
-                Ok(StorageMetadata { size: size, mtime: mtime, })
+        // A file with no content and a file with zero-length content
+        // are different things; only the former is an error.
+        match (file_info.content.as_ref(), file_info.mtime) {
+            (Some(content), Some(mtime)) => {
+                Ok(StorageMetadata {
+                    size: file_info.size.unwrap_or(content.len() as u64),
+                    stored_size: content.len() as u64,
+                    mtime: mtime,
+                    etag: Some(file_info.etag.clone().unwrap_or_else(|| content_etag(content))),
+                    accepts_ranges: true,
+                    content_encoding: file_info.content_encoding.clone(),
+                })
             },
             _ => {
                 Err(MogError::NoContent(key.to_string()))
@@ -115,6 +738,18 @@ impl MemBackend {
         }
     }
 
+    /// Like `file_metadata`, but remembers the checksum it computes,
+    /// so it's only computed once for each version of the content.
+    pub fn cached_file_metadata(&mut self, domain: &str, key: &str) -> MogResult<StorageMetadata> {
+        if let Some(file_info) = try!(self.file_mut(domain, key)) {
+            if file_info.etag.is_none() {
+                file_info.etag = file_info.content.as_ref().map(|c| content_etag(c));
+            }
+        }
+
+        self.file_metadata(domain, key)
+    }
+
     pub fn store_reader_content<R: Read>(&mut self, domain: &str, key: &str, reader: &mut R) -> MogResult<()> {
         let mut content = vec![];
         try!(io::copy(reader, &mut content));
@@ -122,10 +757,125 @@ impl MemBackend {
     }
 
     pub fn store_bytes_content(&mut self, domain: &str, key: &str, content: &[u8]) -> MogResult<()> {
-        let file_info = try!(try!(self.file_mut(domain, key)).ok_or(MogError::UnknownKey(key.to_string())));
-        file_info.size = Some(content.len() as u64);
-        file_info.content = Some(content.to_owned());
-        file_info.mtime = Some(time::now_utc());
+        self.store_encoded_bytes_content(domain, key, content, None)
+    }
+
+    /// Store `content`, which clients should be told is encoded with
+    /// `content_encoding`. The backend's own compression is applied on
+    /// top of that, and taken off again before the content is served,
+    /// so it never shows up here.
+    pub fn store_encoded_bytes_content(&mut self, domain: &str, key: &str, content: &[u8], content_encoding: Option<&str>) -> MogResult<()> {
+        if try!(self.file(domain, key)).is_none() {
+            return Err(MogError::UnknownKey(key.to_string()));
+        }
+
+        let compression = self.compression;
+        let blob = match compression {
+            Compression::None => self.intern_content(content),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::Default);
+                try!(encoder.write_all(content));
+                let gzipped = try!(encoder.finish());
+                self.intern_content(&gzipped)
+            },
+        };
+
+        let old_content = {
+            let file_info = try!(self.file_mut(domain, key)).unwrap();
+            file_info.size = Some(content.len() as u64);
+            file_info.mtime = Some(time::now_utc());
+            file_info.etag = None;
+            file_info.checksum = None;
+            file_info.compression = compression;
+            file_info.content_encoding = content_encoding.map(|e| e.to_string());
+            mem::replace(&mut file_info.content, Some(blob))
+        };
+
+        self.release_content(old_content);
+        Ok(())
+    }
+
+    /// Add `content` to the end of what's stored at `key`. A file
+    /// without any content yet is treated as empty. The file keeps its
+    /// content encoding, so it's up to the caller to append something
+    /// which makes sense there.
+    pub fn append_bytes_content(&mut self, domain: &str, key: &str, content: &[u8]) -> MogResult<()> {
+        let (mut appended, content_encoding) = {
+            let file_info = try!(try!(self.file(domain, key)).ok_or(MogError::UnknownKey(key.to_string())));
+            let existing = match file_info.content {
+                Some(_) => try!(self.read_content(domain, key)).to_vec(),
+                None => vec![],
+            };
+            (existing, file_info.content_encoding.clone())
+        };
+
+        appended.extend_from_slice(content);
+        self.store_encoded_bytes_content(domain, key, &appended, content_encoding.as_ref().map(|e| &e[..]))
+    }
+
+    /// See `SyncMemBackend::swap_content`.
+    pub fn swap_content(&mut self, domain: &str, key_a: &str, key_b: &str) -> MogResult<()> {
+        try!(try!(self.domain_mut(domain)).swap_content(key_a, key_b));
+        self.log_change(WalEntry::SwapContent(domain.to_string(), key_a.to_string(), key_b.to_string()));
+        Ok(())
+    }
+
+    /// The file at `key` as an export record, or `None` if there's no
+    /// closed file there (any more).
+    fn export_file(&self, domain: &str, key: &str, with_content: bool) -> MogResult<Option<ExportedFile>> {
+        let file_info = match try!(self.file(domain, key)) {
+            Some(f) if !f.is_open() => f,
+            _ => return Ok(None),
+        };
+
+        let content = match (with_content, file_info.content.is_some()) {
+            (true, true) => Some(try!(self.read_content(domain, key))),
+            _ => None,
+        };
+
+        Ok(Some(ExportedFile {
+            fid: file_info.fid(),
+            key: key.to_string(),
+            size: file_info.size,
+            mtime: file_info.mtime.map(|t| t.to_timespec().sec),
+            class: file_info.class.clone(),
+            content_encoding: file_info.content_encoding.clone(),
+            content: content,
+        }))
+    }
+
+    /// Recreate an exported file, replacing whatever's at its key.
+    fn import_file(&mut self, domain: &str, mut record: ExportedFile) -> MogResult<()> {
+        if let Some(ref class) = record.class {
+            if try!(self.domain(domain)).mindevcount(Some(&class[..])).is_none() {
+                return Err(MogError::UnregClass(class.clone()));
+            }
+        }
+
+        self.next_fid = cmp::max(self.next_fid, record.fid + 1);
+        let mut file_info = MemFileInfo::new(record.fid, &record.key);
+        file_info.size = record.size;
+        file_info.class = record.class;
+        let replaced = {
+            let domain = try!(self.domain_mut(domain));
+            let replaced = domain.remove_file(&record.key);
+            try!(domain.add_file(&record.key, file_info));
+            replaced
+        };
+        if let Some(old_file) = replaced {
+            self.release_content(old_file.content);
+        }
+
+        if let Some(content) = record.content.take() {
+            try!(self.store_encoded_bytes_content(domain, &record.key, &content[..], record.content_encoding.as_ref().map(|e| &e[..])));
+        }
+
+        {
+            let file_info = try!(self.file_mut(domain, &record.key)).unwrap();
+            file_info.mtime = record.mtime.map(|sec| time::at_utc(Timespec::new(sec, 0)));
+            file_info.content_encoding = record.content_encoding.clone();
+        }
+        self.log_change(WalEntry::ImportFile(domain.to_string(), record));
         Ok(())
     }
 
@@ -133,7 +883,10 @@ impl MemBackend {
         let file_info = try!(try!(self.file(domain, key)).ok_or(MogError::UnknownKey(key.to_string())));
         match file_info.content {
             Some(ref reader) => {
-                try!(io::copy(&mut Cursor::new(reader), writer));
+                match file_info.compression {
+                    Compression::None => try!(io::copy(&mut Cursor::new(&reader[..]), writer)),
+                    Compression::Gzip => try!(io::copy(&mut try!(GzDecoder::new(Cursor::new(&reader[..]))), writer)),
+                };
                 Ok(())
             },
             None => {
@@ -142,8 +895,142 @@ impl MemBackend {
         }
     }
 
+    /// Up to the first `n` bytes of a file's content, or all of it if
+    /// it's shorter.
+    pub fn peek_content(&self, domain: &str, key: &str, n: usize) -> MogResult<Vec<u8>> {
+        self.read_range(domain, key, 0..n as u64)
+    }
+
+    /// The bytes of a file's content in `range`, cut short if it runs
+    /// past the end, and empty if it starts past it. Only as much of
+    /// compressed content as it takes is decompressed.
+    pub fn read_range(&self, domain: &str, key: &str, range: ops::Range<u64>) -> MogResult<Vec<u8>> {
+        let file_info = try!(try!(self.file(domain, key)).ok_or(MogError::UnknownKey(key.to_string())));
+        let len = range.end.saturating_sub(range.start);
+        match file_info.content {
+            Some(ref content) => {
+                match file_info.compression {
+                    Compression::None => {
+                        let start = cmp::min(range.start, content.len() as u64) as usize;
+                        let end = cmp::min(range.start.saturating_add(len), content.len() as u64) as usize;
+                        Ok(content[start..end].to_vec())
+                    },
+                    Compression::Gzip => {
+                        let mut decoder = try!(GzDecoder::new(Cursor::new(&content[..])));
+                        try!(io::copy(&mut (&mut decoder).take(range.start), &mut io::sink()));
+                        let mut bytes = vec![];
+                        try!(decoder.take(len).read_to_end(&mut bytes));
+                        Ok(bytes)
+                    },
+                }
+            },
+            None => Err(MogError::NoContent(key.to_string())),
+        }
+    }
+
+    /// Like `get_content`, but returns the content itself instead of
+    /// copying it into a writer. Content the backend compressed has to
+    /// be decompressed, so that's still a copy.
+    pub fn read_content(&self, domain: &str, key: &str) -> MogResult<MemContent> {
+        let file_info = try!(try!(self.file(domain, key)).ok_or(MogError::UnknownKey(key.to_string())));
+        match file_info.content {
+            Some(ref content) => {
+                match file_info.compression {
+                    Compression::None => Ok(MemContent(content.clone())),
+                    Compression::Gzip => {
+                        let mut decompressed = vec![];
+                        try!(try!(GzDecoder::new(Cursor::new(&content[..]))).read_to_end(&mut decompressed));
+                        Ok(MemContent(Arc::new(decompressed)))
+                    },
+                }
+            },
+            None => {
+                Err(MogError::NoContent(key.to_string()))
+            }
+        }
+    }
+
     // Utility methods.
 
+    /// The next fid, which no file has had before.
+    fn allocate_fid(&mut self) -> u64 {
+        let fid = self.next_fid;
+        self.next_fid += 1;
+        fid
+    }
+
+    /// Where a file lives, as (devid, URL) pairs, in the order the
+    /// backend's `Placement` says.
+    fn dest_paths(&self, domain: &str, key: &str) -> Vec<(u64, Url)> {
+        if self.devices.is_empty() {
+            return vec![ (1, self.url_for_key(domain, key)) ];
+        }
+
+        let mut devices = self.devices.clone();
+        match self.placement {
+            Placement::ByHealth => {
+                devices.sort_by(|a, b| b.health.cmp(&a.health).then(a.devid.cmp(&b.devid)));
+            },
+            Placement::ConsistentHash => {
+                let score = |d: &MemDevice| fnv1a(format!("{}\0{}\0{}", domain, key, d.devid).as_bytes());
+                devices.sort_by(|a, b| score(b).cmp(&score(a)));
+            },
+        }
+
+        devices.into_iter().map(|d| {
+            let mut device_url = self.base_url.clone();
+            device_url.path_segments_mut().unwrap().pop_if_empty().push(&format!("dev{}", d.devid));
+            (d.devid, url_for_key(&device_url, domain, key))
+        }).collect()
+    }
+
+    /// Returns a buffer holding `content`, which will be shared with
+    /// any other files holding the same content if dedup is on.
+    fn intern_content(&mut self, content: &[u8]) -> Arc<Vec<u8>> {
+        if !self.dedup {
+            return Arc::new(content.to_owned());
+        }
+
+        match self.blobs.entry(content_hash(content)) {
+            Entry::Occupied(mut e) => {
+                match e.get().upgrade() {
+                    Some(ref shared) if &shared[..] == content => shared.clone(),
+                    // A hash collision; just don't share this one.
+                    Some(_) => Arc::new(content.to_owned()),
+                    None => {
+                        let blob = Arc::new(content.to_owned());
+                        e.insert(Arc::downgrade(&blob));
+                        blob
+                    },
+                }
+            },
+            Entry::Vacant(e) => {
+                let blob = Arc::new(content.to_owned());
+                e.insert(Arc::downgrade(&blob));
+                blob
+            },
+        }
+    }
+
+    /// Lets go of content that's no longer referenced by a file,
+    /// forgetting the shared buffer if nothing else is using it.
+    ///
+    /// Whether anything else is can't be told from the reference
+    /// count while we hold one, since `read_content` hands out
+    /// references of its own; so let go first, and then see whether
+    /// the buffer's still there.
+    fn release_content(&mut self, content: Option<Arc<Vec<u8>>>) {
+        let hash = match content {
+            Some(ref b) if !self.blobs.is_empty() => content_hash(b),
+            _ => return,
+        };
+        mem::drop(content);
+
+        if self.blobs.get(&hash).map(|w| w.upgrade().is_none()).unwrap_or(false) {
+            self.blobs.remove(&hash);
+        }
+    }
+
     fn file(&self, domain: &str, key: &str) -> MogResult<Option<&MemFileInfo>> {
         self.domain(domain).map(|d| d.file(key))
     }
@@ -194,44 +1081,192 @@ impl SyncMemBackend {
         }
     }
 
-    pub fn base_url(&self) -> Url {
-        self.1.clone()
+    /// Borrow a file's content without copying it; see
+    /// `MemBackend::read_content`. Content is held in shared buffers,
+    /// so the read lock is only held while the file is looked up, not
+    /// for as long as the content is: a later write replaces the
+    /// buffer rather than changing it, and the `MemContent` keeps
+    /// what was there when it was read.
+    pub fn read_content(&self, domain: &str, key: &str) -> MogResult<MemContent> {
+        try!(self.0.read()).read_content(domain, key)
     }
 
-    pub fn set_base_url(&mut self, new_url: Url) -> MogResult<()> {
-        let mut guard = try!(self.0.write());
-        guard.base_url = new_url.clone();
-        self.1 = new_url;
-        Ok(())
+    /// Up to the first `n` bytes of a file's content: all of it, if
+    /// it's no longer than that. See `MemBackend::peek_content`.
+    pub fn peek_content(&self, domain: &str, key: &str, n: usize) -> MogResult<Vec<u8>> {
+        try!(self.0.read()).peek_content(domain, key, n)
     }
-}
 
-impl Backend for SyncMemBackend {
-    fn create_domain(&self, request: &CreateDomain) -> MogResult<CreateDomain> {
-        try!(self.0.write()).create_domain(&request)
+    /// See `MemBackend::read_range`.
+    pub fn read_range(&self, domain: &str, key: &str, range: ops::Range<u64>) -> MogResult<Vec<u8>> {
+        try!(self.0.read()).read_range(domain, key, range)
     }
 
-    fn create_open(&self, request: &CreateOpen) -> MogResult<CreateOpenResponse> {
-        try!(self.0.write()).create_open(&request)
+    /// A file's content, `chunk_size` bytes at a time (the last chunk
+    /// may be shorter). The content is read up front, as with
+    /// `read_content`, so no lock is held while iterating.
+    pub fn content_chunks(&self, domain: &str, key: &str, chunk_size: usize) -> MogResult<ContentChunks> {
+        if chunk_size == 0 {
+            return Err(MogError::Other("invalid_chunk_size".to_string(), Some("The chunk size must be at least 1".to_string())));
+        }
+
+        Ok(ContentChunks {
+            content: try!(self.read_content(domain, key)),
+            chunk_size: chunk_size,
+            offset: 0,
+        })
     }
 
-    fn create_close(&self, _request: &CreateClose) -> MogResult<()> {
-        // There's nothing to do here, since the create_open request
-        // created the entry, and the storage server request already
-        // stored the file. Just say it worked. Thought: if there was
-        // no create_open or storage server request, should this
-        // return an error?
-        Ok(())
+    /// Read all of `reader` and add it to the end of the content at
+    /// `key`; see `MemBackend::append_bytes_content`. The reader is
+    /// drained before the lock is taken, and the append happens under
+    /// a single write lock, so concurrent appends don't lose each
+    /// other's content.
+    pub fn append_content<R: Read>(&self, domain: &str, key: &str, reader: &mut R) -> MogResult<()> {
+        let mut content = vec![];
+        try!(io::copy(reader, &mut content));
+        try!(self.0.write()).append_bytes_content(domain, key, &content)
     }
 
-    fn create_class(&self, request: &CreateClass) -> MogResult<CreateClassResponse> {
-        // The in-memory backend doesn't have the concept of storage
-        // classes, so just return an Ok response echoing the request.
-        Ok(CreateClassResponse {
-            domain: request.domain.clone(),
-            class: request.class.clone(),
-            mindevcount: request.mindevcount,
-        })
+    /// Exchange the content of the files at `key_a` and `key_b` (and
+    /// their fids, sizes and mtimes), under a single write lock, so
+    /// that nothing ever sees both keys with the same content, or
+    /// either with the other's content and its own size. Fails with
+    /// `UnknownKey` if either file isn't there, or hasn't been closed.
+    pub fn swap_content(&self, domain: &str, key_a: &str, key_b: &str) -> MogResult<()> {
+        try!(self.0.write()).swap_content(domain, key_a, key_b)
+    }
+
+    /// Write out the closed files in `domain` as JSON lines, one
+    /// object per file, in key order:
+    ///
+    /// ```text
+    /// {"class":null,"content":"dGVzdA==","content_encoding":null,"fid":3,"key":"test/key/1","mtime":1466100000,"size":4}
+    /// ```
+    ///
+    /// `content` is the base64 of the file's content, or null if it
+    /// has none or `with_content` isn't set. Each file is looked up
+    /// and written in turn, with the lock only held while it's looked
+    /// up, so only one file's content is in memory at a time. Returns
+    /// how many files were written.
+    pub fn export_domain<W: Write>(&self, domain: &str, writer: &mut W, with_content: bool) -> MogResult<usize> {
+        let keys: Vec<String> = try!(try!(self.0.read()).domain(domain)).files()
+            .filter(|&(_, f)| !f.is_open())
+            .map(|(k, _)| k.to_string())
+            .collect();
+        let mut count = 0;
+
+        for key in keys {
+            let record = try!(try!(self.0.read()).export_file(domain, &key, with_content));
+            if let Some(record) = record {
+                try!(writeln!(writer, "{}", record.to_json()));
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Read files written by `export_domain` into `domain`, replacing
+    /// any already at their keys. Their classes have to exist in the
+    /// domain already. The files are read and stored one line at a
+    /// time; if a line can't be read, the files before it are still
+    /// imported. Returns how many files were.
+    pub fn import_domain<R: BufRead>(&self, domain: &str, reader: &mut R) -> MogResult<usize> {
+        let mut count = 0;
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = try!(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record = try!(ExportedFile::from_json(&line).map_err(|e| {
+                MogError::Other("invalid_export".to_string(), Some(format!("Line {}: {}", i + 1, e)))
+            }));
+            try!(try!(self.0.write()).import_file(domain, record));
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// See `MemBackend::enable_wal`.
+    pub fn enable_wal(&self, capacity: usize) -> MogResult<()> {
+        try!(self.0.write()).enable_wal(capacity);
+        Ok(())
+    }
+
+    /// A copy of what's in the write-ahead log; see
+    /// `MemBackend::wal_entries`.
+    pub fn wal_entries(&self) -> MogResult<Vec<WalEntry>> {
+        Ok(try!(self.0.read()).wal_entries())
+    }
+
+    /// See `MemBackend::replay`. The whole log is replayed under the
+    /// one lock.
+    pub fn replay(&self, wal: &[WalEntry]) -> MogResult<()> {
+        try!(self.0.write()).replay(wal)
+    }
+
+    /// See `MemBackend::set_strict_sizes`.
+    pub fn set_strict_sizes(&self, strict: bool) -> MogResult<()> {
+        try!(self.0.write()).set_strict_sizes(strict);
+        Ok(())
+    }
+
+    /// See `MemBackend::set_placement`.
+    pub fn set_placement(&self, placement: Placement) -> MogResult<()> {
+        try!(self.0.write()).set_placement(placement);
+        Ok(())
+    }
+
+    /// See `MemBackend::set_device_health`.
+    pub fn set_device_health(&self, devid: u64, health: u32) -> MogResult<()> {
+        try!(self.0.write()).set_device_health(devid, health);
+        Ok(())
+    }
+
+    /// See `MemBackend::purge_stale_opens`.
+    pub fn purge_stale_opens(&self, max_age: time::Duration) -> MogResult<usize> {
+        Ok(try!(self.0.write()).purge_stale_opens(max_age))
+    }
+
+    pub fn base_url(&self) -> Url {
+        self.1.clone()
+    }
+
+    pub fn dedup_stats(&self) -> MogResult<DedupStats> {
+        Ok(try!(self.0.read()).dedup_stats())
+    }
+
+    pub fn set_base_url(&mut self, new_url: Url) -> MogResult<()> {
+        let mut guard = try!(self.0.write());
+        guard.base_url = new_url.clone();
+        self.1 = new_url;
+        Ok(())
+    }
+}
+
+impl Backend for SyncMemBackend {
+    fn create_domain(&self, request: &CreateDomain) -> MogResult<CreateDomain> {
+        try!(self.0.write()).create_domain(&request)
+    }
+
+    fn create_open(&self, request: &CreateOpen) -> MogResult<CreateOpenResponse> {
+        try!(self.0.write()).create_open(&request)
+    }
+
+    fn create_close(&self, request: &CreateClose) -> MogResult<()> {
+        try!(self.0.write()).create_close(&request)
+    }
+
+    fn create_cancel(&self, request: &CreateCancel) -> MogResult<()> {
+        try!(self.0.write()).create_cancel(&request)
+    }
+
+    fn create_class(&self, request: &CreateClass) -> MogResult<CreateClassResponse> {
+        try!(self.0.write()).create_class(&request)
     }
 
     fn get_paths(&self, request: &GetPaths) -> MogResult<GetPathsResponse> {
@@ -250,9 +1285,47 @@ impl Backend for SyncMemBackend {
         try!(self.0.write()).rename(&request)
     }
 
+    fn rename_many(&self, request: &RenameMany) -> MogResult<()> {
+        try!(self.0.write()).rename_many(&request)
+    }
+
+    fn copy(&self, request: &CopyKey) -> MogResult<()> {
+        try!(self.0.write()).copy(&request)
+    }
+
+    fn usage(&self, request: &Usage) -> MogResult<UsageResponse> {
+        try!(self.0.read()).usage(&request)
+    }
+
+    fn locate(&self, request: &Locate) -> MogResult<LocateResponse> {
+        try!(self.0.read()).locate(&request)
+    }
+
+    fn update_class(&self, request: &UpdateClass) -> MogResult<()> {
+        try!(self.0.write()).update_class(&request)
+    }
+
+    fn replicate(&self, request: &Replicate) -> MogResult<()> {
+        try!(self.0.write()).replicate(&request)
+    }
+
     fn list_keys(&self, request: &ListKeys) -> MogResult<ListKeysResponse> {
         try!(self.0.read()).list_keys(&request)
     }
+
+    fn list_keys_since(&self, request: &ListKeysSince) -> MogResult<ListKeysSinceResponse> {
+        try!(self.0.read()).list_keys_since(&request)
+    }
+
+    fn list_all_keys(&self, request: &ListAllKeys) -> MogResult<ListAllKeysResponse> {
+        try!(self.0.read()).list_all_keys(&request)
+    }
+}
+
+impl InProcessStorage for SyncMemBackend {
+    fn read_range(&self, domain: &str, key: &str, range: ops::Range<u64>) -> MogResult<Vec<u8>> {
+        SyncMemBackend::read_range(self, domain, key, range)
+    }
 }
 
 impl StorageBackend for SyncMemBackend {
@@ -261,39 +1334,210 @@ impl StorageBackend for SyncMemBackend {
     }
 
     fn file_metadata(&self, domain: &str, key: &str) -> MogResult<StorageMetadata> {
-        try!(self.0.read()).file_metadata(domain, key)
+        {
+            // Only take the write lock if there's a checksum to cache.
+            let backend = try!(self.0.read());
+            if try!(backend.file(domain, key)).map(|f| f.etag.is_some() || f.content.is_none()).unwrap_or(true) {
+                return backend.file_metadata(domain, key);
+            }
+        }
+
+        try!(self.0.write()).cached_file_metadata(domain, key)
     }
 
     fn store_reader_content<R: Read>(&self, domain: &str, key: &str, reader: &mut R) -> MogResult<()> {
-        try!(self.0.write()).store_reader_content(domain, key, reader)
+        // Read the body before taking the lock, so a slow upload
+        // doesn't hold up everyone else.
+        let mut content = vec![];
+        try!(io::copy(reader, &mut content));
+        self.store_bytes_content(domain, key, &content)
     }
 
     fn store_bytes_content(&self, domain: &str, key: &str, content: &[u8]) -> MogResult<()> {
         try!(self.0.write()).store_bytes_content(domain, key, content)
     }
 
+    fn store_encoded_reader_content<R: Read>(&self, domain: &str, key: &str, reader: &mut R, content_encoding: Option<&str>) -> MogResult<()> {
+        let mut content = vec![];
+        try!(io::copy(reader, &mut content));
+        try!(self.0.write()).store_encoded_bytes_content(domain, key, &content, content_encoding)
+    }
+
     fn get_content<W: Write>(&self, domain: &str, key: &str, writer: &mut W) -> MogResult<()> {
         try!(self.0.read()).get_content(domain, key, writer)
     }
 }
 
-pub fn url_for_key(base_url: &Url, domain: &str, key: &str) -> Url {
-    let mut new_path: Vec<&str> = base_url.path_segments().unwrap().collect();
-    new_path.extend([ "d", domain, "k" ].iter());
-    new_path.extend(key.split("/"));
-    new_path = new_path.into_iter().skip_while(|p| p.is_empty()).collect();
+/// A file, as `SyncMemBackend::export_domain` writes it out.
+#[derive(Debug, Clone)]
+pub struct ExportedFile {
+    fid: u64,
+    key: String,
+    size: Option<u64>,
+    mtime: Option<i64>,
+    class: Option<String>,
+    content_encoding: Option<String>,
+    content: Option<MemContent>,
+}
+
+impl ExportedFile {
+    fn to_json(&self) -> Json {
+        let string_or_null = |s: &Option<String>| s.clone().map(Json::String).unwrap_or(Json::Null);
+        let mut obj = BTreeMap::new();
+        obj.insert("fid".to_string(), Json::U64(self.fid));
+        obj.insert("key".to_string(), Json::String(self.key.clone()));
+        obj.insert("size".to_string(), self.size.map(Json::U64).unwrap_or(Json::Null));
+        obj.insert("mtime".to_string(), self.mtime.map(Json::I64).unwrap_or(Json::Null));
+        obj.insert("class".to_string(), string_or_null(&self.class));
+        obj.insert("content_encoding".to_string(), string_or_null(&self.content_encoding));
+        obj.insert("content".to_string(), self.content.as_ref().map(|c| Json::String(c.to_base64(STANDARD))).unwrap_or(Json::Null));
+        Json::Object(obj)
+    }
+
+    fn from_json(line: &str) -> Result<ExportedFile, String> {
+        let json = try!(Json::from_str(line).map_err(|e| format!("{}", e)));
+        let obj = try!(json.as_object().ok_or("Not an object".to_string()));
+        let optional = |name: &str| obj.get(name).and_then(|v| if v.is_null() { None } else { Some(v) });
+        let optional_string = |name: &str| -> Result<Option<String>, String> {
+            match optional(name) {
+                Some(v) => v.as_string().map(|s| Some(s.to_string())).ok_or(format!("Bad {}", name)),
+                None => Ok(None),
+            }
+        };
+
+        let fid = try!(optional("fid").and_then(Json::as_u64).ok_or("Missing or bad fid".to_string()));
+        let key = try!(optional_string("key")).unwrap_or_default();
+        if key.is_empty() {
+            return Err("Missing key".to_string());
+        }
+        let size = match optional("size") {
+            Some(v) => Some(try!(v.as_u64().ok_or("Bad size".to_string()))),
+            None => None,
+        };
+        let mtime = match optional("mtime") {
+            Some(v) => Some(try!(v.as_i64().ok_or("Bad mtime".to_string()))),
+            None => None,
+        };
+        let content = match try!(optional_string("content")) {
+            Some(encoded) => Some(MemContent(Arc::new(try!(encoded.from_base64().map_err(|e| format!("Bad content: {}", e)))))),
+            None => None,
+        };
+
+        Ok(ExportedFile {
+            fid: fid,
+            key: key,
+            size: size,
+            mtime: mtime,
+            class: try!(optional_string("class")),
+            content_encoding: try!(optional_string("content_encoding")),
+            content: content,
+        })
+    }
+}
+
+/// The file at `key`, unless it doesn't exist or hasn't been closed
+/// yet.
+fn closed_file<'a>(domain: &'a MemDomain, key: &str) -> MogResult<&'a MemFileInfo> {
+    match domain.file(key) {
+        Some(file) if !file.is_open() => Ok(file),
+        _ => Err(MogError::UnknownKey(key.to_string())),
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`. Unlike `DefaultHasher`'s, it's
+/// fixed, so consistently-hashed files keep their devices across
+/// builds of the tracker.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn content_etag(content: &[u8]) -> String {
+    format!("{:016x}", content_hash(content))
+}
+
+fn content_hash(content: &[u8]) -> ContentHash {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Orders `(mtime, key)` pairs newest first, with files that have no
+/// mtime last. Ties are broken by the key, so that the ordering is
+/// total and usable as a cursor.
+fn cmp_mtime_desc(a: &(Option<Timespec>, &str), b: &(Option<Timespec>, &str)) -> Ordering {
+    let by_mtime = match (a.0, b.0) {
+        (Some(ta), Some(tb)) => tb.cmp(&ta),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    };
+
+    match by_mtime {
+        Ordering::Equal => a.1.cmp(b.1),
+        o => o,
+    }
+}
+
+/// Encodes the position of a file in an mtime-ordered listing as a
+/// `list_keys` cursor, like "1466100000.000000123:some/key", or
+/// "-:some/key" for a file without an mtime.
+fn mtime_cursor(mtime: Option<Timespec>, key: &str) -> String {
+    match mtime {
+        Some(ts) => format!("{}.{:09}:{}", ts.sec, ts.nsec, key),
+        None => format!("-:{}", key),
+    }
+}
 
-    let mut key_url = base_url.clone();
-    key_url.set_path(&new_path.join("/"));
-    key_url
+/// What a `list_keys` with `with_sizes` says about a file.
+fn key_details(file: &MemFileInfo) -> KeyDetails {
+    KeyDetails {
+        size: file.size,
+        mtime: file.mtime.map(|t| t.to_timespec().sec),
+    }
+}
+
+/// The inverse of `mtime_cursor`.
+fn parse_mtime_cursor(cursor: &str) -> Option<(Option<Timespec>, &str)> {
+    let mut parts = cursor.splitn(2, ':');
+    let mtime_str = parts.next().unwrap_or("");
+    let key = match parts.next() {
+        Some(k) => k,
+        None => return None,
+    };
+
+    if mtime_str == "-" {
+        return Some((None, key));
+    }
+
+    let mut mtime_parts = mtime_str.splitn(2, '.');
+    let sec = mtime_parts.next().and_then(|s| s.parse::<i64>().ok());
+    let nsec = mtime_parts.next().and_then(|s| s.parse::<i32>().ok());
+
+    match (sec, nsec) {
+        (Some(sec), Some(nsec)) => Some((Some(Timespec::new(sec, nsec)), key)),
+        _ => None,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use mogilefs_common::{Backend, MogError};
+    use mogilefs_common::{Backend, MogError, ReadOnly};
     use mogilefs_common::requests::*;
+    use std::collections::HashSet;
     use std::io::Cursor;
+    use std::mem;
+    use std::sync::{Arc, mpsc};
+    use std::thread;
+    use super::{Compression, DedupStats, ExportedFile, MemBackend, MemContent, MemFileInfo, Placement, SyncMemBackend, WalEntry};
+    use super::super::super::backend::StorageBackend;
+    use time::{self, Timespec};
     use super::super::super::test_support::*;
+    use url::Url;
 
     #[test]
     fn backend_get_file() {
@@ -369,7 +1613,7 @@ mod tests {
         //     Url::parse(format!("http://{}/{}", TEST_HOST, TEST_BASE_PATH).as_ref()).unwrap());
 
         {
-            let req = CreateOpen { domain: TEST_DOMAIN.to_string(), class: None, key: "test/key/3".to_string(), multi_dest: true, size: None };
+            let req = CreateOpen { multi_dest: true, ..open_request("test/key/3") };
             let mut backend = sync_backend.0.write().unwrap();
             let co_result = backend.create_open(&req);
             assert!(co_result.is_ok());
@@ -391,7 +1635,7 @@ mod tests {
         }
 
         {
-            let req = CreateOpen { domain: TEST_DOMAIN.to_string(), class: None, key: TEST_KEY_1.to_string(), multi_dest: true, size: None };
+            let req = CreateOpen { multi_dest: true, ..open_request(TEST_KEY_1) };
             let mut backend = sync_backend.0.write().unwrap();
             let co_result = backend.create_open(&req);
             assert!(co_result.is_ok(), "Create open with duplicate key result was {:?}", co_result);
@@ -411,13 +1655,108 @@ mod tests {
         // }
     }
 
+    #[test]
+    fn backend_stale_opens() {
+        let mut backend = backend_fixture();
+        open_file(&mut backend, "test/key/3");
+        let open_res = open_file(&mut backend, "test/key/4");
+        backend.create_close(&CreateClose {
+            domain: TEST_DOMAIN.to_string(),
+            key: "test/key/4".to_string(),
+            fid: open_res.fid,
+            devid: 1,
+            path: open_res.paths[0].1.clone(),
+            checksum: None,
+        }).unwrap();
+
+        // The open file is invisible until it's closed.
+        let info_req = FileInfo { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() };
+        assert!(matches!(backend.file_info(&info_req), Err(MogError::UnknownKey(..))));
+        let paths_req = GetPaths { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string(), noverify: false, pathcount: None };
+        assert!(matches!(backend.get_paths(&paths_req), Err(MogError::UnknownKey(..))));
+        let list_req = ListKeys { domain: TEST_DOMAIN.to_string(), prefix: None, after: None, limit: None, order: KeyOrder::Lexicographic, with_sizes: false };
+        assert_eq!(vec![ TEST_KEY_1, TEST_KEY_2, "test/key/4" ], backend.list_keys(&list_req).unwrap().keys);
+
+        assert_eq!(0, backend.purge_stale_opens(time::Duration::minutes(10)));
+        assert_eq!(1, backend.purge_stale_opens(time::Duration::zero()));
+        assert!(matches!(backend.file(TEST_DOMAIN, "test/key/3"), Ok(None)));
+        assert!(matches!(backend.file(TEST_DOMAIN, "test/key/4"), Ok(Some(..))));
+    }
+
+    #[test]
+    fn backend_create_cancel() {
+        let mut backend = backend_fixture();
+        let open_res = open_file(&mut backend, "test/key/3");
+        let cancel = |fid: u64, key: &str| CreateCancel { domain: TEST_DOMAIN.to_string(), key: key.to_string(), fid: fid };
+
+        assert!(matches!(backend.create_cancel(&cancel(open_res.fid + 1, "test/key/3")), Err(MogError::UnknownFid)));
+        backend.create_cancel(&cancel(open_res.fid, "test/key/3")).unwrap();
+        assert!(matches!(backend.file(TEST_DOMAIN, "test/key/3"), Ok(None)));
+        assert!(matches!(backend.create_cancel(&cancel(open_res.fid, "test/key/3")), Err(MogError::UnknownKey(..))));
+
+        // Closed files can't be cancelled.
+        assert!(matches!(backend.create_cancel(&cancel(3, TEST_KEY_1)), Err(MogError::UnknownFid)));
+        assert!(matches!(backend.file(TEST_DOMAIN, TEST_KEY_1), Ok(Some(..))));
+
+        // Cancelling an open which a later open of the same key has
+        // replaced leaves the later one alone.
+        let first = backend.create_open(&open_req).unwrap();
+        let second = backend.create_open(&open_req).unwrap();
+        assert!(first.fid != second.fid);
+        assert!(matches!(backend.create_cancel(&cancel(first.fid, "test/key/3")), Err(MogError::UnknownFid)));
+        assert_eq!(Some(second.fid), backend.file(TEST_DOMAIN, "test/key/3").unwrap().map(|f| f.fid()));
+    }
+
+    #[test]
+    fn backend_create_open_if_absent() {
+        let mut backend = backend_fixture();
+
+        {
+            let req = CreateOpen { if_absent: true, ..open_request("test/key/3") };
+            let co_result = backend.create_open(&req);
+            assert!(co_result.is_ok(), "Create open if absent with new key result was {:?}", co_result);
+            assert!(matches!(backend.file(TEST_DOMAIN, "test/key/3"), Ok(Some(..))));
+        }
+
+        {
+            let req = CreateOpen { if_absent: true, ..open_request(TEST_KEY_1) };
+            let co_result = backend.create_open(&req);
+            assert!(
+                matches!(co_result, Err(MogError::KeyExists(ref k)) if k == TEST_KEY_1),
+                "Create open if absent with existing key result was {:?}", co_result);
+
+            // The existing file should be left alone.
+            let file = backend.file(TEST_DOMAIN, TEST_KEY_1).unwrap().unwrap();
+            assert_eq!(3, file.fid());
+            assert!(file.content.is_some());
+        }
+    }
+
     #[test]
     fn domain_list_keys() {
         let backend = backend_fixture();
-        let request = ListKeys { domain: TEST_DOMAIN.to_string(), prefix: None, after: None, limit: None };
+        let request = ListKeys { domain: TEST_DOMAIN.to_string(), prefix: None, after: None, limit: None, order: KeyOrder::Lexicographic, with_sizes: false };
         let list_result = backend.list_keys(&request);
         assert!(list_result.is_ok());
-        assert_eq!(vec![ TEST_KEY_1, TEST_KEY_2 ], list_result.unwrap().0);
+        assert_eq!(vec![ TEST_KEY_1, TEST_KEY_2 ], list_result.unwrap().keys);
+    }
+
+    #[test]
+    fn domain_list_keys_with_sizes() {
+        let backend = backend_fixture();
+        let mut request = ListKeys { domain: TEST_DOMAIN.to_string(), prefix: None, after: None, limit: None, order: KeyOrder::Lexicographic, with_sizes: false };
+        assert_eq!(None, backend.list_keys(&request).unwrap().details);
+
+        request.with_sizes = true;
+        for &order in [ KeyOrder::Lexicographic, KeyOrder::MtimeDesc ].iter() {
+            request.order = order;
+            let list = backend.list_keys(&request).unwrap();
+            let details = list.details.unwrap();
+            assert_eq!(vec![ TEST_KEY_1, TEST_KEY_2 ], list.keys);
+            assert_eq!(Some(TEST_CONTENT_1.len() as u64), details[0].size);
+            assert!(details[0].mtime.is_some());
+            assert_eq!(KeyDetails::default(), details[1]);
+        }
     }
 
     #[test]
@@ -427,12 +1766,14 @@ mod tests {
             domain: TEST_FULL_DOMAIN.to_string(),
             prefix: None,
             after: None,
-            limit: Some(10)
+            limit: Some(10),
+            order: KeyOrder::Lexicographic,
+            with_sizes: false,
         });
         assert!(list_result.is_ok());
         let list = list_result.unwrap();
-        assert_eq!(10, list.0.len());
-        assert!(list.0[0] < list.0[9]);
+        assert_eq!(10, list.keys.len());
+        assert!(list.keys[0] < list.keys[9]);
     }
 
     #[test]
@@ -443,19 +1784,23 @@ mod tests {
             prefix: None,
             after: None,
             limit: Some(10),
+            order: KeyOrder::Lexicographic,
+            with_sizes: false,
         }).unwrap();
-        let after_key = first_list.0.iter().last().unwrap();
+        let after_key = first_list.keys.iter().last().unwrap();
 
         let list_result = backend.list_keys(&ListKeys {
             domain: TEST_FULL_DOMAIN.to_string(),
             prefix: None,
             after: Some(after_key.clone()),
-            limit: None
+            limit: None,
+            order: KeyOrder::Lexicographic,
+            with_sizes: false,
         });
         assert!(list_result.is_ok());
         let list = list_result.unwrap();
-        assert!(after_key < &list.0[0]);
-        assert!(&list.0[0] < list.0.iter().last().unwrap());
+        assert!(after_key < &list.keys[0]);
+        assert!(&list.keys[0] < list.keys.iter().last().unwrap());
     }
 
     #[test]
@@ -466,10 +1811,12 @@ mod tests {
             prefix: Some(TEST_KEY_PREFIX_1.to_string()),
             after: None,
             limit: None,
+            order: KeyOrder::Lexicographic,
+            with_sizes: false,
         });
         assert!(list_result.is_ok());
         let list = list_result.unwrap();
-        for key in list.0.iter() {
+        for key in list.keys.iter() {
             assert!(key.starts_with(TEST_KEY_PREFIX_1), "key {:?} doesn't start with {:?}", key, TEST_KEY_PREFIX_1);
         }
     }
@@ -482,15 +1829,146 @@ mod tests {
             prefix: Some(TEST_KEY_PREFIX_2.to_string()),
             after: Some("bar/prefix/key/98".to_string()),
             limit: Some(10),
+            order: KeyOrder::Lexicographic,
+            with_sizes: false,
         });
 
         assert!(list_result.is_ok());
         let list = list_result.unwrap();
-        for key in list.0.iter() {
+        for key in list.keys.iter() {
             assert!(key.starts_with(TEST_KEY_PREFIX_2), "key {:?} doesn't start with {:?}", key, TEST_KEY_PREFIX_2);
         }
     }
 
+    #[test]
+    fn domain_list_keys_pages_through_large_domain() {
+        let mut backend = full_backend_fixture();
+        {
+            let domain = backend.domains.get_mut(TEST_FULL_DOMAIN).unwrap();
+            for i in 0..20000 {
+                let key = format!("baz/prefix/key/{:05}", i);
+                domain.add_file(&key, MemFileInfo::new(5, &key)).unwrap();
+            }
+        }
+
+        let mut seen = 0;
+        let mut after = None;
+        loop {
+            let page = backend.list_keys(&ListKeys {
+                domain: TEST_FULL_DOMAIN.to_string(),
+                prefix: Some("baz/".to_string()),
+                after: after.clone(),
+                limit: Some(1000),
+                order: KeyOrder::Lexicographic,
+                with_sizes: false,
+            }).unwrap();
+            if page.keys.is_empty() { break; }
+
+            assert_eq!(format!("baz/prefix/key/{:05}", seen), page.keys[0]);
+            seen += page.keys.len();
+            after = page.keys.last().cloned();
+        }
+        assert_eq!(20000, seen);
+    }
+
+    #[test]
+    fn list_keys_since() {
+        let mut backend = full_backend_fixture();
+
+        {
+            let domain = backend.domains.get_mut(TEST_FULL_DOMAIN).unwrap();
+            for (i, key) in [ "foo/prefix/key/1", "foo/prefix/key/2", "foo/prefix/key/3" ].iter().enumerate() {
+                domain.file_mut(key).unwrap().mtime = Some(time::at_utc(Timespec::new(100 + i as i64, 0)));
+            }
+        }
+
+        let list = |after: Option<&str>, limit: Option<u64>| backend.list_keys_since(&ListKeysSince {
+            domain: TEST_FULL_DOMAIN.to_string(),
+            since: 100,
+            after: after.map(|a| a.to_string()),
+            limit: limit,
+        }).unwrap().0;
+
+        // Files stored at or before the time, or never, aren't listed.
+        assert_eq!(vec![ ("foo/prefix/key/2".to_string(), 101), ("foo/prefix/key/3".to_string(), 102) ], list(None, None));
+        assert_eq!(vec![ ("foo/prefix/key/2".to_string(), 101) ], list(None, Some(1)));
+        assert_eq!(vec![ ("foo/prefix/key/3".to_string(), 102) ], list(Some("foo/prefix/key/2"), Some(1)));
+        assert!(list(Some("foo/prefix/key/3"), None).is_empty());
+    }
+
+    #[test]
+    fn list_all_keys() {
+        let mut backend = backend_fixture();
+        for &(domain, key) in [ ("a_domain", "x"), ("z_domain", "a"), ("z_domain", "b") ].iter() {
+            backend.domain_mut(domain).unwrap().add_file(key, MemFileInfo::new(9, key)).unwrap();
+        }
+        backend.domain_mut("z_domain").unwrap().file_mut("b").unwrap().opened_at = Some(time::get_time());
+
+        let list = |after_domain: Option<&str>, after_key: Option<&str>, limit: u64| backend.list_all_keys(&ListAllKeys {
+            after_domain: after_domain.map(|d| d.to_string()),
+            after_key: after_key.map(|k| k.to_string()),
+            limit: Some(limit),
+        }).unwrap().0;
+        let pair = |domain: &str, key: &str| (domain.to_string(), key.to_string());
+
+        assert_eq!(vec![ pair("a_domain", "x"), pair(TEST_DOMAIN, TEST_KEY_1) ], list(None, None, 2));
+
+        // The next page picks up part-way through the domain, and
+        // carries on into the next one.
+        assert_eq!(vec![ pair(TEST_DOMAIN, TEST_KEY_2), pair("z_domain", "a") ], list(Some(TEST_DOMAIN), Some(TEST_KEY_1), 2));
+        assert_eq!(vec![ pair("z_domain", "a") ], list(Some(TEST_DOMAIN), None, 10));
+
+        // The open file isn't listed.
+        assert!(list(Some("z_domain"), Some("a"), 10).is_empty());
+    }
+
+    #[test]
+    fn domain_list_keys_mtime_desc() {
+        let mut backend = full_backend_fixture();
+
+        {
+            let domain = backend.domains.get_mut(TEST_FULL_DOMAIN).unwrap();
+            for (i, key) in [ "foo/prefix/key/1", "foo/prefix/key/2", "foo/prefix/key/3" ].iter().enumerate() {
+                domain.file_mut(key).unwrap().mtime = Some(time::at_utc(Timespec::new(100 + i as i64, 0)));
+            }
+        }
+
+        let first_page = backend.list_keys(&ListKeys {
+            domain: TEST_FULL_DOMAIN.to_string(),
+            prefix: Some(TEST_KEY_PREFIX_1.to_string()),
+            after: None,
+            limit: Some(2),
+            order: KeyOrder::MtimeDesc,
+            with_sizes: false,
+        }).unwrap();
+        assert_eq!(vec![ "foo/prefix/key/3", "foo/prefix/key/2" ], first_page.keys);
+        assert_eq!(Some("102.000000000:foo/prefix/key/2".to_string()), first_page.next_after);
+
+        let second_page = backend.list_keys(&ListKeys {
+            domain: TEST_FULL_DOMAIN.to_string(),
+            prefix: Some(TEST_KEY_PREFIX_1.to_string()),
+            after: first_page.next_after.clone(),
+            limit: Some(2),
+            order: KeyOrder::MtimeDesc,
+            with_sizes: false,
+        }).unwrap();
+
+        // The last file with an mtime, followed by the files without
+        // one, in key order.
+        assert_eq!(vec![ "foo/prefix/key/1", "foo/prefix/key/10" ], second_page.keys);
+        assert_eq!(Some("-:foo/prefix/key/10".to_string()), second_page.next_after);
+
+        let bad_cursor = backend.list_keys(&ListKeys {
+            domain: TEST_FULL_DOMAIN.to_string(),
+            prefix: None,
+            after: Some("foo/prefix/key/1".to_string()),
+            limit: None,
+            order: KeyOrder::MtimeDesc,
+            with_sizes: false,
+        });
+        assert!(matches!(bad_cursor, Err(MogError::Other(ref op, _)) if op == "invalid_after"));
+    }
+
     #[test]
     fn domain_delete_key() {
         let mut backend = backend_fixture();
@@ -508,6 +1986,342 @@ mod tests {
         }
     }
 
+    #[test]
+    fn domain_copy_key() {
+        let mut backend = backend_fixture();
+
+        {
+            let copy_result = backend.copy(&CopyKey {
+                domain: TEST_DOMAIN.to_string(),
+                from_key: TEST_KEY_1.to_string(),
+                to_key: "test/key/3".to_string(),
+            });
+            assert!(matches!(copy_result, Ok(())));
+        }
+
+        {
+            let domain = &backend.domains[TEST_DOMAIN];
+            let original = domain.file(TEST_KEY_1).unwrap();
+            let copy = domain.file("test/key/3").unwrap();
+            assert!(original.fid() != copy.fid());
+            assert_eq!("test/key/3", copy.key());
+            assert_eq!(original.size, copy.size);
+            assert_eq!(original.content, copy.content);
+        }
+
+        {
+            let exists_result = backend.copy(&CopyKey {
+                domain: TEST_DOMAIN.to_string(),
+                from_key: TEST_KEY_1.to_string(),
+                to_key: TEST_KEY_2.to_string(),
+            });
+            assert!(matches!(exists_result, Err(MogError::KeyExists(ref k)) if k == TEST_KEY_2));
+        }
+
+        {
+            let unknown_result = backend.copy(&CopyKey {
+                domain: TEST_DOMAIN.to_string(),
+                from_key: "test/key/4".to_string(),
+                to_key: "test/key/5".to_string(),
+            });
+            assert!(matches!(unknown_result, Err(MogError::UnknownKey(ref k)) if k == "test/key/4"));
+        }
+    }
+
+    #[test]
+    fn locate_key() {
+        let mut backend = backend_fixture();
+        backend.set_device_health(1, 10);
+        backend.set_device_health(2, 20);
+
+        let located = backend.locate(&Locate { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() }).unwrap();
+        assert_eq!(3, located.fid);
+        assert_eq!(TEST_CONTENT_1.len() as u64, located.length);
+        assert_eq!("default", located.class);
+        assert!(located.mtime.is_some());
+        assert_eq!(vec![ Some(2), Some(1) ], located.paths.iter().map(|p| p.devid).collect::<Vec<_>>());
+
+        let located = backend.locate(&Locate { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_2.to_string() }).unwrap();
+        assert_eq!(None, located.mtime);
+        assert!(matches!(backend.locate(&Locate { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() }),
+                         Err(MogError::UnknownKey(..))));
+    }
+
+    #[test]
+    fn create_open_idempotency_token() {
+        let mut backend = backend_fixture();
+        let open = |backend: &mut MemBackend, key: &str, token: Option<&str>| backend.create_open(&CreateOpen {
+            if_absent: true, idempotency_token: token.map(|t| t.to_string()), ..open_request(key)
+        });
+
+        let first = open(&mut backend, "test/key/3", Some("abc")).unwrap();
+
+        // The retry doesn't trip over the file the first one opened.
+        assert_eq!(first, open(&mut backend, "test/key/3", Some("abc")).unwrap());
+        assert!(matches!(open(&mut backend, "test/key/3", Some("def")), Err(MogError::KeyExists(..))));
+        assert!(matches!(open(&mut backend, "test/key/3", None), Err(MogError::KeyExists(..))));
+
+        // The token is only good for the same key, and only while the
+        // file it opened is still there.
+        assert!(open(&mut backend, "test/key/4", Some("abc")).is_ok());
+        backend.delete(&Delete { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() }).unwrap();
+        assert!(open(&mut backend, "test/key/3", Some("abc")).is_ok());
+
+        // Nor once it's expired.
+        backend.set_idempotency_window(time::Duration::seconds(0));
+        assert!(matches!(open(&mut backend, "test/key/3", Some("abc")), Err(MogError::KeyExists(..))));
+
+        // Purging forgets the expired tokens, but not the open files.
+        assert_eq!(0, backend.purge_stale_opens(time::Duration::seconds(3600)));
+        assert!(backend.open_tokens.is_empty());
+    }
+
+    #[test]
+    fn create_open_never_reuses_fids() {
+        let mut backend = backend_fixture();
+        let open = |backend: &mut MemBackend, domain: &str| {
+            backend.create_open(&CreateOpen { domain: domain.to_string(), ..open_request("test/key/3") }).unwrap().fid
+        };
+
+        let first = open(&mut backend, TEST_DOMAIN);
+        backend.delete(&Delete { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() }).unwrap();
+        let second = open(&mut backend, TEST_DOMAIN);
+        backend.create_domain(&CreateDomain { domain: "new_domain".to_string() }).unwrap();
+        let third = open(&mut backend, "new_domain");
+
+        assert!(first < second && second < third, "Fids were {}, {}, {}", first, second, third);
+        assert!(first > 4, "Fid {} clashes with a fixture file's", first);
+    }
+
+    #[test]
+    fn create_open_multi_dest() {
+        let mut backend = backend_fixture();
+        backend.set_device_health(1, 50);
+        backend.set_device_health(2, 90);
+        backend.set_device_health(3, 70);
+
+        let open = |backend: &mut MemBackend, key: &str, multi_dest: bool| {
+            backend.create_open(&CreateOpen { multi_dest: multi_dest, ..open_request(key) }).unwrap()
+        };
+
+        let single = open(&mut backend, "test/key/3", false);
+        assert!(!single.multi_dest);
+        assert_eq!(vec![ 2 ], single.paths.iter().map(|&(devid, _)| devid).collect::<Vec<_>>());
+
+        let multi = open(&mut backend, "test/key/4", true);
+        assert!(multi.multi_dest);
+        assert_eq!(3, multi.paths.len());
+        assert_eq!(single.paths[0].0, multi.paths[0].0);
+
+        // Without any devices, there's only ever the one path.
+        let mut backend = backend_fixture();
+        assert_eq!(1, open(&mut backend, "test/key/3", true).paths.len());
+        assert_eq!(1, open(&mut backend, "test/key/4", false).paths.len());
+    }
+
+    #[test]
+    fn paths_ordered_by_device_health() {
+        let mut backend = backend_fixture();
+        backend.set_device_health(1, 50);
+        backend.set_device_health(2, 90);
+        backend.set_device_health(3, 10);
+        backend.set_device_health(3, 70);
+
+        let open_res = backend.create_open(&CreateOpen { multi_dest: true, ..open_request("test/key/3") }).unwrap();
+        assert_eq!(vec![ 2, 3, 1 ], open_res.paths.iter().map(|&(devid, _)| devid).collect::<Vec<_>>());
+        assert!(open_res.paths[0].1.path().contains("/dev2/d/"), "path was {}", open_res.paths[0].1);
+
+        let paths = backend.get_paths(&GetPaths { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string(), noverify: true, pathcount: None }).unwrap();
+        assert_eq!(3, paths.0.len());
+        assert_eq!(Some(2), paths.0[0].devid);
+        assert_eq!(format!("http://{}/{}/dev2/d/{}/k/{}", TEST_HOST, TEST_BASE_PATH, TEST_DOMAIN, TEST_KEY_1), paths.0[0].url.to_string());
+    }
+
+    #[test]
+    fn get_paths_pathcount() {
+        let mut backend = backend_fixture();
+        backend.set_device_health(1, 10);
+        backend.set_device_health(2, 30);
+        backend.set_device_health(3, 20);
+
+        let devids = |pathcount: Option<u64>| backend.get_paths(&GetPaths {
+            domain: TEST_DOMAIN.to_string(),
+            key: TEST_KEY_1.to_string(),
+            noverify: true,
+            pathcount: pathcount,
+        }).unwrap().0.into_iter().map(|p| p.devid.unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(vec![ 2 ], devids(Some(1)));
+        assert_eq!(vec![ 2, 3 ], devids(Some(2)));
+        assert_eq!(vec![ 2, 3, 1 ], devids(Some(10)));
+        assert_eq!(vec![ 2, 3, 1 ], devids(Some(0)));
+        assert_eq!(vec![ 2, 3, 1 ], devids(None));
+    }
+
+    #[test]
+    fn consistent_hash_placement() {
+        let devids = |backend: &mut MemBackend, key: &str| {
+            let open_res = backend.create_open(&CreateOpen { multi_dest: true, ..open_request(key) }).unwrap();
+            open_res.paths.iter().map(|&(devid, _)| devid).collect::<Vec<_>>()
+        };
+
+        let mut backend = backend_fixture();
+        backend.set_placement(Placement::ConsistentHash);
+        for devid in 1..6 {
+            backend.set_device_health(devid, 100 - devid as u32);
+        }
+
+        // The same key gets the same order every time, and on every
+        // backend, whatever the devices' health.
+        let first = devids(&mut backend, "test/key/3");
+        assert_eq!(5, first.len());
+        assert_eq!(first, devids(&mut backend, "test/key/3"));
+        backend.set_device_health(1, 0);
+        assert_eq!(first, devids(&mut backend, "test/key/3"));
+
+        let mut other = backend_fixture();
+        other.set_placement(Placement::ConsistentHash);
+        for devid in (1..6).rev() {
+            other.set_device_health(devid, 50);
+        }
+        assert_eq!(first, devids(&mut other, "test/key/3"));
+
+        // Different keys are spread around.
+        let firsts: HashSet<u64> = (0..20).map(|i| devids(&mut backend, &format!("test/key/spread/{}", i))[0]).collect();
+        assert!(firsts.len() > 1, "Every key went to {:?}", firsts);
+
+        // A new device slots in without reordering the rest.
+        backend.set_device_health(6, 50);
+        let with_new: Vec<u64> = devids(&mut backend, "test/key/3").into_iter().filter(|&d| d != 6).collect();
+        assert_eq!(first, with_new);
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        assert_eq!(0xcbf29ce484222325, super::fnv1a(b""));
+        assert_eq!(0xaf63dc4c8601ec8c, super::fnv1a(b"a"));
+        assert_eq!(0x85944171f73967e8, super::fnv1a(b"foobar"));
+    }
+
+    #[test]
+    fn domain_rename_many() {
+        let mut backend = backend_fixture();
+        let pair = |from: &str, to: &str| (from.to_string(), to.to_string());
+        let keys = |backend: &MemBackend| backend.domains[TEST_DOMAIN].files().map(|(k, _)| k.to_string()).collect::<Vec<_>>();
+
+        // The second rename would clobber test/key/2, so the first is
+        // undone too.
+        let request = RenameMany { domain: TEST_DOMAIN.to_string(), pairs: vec![ pair(TEST_KEY_1, "test/key/3"), pair("test/key/3", TEST_KEY_2) ] };
+        assert!(matches!(backend.rename_many(&request), Err(MogError::KeyExists(ref k)) if k == TEST_KEY_2));
+        assert_eq!(vec![ TEST_KEY_1, TEST_KEY_2 ], keys(&backend));
+
+        let request = RenameMany { domain: TEST_DOMAIN.to_string(), pairs: vec![ pair(TEST_KEY_1, "test/key/3"), pair(TEST_KEY_2, "test/key/4") ] };
+        backend.rename_many(&request).unwrap();
+        assert_eq!(vec![ "test/key/3", "test/key/4" ], keys(&backend));
+        assert_eq!(3, backend.domains[TEST_DOMAIN].file("test/key/3").unwrap().fid());
+    }
+
+    #[test]
+    fn domain_rename_key() {
+        let mut backend = backend_fixture();
+        let rename = |backend: &mut MemBackend, to: &str, overwrite: bool| backend.rename(&Rename {
+            domain: TEST_DOMAIN.to_string(),
+            from_key: TEST_KEY_1.to_string(),
+            to_key: to.to_string(),
+            overwrite: overwrite,
+        });
+
+        assert!(matches!(rename(&mut backend, TEST_KEY_2, false), Err(MogError::KeyExists(ref k)) if k == TEST_KEY_2));
+
+        {   // Overwriting a file that's there.
+            assert!(matches!(rename(&mut backend, TEST_KEY_2, true), Ok(())));
+            let domain = &backend.domains[TEST_DOMAIN];
+            assert!(domain.file(TEST_KEY_1).is_none());
+            let renamed = domain.file(TEST_KEY_2).unwrap();
+            assert_eq!(3, renamed.fid());
+            assert_eq!(Some(TEST_CONTENT_1), renamed.content.as_ref().map(|c| &c[..]));
+        }
+
+        {   // Overwriting a file that isn't.
+            let rename_back = backend.rename(&Rename {
+                domain: TEST_DOMAIN.to_string(),
+                from_key: TEST_KEY_2.to_string(),
+                to_key: TEST_KEY_1.to_string(),
+                overwrite: true,
+            });
+            assert!(matches!(rename_back, Ok(())));
+            let domain = &backend.domains[TEST_DOMAIN];
+            assert!(domain.file(TEST_KEY_2).is_none());
+            assert_eq!(3, domain.file(TEST_KEY_1).unwrap().fid());
+        }
+
+        assert!(matches!(rename(&mut backend, "test/key/3", true), Ok(())));
+        assert!(matches!(rename(&mut backend, "test/key/4", true), Err(MogError::UnknownKey(ref k)) if k == TEST_KEY_1));
+    }
+
+    #[test]
+    fn backend_usage() {
+        let mut backend = backend_fixture();
+        backend.create_domain(&CreateDomain { domain: "test_domain_0".to_string() }).unwrap();
+
+        let usage = backend.usage(&Usage { after_domain: None, after_class: None, limit: None }).unwrap();
+        assert_eq!(2, usage.0.len());
+        assert_eq!(UsageRow { domain: "test_domain".to_string(), class: "default".to_string(), files: 2, bytes: TEST_CONTENT_1.len() as u64 }, usage.0[0]);
+        assert_eq!(UsageRow { domain: "test_domain_0".to_string(), class: "default".to_string(), files: 0, bytes: 0 }, usage.0[1]);
+
+        let page = backend.usage(&Usage { after_domain: None, after_class: None, limit: Some(1) }).unwrap();
+        assert_eq!(vec![ usage.0[0].clone() ], page.0);
+
+        let page = backend.usage(&Usage {
+            after_domain: Some("test_domain".to_string()),
+            after_class: Some("default".to_string()),
+            limit: Some(1),
+        }).unwrap();
+        assert_eq!(vec![ usage.0[1].clone() ], page.0);
+    }
+
+    #[test]
+    fn backend_replication() {
+        let mut backend = backend_fixture();
+        let create_class = |backend: &mut MemBackend, class: &str| backend.create_class(&CreateClass {
+            domain: TEST_DOMAIN.to_string(),
+            class: class.to_string(),
+            mindevcount: 3,
+            replpolicy: None,
+            hashtype: None,
+            update: false,
+        });
+        assert!(matches!(create_class(&mut backend, "three_copies"), Ok(CreateClassResponse { mindevcount: 3, .. })));
+        assert!(matches!(create_class(&mut backend, "three_copies"), Err(MogError::Other(..))));
+
+        let open_req = CreateOpen { class: Some("three_copies".to_string()), ..open_request("test/key/3") };
+        let open_res = backend.create_open(&open_req).unwrap();
+        backend.create_close(&CreateClose {
+            domain: TEST_DOMAIN.to_string(),
+            key: "test/key/3".to_string(),
+            fid: open_res.fid,
+            devid: 1,
+            path: open_res.paths[0].1.clone(),
+            checksum: None,
+        }).unwrap();
+
+        let unreg_req = CreateOpen { class: Some("no_such_class".to_string()), ..open_req.clone() };
+        assert!(matches!(backend.create_open(&unreg_req), Err(MogError::UnregClass(ref c)) if c == "no_such_class"));
+
+        let info_req = FileInfo { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() };
+        let repl_req = Replicate { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() };
+        let devcounts: Vec<u64> = (0..4).map(|_| {
+            let devcount = backend.file_info(&info_req).unwrap().devcount;
+            backend.replicate(&repl_req).unwrap();
+            devcount
+        }).collect();
+        assert_eq!(vec![ 1, 2, 3, 3 ], devcounts);
+        assert_eq!("three_copies", backend.file_info(&info_req).unwrap().class);
+
+        let usage = backend.usage(&Usage { after_domain: None, after_class: None, limit: None }).unwrap();
+        assert_eq!(UsageRow { domain: TEST_DOMAIN.to_string(), class: "three_copies".to_string(), files: 1, bytes: 0 }, usage.0[1]);
+    }
+
     #[test]
     fn url_for_key() {
         let backend = backend_fixture();
@@ -556,7 +2370,7 @@ mod tests {
             panic!("Error storing content to {:?}: {}", TEST_KEY_1, e);
         });
 
-        assert_eq!(&new_content, backend.domains[TEST_DOMAIN].file(TEST_KEY_1).unwrap().content.as_ref().unwrap());
+        assert_eq!(&new_content, &**backend.domains[TEST_DOMAIN].file(TEST_KEY_1).unwrap().content.as_ref().unwrap());
     }
 
     #[test]
@@ -568,7 +2382,581 @@ mod tests {
             panic!("Error storing content to {:?}: {}", TEST_KEY_2, e);
         });
 
-        assert_eq!(&new_content, backend.domains[TEST_DOMAIN].file(TEST_KEY_2).unwrap().content.as_ref().unwrap());
+        assert_eq!(&new_content, &**backend.domains[TEST_DOMAIN].file(TEST_KEY_2).unwrap().content.as_ref().unwrap());
+    }
+
+    #[test]
+    fn store_empty_content() {
+        let mut backend = backend_fixture();
+
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_2, b"").unwrap_or_else(|e| {
+            panic!("Error storing empty content to {:?}: {}", TEST_KEY_2, e);
+        });
+
+        {
+            let file = backend.domains[TEST_DOMAIN].file(TEST_KEY_2).unwrap();
+            assert_eq!(Some(0), file.size);
+            assert!(matches!(file.content, Some(ref c) if c.is_empty()));
+        }
+
+        let metadata = backend.file_metadata(TEST_DOMAIN, TEST_KEY_2);
+        assert!(matches!(metadata, Ok(ref m) if m.size == 0), "Empty file metadata was {:?}", metadata);
+
+        let mut content = vec![];
+        backend.get_content(TEST_DOMAIN, TEST_KEY_2, &mut content).unwrap_or_else(|e| {
+            panic!("Error retrieving empty content from {:?}: {}", TEST_KEY_2, e);
+        });
+        assert!(content.is_empty());
+    }
+
+    #[test]
+    fn file_metadata_etag() {
+        let backend = sync_backend_fixture();
+        let metadata = backend.file_metadata(TEST_DOMAIN, TEST_KEY_1).unwrap();
+        assert!(metadata.accepts_ranges);
+        assert!(metadata.etag.is_some());
+
+        {   // It's cached now.
+            let inner = backend.0.read().unwrap();
+            assert_eq!(metadata.etag, inner.domains[TEST_DOMAIN].file(TEST_KEY_1).unwrap().etag);
+        }
+
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, b"Different content").unwrap();
+        let new_metadata = backend.file_metadata(TEST_DOMAIN, TEST_KEY_1).unwrap();
+        assert!(new_metadata.etag.is_some());
+        assert!(metadata.etag != new_metadata.etag);
+    }
+
+    #[test]
+    fn file_metadata_no_content() {
+        let backend = backend_fixture();
+        assert!(matches!(backend.file_metadata(TEST_DOMAIN, TEST_KEY_2).unwrap_err(),
+                         MogError::NoContent(ref k) if k == TEST_KEY_2));
+    }
+
+    #[test]
+    fn store_dedup_content() {
+        let mut backend = backend_fixture();
+        backend.set_dedup(true);
+        let shared: &'static [u8] = b"Shared thumbnail content";
+
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, shared).unwrap();
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_2, shared).unwrap();
+        assert_eq!(DedupStats { unique_blobs: 1, logical_files: 2 }, backend.dedup_stats());
+        assert_eq!(1, backend.blobs.len());
+
+        let mut content = vec![];
+        backend.get_content(TEST_DOMAIN, TEST_KEY_2, &mut content).unwrap();
+        assert_eq!(shared, &content[..]);
+
+        // Overwriting one of them un-shares it.
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, b"Different content").unwrap();
+        assert_eq!(DedupStats { unique_blobs: 2, logical_files: 2 }, backend.dedup_stats());
+        assert_eq!(2, backend.blobs.len());
+
+        // Deleting the last user of a blob drops it.
+        backend.delete(&Delete { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_2.to_string() }).unwrap();
+        assert_eq!(DedupStats { unique_blobs: 1, logical_files: 1 }, backend.dedup_stats());
+        assert_eq!(1, backend.blobs.len());
+
+        backend.delete(&Delete { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() }).unwrap();
+        assert_eq!(DedupStats { unique_blobs: 0, logical_files: 0 }, backend.dedup_stats());
+        assert!(backend.blobs.is_empty());
+    }
+
+    #[test]
+    fn read_only_backend() {
+        let backend = ReadOnly(sync_backend_fixture());
+        let domain = || TEST_DOMAIN.to_string();
+        let key = || TEST_KEY_1.to_string();
+        let read_only = |e: MogError| matches!(e, MogError::ReadOnly);
+
+        assert!(read_only(backend.create_domain(&CreateDomain { domain: "new_domain".to_string() }).unwrap_err()));
+        assert!(read_only(backend.create_class(&CreateClass {
+            domain: domain(), class: "new_class".to_string(), mindevcount: 2, replpolicy: None, hashtype: None, update: false,
+        }).unwrap_err()));
+        assert!(read_only(backend.create_open(&open_request("test/key/3")).unwrap_err()));
+        assert!(read_only(backend.create_close(&CreateClose {
+            domain: domain(), key: key(), fid: 3, devid: 1, path: "http://127.0.0.1/".to_string(), checksum: None,
+        }).unwrap_err()));
+        assert!(read_only(backend.delete(&Delete { domain: domain(), key: key() }).unwrap_err()));
+        assert!(read_only(backend.delete_counted(&Delete { domain: domain(), key: key() }).unwrap_err()));
+        assert!(read_only(backend.rename(&Rename { domain: domain(), from_key: key(), to_key: "test/key/3".to_string(), overwrite: false }).unwrap_err()));
+        assert!(read_only(backend.copy(&CopyKey { domain: domain(), from_key: key(), to_key: "test/key/3".to_string() }).unwrap_err()));
+        assert!(read_only(backend.replicate(&Replicate { domain: domain(), key: key() }).unwrap_err()));
+
+        assert!(backend.get_paths(&GetPaths { domain: domain(), key: key(), noverify: true, pathcount: None }).is_ok());
+        assert!(backend.file_info(&FileInfo { domain: domain(), key: key() }).is_ok());
+        assert!(backend.list_keys(&ListKeys { domain: domain(), prefix: None, after: None, limit: None, order: KeyOrder::Lexicographic, with_sizes: false }).is_ok());
+        assert!(backend.usage(&Usage { after_domain: None, after_class: None, limit: None }).is_ok());
+        assert!(backend.server_version(&ServerVersion).is_ok());
+    }
+
+    #[test]
+    fn compressed_content_round_trip() {
+        let mut backend = backend_fixture();
+        backend.set_compression(Compression::Gzip);
+
+        // Content stored before compression was turned on still reads
+        // back as it was.
+        let mut fetched = vec![];
+        backend.get_content(TEST_DOMAIN, TEST_KEY_1, &mut fetched).unwrap();
+        assert_eq!(TEST_CONTENT_1, &fetched[..]);
+
+        let new_content: Vec<u8> = b"Some rather repetitive content. ".iter().cycle().take(4096).cloned().collect();
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, &new_content).unwrap();
+
+        let mut fetched = vec![];
+        backend.get_content(TEST_DOMAIN, TEST_KEY_1, &mut fetched).unwrap();
+        assert_eq!(new_content, fetched);
+
+        let metadata = backend.file_metadata(TEST_DOMAIN, TEST_KEY_1).unwrap();
+        assert_eq!(new_content.len() as u64, metadata.size);
+        assert!(metadata.stored_size < metadata.size);
+    }
+
+    #[test]
+    fn read_content_shares_buffers() {
+        let backend = sync_backend_fixture();
+        let content = backend.read_content(TEST_DOMAIN, TEST_KEY_1).unwrap();
+        assert_eq!(TEST_CONTENT_1, &content[..]);
+
+        // Overwriting the file doesn't change what was already read,
+        // and doesn't have to wait for it to be dropped.
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, b"Newer content").unwrap();
+        assert_eq!(TEST_CONTENT_1, &content[..]);
+        assert_eq!(b"Newer content", &backend.read_content(TEST_DOMAIN, TEST_KEY_1).unwrap()[..]);
+
+        assert!(matches!(backend.read_content(TEST_DOMAIN, TEST_KEY_2), Err(MogError::NoContent(..))));
+        assert!(matches!(backend.read_content(TEST_DOMAIN, "test/key/3"), Err(MogError::UnknownKey(..))));
+    }
+
+    #[test]
+    fn peek_content() {
+        let backend = sync_backend_fixture();
+        assert_eq!(&TEST_CONTENT_1[..4], &backend.peek_content(TEST_DOMAIN, TEST_KEY_1, 4).unwrap()[..]);
+        assert_eq!(TEST_CONTENT_1, &backend.peek_content(TEST_DOMAIN, TEST_KEY_1, 1000).unwrap()[..]);
+        assert!(backend.peek_content(TEST_DOMAIN, TEST_KEY_1, 0).unwrap().is_empty());
+        assert!(matches!(backend.peek_content(TEST_DOMAIN, TEST_KEY_2, 4), Err(MogError::NoContent(..))));
+
+        let mut backend = backend_fixture();
+        backend.set_compression(Compression::Gzip);
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, TEST_CONTENT_1).unwrap();
+        assert_eq!(&TEST_CONTENT_1[..4], &backend.peek_content(TEST_DOMAIN, TEST_KEY_1, 4).unwrap()[..]);
+    }
+
+    #[test]
+    fn read_range() {
+        let backend = sync_backend_fixture();
+        assert_eq!(&TEST_CONTENT_1[2..6], &backend.read_range(TEST_DOMAIN, TEST_KEY_1, 2..6).unwrap()[..]);
+        assert_eq!(&TEST_CONTENT_1[2..], &backend.read_range(TEST_DOMAIN, TEST_KEY_1, 2..1000).unwrap()[..]);
+        assert!(backend.read_range(TEST_DOMAIN, TEST_KEY_1, 1000..1004).unwrap().is_empty());
+        assert!(backend.read_range(TEST_DOMAIN, TEST_KEY_1, 6..2).unwrap().is_empty());
+
+        let mut backend = backend_fixture();
+        backend.set_compression(Compression::Gzip);
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, TEST_CONTENT_1).unwrap();
+        assert_eq!(&TEST_CONTENT_1[2..6], &backend.read_range(TEST_DOMAIN, TEST_KEY_1, 2..6).unwrap()[..]);
+        assert_eq!(&TEST_CONTENT_1[2..], &backend.read_range(TEST_DOMAIN, TEST_KEY_1, 2..1000).unwrap()[..]);
+    }
+
+    #[test]
+    fn create_close_checks_declared_size() {
+        let open_and_store = |backend: &mut MemBackend, key: &str, size: Option<u64>| {
+            let open_res = backend.create_open(&CreateOpen { size: size, ..open_request(key) }).unwrap();
+            backend.store_bytes_content(TEST_DOMAIN, key, b"half").unwrap();
+            CreateClose {
+                domain: TEST_DOMAIN.to_string(), key: key.to_string(), fid: open_res.fid, devid: 1, path: open_res.paths[0].1.clone(), checksum: None,
+            }
+        };
+
+        // By default, a mismatch is only a warning.
+        let mut backend = backend_fixture();
+        let close = open_and_store(&mut backend, "test/key/3", Some(10));
+        assert!(backend.create_close(&close).is_ok());
+
+        backend.set_strict_sizes(true);
+        let close = open_and_store(&mut backend, "test/key/4", Some(10));
+        assert!(matches!(backend.create_close(&close), Err(MogError::SizeMismatch { declared: 10, actual: 4 })));
+        assert!(backend.file(TEST_DOMAIN, "test/key/4").unwrap().unwrap().is_open());
+
+        let close = open_and_store(&mut backend, "test/key/5", Some(4));
+        assert!(backend.create_close(&close).is_ok());
+        let close = open_and_store(&mut backend, "test/key/6", None);
+        assert!(backend.create_close(&close).is_ok());
+    }
+
+    #[test]
+    fn swap_content() {
+        let backend = sync_backend_fixture();
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_2, b"green").unwrap();
+        backend.swap_content(TEST_DOMAIN, TEST_KEY_1, TEST_KEY_2).unwrap();
+
+        assert_eq!(b"green", &backend.read_content(TEST_DOMAIN, TEST_KEY_1).unwrap()[..]);
+        assert_eq!(TEST_CONTENT_1, &backend.read_content(TEST_DOMAIN, TEST_KEY_2).unwrap()[..]);
+        backend.with_file(TEST_DOMAIN, TEST_KEY_1, |f| {
+            assert_eq!((4, Some(5)), (f.fid(), f.size));
+            Ok(())
+        }).unwrap();
+        backend.with_file(TEST_DOMAIN, TEST_KEY_2, |f| {
+            assert_eq!(3, f.fid());
+            Ok(())
+        }).unwrap();
+
+        let result = backend.swap_content(TEST_DOMAIN, TEST_KEY_1, "test/key/3");
+        assert!(matches!(result, Err(MogError::UnknownKey(ref k)) if k == "test/key/3"));
+        assert_eq!(b"green", &backend.read_content(TEST_DOMAIN, TEST_KEY_1).unwrap()[..]);
+    }
+
+    #[test]
+    fn concurrent_swaps_are_atomic() {
+        let backend = sync_backend_fixture();
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, b"blue").unwrap();
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_2, b"green!").unwrap();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        for _ in 0..4 {
+            let backend = backend.clone();
+            let done_tx = done_tx.clone();
+            thread::spawn(move || {
+                for _ in 0..500 {
+                    // Look at both files under the one lock, as a
+                    // request would see them.
+                    let guard = backend.0.read().unwrap();
+                    let versions: Vec<(Vec<u8>, Option<u64>)> = [ TEST_KEY_1, TEST_KEY_2 ].iter().map(|key| {
+                        let file = guard.file(TEST_DOMAIN, key).unwrap().unwrap();
+                        (guard.read_content(TEST_DOMAIN, key).unwrap().to_vec(), file.size)
+                    }).collect();
+                    let consistent = versions.iter().all(|&(ref c, size)| Some(c.len() as u64) == size)
+                        && versions[0].0 != versions[1].0;
+                    if !consistent {
+                        done_tx.send(Err(format!("{:?}", versions))).unwrap();
+                        return;
+                    }
+                }
+                done_tx.send(Ok(())).unwrap();
+            });
+        }
+
+        for _ in 0..500 {
+            backend.swap_content(TEST_DOMAIN, TEST_KEY_1, TEST_KEY_2).unwrap();
+        }
+
+        for _ in 0..4 {
+            if let Err(versions) = done_rx.recv().unwrap() {
+                panic!("Saw a half-swapped state: {}", versions);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_stores_and_lists() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 250;
+
+        let mut fixture = backend_fixture();
+        fixture.set_dedup(true);
+        let backend = SyncMemBackend::new(fixture);
+        let (done_tx, done_rx) = mpsc::channel();
+
+        for t in 0..THREADS {
+            let backend = backend.clone();
+            let done_tx = done_tx.clone();
+
+            thread::spawn(move || {
+                for i in 0..ROUNDS {
+                    // The threads share a handful of keys, so they're
+                    // forever stepping on each other's files.
+                    let key = format!("stress/key/{}", (t + i) % 5);
+                    let content = format!("content {}", (t + i) % 3);
+
+                    let result = match (t + i) % 5 {
+                        0 => backend.create_open(&open_request(&key)).map(|_| ()),
+                        1 => backend.store_reader_content(TEST_DOMAIN, &key, &mut Cursor::new(content.into_bytes())).and_then(|_| {
+                            backend.create_close(&CreateClose {
+                                domain: TEST_DOMAIN.to_string(),
+                                key: key.clone(),
+                                fid: 0,
+                                devid: 1,
+                                path: backend.url_for_key(TEST_DOMAIN, &key),
+                                checksum: None,
+                            })
+                        }),
+                        2 => backend.list_keys(&ListKeys {
+                            domain: TEST_DOMAIN.to_string(),
+                            prefix: Some("stress/".to_string()),
+                            after: None,
+                            limit: None,
+                            order: KeyOrder::MtimeDesc,
+                            with_sizes: false,
+                        }).map(|_| ()),
+                        3 => {
+                            let mut fetched = vec![];
+                            backend.get_content(TEST_DOMAIN, &key, &mut fetched)
+                                .and_then(|_| backend.read_content(TEST_DOMAIN, &key))
+                                .map(|_| ())
+                        },
+                        _ => backend.delete(&Delete { domain: TEST_DOMAIN.to_string(), key: key.clone() }),
+                    };
+
+                    match result {
+                        Ok(_) | Err(MogError::UnknownKey(..)) | Err(MogError::NoContent(..)) => {},
+                        Err(e) => panic!("Thread {} failed in round {}: {}", t, i, e),
+                    }
+                }
+
+                done_tx.send(t).unwrap();
+            });
+        }
+        mem::drop(done_tx);
+
+        for _ in 0..THREADS {
+            done_rx.recv_timeout(::std::time::Duration::from_secs(30)).expect("A thread panicked or got stuck");
+        }
+
+        // Whatever's left has one of the contents that was stored, and
+        // the dedup table has exactly the buffers those files use.
+        let inner = backend.0.read().unwrap();
+        let mut file_blobs = HashSet::new();
+        for (key, file) in inner.domains[TEST_DOMAIN].files().filter(|&(k, _)| k.starts_with("stress/")) {
+            if let Some(ref content) = file.content {
+                assert!(content.starts_with(b"content "), "{:?} has {:?}", key, content);
+                file_blobs.insert(&**content as *const Vec<u8>);
+            }
+        }
+
+        let live_blobs: HashSet<*const Vec<u8>> = inner.blobs.values()
+            .filter_map(|b| b.upgrade())
+            .map(|b| &*b as *const Vec<u8>)
+            .collect();
+        assert_eq!(file_blobs, live_blobs);
+        assert!(live_blobs.len() <= 3);
+    }
+
+    #[test]
+    fn content_chunks() {
+        let backend = sync_backend_fixture();
+        let chunks: Vec<Vec<u8>> = backend.content_chunks(TEST_DOMAIN, TEST_KEY_1, 4).unwrap().map(|c| c.unwrap()).collect();
+        assert_eq!((TEST_CONTENT_1.len() + 3) / 4, chunks.len());
+        assert!(chunks.iter().all(|c| !c.is_empty() && c.len() <= 4));
+        assert_eq!(TEST_CONTENT_1, &chunks.concat()[..]);
+
+        let whole: Vec<Vec<u8>> = backend.content_chunks(TEST_DOMAIN, TEST_KEY_1, 1024).unwrap().map(|c| c.unwrap()).collect();
+        assert_eq!(vec![ TEST_CONTENT_1.to_vec() ], whole);
+
+        assert!(backend.content_chunks(TEST_DOMAIN, TEST_KEY_1, 0).is_err());
+        assert!(matches!(backend.content_chunks(TEST_DOMAIN, TEST_KEY_2, 4), Err(MogError::NoContent(..))));
+    }
+
+    #[test]
+    fn read_compressed_content() {
+        let mut backend = backend_fixture();
+        backend.set_compression(Compression::Gzip);
+        let new_content: Vec<u8> = b"Some rather repetitive content. ".iter().cycle().take(4096).cloned().collect();
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, &new_content).unwrap();
+        assert_eq!(&new_content[..], &backend.read_content(TEST_DOMAIN, TEST_KEY_1).unwrap()[..]);
+    }
+
+    #[test]
+    fn export_and_import_domain() {
+        let source = sync_backend_fixture();
+        let binary_content = b"\x00\xff\r\nbinary\x1f";
+        source.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, binary_content).unwrap();
+        let mut exported = vec![];
+        assert_eq!(2, source.export_domain(TEST_DOMAIN, &mut exported, true).unwrap());
+
+        let dest = SyncMemBackend::new(MemBackend::new(source.base_url()));
+        assert_eq!(2, dest.import_domain(TEST_DOMAIN, &mut Cursor::new(exported)).unwrap());
+        assert_eq!(&binary_content[..], &dest.read_content(TEST_DOMAIN, TEST_KEY_1).unwrap()[..]);
+        let source_info = source.file_info(&FileInfo { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() }).unwrap();
+        let dest_info = dest.file_info(&FileInfo { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() }).unwrap();
+        assert_eq!((source_info.fid, source_info.length), (dest_info.fid, dest_info.length));
+        dest.with_file(TEST_DOMAIN, TEST_KEY_1, |f| {
+            assert!(f.mtime.is_some());
+            Ok(())
+        }).unwrap();
+        assert!(matches!(dest.read_content(TEST_DOMAIN, TEST_KEY_2), Err(MogError::NoContent(..))));
+
+        let mut metadata_only = vec![];
+        source.export_domain(TEST_DOMAIN, &mut metadata_only, false).unwrap();
+        let metadata_only = String::from_utf8(metadata_only).unwrap();
+        assert_eq!(2, metadata_only.lines().filter(|l| l.contains("\"content\":null")).count());
+
+        let bad_class = b"{\"fid\":7,\"key\":\"test/key/7\",\"class\":\"nope\"}\n".to_vec();
+        assert!(matches!(dest.import_domain(TEST_DOMAIN, &mut Cursor::new(bad_class)), Err(MogError::UnregClass(..))));
+        let not_json = b"\n{\"fid\":7,\"key\":\"test/key/7\"}\nnot json\n".to_vec();
+        match dest.import_domain(TEST_DOMAIN, &mut Cursor::new(not_json)) {
+            Err(MogError::Other(ref kind, Some(ref msg))) if kind == "invalid_export" => assert!(msg.starts_with("Line 3:"), "Message was {:?}", msg),
+            r => panic!("Importing a bad line gave {:?}", r),
+        }
+        assert!(dest.with_file(TEST_DOMAIN, "test/key/7", |_| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn content_encoding_is_independent_of_compression() {
+        let mut backend = backend_fixture();
+        backend.set_compression(Compression::Gzip);
+
+        // The backend gzips everything, but that's its own business.
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, b"plain").unwrap();
+        assert_eq!(None, backend.file_metadata(TEST_DOMAIN, TEST_KEY_1).unwrap().content_encoding);
+
+        // Content which was gzipped to begin with comes back still
+        // gzipped, and says so.
+        let gzipped = b"\x1f\x8b pretend this is gzipped";
+        backend.store_encoded_bytes_content(TEST_DOMAIN, TEST_KEY_1, gzipped, Some("gzip")).unwrap();
+        let mut fetched = vec![];
+        backend.get_content(TEST_DOMAIN, TEST_KEY_1, &mut fetched).unwrap();
+        assert_eq!(&gzipped[..], &fetched[..]);
+
+        let metadata = backend.file_metadata(TEST_DOMAIN, TEST_KEY_1).unwrap();
+        assert_eq!(Some("gzip".to_string()), metadata.content_encoding);
+        assert_eq!(gzipped.len() as u64, metadata.size);
+
+        let info = backend.file_info(&FileInfo { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() }).unwrap();
+        assert_eq!(Some("gzip".to_string()), info.content_encoding);
+
+        // Copies keep it; storing new content replaces it.
+        backend.copy(&CopyKey { domain: TEST_DOMAIN.to_string(), from_key: TEST_KEY_1.to_string(), to_key: "test/key/3".to_string() }).unwrap();
+        assert_eq!(Some("gzip".to_string()), backend.file_metadata(TEST_DOMAIN, "test/key/3").unwrap().content_encoding);
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, b"plain again").unwrap();
+        assert_eq!(None, backend.file_metadata(TEST_DOMAIN, TEST_KEY_1).unwrap().content_encoding);
+    }
+
+    #[test]
+    fn append_content() {
+        let backend = sync_backend_fixture();
+
+        // File 2 has no content yet, so it starts out empty.
+        backend.append_content(TEST_DOMAIN, TEST_KEY_2, &mut Cursor::new(b"hello".to_vec())).unwrap();
+        backend.append_content(TEST_DOMAIN, TEST_KEY_2, &mut Cursor::new(b" world".to_vec())).unwrap();
+        assert_eq!(b"hello world", &backend.read_content(TEST_DOMAIN, TEST_KEY_2).unwrap()[..]);
+        backend.with_file(TEST_DOMAIN, TEST_KEY_2, |f| {
+            assert_eq!(Some(11), f.size);
+            assert!(f.mtime.is_some());
+            Ok(())
+        }).unwrap();
+
+        let mut expected = TEST_CONTENT_1.to_vec();
+        expected.extend_from_slice(b" and more");
+        backend.append_content(TEST_DOMAIN, TEST_KEY_1, &mut Cursor::new(b" and more".to_vec())).unwrap();
+        assert_eq!(expected, backend.read_content(TEST_DOMAIN, TEST_KEY_1).unwrap().to_vec());
+        assert_eq!(expected.len() as u64, backend.file_metadata(TEST_DOMAIN, TEST_KEY_1).unwrap().size);
+
+        let result = backend.append_content(TEST_DOMAIN, "test/key/3", &mut Cursor::new(b"nope".to_vec()));
+        assert!(matches!(result, Err(MogError::UnknownKey(ref k)) if k == "test/key/3"));
+    }
+
+    #[test]
+    fn wal_replay() {
+        let mut backend = MemBackend::new(Url::parse("http://test.host/").unwrap());
+        backend.enable_wal(100);
+        backend.create_domain(&CreateDomain { domain: TEST_DOMAIN.to_string() }).unwrap();
+        backend.create_class(&CreateClass {
+            domain: TEST_DOMAIN.to_string(), class: "two_copies".to_string(), mindevcount: 2, replpolicy: None, hashtype: None, update: false,
+        }).unwrap();
+        for key in [ TEST_KEY_1, TEST_KEY_2 ].iter() {
+            let open_res = backend.create_open(&CreateOpen { class: Some("two_copies".to_string()), ..open_request(key) }).unwrap();
+            backend.create_close(&CreateClose {
+                domain: TEST_DOMAIN.to_string(), key: key.to_string(), fid: open_res.fid, devid: 1, path: open_res.paths[0].1.clone(), checksum: None,
+            }).unwrap();
+        }
+        backend.rename(&Rename { domain: TEST_DOMAIN.to_string(), from_key: TEST_KEY_1.to_string(), to_key: "test/key/3".to_string(), overwrite: false }).unwrap();
+        backend.delete(&Delete { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_2.to_string() }).unwrap();
+
+        // Failures aren't recorded.
+        assert!(backend.delete(&Delete { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_2.to_string() }).is_err());
+        assert_eq!(8, backend.wal_entries().len());
+
+        let mut replayed = MemBackend::new(Url::parse("http://test.host/").unwrap());
+        replayed.replay(&backend.wal_entries()).unwrap();
+        let files = |b: &MemBackend| -> Vec<(String, u64, Option<String>)> {
+            b.domains[TEST_DOMAIN].files().map(|(k, f)| (k.to_string(), f.fid(), f.class.clone())).collect()
+        };
+        assert_eq!(files(&backend), files(&replayed));
+        assert_eq!(vec![ "test/key/3" ], files(&replayed).iter().map(|f| &f.0[..]).collect::<Vec<_>>());
+
+        // Only the newest entries are kept.
+        backend.enable_wal(2);
+        let entries = backend.wal_entries();
+        assert_eq!(2, entries.len());
+        assert!(matches!(entries[0], WalEntry::Rename(..)));
+        assert!(matches!(entries[1], WalEntry::Delete(..)));
+    }
+
+    #[test]
+    fn wal_records_every_change() {
+        let mut backend = MemBackend::new(Url::parse("http://test.host/").unwrap());
+        backend.enable_wal(100);
+        backend.create_domain(&CreateDomain { domain: TEST_DOMAIN.to_string() }).unwrap();
+        backend.create_class(&CreateClass {
+            domain: TEST_DOMAIN.to_string(), class: "two_copies".to_string(), mindevcount: 2, replpolicy: None, hashtype: None, update: false,
+        }).unwrap();
+        for key in [ TEST_KEY_1, TEST_KEY_2 ].iter() {
+            let open_res = open_file(&mut backend, key);
+            backend.create_close(&CreateClose {
+                domain: TEST_DOMAIN.to_string(), key: key.to_string(), fid: open_res.fid, devid: 1, path: open_res.paths[0].1.clone(), checksum: None,
+            }).unwrap();
+        }
+
+        backend.copy(&CopyKey { domain: TEST_DOMAIN.to_string(), from_key: TEST_KEY_1.to_string(), to_key: "test/key/3".to_string() }).unwrap();
+        backend.update_class(&UpdateClass { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string(), new_class: "two_copies".to_string() }).unwrap();
+        backend.replicate(&Replicate { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() }).unwrap();
+        backend.rename_many(&RenameMany { domain: TEST_DOMAIN.to_string(), pairs: vec![ (TEST_KEY_2.to_string(), "test/key/4".to_string()) ] }).unwrap();
+        backend.swap_content(TEST_DOMAIN, TEST_KEY_1, "test/key/4").unwrap();
+        let cancelled = open_file(&mut backend, "test/key/5");
+        backend.create_cancel(&CreateCancel { domain: TEST_DOMAIN.to_string(), key: "test/key/5".to_string(), fid: cancelled.fid }).unwrap();
+        open_file(&mut backend, "test/key/6");
+        assert_eq!(1, backend.purge_stale_opens(time::Duration::zero()));
+        backend.import_file(TEST_DOMAIN, ExportedFile {
+            fid: 100, key: "test/key/7".to_string(), size: Some(3), mtime: None, class: None, content_encoding: None,
+            content: Some(MemContent(Arc::new(b"abc".to_vec()))),
+        }).unwrap();
+
+        let entries = backend.wal_entries();
+        assert_eq!(16, entries.len());
+        assert!(matches!(entries[13], WalEntry::CreateOpen(ref r) if r.key == "test/key/6"));
+        assert!(matches!(entries[14], WalEntry::CreateCancel(ref r) if r.key == "test/key/6"));
+        assert!(matches!(entries[15], WalEntry::ImportFile(_, ref f) if f.fid == 100 && f.content.is_none()));
+
+        let mut replayed = MemBackend::new(Url::parse("http://test.host/").unwrap());
+        replayed.replay(&entries).unwrap();
+        let files = |b: &MemBackend| -> Vec<(String, u64, Option<String>, u64)> {
+            b.domains[TEST_DOMAIN].files().map(|(k, f)| (k.to_string(), f.fid(), f.class.clone(), f.devcount)).collect()
+        };
+        assert_eq!(files(&backend), files(&replayed));
+        assert!(matches!(replayed.read_content(TEST_DOMAIN, "test/key/7"), Err(MogError::NoContent(..))));
+        assert_eq!(open_file(&mut backend, "test/key/8").fid, open_file(&mut replayed, "test/key/8").fid);
+    }
+
+    #[test]
+    fn wal_replay_after_failures() {
+        let mut backend = MemBackend::new(Url::parse("http://test.host/").unwrap());
+        backend.enable_wal(100);
+        backend.set_strict_sizes(true);
+        backend.create_domain(&CreateDomain { domain: TEST_DOMAIN.to_string() }).unwrap();
+
+        // Neither a failed open nor a failed copy uses up a fid.
+        let unreg_req = CreateOpen { class: Some("no_such_class".to_string()), ..open_request(TEST_KEY_1) };
+        assert!(matches!(backend.create_open(&unreg_req), Err(MogError::UnregClass(..))));
+        assert!(matches!(backend.copy(&CopyKey { domain: TEST_DOMAIN.to_string(), from_key: TEST_KEY_2.to_string(), to_key: TEST_KEY_1.to_string() }),
+                         Err(MogError::UnknownKey(..))));
+
+        let open_res = backend.create_open(&CreateOpen { size: Some(4), ..open_request(TEST_KEY_1) }).unwrap();
+        backend.store_bytes_content(TEST_DOMAIN, TEST_KEY_1, b"four").unwrap();
+        backend.create_close(&CreateClose {
+            domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string(), fid: open_res.fid, devid: 1, path: open_res.paths[0].1.clone(), checksum: None,
+        }).unwrap();
+
+        // The replayed close has no content to match its declared
+        // size, but goes through anyway.
+        let mut replayed = MemBackend::new(Url::parse("http://test.host/").unwrap());
+        replayed.set_strict_sizes(true);
+        replayed.replay(&backend.wal_entries()).unwrap();
+        assert!(replayed.strict_sizes);
+        let fid = |b: &MemBackend| b.file(TEST_DOMAIN, TEST_KEY_1).unwrap().map(|f| f.fid());
+        assert_eq!(Some(open_res.fid), fid(&replayed));
+        assert_eq!(fid(&backend), fid(&replayed));
     }
 
     #[test]
@@ -582,10 +2970,9 @@ mod tests {
 
 #[cfg(test)]
 pub mod test_support {
-    use std::collections::HashMap;
     use super::*;
-    use super::super::MemDomain;
-    use super::super::model::test_support::{domain_fixture, full_domain_fixture};
+    use mogilefs_common::requests::{CreateOpen, CreateOpenResponse};
+    use super::super::model::test_support::{TEST_DOMAIN, domain_fixture, full_domain_fixture};
     use url::Url;
 
     pub static TEST_HOST: &'static str = "test.host";
@@ -596,22 +2983,18 @@ pub mod test_support {
     }
 
     pub fn backend_fixture() -> MemBackend {
-        let mut backend = MemBackend {
-            domains: HashMap::new(),
-            empty_domain: MemDomain::new(""),
-            base_url: TEST_BASE_URL.clone(),
-        };
+        let mut backend = MemBackend::new(TEST_BASE_URL.clone());
+        // Past the fixture files' fids.
+        backend.next_fid = 5;
         let domain = domain_fixture();
         backend.domains.insert(domain.name().to_string(), domain);
         backend
     }
 
     pub fn full_backend_fixture() -> MemBackend {
-        let mut backend = MemBackend {
-            domains: HashMap::new(),
-            empty_domain: MemDomain::new(""),
-            base_url: TEST_BASE_URL.clone(),
-        };
+        let mut backend = MemBackend::new(TEST_BASE_URL.clone());
+        // Past the fixture files' fids.
+        backend.next_fid = 5;
         let domain = full_domain_fixture();
         backend.domains.insert(domain.name().to_string(), domain);
         backend
@@ -620,4 +3003,55 @@ pub mod test_support {
     pub fn sync_backend_fixture() -> SyncMemBackend {
         SyncMemBackend::new(backend_fixture())
     }
+
+    /// A plain `create_open` of `key` in the test domain, for a test
+    /// to change what it cares about with `..open_request(key)`.
+    pub fn open_request(key: &str) -> CreateOpen {
+        CreateOpen {
+            domain: TEST_DOMAIN.to_string(), class: None, key: key.to_string(),
+            multi_dest: false, size: None, if_absent: false, idempotency_token: None,
+        }
+    }
+
+    /// Open `key` in the test domain with `open_request(key)`.
+    pub fn open_file(backend: &mut MemBackend, key: &str) -> CreateOpenResponse {
+        backend.create_open(&open_request(key)).unwrap()
+    }
+}
+
+#[cfg(all(test, feature = "nightly"))]
+mod benches {
+    use mogilefs_common::Backend;
+    use mogilefs_common::requests::*;
+    use super::super::MemFileInfo;
+    use super::super::model::test_support::*;
+    use super::test_support::*;
+    use test::Bencher;
+
+    static BENCH_KEY_COUNT: u32 = 100000;
+
+    /// Page through the last keys of a big domain, which used to mean
+    /// skipping over all the keys before the cursor.
+    #[bench]
+    fn list_keys_last_page(b: &mut Bencher) {
+        let mut backend = full_backend_fixture();
+        {
+            let domain = backend.domains.get_mut(TEST_FULL_DOMAIN).unwrap();
+            for i in 0..BENCH_KEY_COUNT {
+                let key = format!("{}/bench/{:06}", TEST_KEY_PREFIX_1, i);
+                domain.add_file(&key, MemFileInfo::new(i as u64 + 10, &key)).unwrap();
+            }
+        }
+
+        let request = ListKeys {
+            domain: TEST_FULL_DOMAIN.to_string(),
+            prefix: Some(TEST_KEY_PREFIX_1.to_string()),
+            after: Some(format!("{}/bench/{:06}", TEST_KEY_PREFIX_1, BENCH_KEY_COUNT - 100)),
+            limit: Some(100),
+            order: KeyOrder::Lexicographic,
+            with_sizes: false,
+        };
+
+        b.iter(|| backend.list_keys(&request).unwrap());
+    }
 }
@@ -1,7 +1,7 @@
 //! In-memory implementations of MogileFS, for testing purposes.
 
-pub use self::mem_backend::{MemBackend, SyncMemBackend};
-pub use self::model::{MemDomain, MemFileInfo};
+pub use self::mem_backend::{ContentChunks, DedupStats, ExportedFile, MemBackend, MemContent, MemDevice, Placement, SyncMemBackend, WalEntry};
+pub use self::model::{Compression, MemDomain, MemFileInfo};
 
 mod mem_backend;
 mod model;
@@ -1,11 +1,19 @@
 use mogilefs_common::{MogError, MogResult};
 use std::collections::{btree_map, BTreeMap};
-use time::Tm;
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+use time::{Timespec, Tm};
+
+/// The mindevcount of the "default" class, which every domain has
+/// without having to create it. It's the same as in MogileFS.
+pub const DEFAULT_MINDEVCOUNT: u64 = 2;
 
 #[derive(Debug, Default)]
 pub struct MemDomain {
     name: String,
     files: BTreeMap<String, MemFileInfo>,
+    classes: BTreeMap<String, u64>,
 }
 
 impl MemDomain {
@@ -13,6 +21,7 @@ impl MemDomain {
         MemDomain {
             name: name.to_string(),
             files: BTreeMap::new(),
+            classes: BTreeMap::new(),
         }
     }
 
@@ -20,6 +29,68 @@ impl MemDomain {
         &self.name
     }
 
+    /// Add a class to the domain, or change the mindevcount of an
+    /// existing one if `update` is set.
+    pub fn add_class(&mut self, class: &str, mindevcount: u64, update: bool) -> MogResult<()> {
+        if mindevcount < 1 {
+            Err(MogError::InvalidMindevcount)
+        } else if !update && (class == "default" || self.classes.contains_key(class)) {
+            Err(MogError::Other("class_exists".to_string(), Some(format!("Class {:?} already exists", class))))
+        } else if update && class != "default" && !self.classes.contains_key(class) {
+            Err(MogError::UnregClass(class.to_string()))
+        } else {
+            if class != "default" {
+                self.classes.insert(class.to_string(), mindevcount);
+            }
+            Ok(())
+        }
+    }
+
+    /// The mindevcount of `class`, or of the default class if that's
+    /// `None`. Returns `None` if there's no such class.
+    pub fn mindevcount(&self, class: Option<&str>) -> Option<u64> {
+        match class {
+            None | Some("default") => Some(DEFAULT_MINDEVCOUNT),
+            Some(c) => self.classes.get(c).cloned(),
+        }
+    }
+
+    /// The names of the domain's classes, including "default", in
+    /// order.
+    pub fn class_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.classes.keys().map(|c| &c[..]).collect();
+        names.push("default");
+        names.sort();
+        names
+    }
+
+    /// Move the file at `key` to `class`, which must be one of the
+    /// domain's. The file keeps the copies it has: one with fewer than
+    /// the new class wants is left under-replicated (with a devcount
+    /// below the mindevcount) for `replicate` to catch up, and one
+    /// with more keeps the extras.
+    pub fn update_class(&mut self, key: &str, class: &str) -> MogResult<()> {
+        try!(self.mindevcount(Some(class)).ok_or(MogError::UnregClass(class.to_string())));
+        let file = try!(self.files.get_mut(key).ok_or(MogError::UnknownKey(key.to_string())));
+        file.class = if class == "default" { None } else { Some(class.to_string()) };
+        Ok(())
+    }
+
+    /// Simulate a file being replicated to one more device, up to its
+    /// class's mindevcount. Returns the file's new devcount.
+    pub fn replicate(&mut self, key: &str) -> MogResult<u64> {
+        let mindevcount = {
+            let file = try!(self.files.get(key).ok_or(MogError::UnknownKey(key.to_string())));
+            self.mindevcount(file.class.as_ref().map(|c| &c[..])).unwrap_or(DEFAULT_MINDEVCOUNT)
+        };
+
+        let file = self.files.get_mut(key).unwrap();
+        if file.devcount < mindevcount {
+            file.devcount += 1;
+        }
+        Ok(file.devcount)
+    }
+
     pub fn file(&self, key: &str) -> Option<&MemFileInfo> {
         self.files.get(key)
     }
@@ -29,7 +100,31 @@ impl MemDomain {
     }
 
     pub fn files<'a>(&'a self) -> Files<'a> {
-        Files { inner: self.files.iter(), }
+        self.files_from(Bound::Unbounded)
+    }
+
+    /// The files with keys from `start` on, in order. This seeks
+    /// straight to `start`, rather than walking past the keys before
+    /// it.
+    pub fn files_from<'a>(&'a self, start: Bound<&str>) -> Files<'a> {
+        Files { inner: self.files.range::<str, _>((start, Bound::Unbounded)), }
+    }
+
+    /// The files with keys starting with `prefix`, from `start` on
+    /// (which should be no earlier than `prefix`), in order. Only the
+    /// matching keys are visited.
+    pub fn files_with_prefix<'a>(&'a self, prefix: &str, start: Bound<&str>) -> Files<'a> {
+        let end = match prefix_upper_bound(prefix) {
+            Some(end) => end,
+            None => return self.files_from(start),
+        };
+
+        // BTreeMap::range panics on a start past its end.
+        let start = match start {
+            Bound::Included(s) | Bound::Excluded(s) if s >= &end[..] => Bound::Included(&end[..]),
+            s => s,
+        };
+        Files { inner: self.files.range::<str, _>((start, Bound::Excluded(&end[..]))), }
     }
 
     pub fn add_file(&mut self, key: &str, info: MemFileInfo) -> MogResult<&MemFileInfo> {
@@ -41,22 +136,144 @@ impl MemDomain {
         self.files.remove(key)
     }
 
-    pub fn rename(&mut self, from: &str, to: &str) -> MogResult<()> {
+    /// Move the file at `from` to `to`. If `overwrite` is set, a file
+    /// already at `to` is replaced, and returned so its content can be
+    /// released.
+    pub fn rename(&mut self, from: &str, to: &str, overwrite: bool) -> MogResult<Option<MemFileInfo>> {
+        if !overwrite && self.files.contains_key(to) {
+            Err(MogError::KeyExists(to.to_string()))
+        } else if !self.files.contains_key(from) {
+            Err(MogError::UnknownKey(from.to_string()))
+        } else if from == to {
+            Ok(None)
+        } else {
+            Ok(self.move_file(from, to))
+        }
+    }
+
+    /// Move the file at `from`, if there is one, to `to`, returning
+    /// whatever that replaces. Unlike `rename`, there's nothing to
+    /// check, so nothing can fail.
+    fn move_file(&mut self, from: &str, to: &str) -> Option<MemFileInfo> {
+        match self.files.remove(from) {
+            Some(mut file_info) => {
+                file_info.key = to.to_string();
+                self.files.insert(to.to_string(), file_info)
+            },
+            None => None,
+        }
+    }
+
+    /// Rename each pair of keys in turn, without replacing any
+    /// existing files. If one of the renames fails, the ones before it
+    /// are undone, and the error is returned. They're undone in
+    /// reverse, getting back to how things were before each in turn,
+    /// so moving each file straight back can't replace anything.
+    pub fn rename_many(&mut self, pairs: &[(String, String)]) -> MogResult<()> {
+        for (i, &(ref from, ref to)) in pairs.iter().enumerate() {
+            if let Err(e) = self.rename(from, to, false) {
+                for &(ref done_from, ref done_to) in pairs[..i].iter().rev() {
+                    self.move_file(done_to, done_from);
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Exchange the content of the closed files at `a` and `b`, along
+    /// with their fids and everything else which goes with the content
+    /// (its size, mtime, encoding and checksums). Each keeps its own
+    /// class and devcount.
+    pub fn swap_content(&mut self, a: &str, b: &str) -> MogResult<()> {
+        for key in [ a, b ].iter() {
+            match self.files.get(*key) {
+                Some(file) if !file.is_open() => {},
+                _ => return Err(MogError::UnknownKey(key.to_string())),
+            }
+        }
+
+        if a == b {
+            return Ok(());
+        }
+
+        let mut file_a = self.files.remove(a).unwrap();
+        {
+            let file_b = self.files.get_mut(b).unwrap();
+            mem::swap(&mut file_a.fid, &mut file_b.fid);
+            mem::swap(&mut file_a.content, &mut file_b.content);
+            mem::swap(&mut file_a.size, &mut file_b.size);
+            mem::swap(&mut file_a.mtime, &mut file_b.mtime);
+            mem::swap(&mut file_a.etag, &mut file_b.etag);
+            mem::swap(&mut file_a.compression, &mut file_b.compression);
+            mem::swap(&mut file_a.content_encoding, &mut file_b.content_encoding);
+            mem::swap(&mut file_a.checksum, &mut file_b.checksum);
+        }
+        self.files.insert(a.to_string(), file_a);
+        Ok(())
+    }
+
+    /// Copy the file at `from` to a new file at `to`, with the new
+    /// fid. The content is shared rather than duplicated, since it's
+    /// only ever replaced, never modified in place.
+    pub fn copy(&mut self, from: &str, to: &str, fid: u64) -> MogResult<()> {
+        try!(self.can_copy(from, to));
+        let file_info = {
+            let source = &self.files[from];
+            MemFileInfo {
+                content: source.content.clone(),
+                size: source.size,
+                mtime: source.mtime,
+                class: source.class.clone(),
+                compression: source.compression,
+                content_encoding: source.content_encoding.clone(),
+                checksum: source.checksum.clone(),
+                ..MemFileInfo::new(fid, to)
+            }
+        };
+        self.files.insert(to.to_string(), file_info);
+        Ok(())
+    }
+
+    /// Whether `copy(from, to, ..)` would work: there's a file at
+    /// `from`, and nothing at `to` yet.
+    pub fn can_copy(&self, from: &str, to: &str) -> MogResult<()> {
         if self.files.contains_key(to) {
             Err(MogError::KeyExists(to.to_string()))
         } else if !self.files.contains_key(from) {
             Err(MogError::UnknownKey(from.to_string()))
         } else {
-            let mut file_info = self.files.remove(from).unwrap();
-            file_info.key = to.to_string();
-            self.files.insert(to.to_string(), file_info);
             Ok(())
         }
     }
 }
 
+/// The first string after all the strings starting with `prefix`, or
+/// `None` if there isn't one (the prefix is empty, or all
+/// `char::MAX`). This works on chars rather than bytes, since bumping
+/// the last byte of a multi-byte char wouldn't leave a valid string.
+pub fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+
+    while let Some(last) = chars.pop() {
+        let next = match last as u32 + 1 {
+            // Skip over the surrogates, which aren't chars.
+            0xD800 => Some('\u{E000}'),
+            n => ::std::char::from_u32(n),
+        };
+
+        if let Some(c) = next {
+            chars.push(c);
+            return Some(chars.into_iter().collect());
+        }
+    }
+
+    None
+}
+
 pub struct Files<'a> {
-    inner: btree_map::Iter<'a, String, MemFileInfo>,
+    inner: btree_map::Range<'a, String, MemFileInfo>,
 }
 
 impl<'a> Iterator for Files<'a> {
@@ -75,9 +292,38 @@ impl<'a> Iterator for Files<'a> {
 pub struct MemFileInfo {
     fid: u64,
     key: String,
-    pub content: Option<Vec<u8>>,
+    pub content: Option<Arc<Vec<u8>>>,
     pub size: Option<u64>,
     pub mtime: Option<Tm>,
+    /// The file's class, or `None` for the default class.
+    pub class: Option<String>,
+    /// How many devices the file has (pretend to have) been
+    /// replicated to so far.
+    pub devcount: u64,
+    /// When the file was opened, if it hasn't been closed yet.
+    pub opened_at: Option<Timespec>,
+    /// The content's checksum, once something has asked for it.
+    pub etag: Option<String>,
+    /// How `content` is encoded.
+    pub compression: Compression,
+    /// The `Content-Encoding` the content was stored with. This is how
+    /// the content is encoded as far as clients are concerned, and has
+    /// nothing to do with `compression`: gzipped content is gzipped
+    /// whether or not the backend gzips it again.
+    pub content_encoding: Option<String>,
+    /// The checksum the file was closed with, as the client gave it.
+    pub checksum: Option<String>,
+    /// The size the file was opened with, if the client gave one.
+    pub declared_size: Option<u64>,
+}
+
+/// How a `MemBackend` holds the content it's given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// As it was stored.
+    None,
+    /// Gzipped, and un-gzipped again on the way out.
+    Gzip,
 }
 
 impl MemFileInfo {
@@ -88,6 +334,14 @@ impl MemFileInfo {
             content: None,
             size: None,
             mtime: None,
+            class: None,
+            devcount: 1,
+            opened_at: None,
+            etag: None,
+            compression: Compression::None,
+            content_encoding: None,
+            checksum: None,
+            declared_size: None,
         }
     }
 
@@ -98,10 +352,18 @@ impl MemFileInfo {
     pub fn key(&self) -> &str {
         &self.key
     }
+
+    /// Whether the file has been opened but not yet closed.
+    pub fn is_open(&self) -> bool {
+        self.opened_at.is_some()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use mogilefs_common::MogError;
+    use std::ops::Bound;
+    use std::sync::Arc;
     use super::*;
     use super::super::super::test_support::*;
 
@@ -112,6 +374,36 @@ mod tests {
         assert!(domain.files.is_empty());
     }
 
+    #[test]
+    fn prefix_upper_bounds() {
+        assert_eq!(None, prefix_upper_bound(""));
+        assert_eq!(Some("foo/prefiy".to_string()), prefix_upper_bound("foo/prefix"));
+        assert_eq!(Some("a\u{80}".to_string()), prefix_upper_bound("a\u{7f}"));
+        assert_eq!(Some("a\u{800}".to_string()), prefix_upper_bound("a\u{7ff}"));
+        assert_eq!(Some("a\u{e000}".to_string()), prefix_upper_bound("a\u{d7ff}"));
+        assert_eq!(Some("b".to_string()), prefix_upper_bound("a\u{10ffff}\u{10ffff}"));
+        assert_eq!(None, prefix_upper_bound("\u{10ffff}"));
+    }
+
+    #[test]
+    fn domain_files_with_prefix() {
+        let mut domain = MemDomain::new(TEST_DOMAIN);
+        for key in &[ "a", "a\u{7f}", "a\u{7f}x", "a\u{80}", "a\u{10ffff}", "a\u{10ffff}x", "b" ] {
+            domain.add_file(key, MemFileInfo::new(1, key)).unwrap();
+        }
+        let keys = |prefix: &str, start: Bound<&str>| domain.files_with_prefix(prefix, start).map(|(k, _)| k.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(vec![ "a\u{7f}", "a\u{7f}x" ], keys("a\u{7f}", Bound::Included("a\u{7f}")));
+        assert_eq!(vec![ "a\u{10ffff}", "a\u{10ffff}x" ], keys("a\u{10ffff}", Bound::Included("a\u{10ffff}")));
+        assert_eq!(vec![ "a\u{7f}x" ], keys("a\u{7f}", Bound::Excluded("a\u{7f}")));
+        assert_eq!(7, keys("", Bound::Unbounded).len());
+
+        // A start past the prefix's keys finds nothing, rather than
+        // upsetting the BTreeMap.
+        assert!(keys("a\u{7f}", Bound::Excluded("a\u{80}")).is_empty());
+        assert!(keys("a\u{7f}", Bound::Excluded("z")).is_empty());
+    }
+
     #[test]
     fn domain_get_file() {
         let mut domain = domain_fixture();
@@ -146,12 +438,12 @@ mod tests {
 
         {   // Modify the content of the file.
             let mut_file = domain.file_mut(TEST_KEY_1).unwrap();
-            mut_file.content = Some(new_content.clone());
+            mut_file.content = Some(Arc::new(new_content.clone()));
         }
 
         {   // Pull it back out and make sure that it's the same.
             let file = domain.file(TEST_KEY_1).unwrap();
-            assert_eq!(Some(new_content.clone()), file.content);
+            assert_eq!(Some(Arc::new(new_content.clone())), file.content);
         }
     }
 
@@ -179,7 +471,7 @@ mod tests {
 
         {   // Add a new file to the domain.
             let mut file = MemFileInfo::new(5, new_key);
-            file.content = Some(content.clone());
+            file.content = Some(Arc::new(content.clone()));
             file.size = Some(content.len() as u64);
             domain.add_file(new_key, file).unwrap();
         }
@@ -190,7 +482,7 @@ mod tests {
             let file = file.unwrap();
             assert_eq!(5, file.fid());
             assert_eq!(new_key, file.key());
-            assert_eq!(Some(&content), file.content.as_ref());
+            assert_eq!(Some(&content), file.content.as_ref().map(|c| &**c));
             assert_eq!(Some(content.len() as u64), file.size);
         }
 
@@ -206,6 +498,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn domain_classes() {
+        let mut domain = domain_fixture();
+        assert_eq!(vec![ "default" ], domain.class_names());
+        assert_eq!(Some(DEFAULT_MINDEVCOUNT), domain.mindevcount(None));
+
+        domain.add_class("three_copies", 3, false).unwrap();
+        assert_eq!(vec![ "default", "three_copies" ], domain.class_names());
+        assert_eq!(Some(3), domain.mindevcount(Some("three_copies")));
+        assert_eq!(None, domain.mindevcount(Some("no_such_class")));
+
+        assert!(matches!(domain.add_class("three_copies", 4, false), Err(MogError::Other(..))));
+        assert!(matches!(domain.add_class("no_copies", 0, false), Err(MogError::InvalidMindevcount)));
+        assert!(matches!(domain.add_class("no_such_class", 2, true), Err(MogError::UnregClass(..))));
+
+        domain.add_class("three_copies", 4, true).unwrap();
+        assert_eq!(Some(4), domain.mindevcount(Some("three_copies")));
+    }
+
+    #[test]
+    fn domain_replicate() {
+        let mut domain = domain_fixture();
+        assert_eq!(2, domain.replicate(TEST_KEY_1).unwrap());
+        assert_eq!(2, domain.replicate(TEST_KEY_1).unwrap());
+        assert!(matches!(domain.replicate("test/key/3"), Err(MogError::UnknownKey(..))));
+    }
+
     #[test]
     fn domain_remove_file() {
         let mut domain = domain_fixture();
@@ -231,6 +550,7 @@ mod tests {
 
 #[cfg(test)]
 pub mod test_support {
+    use std::sync::Arc;
     use super::*;
     use time;
 
@@ -257,21 +577,9 @@ pub mod test_support {
             let key_p1 = format!("{}/key/{}", TEST_KEY_PREFIX_1, i+1);
             let key_p2 = format!("{}/key/{}", TEST_KEY_PREFIX_2, i+1);
 
-            domain.files.insert(key_p1.clone(), MemFileInfo {
-                fid: 1,
-                key: key_p1,
-                content: None,
-                size: None,
-                mtime: None,
-            });
-
-            domain.files.insert(key_p2.clone(), MemFileInfo {
-                fid: 2,
-                key: key_p2,
-                content: None,
-                size: None,
-                mtime: None,
-            });
+            domain.files.insert(key_p1.clone(), MemFileInfo::new(1, &key_p1));
+
+            domain.files.insert(key_p2.clone(), MemFileInfo::new(2, &key_p2));
         }
 
         domain
@@ -279,21 +587,14 @@ pub mod test_support {
 
     pub fn file_1_fixture() -> MemFileInfo {
         MemFileInfo {
-            fid: 3,
-            key: TEST_KEY_1.to_string(),
-            content: Some(Vec::from(TEST_CONTENT_1)),
+            content: Some(Arc::new(Vec::from(TEST_CONTENT_1))),
             size: Some(TEST_CONTENT_1.len() as u64),
             mtime: Some(time::now_utc()),
+            ..MemFileInfo::new(3, TEST_KEY_1)
         }
     }
 
     pub fn file_2_fixture() -> MemFileInfo {
-        MemFileInfo {
-            fid: 4,
-            key: TEST_KEY_2.to_string(),
-            content: None,
-            size: None,
-            mtime: None,
-        }
+        MemFileInfo::new(4, TEST_KEY_2)
     }
 }
@@ -20,19 +20,47 @@ impl<B: StorageBackend> StorageHandler<B> {
         }
     }
 
-    fn handle_get(&self, _request: &Request, domain: &str, key: &str) -> IronResult<Response> {
+    fn handle_get(&self, request: &Request, domain: &str, key: &str) -> IronResult<Response> {
         let metadata = try!(self.backend.file_metadata(domain, key).map_err(|e| coerce_mogerror(e)));
+
+        // HTTP dates only go down to the second.
+        if let Some(&headers::IfModifiedSince(headers::HttpDate(since))) = request.headers.get::<headers::IfModifiedSince>() {
+            if metadata.mtime.to_timespec().sec <= since.to_timespec().sec {
+                return Ok(Response::with((
+                    Status::NotModified,
+                    Header(headers::LastModified(headers::HttpDate(metadata.mtime))),)));
+            }
+        }
+
         let mut content = vec![];
         try!(self.backend.get_content(domain, key, &mut content).map_err(|e| coerce_mogerror(e)));
-        Ok(Response::with((
+        let mut response = Response::with((
             Status::Ok,
             Header(headers::LastModified(headers::HttpDate(metadata.mtime))),
             Header(headers::ContentLength(metadata.size as u64)),
-            content,)))
+            content,));
+
+        if let Some(etag) = metadata.etag {
+            response.headers.set(headers::ETag(headers::EntityTag::strong(etag)));
+        }
+
+        if metadata.accepts_ranges {
+            response.headers.set(headers::AcceptRanges(vec![ headers::RangeUnit::Bytes ]));
+        }
+
+        if let Some(encoding) = metadata.content_encoding {
+            response.headers.set_raw("Content-Encoding", vec![ encoding.into_bytes() ]);
+        }
+
+        Ok(response)
     }
 
     fn handle_put(&self, request: &mut Request, domain: &str, key: &str) -> IronResult<Response> {
-        match self.backend.store_reader_content(domain, key, &mut request.body) {
+        let content_encoding = request.headers.get_raw("Content-Encoding")
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).into_owned());
+
+        match self.backend.store_encoded_reader_content(domain, key, &mut request.body, content_encoding.as_ref().map(|e| &e[..])) {
             Ok(_) => Ok(Response::with((Status::Ok,))),
             Err(MogError::UnknownKey(ref k)) => {
                 return Ok(Response::with((Status::NotFound, format!("Unknown key: {:?}\n", k))));
@@ -104,3 +132,155 @@ fn coerce_mogerror(err: MogError) -> IronError {
 
     IronError::new(err, modifier)
 }
+
+#[cfg(test)]
+mod tests {
+    use iron::{Chain, Handler, Iron, Listening};
+    use mogilefs_client::MogClient;
+    use mogilefs_common::MogError;
+    use std::io::{self, Cursor};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use super::StorageHandler;
+    use super::super::super::mem::{MemBackend, SyncMemBackend};
+    use super::super::super::range::RangeMiddleware;
+    use super::super::tracker::Tracker;
+    use url::Url;
+
+    /// Start a storage server in front of a new backend with a
+    /// "test_domain" domain, and a client which goes to the backend
+    /// in-process for its tracker.
+    fn storage_fixture() -> (Listening, SyncMemBackend, MogClient) {
+        storage_fixture_with(StorageHandler::new)
+    }
+
+    /// Like `storage_fixture`, but lets the handler the server runs be
+    /// built around the backend.
+    fn storage_fixture_with<H, F>(handler: F) -> (Listening, SyncMemBackend, MogClient)
+        where H: Handler, F: FnOnce(SyncMemBackend) -> H
+    {
+        // The backend hands out URLs pointing at the storage server,
+        // whose port isn't known until it's listening.
+        let mut backend = SyncMemBackend::new(MemBackend::new(Url::parse("http://127.0.0.1/").unwrap()));
+        let listening = Iron::new(handler(backend.clone())).http(("127.0.0.1", 0)).unwrap();
+        backend.set_base_url(Url::parse(&format!("http://{}/", listening.socket)).unwrap()).unwrap();
+
+        let mut client = MogClient::in_process(Tracker::new(backend.clone()));
+        client.ensure_domain("test_domain").unwrap();
+        (listening, backend, client)
+    }
+
+    #[test]
+    fn binary_content_round_trip() {
+        let (mut listening, _, mut client) = storage_fixture();
+
+        let mut content: Vec<u8> = (0..256).map(|b| b as u8).collect();
+        content.extend_from_slice(b"line one\r\nline two\r\n\r\n\0\0\r\n\xff\xfe\xc3(\n\r");
+
+        client.store_data("test_domain".to_string(), None, "binary/key".to_string(), &mut Cursor::new(content.clone())).unwrap();
+        let mut fetched = vec![];
+        let len = client.get_file("test_domain".to_string(), "binary/key".to_string(), &mut fetched).unwrap();
+        assert_eq!(content.len() as u64, len);
+        assert_eq!(content, fetched);
+
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn get_file_verified() {
+        let (mut listening, backend, mut client) = storage_fixture();
+        client.store_data("test_domain".to_string(), None, "test/key".to_string(), &mut Cursor::new(b"hello world".to_vec())).unwrap();
+        let set_checksum = |checksum: &str| backend.with_file_mut("test_domain", "test/key", |f| {
+            f.checksum = Some(checksum.to_string());
+            Ok(())
+        }).unwrap();
+
+        set_checksum("MD5:5EB63BBBE01EEED093CB22BB8F5ACDC3");
+        let mut fetched = vec![];
+        assert_eq!(11, client.get_file_verified("test_domain".to_string(), "test/key".to_string(), &mut fetched).unwrap());
+        assert_eq!(b"hello world", &fetched[..]);
+
+        set_checksum("MD5:00000000000000000000000000000000");
+        let result = client.get_file_verified("test_domain".to_string(), "test/key".to_string(), &mut vec![]);
+        assert!(matches!(result, Err(MogError::ChecksumMismatch(..))), "Result was {:?}", result);
+
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn append_data() {
+        let (mut listening, _, mut client) = storage_fixture();
+        client.store_data("test_domain".to_string(), None, "test/key".to_string(), &mut Cursor::new(vec![])).unwrap();
+
+        client.append_data("test_domain".to_string(), None, "test/key".to_string(), &mut Cursor::new(b"hello".to_vec())).unwrap();
+        client.append_data("test_domain".to_string(), None, "test/key".to_string(), &mut Cursor::new(b" world".to_vec())).unwrap();
+        let mut fetched = vec![];
+        client.get_file("test_domain".to_string(), "test/key".to_string(), &mut fetched).unwrap();
+        assert_eq!(b"hello world", &fetched[..]);
+
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn peek() {
+        let (mut listening, _, mut client) = storage_fixture();
+        client.store_data("test_domain".to_string(), None, "test/key".to_string(), &mut Cursor::new(b"\x89PNG\r\n\x1a\nrest of it".to_vec())).unwrap();
+
+        assert_eq!(b"\x89PNG", &client.peek("test_domain".to_string(), "test/key".to_string(), 4).unwrap()[..]);
+        assert_eq!(b"\x89PNG\r\n\x1a\nrest of it", &client.peek("test_domain".to_string(), "test/key".to_string(), 1000).unwrap()[..]);
+        assert!(client.peek("test_domain".to_string(), "test/key".to_string(), 0).unwrap().is_empty());
+
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn get_file_ranges() {
+        let (mut listening, backend, mut client) = storage_fixture_with(|backend| {
+            let mut chain = Chain::new(StorageHandler::new(backend));
+            chain.around(RangeMiddleware);
+            chain
+        });
+        client.store_data("test_domain".to_string(), None, "test/key".to_string(), &mut Cursor::new(b"0123456789abcdefghij".to_vec())).unwrap();
+
+        // The range middleware only serves one range at a time, so
+        // these are fetched one by one.
+        let ranges = [ 10..14, 0..4, 2..6, 18..30 ];
+        let fetched = client.get_file_ranges("test_domain".to_string(), "test/key".to_string(), &ranges).unwrap();
+        for (range, bytes) in ranges.iter().zip(fetched.iter()) {
+            assert_eq!(backend.read_range("test_domain", "test/key", range.clone()).unwrap(), *bytes);
+        }
+
+        listening.close().unwrap();
+    }
+
+    #[test]
+    fn store_stream() {
+        let (mut listening, _, mut client) = storage_fixture();
+
+        // The gaps between chunks add up to longer than the idle
+        // timeout, but none of them is.
+        let (tx, rx) = mpsc::sync_channel(1);
+        let producer = thread::spawn(move || {
+            for chunk in [ &b"hello"[..], b" ", b"world" ].iter() {
+                thread::sleep(Duration::from_millis(100));
+                tx.send(chunk.to_vec()).unwrap();
+            }
+        });
+        client.store_stream("test_domain".to_string(), None, "test/key".to_string(), rx, Some(Duration::from_millis(250))).unwrap();
+        producer.join().unwrap();
+
+        let mut fetched = vec![];
+        client.get_file("test_domain".to_string(), "test/key".to_string(), &mut fetched).unwrap();
+        assert_eq!(b"hello world", &fetched[..]);
+
+        // A source which stops sending, without hanging up, times out.
+        let (tx, rx) = mpsc::sync_channel(1);
+        tx.send(b"stalled".to_vec()).unwrap();
+        let result = client.store_stream("test_domain".to_string(), None, "test/key/2".to_string(), rx, Some(Duration::from_millis(100)));
+        assert!(matches!(result, Err(MogError::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut), "Result was {:?}", result);
+        drop(tx);
+
+        listening.close().unwrap();
+    }
+}
@@ -1,10 +1,10 @@
 use mio::tcp::{Shutdown, TcpListener, TcpStream};
 use mio::util::Slab;
 use mio::{self, EventLoop, EventSet, PollOpt, Token, TryRead, TryWrite};
-use mogilefs_common::{Backend, BufReadMb, Renderable};
+use mogilefs_common::{Backend, MogError};
 use self::notification::Notification;
 use self::tracker_pool::TrackerPool;
-use std::io::{BufReader, Cursor, Read, Write};
+use std::io::{Cursor, Write};
 use std::net::ToSocketAddrs;
 use std::rc::Rc;
 use super::Tracker;
@@ -18,8 +18,6 @@ pub mod error;
 mod notification;
 mod tracker_pool;
 
-static CRLF: &'static [u8] = &[ b'\r', b'\n' ];
-
 lazy_static!{
     static ref READABLE: EventSet = EventSet::readable() | EventSet::hup() | EventSet::error();
     static ref WRITABLE: EventSet = EventSet::writable() | EventSet::hup() | EventSet::error();
@@ -107,6 +105,7 @@ impl<B: 'static + Backend> Handler<B> {
                     .ok_or(EventedError::TooManyConnections)
                     .and_then(|token| {
                         info!("New connection {:?} from {:?}", token, peer_addr);
+                        self.tracker.stats().connection_opened();
                         trace!("Registering {:?} as {:?} / {:?}", token, *READABLE, *EDGE_ONESHOT);
                         event_loop.register(
                             &self.conns[token].stream, token,
@@ -125,7 +124,10 @@ impl<B: 'static + Backend> Handler<B> {
 
     fn close(&mut self, event_loop: &mut EventLoop<Self>, token: Token) -> EventedResult<()> {
         match self.conns.remove(token) {
-            Some(conn) => conn.shutdown(event_loop),
+            Some(conn) => {
+                self.tracker.stats().connection_closed();
+                conn.shutdown(event_loop)
+            },
             None => Err(EventedError::UnknownConnection(token)),
         }
     }
@@ -193,7 +195,7 @@ impl<B: 'static + Backend> mio::Handler for Handler<B> {
                     });
                 }
 
-                if events.is_error() || events.is_hup() {
+                if events.is_error() || events.is_hup() || conn.is_finished() {
                     trace!("Notifying event loop of closed / errored connection {:?}: {:?}", token, events);
                     event_loop.channel().send(Notification::close_connection(token)).unwrap_or_else(|e| {
                         error!("Error notifying event loop of closed / errored conection {:?}: {}",
@@ -222,10 +224,7 @@ impl<B: 'static + Backend> mio::Handler for Handler<B> {
                 });
             },
             Notification::Response(token, response) => {
-                let rendered: Vec<u8> = match response {
-                    Ok(resp) => format!("{}\r\n", resp.render()).bytes().collect(),
-                    Err(e) => format!("{}\r\n", e.render()).bytes().collect(),
-                };
+                let rendered: Vec<u8> = format!("{}\r\n", response).bytes().collect();
 
                 self.write_response(event_loop, token, &rendered).unwrap_or_else(|e| {
                     error!("Error writing tracker response to {:?}: {}", token, e);
@@ -253,6 +252,7 @@ struct Connection<B: Backend> {
     out_buf: Vec<u8>,
     tracker: Rc<TrackerPool<B>>,
     current: Option<Vec<u8>>,
+    closing: bool,
 }
 
 impl<B: 'static + Backend> Connection<B> {
@@ -264,15 +264,22 @@ impl<B: 'static + Backend> Connection<B> {
             out_buf: Vec::new(),
             tracker: tracker,
             current: None,
+            closing: false,
         }
     }
 
+    /// Whether the connection is waiting to be closed, and has
+    /// nothing left to write.
+    fn is_finished(&self) -> bool {
+        self.closing && self.out_buf.is_empty()
+    }
+
     fn read(&mut self, event_loop: &mut EventLoop<Handler<B>>) -> EventedResult<EventSet> {
         match self.stream.try_read_buf(&mut self.in_buf) {
             Ok(Some(n)) => {
                 trace!("Read {} bytes from {:?}", n, self.token);
                 self.maybe_dispatch_request(event_loop);
-                Ok(*READABLE)
+                Ok(if self.closing { *WRITABLE } else { *READABLE })
             },
             Ok(None) => {
                 trace!("No more bytes to read from {:?}", self.token);
@@ -326,29 +333,45 @@ impl<B: 'static + Backend> Connection<B> {
     }
 
     fn maybe_dispatch_request(&mut self, event_loop: &mut EventLoop<Handler<B>>) {
-        if self.current.is_none() && self.in_buf.windows(2).position(|x| x == CRLF).is_some() {
-            let mut request = vec![];
-            let mut rest = vec![];
-
-            {
-                // There should be no way this can go wrong; we're reading
-                // from and writing to Vecs.
-                let mut reader = BufReader::new(Cursor::new(&self.in_buf));
-                reader.read_until_mb(CRLF, &mut request).unwrap();
-                reader.read_to_end(&mut rest).unwrap();
-            }
+        if self.closing {
+            self.in_buf.clear();
+            return;
+        }
 
-            debug!("request_line = {:?}", String::from_utf8_lossy(&request));
+        // Lines end at a LF, with or without a CR before it, the same
+        // as with the threaded listener's default delimiter.
+        let max_line_length = self.tracker.max_line_length();
+        let line_end = self.in_buf.iter().position(|&b| b == b'\n');
+        let line_length = match line_end {
+            Some(end) if end > 0 && self.in_buf[end - 1] == b'\r' => end - 1,
+            Some(end) => end,
+            None => self.in_buf.len(),
+        };
+        if line_length > max_line_length {
+            warn!("Request line from {:?} is longer than {} bytes, closing connection",
+                  self.token, max_line_length);
+            let rendered = self.tracker.render_response(&Err(MogError::RequestTooLong));
+            self.out_buf.extend(format!("{}\r\n", rendered).bytes());
+            self.in_buf.clear();
+            self.closing = true;
+            return;
+        }
 
-            // Cut the delimiter off of the request.
-            let len = request.len();
-            request = request.into_iter().take(len - 2).collect();
+        let end = match line_end {
+            Some(end) if self.current.is_none() => end,
+            _ => return,
+        };
 
-            // Ship it off to the tracker code.
-            self.current = Some(request.clone());
-            self.in_buf = rest;
-            self.tracker.handle(request, self.token, event_loop.channel());
-        }
+        // Cut the delimiter off of the request.
+        let request = self.in_buf[..line_length].to_vec();
+        let rest = self.in_buf[end + 1..].to_vec();
+
+        debug!("request_line = {:?}", String::from_utf8_lossy(&request));
+
+        // Ship it off to the tracker code.
+        self.current = Some(request.clone());
+        self.in_buf = rest;
+        self.tracker.handle(request, self.token, event_loop.channel());
     }
 
     fn write_response(&mut self, event_loop: &mut EventLoop<Handler<B>>, response_bytes: &[u8]) -> EventedResult<()> {
@@ -486,6 +509,44 @@ mod tests {
         handle.join().unwrap();
     }
 
+    #[test]
+    fn request_line_too_long() {
+        let mut tracker = Tracker::new(sync_backend_fixture());
+        tracker.set_max_line_length(16);
+        let mut server = EventedListener::new("127.0.0.1:0", tracker, 1, 1).unwrap();
+        let server_addr = server.handler.listener.local_addr().unwrap();
+        let channel = server.event_loop.channel();
+
+        let handle = client_thread(server_addr, move|mut reader, mut writer| {
+            let mut resp = String::new();
+
+            writer.write("noop\r\n".as_bytes()).unwrap();
+            reader.read_line(&mut resp).unwrap();
+            assert_eq!("OK \r\n", resp);
+            resp.clear();
+
+            // A bare LF ends a line too, and doesn't count towards its
+            // length, so this is exactly the limit.
+            writer.write("noop trace_id=ab\n".as_bytes()).unwrap();
+            reader.read_line(&mut resp).unwrap();
+            assert!(resp.starts_with("OK "), "Response was {:?}", resp);
+            resp.clear();
+
+            writer.write("file_info domain=test_domain&key=test/key/1\r\n".as_bytes()).unwrap();
+            reader.read_line(&mut resp).unwrap();
+            assert!(resp.starts_with("ERR request_too_long "), "Response was {:?}", resp);
+            resp.clear();
+
+            // And then the connection is closed.
+            assert_eq!(0, reader.read_line(&mut resp).unwrap_or(0));
+
+            channel.send(Notification::shutdown()).unwrap();
+        });
+
+        server.run().unwrap();
+        handle.join().unwrap();
+    }
+
     #[test]
     fn multiple_connections() {
         let _ = env_logger::init();
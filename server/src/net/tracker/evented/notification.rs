@@ -1,11 +1,11 @@
 use mio::Token;
-use mogilefs_common::{MogResult, Response};
 
 #[derive(Debug)]
 pub enum Notification {
     CloseConnection(Token),
     Shutdown,
-    Response(Token, MogResult<Response>),
+    /// A rendered response line, without the trailing CRLF.
+    Response(Token, String),
 }
 
 impl Notification {
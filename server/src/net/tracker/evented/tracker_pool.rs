@@ -1,8 +1,9 @@
 use mio::{Sender, Token};
-use mogilefs_common::Backend;
+use mogilefs_common::{Backend, MogResult, Response};
 use std::sync::Arc;
 use super::notification::Notification;
 use super::super::Tracker;
+use super::super::stats::TrackerStats;
 use threadpool::ThreadPool;
 
 pub struct TrackerPool<B: Backend> {
@@ -18,11 +19,23 @@ impl<B: 'static + Backend> TrackerPool<B> {
         }
     }
 
+    pub fn render_response(&self, response: &MogResult<Response>) -> String {
+        self.tracker.render_response(response)
+    }
+
+    pub fn stats(&self) -> &TrackerStats {
+        self.tracker.stats()
+    }
+
+    pub fn max_line_length(&self) -> usize {
+        self.tracker.max_line_length()
+    }
+
     pub fn handle(&self, request_line: Vec<u8>, token: Token, response_to: Sender<Notification>) {
         let tracker = self.tracker.clone();
         self.thread_pool.execute(move|| {
-            let response = tracker.handle_bytes(request_line.as_ref());
-            response_to.send(Notification::Response(token, response)).unwrap_or_else(|e| {
+            let rendered = tracker.handle_line(request_line.as_ref());
+            response_to.send(Notification::Response(token, rendered)).unwrap_or_else(|e| {
                 error!("Error sending response to event loop connection {:?}: {:?}", token, e);
             });
         })
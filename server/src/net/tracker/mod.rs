@@ -1,16 +1,70 @@
 use chrono::UTC;
-use mogilefs_common::{Backend, MogError, MogResult, Request, Response, FromBytes};
+use mogilefs_client::InProcessTracker;
+use mogilefs_common::{Backend, MogError, MogResult, Request, Response, ResponseFormat, Renderable};
+use mogilefs_common::{TRACE_ID_ARG, ToArgs, ToResponse, parse_request, split_request_line, trace_id_from_bytes, to_json_string};
 use r2d2;
 use statsd::client::{Client as StatsdClient};
+use std::cmp;
+use std::collections::HashMap;
 use super::super::r2d2_statsd::StatsdConnectionManager;
+use self::stats::TrackerStats;
+use url::form_urlencoded::{self, Serializer};
 
 pub mod evented;
+pub mod stats;
 pub mod threaded;
 
+/// The default limit on the length of a request line, in bytes.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A handler for a command registered with `Tracker::register`. It's
+/// given the backend and the (still urlencoded) arguments of the
+/// request line.
+pub type CommandHandler<B> = Box<Fn(&B, &[u8]) -> MogResult<Response> + Send + Sync>;
+
+/// A handler for the errors requests get from the backend, set with
+/// `Tracker::set_error_handler`.
+pub type ErrorHandler = Box<Fn(&Request, &MogError) + Send + Sync>;
+
+/// A request for a registered command, as the error handler sees it.
+/// Only the command's handler knows what its arguments mean, so
+/// they're left as they came.
+#[derive(Debug)]
+struct RegisteredRequest<'a> {
+    op: &'static str,
+    args: &'a [u8],
+}
+
+impl<'a> Request for RegisteredRequest<'a> {
+    fn op(&self) -> &'static str { self.op }
+
+    // There's no telling, so it's taken to.
+    fn is_mutating(&self) -> bool { true }
+
+    fn response_from_bytes(&self, _bytes: &[u8]) -> MogResult<Response> {
+        Err(MogError::BadResponse)
+    }
+
+    fn perform(&self, _backend: &Backend) -> MogResult<Response> {
+        Err(MogError::UnknownCommand(Some(self.op.to_string())))
+    }
+}
+
+impl<'a> ToArgs for RegisteredRequest<'a> {
+    fn to_args(&self) -> Vec<(String, String)> {
+        form_urlencoded::parse(self.args).map(|(k, v)| (k.into_owned(), v.into_owned())).collect()
+    }
+}
+
 /// The tracker object.
 pub struct Tracker<B: Backend> {
     backend: B,
     statsd_pool: Option<r2d2::Pool<StatsdConnectionManager>>,
+    response_format: ResponseFormat,
+    max_line_length: usize,
+    stats: TrackerStats,
+    commands: HashMap<&'static str, CommandHandler<B>>,
+    error_handler: Option<ErrorHandler>,
 }
 
 impl<B: Backend> Tracker<B> {
@@ -19,9 +73,118 @@ impl<B: Backend> Tracker<B> {
         Tracker {
             backend: backend,
             statsd_pool: None,
+            response_format: ResponseFormat::Urlencoded,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            stats: TrackerStats::new(),
+            commands: HashMap::new(),
+            error_handler: None,
+        }
+    }
+
+    /// Handle requests for `op` with `handler`, rather than passing
+    /// them to the backend as one of the built-in requests. This is
+    /// how a backend can add commands of its own; registering the name
+    /// of a built-in command replaces it.
+    pub fn register<F>(&mut self, op: &'static str, handler: F)
+        where F: Fn(&B, &[u8]) -> MogResult<Response> + Send + Sync + 'static
+    {
+        self.commands.insert(op, Box::new(handler));
+    }
+
+    /// Call `handler` with the request and the error whenever the
+    /// backend fails a request through no fault of the client's (see
+    /// `MogError::is_client_error`), before the error is rendered.
+    /// Requests which couldn't be parsed never get as far as the
+    /// backend, so they don't count. The request's `op()` and the
+    /// error's `error_kind()` tell what went wrong where; a registered
+    /// command's request has its op, and its arguments as they came.
+    pub fn set_error_handler<F>(&mut self, handler: F)
+        where F: Fn(&Request, &MogError) + Send + Sync + 'static
+    {
+        self.error_handler = Some(Box::new(handler));
+    }
+
+    /// Set the format the listeners should render responses in. The
+    /// default is the classic urlencoded MogileFS format.
+    pub fn set_response_format(&mut self, format: ResponseFormat) {
+        self.response_format = format;
+    }
+
+    pub fn response_format(&self) -> ResponseFormat {
+        self.response_format
+    }
+
+    /// Set the longest request line the listeners will accept. A
+    /// client which sends a longer one gets a `request_too_long`
+    /// error, and its connection is closed.
+    pub fn set_max_line_length(&mut self, max_line_length: usize) {
+        self.max_line_length = max_line_length;
+    }
+
+    pub fn max_line_length(&self) -> usize {
+        self.max_line_length
+    }
+
+    /// The backend the tracker is serving, for maintenance which
+    /// doesn't go through the protocol. It's shared with every
+    /// connection, so it's up to the backend to make that safe (as
+    /// `SyncMemBackend`'s lock does).
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// The tracker's request, error, and connection counters.
+    pub fn stats(&self) -> &TrackerStats {
+        &self.stats
+    }
+
+    /// Render the result of handling a request into a response line
+    /// (without the trailing CRLF), in this tracker's response format.
+    pub fn render_response(&self, response: &MogResult<Response>) -> String {
+        // Despite both arms being identical, I have to break it out
+        // because the result itself is not Renderable.
+        match *response {
+            Ok(ref resp) => resp.render_as(self.response_format),
+            Err(ref e) => e.render_as(self.response_format),
         }
     }
 
+    /// Render a response, echoing the request's trace id back to the
+    /// client if it sent one. The urlencoded error line has no room
+    /// for extra arguments, so the trace id is left off of those.
+    pub fn render_traced_response(&self, response: &MogResult<Response>, trace_id: Option<&str>) -> String {
+        let rendered = self.render_response(response);
+        let trace_id = match trace_id {
+            Some(id) => id,
+            None => return rendered,
+        };
+
+        match (self.response_format, response) {
+            (ResponseFormat::Urlencoded, &Ok(..)) => {
+                let trace_arg = Serializer::new(String::new()).append_pair(TRACE_ID_ARG, trace_id).finish();
+                if rendered.ends_with(' ') {
+                    format!("{}{}", rendered, trace_arg)
+                } else {
+                    format!("{}&{}", rendered, trace_arg)
+                }
+            },
+            (ResponseFormat::Urlencoded, &Err(..)) => rendered,
+            (ResponseFormat::Json, _) => {
+                let mut rendered = rendered;
+                rendered.pop();
+                format!("{},\"{}\":{}}}", rendered, TRACE_ID_ARG, to_json_string(trace_id))
+            },
+        }
+    }
+
+    /// Handle a raw request line from the network, returning the
+    /// rendered response line (without the trailing CRLF).
+    pub fn handle_line(&self, request_bytes: &[u8]) -> String {
+        let trace_id = trace_id_from_bytes(request_bytes);
+        let response = self.handle_bytes(request_bytes);
+        self.render_traced_response(&response, trace_id.as_ref().map(|id| id.as_ref()))
+    }
+
     pub fn report_stats_to(&mut self, host: &str, prefix: &str) -> MogResult<()> {
         debug!("Reporting stats to statsd at {:?} with prefix {:?}", host, prefix);
 
@@ -42,13 +205,23 @@ impl<B: Backend> Tracker<B> {
     }
 
     /// Parse the bytes of a MogileFS request from the network into a
-    /// Request, and hand that off to the Backend for processing.
+    /// Request (see `parse_request`), and hand that off to the Backend
+    /// for processing.
     pub fn handle_bytes(&self, request_bytes: &[u8]) -> MogResult<Response> {
-        match Box::<Request>::from_bytes(request_bytes) {
-            Ok(request) => self.handle_request(&*request),
+        let trace_id = trace_id_from_bytes(request_bytes);
+        let trace_id = trace_id.as_ref().map(|id| id.as_ref());
+
+        let (op, args) = split_request_line(request_bytes);
+        if let Some((&op, handler)) = self.commands.iter().find(|&(name, _)| name.as_bytes() == op) {
+            return self.handle_registered(op, handler, args, trace_id);
+        }
+
+        match parse_request(request_bytes) {
+            Ok(request) => self.handle_traced_request(&*request, trace_id),
             Err(e) => {
-                error!("Error parsing request: {}, raw request = {:?}",
-                       e, String::from_utf8_lossy(request_bytes));
+                error!("[trace_id={}] Error parsing request: {}, raw request = {:?}",
+                       trace_id.unwrap_or("-"), e, String::from_utf8_lossy(request_bytes));
+                self.stats.count_error(&e);
                 Err(e)
             }
         }
@@ -56,22 +229,74 @@ impl<B: Backend> Tracker<B> {
 
     /// Handle a Request.
     pub fn handle_request(&self, request: &Request) -> MogResult<Response> {
-        info!("request = {:?}", request);
+        self.handle_traced_request(request, None)
+    }
+
+    fn handle_traced_request(&self, request: &Request, trace_id: Option<&str>) -> MogResult<Response> {
+        let trace_id = trace_id.unwrap_or("-");
+        info!("[trace_id={}] request = {:?}", trace_id, request);
+
+        let response = self.dispatch(request.op(), || {
+            if request.op() == "get_stats" {
+                // The backend doesn't know about these; they're ours.
+                Ok(self.stats.snapshot().to_response())
+            } else {
+                self.backend.handle(request)
+            }
+        });
+
+        self.report_error(request, &response);
+        info!("[trace_id={}] response = {:?}", trace_id, response);
+        response
+    }
+
+    fn handle_registered(&self, op: &'static str, handler: &CommandHandler<B>, args: &[u8], trace_id: Option<&str>) -> MogResult<Response> {
+        let trace_id = trace_id.unwrap_or("-");
+        info!("[trace_id={}] request = {} {:?}", trace_id, op, String::from_utf8_lossy(args));
+
+        let response = self.dispatch(op, || handler(&self.backend, args));
+
+        self.report_error(&RegisteredRequest { op: op, args: args }, &response);
+        info!("[trace_id={}] response = {:?}", trace_id, response);
+        response
+    }
+
+    /// Pass `response` to the error handler, if there is one, and it's
+    /// an error which isn't the client's fault.
+    fn report_error(&self, request: &Request, response: &MogResult<Response>) {
+        match (response, self.error_handler.as_ref()) {
+            (&Err(ref e), Some(handler)) if !e.is_client_error() => handler(request, e),
+            _ => {},
+        }
+    }
+
+    /// Run `handle` for a request for `op`, keeping the stats and
+    /// statsd counters and timers for it, whether it's a built-in
+    /// command or a registered one.
+    fn dispatch<F>(&self, op: &str, handle: F) -> MogResult<Response>
+        where F: FnOnce() -> MogResult<Response>
+    {
         let start = UTC::now();
 
         self.with_statsd(|statsd| {
             let lock = UTC::now();
-            let op_counter = format!("mogilefs_server.tracker.requests.{}", request.op());
+            let op_counter = format!("mogilefs_server.tracker.requests.{}", op);
             statsd.incr(&op_counter);
 
-            let lock_time_counter = format!("mogilefs_server.tracker.statsd.lock_wait_time.pre.{}", request.op());
+            let lock_time_counter = format!("mogilefs_server.tracker.statsd.lock_wait_time.pre.{}", op);
             statsd.timer(&lock_time_counter, (lock - start).num_milliseconds() as f64);
         });
 
         let begin = UTC::now();
-        let response = self.backend.handle(request);
+        let response = handle();
         let end = UTC::now();
 
+        self.stats.count_request(op);
+        self.stats.observe_latency(cmp::max(0, (end - begin).num_microseconds().unwrap_or(0)) as u64);
+        if let Err(ref e) = response {
+            self.stats.count_error(e);
+        }
+
         self.with_statsd(|statsd| {
             let lock = UTC::now();
             if let Err(ref e) = response {
@@ -79,14 +304,13 @@ impl<B: Backend> Tracker<B> {
                 statsd.incr(&err_counter);
             }
 
-            let time_counter = format!("mogilefs_server.tracker.requests.timing.{}", request.op());
+            let time_counter = format!("mogilefs_server.tracker.requests.timing.{}", op);
             statsd.timer(&time_counter, (end - begin).num_milliseconds() as f64);
 
-            let lock_time_counter = format!("mogilefs_server.tracker.statsd.lock_wait_time.post.{}", request.op());
+            let lock_time_counter = format!("mogilefs_server.tracker.statsd.lock_wait_time.post.{}", op);
             statsd.timer(&lock_time_counter, (lock - end).num_milliseconds() as f64);
         });
 
-        info!("response = {:?}", response);
         response
     }
 
@@ -100,3 +324,301 @@ impl<B: Backend> Tracker<B> {
         }
     }
 }
+
+impl<B: Backend> InProcessTracker for Tracker<B> {
+    fn handle_line(&self, request_line: &[u8]) -> String {
+        Tracker::handle_line(self, request_line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, UTC};
+    use mogilefs_client::MogClient;
+    use mogilefs_common::{MogError, Response, ResponseFormat, ToArgs};
+    use mogilefs_common::requests::*;
+    use super::*;
+    use super::super::super::mem::test_support::*;
+
+    #[test]
+    fn handle_bytes_strips_crlf() {
+        let tracker = Tracker::new(sync_backend_fixture());
+        let response = tracker.handle_bytes(b"noop\r\n");
+        assert!(matches!(response, Ok(Response::Empty)), "Response was {:?}", response);
+    }
+
+    #[test]
+    fn handle_bytes_strips_surrounding_spaces() {
+        let tracker = Tracker::new(sync_backend_fixture());
+        let response = tracker.handle_bytes(b" noop ");
+        assert!(matches!(response, Ok(Response::Empty)), "Response was {:?}", response);
+    }
+
+    #[test]
+    fn handle_bytes_allows_extra_spaces_before_args() {
+        let tracker = Tracker::new(sync_backend_fixture());
+
+        // The domain is found, so the complaint is about the key.
+        let response = tracker.handle_bytes(b"create_open  domain=x");
+        assert!(matches!(response, Err(MogError::NoKey)), "Response was {:?}", response);
+
+        let response = tracker.handle_bytes(b"create_open  domain=x&key=test/key/3\r\n");
+        assert!(matches!(response, Ok(Response::CreateOpen(..))), "Response was {:?}", response);
+    }
+
+    #[test]
+    fn render_response_in_tracker_format() {
+        let mut tracker = Tracker::new(sync_backend_fixture());
+        let response = tracker.handle_bytes(b"noop");
+        assert_eq!("OK ", tracker.render_response(&response));
+
+        tracker.set_response_format(ResponseFormat::Json);
+        assert_eq!("{\"status\":\"OK\",\"args\":{}}", tracker.render_response(&response));
+    }
+
+    #[test]
+    fn handle_line_delete_reports_count() {
+        let tracker = Tracker::new(sync_backend_fixture());
+        assert_eq!("OK deleted=1", tracker.handle_line(b"delete domain=test_domain&key=test/key/1"));
+        assert!(tracker.handle_line(b"delete domain=test_domain&key=test/key/1").starts_with("ERR unknown_key "));
+    }
+
+    #[test]
+    fn handle_line_get_stats() {
+        let tracker = Tracker::new(sync_backend_fixture());
+        tracker.handle_line(b"noop");
+        tracker.handle_line(b"noop");
+        tracker.handle_line(b"file_info domain=test_domain&key=test/key/3");
+        tracker.handle_line(b"not_a_command");
+
+        assert_eq!(2, tracker.stats().requests("noop"));
+        assert_eq!(1, tracker.stats().errors("unknown_key"));
+        assert_eq!(1, tracker.stats().errors("unknown_command"));
+
+        let stats = tracker.handle_line(b"get_stats");
+        assert!(stats.starts_with("OK connections=0&"), "Response was {:?}", stats);
+        assert!(stats.contains("&requests_noop=2"), "Response was {:?}", stats);
+        assert!(stats.contains("&errors_unknown_key=1"), "Response was {:?}", stats);
+    }
+
+    #[test]
+    fn in_process_client() {
+        let mut client = MogClient::in_process(Tracker::new(sync_backend_fixture()));
+        assert!(matches!(client.request(&Noop), Ok(Response::Empty)));
+
+        let response = client.request(&FileInfo { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() });
+        match response {
+            Ok(Response::FileInfo(ref info)) => {
+                assert_eq!(TEST_DOMAIN, info.domain);
+                assert_eq!(TEST_CONTENT_1.len() as u64, info.length);
+            },
+            _ => panic!("Response was {:?}", response),
+        }
+
+        let response = client.request(&FileInfo { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() });
+        assert!(matches!(response, Err(MogError::UnknownKey(..))), "Response was {:?}", response);
+    }
+
+    #[test]
+    fn in_process_get_file_ranges() {
+        // Nothing's listening at the backend's storage URLs, so these
+        // can only come from the backend itself.
+        let backend = sync_backend_fixture();
+        let mut client = MogClient::in_process(Tracker::new(backend.clone()));
+        client.set_in_process_storage(backend);
+
+        let ranges = [ 8..12, 0..4, 2..6, 30..40 ];
+        let fetched = client.get_file_ranges(TEST_DOMAIN.to_string(), TEST_KEY_1.to_string(), &ranges).unwrap();
+        assert_eq!(vec![ b"test".to_vec(), b"This".to_vec(), b"is i".to_vec(), vec![] ], fetched);
+
+        let result = client.get_file_ranges(TEST_DOMAIN.to_string(), "test/key/3".to_string(), &ranges);
+        assert!(matches!(result, Err(MogError::UnknownKey(..))), "Result was {:?}", result);
+    }
+
+    #[test]
+    fn in_process_rename_many() {
+        let mut client = MogClient::in_process(Tracker::new(sync_backend_fixture()));
+        let pair = |from: &str, to: &str| (from.to_string(), to.to_string());
+
+        let conflict = client.rename_many(TEST_DOMAIN.to_string(), vec![ pair(TEST_KEY_1, "test/key/3"), pair("test/key/5", "test/key/6") ]);
+        assert_eq!(Some(1), conflict.unwrap());
+        assert!(client.request(&FileInfo { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() }).is_ok());
+
+        let renamed = client.rename_many(TEST_DOMAIN.to_string(), vec![ pair(TEST_KEY_1, "test/key/3") ]);
+        assert_eq!(None, renamed.unwrap());
+        assert!(client.request(&FileInfo { domain: TEST_DOMAIN.to_string(), key: "test/key/3".to_string() }).is_ok());
+    }
+
+    #[test]
+    fn in_process_locate() {
+        let mut client = MogClient::in_process(Tracker::new(sync_backend_fixture()));
+        let located = client.locate(TEST_DOMAIN.to_string(), TEST_KEY_1.to_string()).unwrap();
+        assert_eq!(3, located.fid);
+        assert_eq!(TEST_CONTENT_1.len() as u64, located.length);
+        assert_eq!(1, located.paths.len());
+        assert_eq!(Some(1), located.paths[0].devid);
+
+        assert!(matches!(client.locate(TEST_DOMAIN.to_string(), "test/key/3".to_string()), Err(MogError::UnknownKey(..))));
+    }
+
+    #[test]
+    fn in_process_list_keys_since() {
+        let mut client = MogClient::in_process(Tracker::new(sync_backend_fixture()));
+        let since = UTC::now() - Duration::hours(1);
+        let keys = client.list_keys_since(TEST_DOMAIN, since).unwrap();
+        assert_eq!(vec![ TEST_KEY_1.to_string() ], keys.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+
+        assert!(client.list_keys_since(TEST_DOMAIN, UTC::now() + Duration::hours(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn in_process_list_keys_detailed() {
+        let mut client = MogClient::in_process(Tracker::new(sync_backend_fixture()));
+        let keys = client.list_keys_detailed(TEST_DOMAIN, None, None, None).unwrap();
+        assert_eq!(vec![ (TEST_KEY_1.to_string(), Some(TEST_CONTENT_1.len() as u64)), (TEST_KEY_2.to_string(), None) ], keys);
+    }
+
+    #[test]
+    fn in_process_list_all_keys() {
+        let mut client = MogClient::in_process(Tracker::new(sync_backend_fixture()));
+        let keys: Vec<(String, String)> = client.list_all_keys_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(vec![ (TEST_DOMAIN.to_string(), TEST_KEY_1.to_string()), (TEST_DOMAIN.to_string(), TEST_KEY_2.to_string()) ], keys);
+    }
+
+    #[test]
+    fn in_process_cancel_open() {
+        let mut client = MogClient::in_process(Tracker::new(sync_backend_fixture()));
+        let opened = client.request(&open_request("test/key/3")).ok().and_then(|r| r.downcast::<CreateOpenResponse>()).unwrap();
+
+        client.cancel_open(TEST_DOMAIN.to_string(), "test/key/3".to_string(), opened.fid).unwrap();
+        let cancel_again = client.cancel_open(TEST_DOMAIN.to_string(), "test/key/3".to_string(), opened.fid);
+        assert!(matches!(cancel_again, Err(MogError::UnknownKey(..))), "Second cancel was {:?}", cancel_again);
+        assert!(matches!(client.cancel_open(TEST_DOMAIN.to_string(), TEST_KEY_1.to_string(), 3), Err(MogError::UnknownFid)));
+    }
+
+    #[test]
+    fn in_process_update_class() {
+        let backend = sync_backend_fixture();
+        backend.create_class(&CreateClass {
+            domain: TEST_DOMAIN.to_string(),
+            class: "three_copies".to_string(),
+            mindevcount: 3,
+            replpolicy: None,
+            hashtype: None,
+            update: false,
+        }).unwrap();
+        let mut client = MogClient::in_process(Tracker::new(backend));
+        let file_info = |client: &mut MogClient| {
+            let req = FileInfo { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() };
+            client.request(&req).ok().and_then(|r| r.downcast::<FileInfoResponse>()).unwrap()
+        };
+
+        client.update_class(TEST_DOMAIN.to_string(), TEST_KEY_1.to_string(), "three_copies".to_string()).unwrap();
+        let info = file_info(&mut client);
+        assert_eq!(("three_copies", 1), (&info.class[..], info.devcount));
+
+        for _ in 0..2 {
+            client.request(&Replicate { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() }).unwrap();
+        }
+        assert_eq!(3, file_info(&mut client).devcount);
+
+        // The default class only wants two copies, but the third
+        // isn't thrown away.
+        client.update_class(TEST_DOMAIN.to_string(), TEST_KEY_1.to_string(), "default".to_string()).unwrap();
+        let info = file_info(&mut client);
+        assert_eq!(("default", 3), (&info.class[..], info.devcount));
+
+        let result = client.update_class(TEST_DOMAIN.to_string(), TEST_KEY_1.to_string(), "no_such_class".to_string());
+        assert!(matches!(result, Err(MogError::UnregClass(..))), "Result was {:?}", result);
+        let result = client.update_class(TEST_DOMAIN.to_string(), "test/key/3".to_string(), "default".to_string());
+        assert!(matches!(result, Err(MogError::UnknownKey(..))), "Result was {:?}", result);
+
+        // An unknown domain isn't created along the way.
+        let result = client.update_class("no_such_domain".to_string(), TEST_KEY_1.to_string(), "default".to_string());
+        assert!(matches!(result, Err(MogError::UnregDomain(..))), "Result was {:?}", result);
+        let result = client.request(&CreateDomain { domain: "no_such_domain".to_string() });
+        assert!(result.is_ok(), "Result was {:?}", result);
+    }
+
+    #[test]
+    fn registered_commands() {
+        let mut tracker = Tracker::new(sync_backend_fixture());
+        tracker.register("purge_cache", |_, args| {
+            if args == b"everything=1" { Ok(Response::Empty) } else { Err(MogError::BadResponse) }
+        });
+        tracker.register("noop", |_, _| Err(MogError::UnknownCommand(Some("noop".to_string()))));
+
+        assert!(matches!(tracker.handle_bytes(b"purge_cache everything=1\r\n"), Ok(Response::Empty)));
+        assert!(matches!(tracker.handle_bytes(b"purge_cache"), Err(MogError::BadResponse)));
+        assert!(matches!(tracker.handle_bytes(b"noop"), Err(MogError::UnknownCommand(..))));
+        assert!(matches!(tracker.handle_bytes(b"file_info domain=test_domain&key=test/key/1"), Ok(Response::FileInfo(..))));
+
+        // Registered commands are counted just like the built-in ones.
+        assert_eq!(1, tracker.stats().requests("noop"));
+        assert_eq!(1, tracker.stats().errors("unknown_command"));
+        assert_eq!(1, tracker.stats().errors("other"));
+    }
+
+    #[test]
+    fn error_handler() {
+        let errors = ::std::sync::Arc::new(::std::sync::Mutex::new(Vec::new()));
+        let mut tracker = Tracker::new(sync_backend_fixture());
+        let handler_errors = errors.clone();
+        tracker.set_error_handler(move |req, err| {
+            handler_errors.lock().unwrap().push(format!("{} {}", req.op(), err.error_kind()));
+        });
+
+        // The client's mistakes are left out...
+        tracker.handle_line(b"file_info domain=test_domain&key=test/key/1");
+        tracker.handle_line(b"file_info domain=test_domain&key=test/key/3");
+        tracker.handle_line(b"not_a_command");
+        tracker.handle_line(b"file_info domain=test_domain");
+        assert!(errors.lock().unwrap().is_empty());
+
+        // ... but the backend's own failures aren't.
+        let poisoner = tracker.backend().clone();
+        let _ = ::std::thread::spawn(move || {
+            poisoner.with_file_mut(TEST_DOMAIN, TEST_KEY_1, |_| panic!("Poisoning the backend's lock"))
+        }).join();
+        tracker.handle_line(b"file_info domain=test_domain&key=test/key/1");
+        assert_eq!(vec![ "file_info poisoned_mutex".to_string() ], *errors.lock().unwrap());
+    }
+
+    #[test]
+    fn error_handler_sees_registered_commands() {
+        let errors = ::std::sync::Arc::new(::std::sync::Mutex::new(Vec::new()));
+        let mut tracker = Tracker::new(sync_backend_fixture());
+        let handler_errors = errors.clone();
+        tracker.set_error_handler(move |req, err| {
+            handler_errors.lock().unwrap().push(format!("{} {} {:?}", req.op(), err.error_kind(), req.to_args()));
+        });
+        tracker.register("flush_disk", |_, _| Err(MogError::Io(::std::io::Error::new(::std::io::ErrorKind::Other, "disk gone"))));
+        tracker.register("recount", |_, _| Err(MogError::PoisonedMutex));
+        tracker.register("lookup", |_, _| Err(MogError::UnknownKey("k".to_string())));
+
+        tracker.handle_line(b"lookup key=k");
+        tracker.handle_line(b"flush_disk device=dev%201");
+        tracker.handle_line(b"recount");
+        assert_eq!(vec![ "flush_disk io_error [(\"device\", \"dev 1\")]".to_string(), "recount poisoned_mutex []".to_string() ],
+                   *errors.lock().unwrap());
+    }
+
+    #[test]
+    fn handle_line_echoes_trace_id() {
+        let mut tracker = Tracker::new(sync_backend_fixture());
+        assert_eq!("OK trace_id=abc123", tracker.handle_line(b"noop trace_id=abc123\r\n"));
+        assert_eq!("OK ", tracker.handle_line(b"noop\r\n"));
+
+        let response = tracker.handle_line(b"file_info domain=test_domain&key=test/key/1&trace_id=abc123");
+        assert!(response.starts_with("OK "), "Response was {:?}", response);
+        assert!(response.ends_with("&trace_id=abc123"), "Response was {:?}", response);
+
+        // No room for it on an error line.
+        let response = tracker.handle_line(b"file_info domain=test_domain&trace_id=abc123");
+        assert!(response.starts_with("ERR no_key "), "Response was {:?}", response);
+
+        tracker.set_response_format(ResponseFormat::Json);
+        assert_eq!("{\"status\":\"OK\",\"args\":{},\"trace_id\":\"abc123\"}",
+                   tracker.handle_line(b"noop trace_id=abc123"));
+    }
+}
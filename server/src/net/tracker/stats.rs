@@ -0,0 +1,216 @@
+//! Counters for what a tracker has been up to.
+
+use mogilefs_common::{MogError, supported_commands};
+use mogilefs_common::requests::GetStatsResponse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The error kinds counted individually; anything else is counted
+/// under "other".
+static ERROR_KINDS: &'static [&'static str] = &[
+    "domain_exists", "invalid_mindevcount", "key_exists", "no_class",
+    "no_content", "no_devid", "no_domain", "no_fid", "no_key", "no_path",
+    "out_of_space", "rate_limited", "request_too_long", "size_mismatch", "unknown_command",
+    "unknown_fid", "unknown_key", "unreg_class", "unreg_domain", "io_error", "poisoned_mutex", "pool_exhausted", "other",
+];
+
+/// The upper bounds of the request latency histogram's buckets, in
+/// milliseconds. Anything slower goes in one last, unbounded bucket.
+static LATENCY_BUCKETS_MS: &'static [u64] = &[
+    1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000,
+];
+
+/// Request, error, and connection counters for a tracker.
+///
+/// The set of counters is fixed when the tracker is created, so
+/// updating one is just an atomic increment, with no locking.
+#[derive(Debug)]
+pub struct TrackerStats {
+    requests: HashMap<&'static str, AtomicUsize>,
+    errors: HashMap<&'static str, AtomicUsize>,
+    connections: AtomicUsize,
+    latency_buckets: Vec<AtomicUsize>,
+    latency_sum_us: AtomicUsize,
+}
+
+impl TrackerStats {
+    pub fn new() -> TrackerStats {
+        TrackerStats {
+            requests: supported_commands().into_iter().map(|op| (op, AtomicUsize::new(0))).collect(),
+            errors: ERROR_KINDS.iter().map(|&kind| (kind, AtomicUsize::new(0))).collect(),
+            connections: AtomicUsize::new(0),
+            latency_buckets: (0..LATENCY_BUCKETS_MS.len() + 1).map(|_| AtomicUsize::new(0)).collect(),
+            latency_sum_us: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count a request for `op`.
+    pub fn count_request(&self, op: &str) {
+        if let Some(counter) = self.requests.get(op) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count an error response.
+    pub fn count_error(&self, error: &MogError) {
+        let counter = self.errors.get(error.error_kind()).unwrap_or(&self.errors["other"]);
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count how long the backend took over a request, in
+    /// microseconds.
+    pub fn observe_latency(&self, micros: u64) {
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|&ms| micros <= ms * 1000).unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_us.fetch_add(micros as usize, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The number of requests for `op` so far.
+    pub fn requests(&self, op: &str) -> usize {
+        self.requests.get(op).map(|c| c.load(Ordering::Relaxed)).unwrap_or(0)
+    }
+
+    /// The number of errors of `kind` so far.
+    pub fn errors(&self, kind: &str) -> usize {
+        self.errors.get(kind).map(|c| c.load(Ordering::Relaxed)).unwrap_or(0)
+    }
+
+    /// The number of connections currently open.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Take a snapshot of the non-zero counters, plus the number of
+    /// open connections.
+    pub fn snapshot(&self) -> GetStatsResponse {
+        let mut stats = vec![ ("connections".to_string(), self.connections() as u64) ];
+
+        for (op, counter) in self.requests.iter() {
+            let count = counter.load(Ordering::Relaxed);
+            if count > 0 {
+                stats.push((format!("requests_{}", op), count as u64));
+            }
+        }
+
+        for (kind, counter) in self.errors.iter() {
+            let count = counter.load(Ordering::Relaxed);
+            if count > 0 {
+                stats.push((format!("errors_{}", kind), count as u64));
+            }
+        }
+
+        stats.sort();
+        GetStatsResponse(stats)
+    }
+
+    /// Render the counters in Prometheus's text exposition format,
+    /// for the embedder to serve to a scraper.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics_text(&self) -> String {
+        let mut text = String::new();
+
+        text.push_str("# HELP mogilefs_tracker_requests_total Requests handled, by command.\n");
+        text.push_str("# TYPE mogilefs_tracker_requests_total counter\n");
+        let mut ops: Vec<_> = self.requests.keys().collect();
+        ops.sort();
+        for op in ops {
+            text.push_str(&format!("mogilefs_tracker_requests_total{{op=\"{}\"}} {}\n", op, self.requests(op)));
+        }
+
+        text.push_str("# HELP mogilefs_tracker_errors_total Error responses, by error kind.\n");
+        text.push_str("# TYPE mogilefs_tracker_errors_total counter\n");
+        let mut kinds: Vec<_> = self.errors.keys().collect();
+        kinds.sort();
+        for kind in kinds {
+            text.push_str(&format!("mogilefs_tracker_errors_total{{kind=\"{}\"}} {}\n", kind, self.errors(kind)));
+        }
+
+        text.push_str("# HELP mogilefs_tracker_connections Connections currently open.\n");
+        text.push_str("# TYPE mogilefs_tracker_connections gauge\n");
+        text.push_str(&format!("mogilefs_tracker_connections {}\n", self.connections()));
+
+        text.push_str("# HELP mogilefs_tracker_request_duration_seconds Time the backend took over requests.\n");
+        text.push_str("# TYPE mogilefs_tracker_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (i, counter) in self.latency_buckets.iter().enumerate() {
+            cumulative += counter.load(Ordering::Relaxed);
+            let le = match LATENCY_BUCKETS_MS.get(i) {
+                Some(&ms) => format!("{}", ms as f64 / 1000.0),
+                None => "+Inf".to_string(),
+            };
+            text.push_str(&format!("mogilefs_tracker_request_duration_seconds_bucket{{le=\"{}\"}} {}\n", le, cumulative));
+        }
+        let sum = self.latency_sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        text.push_str(&format!("mogilefs_tracker_request_duration_seconds_sum {}\n", sum));
+        text.push_str(&format!("mogilefs_tracker_request_duration_seconds_count {}\n", cumulative));
+
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mogilefs_common::MogError;
+    use super::*;
+
+    #[test]
+    fn count_requests_and_errors() {
+        let stats = TrackerStats::new();
+        stats.count_request("noop");
+        stats.count_request("noop");
+        stats.count_request("not_a_command");
+        stats.count_error(&MogError::UnknownKey("test/key/3".to_string()));
+        stats.count_error(&MogError::Other("weird".to_string(), None));
+
+        assert_eq!(2, stats.requests("noop"));
+        assert_eq!(0, stats.requests("not_a_command"));
+        assert_eq!(1, stats.errors("unknown_key"));
+        assert_eq!(1, stats.errors("other"));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(Some(0), snapshot.get("connections"));
+        assert_eq!(Some(2), snapshot.get("requests_noop"));
+        assert_eq!(Some(1), snapshot.get("errors_unknown_key"));
+        assert_eq!(None, snapshot.get("requests_delete"));
+    }
+
+    #[test]
+    fn count_connections() {
+        let stats = TrackerStats::new();
+        stats.connection_opened();
+        stats.connection_opened();
+        stats.connection_closed();
+        assert_eq!(1, stats.connections());
+    }
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn metrics_text() {
+        let stats = TrackerStats::new();
+        stats.count_request("noop");
+        stats.count_error(&MogError::UnknownKey("test/key/3".to_string()));
+        stats.connection_opened();
+        stats.observe_latency(500);
+        stats.observe_latency(20_000);
+        stats.observe_latency(60_000_000);
+
+        let text = stats.metrics_text();
+        assert!(text.contains("mogilefs_tracker_requests_total{op=\"noop\"} 1\n"), "{}", text);
+        assert!(text.contains("mogilefs_tracker_requests_total{op=\"delete\"} 0\n"), "{}", text);
+        assert!(text.contains("mogilefs_tracker_errors_total{kind=\"unknown_key\"} 1\n"), "{}", text);
+        assert!(text.contains("mogilefs_tracker_connections 1\n"), "{}", text);
+        assert!(text.contains("mogilefs_tracker_request_duration_seconds_bucket{le=\"0.001\"} 1\n"), "{}", text);
+        assert!(text.contains("mogilefs_tracker_request_duration_seconds_bucket{le=\"0.025\"} 2\n"), "{}", text);
+        assert!(text.contains("mogilefs_tracker_request_duration_seconds_bucket{le=\"10\"} 2\n"), "{}", text);
+        assert!(text.contains("mogilefs_tracker_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"), "{}", text);
+        assert!(text.contains("mogilefs_tracker_request_duration_seconds_count 3\n"), "{}", text);
+    }
+}
@@ -1,13 +1,33 @@
-use std::io::{self, Write, BufRead, BufReader};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Write, BufRead, BufReader, Read};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 use super::Tracker;
-use mogilefs_common::{Backend, Renderable};
+use mogilefs_common::{Backend, MogError};
 
 pub struct ThreadedListener<B: Backend> {
     listener: TcpListener,
     tracker: Arc<Tracker<B>>,
+    delimiter: Arc<Vec<u8>>,
+    tcp_nodelay: bool,
+    thread_name_prefix: String,
+    allowed_ips: Option<Vec<IpAddr>>,
+    rate_limit: Option<RateLimit>,
+}
+
+/// How fast each connection may send requests.
+#[derive(Debug, Clone, Copy)]
+struct RateLimit {
+    per_sec: f64,
+    burst: f64,
 }
 
 impl<B: 'static + Backend> ThreadedListener<B> {
@@ -15,26 +35,166 @@ impl<B: 'static + Backend> ThreadedListener<B> {
         Ok(ThreadedListener {
             listener: try!(TcpListener::bind(addr)),
             tracker: Arc::new(tracker),
+            delimiter: Arc::new(b"\r\n".to_vec()),
+            tcp_nodelay: true,
+            thread_name_prefix: "mogtracker-conn".to_string(),
+            allowed_ips: None,
+            rate_limit: None,
+        })
+    }
+
+    /// Change the sequence which ends request and response lines from
+    /// the protocol's CRLF, for clients behind proxies which rewrite
+    /// it. With the default, a bare LF is accepted on requests too.
+    pub fn set_delimiter(&mut self, delimiter: &[u8]) {
+        self.delimiter = Arc::new(delimiter.to_vec());
+    }
+
+    /// Turn Nagle's algorithm off (the default) or on for accepted
+    /// connections, so responses aren't held back waiting for ACKs.
+    pub fn set_tcp_nodelay(&mut self, nodelay: bool) {
+        self.tcp_nodelay = nodelay;
+    }
+
+    /// Change what connection threads' names start with; the default
+    /// is `mogtracker-conn`. The peer's address is added on the end,
+    /// so backtraces and logs say whose request it was.
+    pub fn set_thread_name_prefix(&mut self, prefix: &str) {
+        self.thread_name_prefix = prefix.to_string();
+    }
+
+    /// Only accept connections from these addresses; anyone else's
+    /// connection is closed as soon as it's accepted. By default,
+    /// connections from anywhere are accepted.
+    pub fn set_allowed_ips(&mut self, ips: Vec<IpAddr>) {
+        self.allowed_ips = Some(ips);
+    }
+
+    /// Limit each connection to `requests_per_sec` requests a second,
+    /// on average, allowing bursts of up to `burst` requests. Requests
+    /// over the limit get a `rate_limited` error, and the connection
+    /// stays open for when the client slows down. By default there's
+    /// no limit.
+    pub fn set_rate_limit(&mut self, requests_per_sec: f64, burst: u32) {
+        self.rate_limit = Some(RateLimit { per_sec: requests_per_sec, burst: burst as f64 });
+    }
+
+    fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        let allowed = match self.allowed_ips {
+            Some(ref ips) => ips,
+            None => return true,
+        };
+
+        // A dual-stack socket sees IPv4 peers as IPv4-mapped IPv6
+        // addresses; compare those as the IPv4 addresses they are.
+        let ip = match *addr {
+            SocketAddr::V4(ref a) => IpAddr::V4(*a.ip()),
+            SocketAddr::V6(ref a) => match a.ip().to_ipv4() {
+                Some(v4) if a.ip().segments()[5] == 0xffff => IpAddr::V4(v4),
+                _ => IpAddr::V6(*a.ip()),
+            },
+        };
+        allowed.contains(&ip)
+    }
+
+    /// A handle on the tracker the listener's connections share, which
+    /// stays usable while the listener runs; see `Tracker::backend`.
+    pub fn tracker(&self) -> Arc<Tracker<B>> {
+        self.tracker.clone()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn run(&self) {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    match stream.peer_addr() {
+                        Ok(ref addr) if self.is_allowed(addr) => {},
+                        peer_addr => {
+                            warn!("Refusing connection from {:?}, which isn't allowed", peer_addr);
+                            continue;
+                        },
+                    }
+
+                    if let Err(e) = stream.set_nodelay(self.tcp_nodelay) {
+                        warn!("Couldn't set TCP_NODELAY on connection from {:?}: {}", stream.peer_addr(), e);
+                    }
+
+                    let thread_name = match stream.peer_addr() {
+                        Ok(addr) => format!("{}-{}", self.thread_name_prefix, addr),
+                        Err(_) => self.thread_name_prefix.clone(),
+                    };
+                    spawn_connection(stream, thread_name, self.tracker.clone(), self.delimiter.clone(), self.rate_limit);
+                },
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// A Unix socket version of `ThreadedListener`, for clients on the
+/// same host, which speaks the same protocol to the same tracker. See
+/// `MogClient::new_unix`.
+#[cfg(unix)]
+pub struct UnixThreadedListener<B: Backend> {
+    listener: UnixListener,
+    path: PathBuf,
+    tracker: Arc<Tracker<B>>,
+    delimiter: Arc<Vec<u8>>,
+    thread_name_prefix: String,
+    rate_limit: Option<RateLimit>,
+}
+
+#[cfg(unix)]
+impl<B: 'static + Backend> UnixThreadedListener<B> {
+    /// Listen on a new socket at `path`, which mustn't exist yet.
+    pub fn new<P: AsRef<Path>>(path: P, tracker: Tracker<B>) -> Result<UnixThreadedListener<B>, io::Error> {
+        Ok(UnixThreadedListener {
+            listener: try!(UnixListener::bind(path.as_ref())),
+            path: path.as_ref().to_path_buf(),
+            tracker: Arc::new(tracker),
+            delimiter: Arc::new(b"\r\n".to_vec()),
+            thread_name_prefix: "mogtracker-conn".to_string(),
+            rate_limit: None,
         })
     }
 
+    /// See `ThreadedListener::set_delimiter`.
+    pub fn set_delimiter(&mut self, delimiter: &[u8]) {
+        self.delimiter = Arc::new(delimiter.to_vec());
+    }
+
+    /// See `ThreadedListener::set_thread_name_prefix`. Unix socket
+    /// peers don't have addresses, so the name is just the prefix.
+    pub fn set_thread_name_prefix(&mut self, prefix: &str) {
+        self.thread_name_prefix = prefix.to_string();
+    }
+
+    /// See `ThreadedListener::set_rate_limit`.
+    pub fn set_rate_limit(&mut self, requests_per_sec: f64, burst: u32) {
+        self.rate_limit = Some(RateLimit { per_sec: requests_per_sec, burst: burst as f64 });
+    }
+
+    /// See `ThreadedListener::tracker`.
+    pub fn tracker(&self) -> Arc<Tracker<B>> {
+        self.tracker.clone()
+    }
+
+    /// The socket's path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn run(&self) {
         for stream in self.listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    let conn_tracker = self.tracker.clone();
-
-                    thread::spawn(move|| {
-                        let peer_addr = stream.peer_addr();
-                        info!("New connection from {:?}", peer_addr);
-                        match handle_connection(stream, conn_tracker) {
-                            Ok(_) => {},
-                            Err(e) => {
-                                error!("Error handling connection from {:?}: {}", peer_addr, e);
-                            }
-                        }
-                        info!("Shutting down connection from {:?}", peer_addr);
-                    });
+                    spawn_connection(stream, self.thread_name_prefix.clone(), self.tracker.clone(), self.delimiter.clone(), self.rate_limit);
                 },
                 Err(e) => {
                     error!("Connection failed: {}", e);
@@ -44,25 +204,385 @@ impl<B: 'static + Backend> ThreadedListener<B> {
     }
 }
 
-fn handle_connection<B: Backend>(mut writer: TcpStream, tracker: Arc<Tracker<B>>) -> Result<(), io::Error> {
-    let reader = BufReader::new(try!(writer.try_clone()));
+/// The socket's removed along with the listener, so a new one can be
+/// bound at the same path.
+#[cfg(unix)]
+impl<B: Backend> Drop for UnixThreadedListener<B> {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Couldn't remove socket {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// The parts of a connection `handle_connection` needs, which TCP and
+/// Unix socket connections both have.
+trait Connection: Read + Write + Sized + Send + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Who's on the other end, for the logs.
+    fn peer(&self) -> String;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn peer(&self) -> String {
+        format!("{:?}", self.peer_addr())
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn peer(&self) -> String {
+        format!("{:?}", self.peer_addr())
+    }
+}
+
+/// Handle `stream` on a thread of its own.
+fn spawn_connection<B: 'static + Backend, C: Connection>(stream: C, thread_name: String, tracker: Arc<Tracker<B>>, delimiter: Arc<Vec<u8>>, rate_limit: Option<RateLimit>) {
+    let spawned = thread::Builder::new().name(thread_name).spawn(move|| {
+        let peer = stream.peer();
+        info!("New connection from {}", peer);
+        tracker.stats().connection_opened();
+        match handle_connection(stream, tracker.clone(), &delimiter, rate_limit) {
+            Ok(_) => {},
+            Err(e) => {
+                error!("Error handling connection from {}: {}", peer, e);
+            }
+        }
+        tracker.stats().connection_closed();
+        info!("Shutting down connection from {}", peer);
+    });
+
+    if let Err(e) = spawned {
+        error!("Couldn't start a thread for a connection: {}", e);
+    }
+}
+
+fn handle_connection<B: Backend, C: Connection>(mut writer: C, tracker: Arc<Tracker<B>>, delimiter: &[u8], rate_limit: Option<RateLimit>) -> Result<(), io::Error> {
+    let mut reader = BufReader::new(try!(writer.try_clone()));
+    let max_line_length = tracker.max_line_length();
+    let mut bucket = rate_limit.map(TokenBucket::new);
+
+    loop {
+        // Read at most one byte more than the limit (plus the
+        // delimiter), so we can tell a line which is exactly the limit
+        // from one which is over it.
+        let mut line = Vec::new();
+        let read = try!(read_line(&mut reader, delimiter, max_line_length + delimiter.len() + 1, &mut line));
+        if read == 0 { break; }
+
+        if line.ends_with(delimiter) {
+            let len = line.len() - delimiter.len();
+            line.truncate(len);
+        } else if delimiter == &b"\r\n"[..] && line.last() == Some(&b'\n') {
+            line.pop();
+        }
+
+        if line.len() > max_line_length {
+            warn!("Request line from {} is longer than {} bytes, closing connection",
+                  writer.peer(), max_line_length);
+            let rendered = tracker.render_response(&Err(MogError::RequestTooLong));
+            try!(write_line(&mut writer, &rendered, delimiter));
+            break;
+        }
 
-    for line in reader.split(b'\n') {
-        let mut line = try!(line);
         debug!("request line = {:?}", String::from_utf8_lossy(&line));
-        if line.last() == Some(&b'\r') { line.pop(); }
-        let response = tracker.handle_bytes(line.as_ref());
-
-        // Despite both arms being identical, I have to break it out
-        // because the result itself is not Renderable.
-        let rendered = match response {
-            Ok(resp) => resp.render(),
-            Err(e) => e.render(),
+
+        if let Some(ref mut bucket) = bucket {
+            if !bucket.take() {
+                debug!("Rate limiting request from {}", writer.peer());
+                tracker.stats().count_error(&MogError::RateLimited);
+                let rendered = tracker.render_response(&Err(MogError::RateLimited));
+                try!(write_line(&mut writer, &rendered, delimiter));
+                continue;
+            }
+        }
+
+        // If a handler panics, log the request that did it and close
+        // just this connection, rather than leaving the default panic
+        // message as the only trace of it.
+        let rendered = match panic::catch_unwind(AssertUnwindSafe(|| tracker.handle_line(line.as_ref()))) {
+            Ok(rendered) => rendered,
+            Err(_) => {
+                error!("Panic handling request {:?} from {}, closing connection",
+                       String::from_utf8_lossy(&line), writer.peer());
+                break;
+            },
         };
 
         debug!("response line = {:?}", rendered);
-        try!(write!(writer, "{}\r\n", rendered));
+        try!(write_line(&mut writer, &rendered, delimiter));
     }
 
     Ok(())
 }
+
+/// A token bucket: it fills at the limit's rate, up to its burst
+/// size, and each request takes a token.
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    filled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            limit: limit,
+            tokens: limit.burst,
+            filled_at: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.filled_at);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        self.tokens = (self.tokens + secs * self.limit.per_sec).min(self.limit.burst);
+        self.filled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Read up to `limit` bytes into `buf`, stopping after `delimiter`
+/// (or, since a bare LF is tolerated with CRLF, after a LF).
+fn read_line<R: BufRead>(reader: &mut R, delimiter: &[u8], limit: usize, buf: &mut Vec<u8>) -> io::Result<usize> {
+    let last = *delimiter.last().unwrap_or(&b'\n');
+    let mut read = 0;
+
+    loop {
+        let n = try!(reader.by_ref().take((limit - buf.len()) as u64).read_until(last, buf));
+        read += n;
+
+        let tolerated_lf = delimiter == &b"\r\n"[..] && buf.last() == Some(&b'\n');
+        if n == 0 || buf.len() >= limit || buf.ends_with(delimiter) || tolerated_lf {
+            return Ok(read);
+        }
+    }
+}
+
+/// Write a response line, and flush it, so it isn't left sitting in a
+/// buffer while the client waits for it.
+fn write_line<W: Write>(writer: &mut W, line: &str, delimiter: &[u8]) -> io::Result<()> {
+    try!(writer.write_all(line.as_bytes()));
+    try!(writer.write_all(delimiter));
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use mogilefs_client::MogClient;
+    use mogilefs_common::{MogResult, Response};
+    #[cfg(unix)]
+    use mogilefs_common::MogError;
+    use mogilefs_common::requests::*;
+    #[cfg(unix)]
+    use std::env;
+    #[cfg(unix)]
+    use std::fs;
+    use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+    use std::net::TcpStream;
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use super::*;
+    use super::super::Tracker;
+    use super::super::super::super::mem::test_support::*;
+
+    #[test]
+    fn write_line_flushes() {
+        let mut writer = BufWriter::new(Vec::new());
+        write_line(&mut writer, "OK ", b"\r\n").unwrap();
+        assert_eq!(b"OK \r\n", &writer.get_ref()[..]);
+    }
+
+    #[test]
+    fn lf_delimited_round_trip() {
+        let mut listener = ThreadedListener::new("127.0.0.1:0", Tracker::new(sync_backend_fixture())).unwrap();
+        listener.set_delimiter(b"\n");
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "noop\n").unwrap();
+        let mut response = Vec::new();
+        BufReader::new(stream).read_until(b'\n', &mut response).unwrap();
+        assert_eq!(b"OK \n".to_vec(), response);
+
+        let mut client = MogClient::new(&[ addr ]);
+        client.set_delimiter(b"\n");
+        let response = client.request(&FileInfo { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() });
+        assert!(matches!(response, Ok(Response::FileInfo(..))), "Response was {:?}", response);
+    }
+
+    #[test]
+    fn panicking_handler_closes_connection() {
+        let mut tracker = Tracker::new(sync_backend_fixture());
+        tracker.register("explode", |_, _| -> MogResult<Response> { panic!("kaboom") });
+        let mut listener = ThreadedListener::new("127.0.0.1:0", tracker).unwrap();
+        listener.set_thread_name_prefix("test-conn");
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "explode\r\n").unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty(), "Response was {:?}", String::from_utf8_lossy(&response));
+
+        // The listener is still there for everyone else.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "noop\r\n").unwrap();
+        let mut response = Vec::new();
+        BufReader::new(stream).read_until(b'\n', &mut response).unwrap();
+        assert_eq!(b"OK \r\n".to_vec(), response);
+    }
+
+    #[test]
+    fn disallowed_peers_are_closed() {
+        let mut listener = ThreadedListener::new("127.0.0.1:0", Tracker::new(sync_backend_fixture())).unwrap();
+        listener.set_allowed_ips(vec![ "10.0.0.1".parse().unwrap() ]);
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let _ = write!(stream, "noop\r\n");
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        assert!(response.is_empty(), "Response was {:?}", String::from_utf8_lossy(&response));
+
+        let mut listener = ThreadedListener::new("127.0.0.1:0", Tracker::new(sync_backend_fixture())).unwrap();
+        listener.set_allowed_ips(vec![ "10.0.0.1".parse().unwrap(), "127.0.0.1".parse().unwrap() ]);
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "noop\r\n").unwrap();
+        let mut response = Vec::new();
+        BufReader::new(stream).read_until(b'\n', &mut response).unwrap();
+        assert_eq!(b"OK \r\n".to_vec(), response);
+    }
+
+    #[test]
+    fn request_line_too_long() {
+        let mut tracker = Tracker::new(sync_backend_fixture());
+        tracker.set_max_line_length(16);
+        let listener = ThreadedListener::new("127.0.0.1:0", tracker).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.run());
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut resp = String::new();
+
+        write!(writer, "noop\r\n").unwrap();
+        reader.read_line(&mut resp).unwrap();
+        assert_eq!("OK \r\n", resp);
+        resp.clear();
+
+        // A bare LF ends a line too, and doesn't count towards its
+        // length, so this is exactly the limit.
+        write!(writer, "noop trace_id=ab\n").unwrap();
+        reader.read_line(&mut resp).unwrap();
+        assert!(resp.starts_with("OK "), "Response was {:?}", resp);
+        resp.clear();
+
+        write!(writer, "file_info domain=test_domain&key=test/key/1\r\n").unwrap();
+        reader.read_line(&mut resp).unwrap();
+        assert!(resp.starts_with("ERR request_too_long "), "Response was {:?}", resp);
+        resp.clear();
+
+        // And then the connection is closed.
+        assert_eq!(0, reader.read_line(&mut resp).unwrap_or(0));
+    }
+
+    #[test]
+    fn rate_limited_requests_are_rejected() {
+        let mut listener = ThreadedListener::new("127.0.0.1:0", Tracker::new(sync_backend_fixture())).unwrap();
+        listener.set_rate_limit(1.0, 2);
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "noop\r\nnoop\r\nnoop\r\nnoop\r\nnoop\r\n").unwrap();
+
+        // Every request gets an answer, so the connection's still
+        // open, but only the burst's worth get through.
+        let mut reader = BufReader::new(stream);
+        let mut responses = vec![];
+        for _ in 0..5 {
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            responses.push(response);
+        }
+        assert_eq!("OK \r\n", responses[0]);
+        assert_eq!("OK \r\n", responses[1]);
+        assert!(responses[2..].iter().any(|r| r.starts_with("ERR rate_limited ")), "Responses were {:?}", responses);
+    }
+
+    #[test]
+    fn backend_is_reachable_while_running() {
+        let listener = ThreadedListener::new("127.0.0.1:0", Tracker::new(sync_backend_fixture())).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tracker = listener.tracker();
+        thread::spawn(move || listener.run());
+
+        let mut client = MogClient::new(&[ addr ]);
+        let devids = |client: &mut MogClient| client.get_paths_with_devs(TEST_DOMAIN.to_string(), TEST_KEY_1.to_string(), None).unwrap()
+            .into_iter().map(|p| p.devid).collect::<Vec<_>>();
+        assert_eq!(vec![ Some(1) ], devids(&mut client));
+
+        tracker.backend().set_device_health(7, 10).unwrap();
+        assert_eq!(vec![ Some(7) ], devids(&mut client));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_round_trip() {
+        let path = env::temp_dir().join(format!("mogtracker-test-{}.sock", ::time::precise_time_ns()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixThreadedListener::new(&path, Tracker::new(sync_backend_fixture())).unwrap();
+        thread::spawn(move || listener.run());
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        write!(stream, "noop\r\n").unwrap();
+        let mut response = Vec::new();
+        BufReader::new(stream).read_until(b'\n', &mut response).unwrap();
+        assert_eq!(b"OK \r\n".to_vec(), response);
+
+        let mut client = MogClient::new_unix(&path);
+        let response = client.request(&FileInfo { domain: TEST_DOMAIN.to_string(), key: TEST_KEY_1.to_string() });
+        assert!(matches!(response, Ok(Response::FileInfo(..))), "Response was {:?}", response);
+        assert!(client.is_connected());
+        assert_eq!(None, client.peer_addr());
+
+        // The same connection's used for the next request.
+        let response = client.request(&Noop);
+        assert!(matches!(response, Ok(Response::Empty)), "Response was {:?}", response);
+
+        // There's no other tracker to send a request to, and trying
+        // doesn't hang up on this one.
+        let response = client.request_to("127.0.0.1:7001".parse().unwrap(), &Noop);
+        assert!(matches!(response, Err(MogError::Other(ref code, _)) if code == "unknown_tracker"), "Response was {:?}", response);
+        assert!(client.is_connected());
+
+        // The listener's thread never lets go of it, so nothing else
+        // will clean up after it.
+        fs::remove_file(&path).unwrap();
+    }
+}
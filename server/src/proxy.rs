@@ -4,7 +4,7 @@
 
 use mogilefs_client::MogClient;
 use mogilefs_common::requests::*;
-use mogilefs_common::{Backend, Request, MogError, MogResult};
+use mogilefs_common::{Backend, Request, Response, MogError, MogResult};
 use std::any::Any;
 use std::cell::RefCell;
 use std::net::SocketAddr;
@@ -27,6 +27,12 @@ impl ProxyTrackerBackend {
     }
 
     fn send_request<Req: Request + ?Sized, Res: Any>(&self, req: &Req) -> MogResult<Res> {
+        self.send_raw_request(req).and_then(|response| {
+            response.downcast::<Res>().ok_or(MogError::BadResponse)
+        })
+    }
+
+    fn send_raw_request<Req: Request + ?Sized>(&self, req: &Req) -> MogResult<Response> {
         CONNECTION.with(|conn_cell| {
             let mut conn_opt = conn_cell.borrow_mut();
 
@@ -39,9 +45,7 @@ impl ProxyTrackerBackend {
             debug!("Sending request {:?} to {:?}", req, conn.peer_addr());
             let response_rslt = conn.request(req);
             debug!("Got response {:?} from {:?}", response_rslt, conn.peer_addr());
-            response_rslt.and_then(|response| {
-                response.downcast::<Res>().ok_or(MogError::BadResponse)
-            })
+            response_rslt
         })
     }
 }
@@ -59,6 +63,11 @@ impl Backend for ProxyTrackerBackend {
         self.send_request(req)
     }
 
+    fn create_cancel(&self, req: &CreateCancel) -> MogResult<()> {
+        // Only our trackers know this one.
+        self.send_request(req)
+    }
+
     fn create_class(&self, req: &CreateClass) -> MogResult<CreateClassResponse> {
         self.send_request(req)
     }
@@ -72,16 +81,44 @@ impl Backend for ProxyTrackerBackend {
     }
     
     fn delete(&self, req: &Delete) -> MogResult<()> {
-        self.send_request(req)
+        self.delete_counted(req).map(|_| ())
+    }
+
+    fn delete_counted(&self, req: &Delete) -> MogResult<DeleteResponse> {
+        // Real trackers don't report a count, but proxying to one of
+        // ours will.
+        match try!(self.send_raw_request(req)) {
+            Response::Delete(r) => Ok(r),
+            Response::Empty => Ok(DeleteResponse { deleted: 1 }),
+            _ => Err(MogError::BadResponse),
+        }
     }
 
     fn rename(&self, req: &Rename) -> MogResult<()> {
         self.send_request(req)
     }
 
+    fn update_class(&self, req: &UpdateClass) -> MogResult<()> {
+        self.send_request(req)
+    }
+
     fn list_keys(&self, req: &ListKeys) -> MogResult<ListKeysResponse> {
         self.send_request(req)
     }
+
+    fn locate(&self, req: &Locate) -> MogResult<LocateResponse> {
+        // Only our trackers know this one.
+        self.send_request(req)
+    }
+
+    fn list_keys_since(&self, req: &ListKeysSince) -> MogResult<ListKeysSinceResponse> {
+        // Nor this one.
+        self.send_request(req)
+    }
+
+    fn list_all_keys(&self, req: &ListAllKeys) -> MogResult<ListAllKeysResponse> {
+        self.send_request(req)
+    }
 }
 
 #[cfg(test)]
@@ -76,6 +76,10 @@ impl Modifier<Response> for RangeModifier {
         if orig_body.is_err() { return; }
         let orig_body = orig_body.unwrap();
 
+        // There's no satisfiable range of an empty body, so just send
+        // back the (empty) whole thing.
+        if orig_body.is_empty() { return; }
+
         debug!("Limiting response to range: {:?} of {:?}", range, orig_body.len());
 
         response.status = Some(Status::PartialContent);
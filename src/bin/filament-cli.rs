@@ -12,7 +12,7 @@ extern crate url;
 use docopt::Docopt;
 use filament::util::SocketAddrList;
 use mogilefs_client::MogClient;
-use mogilefs_common::MogError;
+use mogilefs_common::{MogError, ToResponse};
 use mogilefs_common::requests::*;
 use rustc_serialize::{Decodable, Decoder};
 use url::Url;
@@ -47,6 +47,8 @@ pub fn main() {
             key: opts.arg_key.expect("No key provided."),
             multi_dest: opts.flag_multi_dest,
             size: opts.flag_size,
+            if_absent: opts.flag_if_absent,
+            idempotency_token: None,
         })
     } else if opts.cmd_create_close {
         client.request(&CreateClose {
@@ -92,22 +94,40 @@ pub fn main() {
             domain: opts.arg_domain.expect("No domain provided."),
             from_key: opts.arg_from_key.expect("No source key provided."),
             to_key: opts.arg_to_key.expect("No destination key provided."),
+            overwrite: opts.flag_overwrite,
         })
+    } else if opts.cmd_copy {
+        client.copy(opts.arg_domain.expect("No domain provided."),
+                    opts.arg_from_key.expect("No source key provided."),
+                    opts.arg_to_key.expect("No destination key provided."))
     } else if opts.cmd_update_class {
         client.request(&UpdateClass {
             domain: opts.arg_domain.expect("No domain provided."),
             key: opts.arg_key.expect("No key provided."),
             new_class: opts.arg_new_class.expect("No class name provided."),
         })
+    } else if opts.cmd_replicate {
+        client.request(&Replicate {
+            domain: opts.arg_domain.expect("No domain provided."),
+            key: opts.arg_key.expect("No key provided."),
+        })
     } else if opts.cmd_list_keys {
         client.request(&ListKeys {
             domain: opts.arg_domain.expect("No domain provided."),
             prefix: opts.flag_prefix,
             after: opts.flag_after,
             limit: opts.flag_limit,
+            order: opts.flag_order.map(|o| o.parse().expect("Unknown key order.")).unwrap_or(KeyOrder::Lexicographic),
+            with_sizes: false,
         })
     } else if opts.cmd_noop {
         client.request(&Noop)
+    } else if opts.cmd_server_version {
+        client.request(&ServerVersion)
+    } else if opts.cmd_get_stats {
+        client.request(&GetStats)
+    } else if opts.cmd_usage {
+        client.usage().map(|rows| UsageResponse(rows).to_response())
     } else {
         Err(MogError::Other(format!("No command provided?!?"), None))
     };
@@ -123,15 +143,20 @@ A command-line tool for querying a MogileFS system.
 
 Usage:
   filament-cli [options] create-domain <domain>
-  filament-cli [options] create-open <domain> <key> [--class=STRING --multi-dest --size=N]
+  filament-cli [options] create-open <domain> <key> [--class=STRING --multi-dest --size=N --if-absent]
   filament-cli [options] create-close <domain> <key> <fid> <devid> <path> [--checksum=STRING]
   filament-cli [options] create-class <domain> <class> <mindevcount> [--replpolicy=STRING --hashtype=STRING --update]
   filament-cli [options] file-info <domain> <key>
   filament-cli [options] get-paths <domain> <key> [--no-verify --path-count=N]
-  filament-cli [options] rename <domain> <from-key> <to-key>
+  filament-cli [options] rename <domain> <from-key> <to-key> [--overwrite]
+  filament-cli [options] copy <domain> <from-key> <to-key>
   filament-cli [options] update-class <domain> <key> <new-class>
-  filament-cli [options] list-keys <domain> [--prefix=PREFIX --after=AFTER --limit=N]
+  filament-cli [options] replicate <domain> <key>
+  filament-cli [options] list-keys <domain> [--prefix=PREFIX --after=AFTER --limit=N --order=ORDER]
   filament-cli [options] noop
+  filament-cli [options] server-version
+  filament-cli [options] get-stats
+  filament-cli [options] usage
   filament-cli (-h | --help | -v | --version)
 
 General Options:
@@ -146,13 +171,16 @@ struct Options {
     flag_class: Option<String>,
     flag_multi_dest: bool,
     flag_size: Option<u64>,
+    flag_if_absent: bool,
     flag_checksum: Option<String>,
     flag_replpolicy: Option<String>,
     flag_hashtype: Option<String>,
     flag_update: bool,
+    flag_overwrite: bool,
     flag_prefix: Option<String>,
     flag_after: Option<String>,
     flag_limit: Option<u64>,
+    flag_order: Option<String>,
     flag_no_verify: bool,
     flag_path_count: Option<u64>,
 
@@ -174,7 +202,12 @@ struct Options {
     cmd_file_info: bool,
     cmd_get_paths: bool,
     cmd_rename: bool,
+    cmd_copy: bool,
     cmd_update_class: bool,
+    cmd_replicate: bool,
     cmd_list_keys: bool,
     cmd_noop: bool,
+    cmd_server_version: bool,
+    cmd_get_stats: bool,
+    cmd_usage: bool,
 }
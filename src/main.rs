@@ -16,8 +16,8 @@ extern crate url;
 use docopt::Docopt;
 use filament_ext::{MyOpts, AlternateFinderBackend, PublicFinder, SongFinder};
 use iron::{Chain, Iron, Protocol};
-use mogilefs_common::{BackendStack, AroundMiddleware};
-use mogilefs_server::mem::{MemBackend, SyncMemBackend};
+use mogilefs_common::{BackendStack, AroundMiddleware, ResponseFormat};
+use mogilefs_server::mem::{Compression, MemBackend, SyncMemBackend};
 use mogilefs_server::net::storage::StorageHandler;
 use mogilefs_server::net::tracker::Tracker;
 use mogilefs_server::proxy::ProxyTrackerBackend;
@@ -62,7 +62,12 @@ fn main() {
     });
 
     let tracker = if opts.cmd_mem_tracker {
-        let backend = SyncMemBackend::new(MemBackend::new(opts.flag_base_url.clone()));
+        let mut mem_backend = MemBackend::new(opts.flag_base_url.clone());
+        mem_backend.set_dedup(opts.flag_dedup_content);
+        if opts.flag_compress_content {
+            mem_backend.set_compression(Compression::Gzip);
+        }
+        let backend = SyncMemBackend::new(mem_backend);
         let stack = BackendStack::new(backend.clone());
 
         let storage_addr = opts.flag_storage_ip.0.clone();
@@ -117,6 +122,14 @@ fn main() {
         None
     };
 
+    let tracker = tracker.map(|mut t| {
+        if opts.flag_json_responses {
+            t.set_response_format(ResponseFormat::Json);
+        }
+        t.set_max_line_length(opts.flag_max_line_length);
+        t
+    });
+
     match (tracker, &opts.flag_tracker_io) {
         (Some(tracker), &TrackerIoType::Evented) => run_evented(&opts, tracker),
         (Some(tracker), &TrackerIoType::Threaded) => run_threaded(&opts, tracker),
@@ -176,6 +189,8 @@ General Tracker Options:
   -t N, --tracker-threads=N  How many tracker threads to run.          [default: 4]
   -i T, --tracker-io=T       Which I/O model the tracker should use.   [default: Evented]
                              (can be Threaded or Evented)
+  --json-responses           Render tracker responses as JSON objects.
+  --max-line-length=N        The longest request line to accept, in bytes. [default: 65536]
 
 General Storage Options:
   --storage-ip=IP            The ip:port for the storage server to listen on. [default: 0.0.0.0:7503]
@@ -193,6 +208,8 @@ Database Options:
 
 In-Memory Tracker (mem-tracker) Options:
   (all General Tracker Options and General Storage Options supported)
+  --dedup-content            Store identical file content only once.
+  --compress-content         Hold file content gzipped in memory.
 
 Proxy Tracker (proxy-tracker) Options:
   (all General Tracker Options and Database Options supported)
@@ -213,10 +230,14 @@ struct Options {
     flag_tracker_ip: WrapSocketAddr,
     flag_tracker_threads: usize,
     flag_tracker_io: TrackerIoType,
+    flag_json_responses: bool,
+    flag_max_line_length: usize,
 
     flag_storage_ip: WrapSocketAddr,
     flag_storage_threads: usize,
     flag_base_url: Url,
+    flag_dedup_content: bool,
+    flag_compress_content: bool,
 
     flag_db_host: Option<WrapSocketAddr>,
     flag_db_user: String,